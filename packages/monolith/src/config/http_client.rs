@@ -0,0 +1,28 @@
+use serde::{Deserialize, Serialize};
+
+use super::env_var_or;
+
+/// Config for the HTTP clients used for all outbound requests (see `HttpClientFactory`):
+/// - `connect_timeout_secs`: How long to wait for a connection to be established
+/// - `request_timeout_secs`: How long a single request may take in total, including reading its response (0 disables this)
+/// - `pool_idle_timeout_secs`: How long an idle pooled connection is kept open (0 keeps them open indefinitely)
+/// - `pool_max_idle_per_host`: The max number of idle pooled connections kept open to each host
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct HttpClientConfig {
+    pub connect_timeout_secs: u64,
+    pub request_timeout_secs: u64,
+    pub pool_idle_timeout_secs: u64,
+    pub pool_max_idle_per_host: usize
+}
+
+impl HttpClientConfig {
+    /// Load the config from env vars, falling back to defaults for any which are missing.
+    pub(super) fn load() -> Self {
+        Self {
+            connect_timeout_secs: env_var_or("HTTP_CONNECT_TIMEOUT_SECS", 10),
+            request_timeout_secs: env_var_or("HTTP_REQUEST_TIMEOUT_SECS", 300),
+            pool_idle_timeout_secs: env_var_or("HTTP_POOL_IDLE_TIMEOUT_SECS", 90),
+            pool_max_idle_per_host: env_var_or("HTTP_POOL_MAX_IDLE_PER_HOST", 32)
+        }
+    }
+}
@@ -1,11 +1,56 @@
-use std::env::{self, VarError};
+use std::{env::{self, VarError}, str::FromStr};
 
 use serde::{Deserialize, Serialize};
 
-/// Config for the image classifier module.
+use super::env_var_or;
+
+/// Config for the image classifier module:
+/// - `embedder_endpoint`: The endpoint for embedding item descriptions and images
+/// - `max_concurrent_galleries`: The max number of galleries being embedded at once
+/// - `max_concurrent_image_downloads`: The max number of item images being downloaded at once, across all galleries
+/// - `max_image_bytes`: The max size of a downloaded image; items whose image is larger are skipped
+/// - `min_images`: The min number of images an item must have to be embedded; items with fewer are skipped
+/// - `transcode_images`: Whether images in formats the embedder can't ingest (ie WebP or AVIF) are transcoded; if not, their items are skipped
+/// - `transcode_format`: The format images are transcoded (or re-encoded after downscaling) into
+/// - `max_image_dimension`: The max width/height of an image sent to the embedder; larger images are downscaled (0 disables this)
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct ItemEmbedderConfig {
-    pub embedder_endpoint: String
+    pub embedder_endpoint: String,
+    pub max_concurrent_galleries: usize,
+    pub max_concurrent_image_downloads: usize,
+    pub max_image_bytes: usize,
+    pub min_images: usize,
+    pub transcode_images: bool,
+    pub transcode_format: TranscodeFormat,
+    pub max_image_dimension: u32
+}
+
+/// A format that the embedder can ingest, which other images are transcoded into.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TranscodeFormat {
+    Jpeg,
+    Png
+}
+
+impl TranscodeFormat {
+    /// The equivalent format in the `image` crate.
+    pub fn image_format(&self) -> image::ImageFormat {
+        match self {
+            TranscodeFormat::Jpeg => image::ImageFormat::Jpeg,
+            TranscodeFormat::Png => image::ImageFormat::Png
+        }
+    }
+}
+
+impl FromStr for TranscodeFormat {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "jpeg" | "jpg" => Ok(TranscodeFormat::Jpeg),
+            "png" => Ok(TranscodeFormat::Png),
+            other => Err(format!("Unknown transcode format: {other}"))
+        }
+    }
 }
 
 impl ItemEmbedderConfig {
@@ -14,7 +59,14 @@ impl ItemEmbedderConfig {
         Ok(
             ItemEmbedderConfig {
                 embedder_endpoint: env::var("EMBEDDER_ENDPOINT")?,
+                max_concurrent_galleries: env_var_or("ITEM_EMBEDDER_MAX_CONCURRENT_GALLERIES", 2),
+                max_concurrent_image_downloads: env_var_or("ITEM_EMBEDDER_MAX_CONCURRENT_IMAGE_DOWNLOADS", 8),
+                max_image_bytes: env_var_or("ITEM_EMBEDDER_MAX_IMAGE_BYTES", 10 * 1024 * 1024),
+                min_images: env_var_or("ITEM_EMBEDDER_MIN_IMAGES", 1),
+                transcode_images: env_var_or("ITEM_EMBEDDER_TRANSCODE_IMAGES", true),
+                transcode_format: env_var_or("ITEM_EMBEDDER_TRANSCODE_FORMAT", TranscodeFormat::Png),
+                max_image_dimension: env_var_or("ITEM_EMBEDDER_MAX_IMAGE_DIMENSION", 1024)
             }
         )
     }
-}
\ No newline at end of file
+}
@@ -1,34 +1,216 @@
-use std::env::{self, VarError};
+use std::{collections::HashMap, env::{self, VarError}, str::FromStr};
 
 use serde::{Deserialize, Serialize};
 
+use crate::galleries::eval_criteria::EvaluationCriteria;
+
+use super::{env_var_list, env_var_or};
+
 /// Config for the item analysis module.
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct ItemAnalysisConfig {
+    // The LLM provider used for analysis.
+    pub provider: AnalysisProviderKind,
+    // Providers tried in order if the primary provider is unavailable (ie times out, or returns a 429/5xx) for a marketplace.
+    // Errors caused by the items themselves (ie unparseable answers) don't fall through.
+    pub fallback_providers: Vec<AnalysisProviderKind>,
     // These are used for accessing the Anthropic API.
+    // Each provider's timeout bounds the analysis of a single marketplace's items.
     pub anthropic_api_endpoint: String,
     pub anthropic_api_key: String,
     pub anthropic_model: String,
     pub anthropic_version: String,
+    pub anthropic_timeout_secs: u64,
     // These are used for accessing the OpenAI API.
     pub openai_api_endpoint: String,
     pub openai_api_key: String,
-    pub openai_model: String
+    pub openai_model: String,
+    pub openai_timeout_secs: u64,
+    // These are used for accessing the Gemini API.
+    pub gemini_api_endpoint: String,
+    pub gemini_api_key: String,
+    pub gemini_model: String,
+    pub gemini_timeout_secs: u64,
+    // The max number of galleries being analyzed at once.
+    pub max_concurrent_galleries: usize,
+    // The price of each model's tokens, for estimating the cost of analysis.
+    pub model_prices: HashMap<String, ModelPrice>,
+    // Whether to constrain the provider's output to a JSON schema (OpenAI structured outputs/Anthropic tool use),
+    // instead of parsing it from free text; providers which don't support it always use free text.
+    pub structured_output: bool,
+    // How strictly free-text answers are parsed; lenient parsing recovers answers wrapped in prose or markdown code fences.
+    pub parse_strictness: AnalysisParseStrictness,
+    // If set, fills the unset fields of the evaluation criteria of newly created galleries.
+    pub default_evaluation_criteria: Option<EvaluationCriteria>,
+    // How long an unchanged item's analysis is reused for, instead of re-analyzing it; 0 disables caching.
+    pub cache_ttl_secs: u64,
+    // How often items which failed analysis are retried from the retry queue; 0 disables the retry queue.
+    pub retry_queue_interval_secs: u64,
+    // How many times items are retried before their marketplace is recorded as failed.
+    pub retry_queue_max_attempts: u32,
+    // The models which a gallery's evaluation criteria may use instead of the primary provider's model; if empty, no overrides are allowed.
+    pub allowed_model_overrides: Vec<String>
+}
+
+/// The price of a model's tokens, in USD per token.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+pub struct ModelPrice {
+    pub prompt_price: f64,
+    pub completion_price: f64
+}
+
+/// The LLM providers available for item analysis.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AnalysisProviderKind {
+    Anthropic,
+    OpenAI,
+    Gemini
+}
+
+impl AnalysisProviderKind {
+    /// The provider's name, as used in the config.
+    pub fn name(&self) -> &'static str {
+        match self {
+            AnalysisProviderKind::Anthropic => "anthropic",
+            AnalysisProviderKind::OpenAI => "openai",
+            AnalysisProviderKind::Gemini => "gemini"
+        }
+    }
+}
+
+impl FromStr for AnalysisProviderKind {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "anthropic" => Ok(AnalysisProviderKind::Anthropic),
+            "openai" => Ok(AnalysisProviderKind::OpenAI),
+            "gemini" => Ok(AnalysisProviderKind::Gemini),
+            other => Err(format!("Unknown analysis provider: {other}"))
+        }
+    }
+}
+
+/// How strictly the LLM's answers are parsed.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AnalysisParseStrictness {
+    /// The whole response must be clean JSON.
+    Strict,
+    /// If the response isn't clean JSON, the first valid JSON object in it is parsed instead (ie inside a markdown code fence, or surrounded by prose).
+    Lenient
+}
+
+impl FromStr for AnalysisParseStrictness {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "strict" => Ok(AnalysisParseStrictness::Strict),
+            "lenient" => Ok(AnalysisParseStrictness::Lenient),
+            other => Err(format!("Unknown analysis parse strictness: {other}"))
+        }
+    }
 }
 
 impl ItemAnalysisConfig {
     /// Load the config from env vars. Returns a `VarError` if any are missing.
     pub(super) fn load() -> Result<Self, VarError> {
+        let provider = env_var_or("ANALYSIS_PROVIDER", AnalysisProviderKind::Anthropic);
         Ok(
             ItemAnalysisConfig {
+                provider,
+                fallback_providers: load_fallback_providers(provider),
                 anthropic_api_endpoint: env::var("ANTHROPIC_API_ENDPOINT")?,
                 anthropic_api_key: env::var("ANTHROPIC_API_KEY")?,
                 anthropic_model: env::var("ANTHROPIC_MODEL")?,
                 anthropic_version: env::var("ANTHROPIC_VERSION")?,
+                anthropic_timeout_secs: env_var_or("ANTHROPIC_TIMEOUT_SECS", 600),
                 openai_api_endpoint: env::var("OPENAI_API_ENDPOINT")?,
                 openai_api_key: env::var("OPENAI_API_KEY")?,
                 openai_model: env::var("OPENAI_MODEL")?,
+                openai_timeout_secs: env_var_or("OPENAI_TIMEOUT_SECS", 600),
+                gemini_api_endpoint: env_var_or("GEMINI_API_ENDPOINT", "https://generativelanguage.googleapis.com/v1beta/models".into()),
+                gemini_api_key: env::var("GEMINI_API_KEY").unwrap_or_default(),
+                gemini_model: env_var_or("GEMINI_MODEL", "gemini-2.0-flash".into()),
+                gemini_timeout_secs: env_var_or("GEMINI_TIMEOUT_SECS", 600),
+                max_concurrent_galleries: env_var_or("ITEM_ANALYSIS_MAX_CONCURRENT_GALLERIES", 2),
+                model_prices: load_model_prices(),
+                structured_output: env_var_or("ANALYSIS_STRUCTURED_OUTPUT", false),
+                parse_strictness: env_var_or("ANALYSIS_PARSE_STRICTNESS", AnalysisParseStrictness::Strict),
+                default_evaluation_criteria: load_default_evaluation_criteria(),
+                cache_ttl_secs: env_var_or("ANALYSIS_CACHE_TTL_SECS", 86400),
+                retry_queue_interval_secs: env_var_or("ANALYSIS_RETRY_QUEUE_INTERVAL_SECS", 600),
+                retry_queue_max_attempts: env_var_or("ANALYSIS_RETRY_QUEUE_MAX_ATTEMPTS", 3),
+                allowed_model_overrides: env_var_list("ANALYSIS_ALLOWED_MODEL_OVERRIDES"),
             }
         )
     }
+
+    /// Returns the model used by a provider.
+    pub fn model_for(&self, provider: AnalysisProviderKind) -> &str {
+        match provider {
+            AnalysisProviderKind::Anthropic => &self.anthropic_model,
+            AnalysisProviderKind::OpenAI => &self.openai_model,
+            AnalysisProviderKind::Gemini => &self.gemini_model,
+        }
+    }
+}
+
+/// Load the fallback providers, formatted as provider names separated by commas, in the order they're tried.
+/// 
+/// Unknown providers, the primary provider and duplicates are skipped.
+fn load_fallback_providers(primary: AnalysisProviderKind) -> Vec<AnalysisProviderKind> {
+    let mut providers = vec![];
+    for name in env_var_list("ANALYSIS_FALLBACK_PROVIDERS") {
+        match name.parse::<AnalysisProviderKind>() {
+            Ok(provider) if provider != primary && !providers.contains(&provider) => providers.push(provider),
+            Ok(_) => tracing::warn!("Fallback provider ({name}) in ANALYSIS_FALLBACK_PROVIDERS is the primary provider or a duplicate; skipping it"),
+            Err(err) => tracing::warn!("{err} in ANALYSIS_FALLBACK_PROVIDERS; skipping it")
+        }
+    }
+    providers
+}
+
+/// Load the default evaluation criteria, formatted as the JSON of an `EvaluationCriteria`.
+/// 
+/// Returns `None` if it isn't set, or is unparseable/invalid.
+fn load_default_evaluation_criteria() -> Option<EvaluationCriteria> {
+    let json = env::var("ANALYSIS_DEFAULT_EVALUATION_CRITERIA")
+        .ok()
+        .filter(|json| !json.trim().is_empty())?;
+    let criteria = match serde_json::from_str::<EvaluationCriteria>(&json) {
+        Ok(criteria) => criteria,
+        Err(err) => {
+            tracing::warn!("Could not parse ANALYSIS_DEFAULT_EVALUATION_CRITERIA; not using default evaluation criteria: {err}");
+            return None;
+        }
+    };
+    if let Err(err) = criteria.validate() {
+        tracing::warn!("ANALYSIS_DEFAULT_EVALUATION_CRITERIA is invalid; not using default evaluation criteria: {err}");
+        return None;
+    }
+    Some(criteria)
+}
+
+/// Load the model prices, formatted as `model=prompt_price/completion_price` entries separated by commas.
+/// 
+/// Unparseable entries are skipped.
+fn load_model_prices() -> HashMap<String, ModelPrice> {
+    env_var_list("ANALYSIS_MODEL_PRICES")
+        .into_iter()
+        .filter_map(|entry| {
+            let parsed = entry
+                .split_once('=')
+                .and_then(|(model, prices)| {
+                    let (prompt_price, completion_price) = prices.split_once('/')?;
+                    let price = ModelPrice {
+                        prompt_price: prompt_price.trim().parse().ok()?,
+                        completion_price: completion_price.trim().parse().ok()?
+                    };
+                    Some((model.trim().to_string(), price))
+                });
+            if parsed.is_none() {
+                tracing::warn!("Could not parse model price ({entry}) in ANALYSIS_MODEL_PRICES; skipping it");
+            }
+            parsed
+        })
+        .collect()
 }
\ No newline at end of file
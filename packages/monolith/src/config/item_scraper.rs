@@ -1,18 +1,65 @@
-use std::env::VarError;
+use std::{collections::HashMap, env::VarError};
 use serde::{Deserialize, Serialize};
+use crate::{galleries::domain_types::Marketplace, utils::proxy::ProxyConfig};
 
-/// Config for the scraper scheduler module.
+use super::{env_marketplace_proxies, env_proxy, env_var_list, env_var_or};
+
+/// Config for the item scraper module:
+/// - `max_retries`: The max number of times a failed marketplace's items are re-scraped
+/// - `retry_base_delay_ms`: The delay before the first retry, doubled for each subsequent retry
+/// - `max_concurrent_galleries`: The max number of galleries being item-scraped at once
+/// - `breaker_failure_threshold`: The number of consecutive failed item scrapes after which a marketplace is short-circuited (0 disables this)
+/// - `breaker_cooldown_secs`: How long a marketplace is short-circuited for, before a trial item scrape is allowed
+/// - `user_agents`: The user agents rotated through for item requests (a default is used if empty)
+/// - `proxy`: The proxy that item requests are routed through, if any
+/// - `marketplace_proxies`: Proxies overriding `proxy` for specific marketplaces
+/// - `base_currency`: The currency that scraped items' prices are normalized into
+/// - `exchange_rate_api_endpoint`: The API returning exchange rates against the base currency (if empty, other currencies aren't normalized)
+/// - `exchange_rate_cache_ttl_secs`: How long fetched exchange rates are used before being refreshed
+/// - `item_refetch_ttl_secs`: Items already scraped in a gallery's run (ie before a restart) which were updated within this long are reused, rather than re-fetched (0 always re-fetches)
+/// - `message_buffer`: The max number of galleries queued for the item scraper; once full, the search scraper waits for room
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct ItemScraperConfig {
-
+    pub max_retries: u32,
+    pub retry_base_delay_ms: u64,
+    pub max_concurrent_galleries: usize,
+    pub breaker_failure_threshold: u32,
+    pub breaker_cooldown_secs: u64,
+    pub user_agents: Vec<String>,
+    pub proxy: Option<ProxyConfig>,
+    pub marketplace_proxies: HashMap<Marketplace, ProxyConfig>,
+    pub base_currency: String,
+    pub exchange_rate_api_endpoint: String,
+    pub exchange_rate_cache_ttl_secs: u64,
+    pub item_refetch_ttl_secs: u64,
+    pub message_buffer: usize
 }
 
 impl ItemScraperConfig {
+    /// Returns the proxy to use for a marketplace; its override if it has one, else the default proxy (if any).
+    pub fn proxy_for(&self, marketplace: &Marketplace) -> Option<&ProxyConfig> {
+        self.marketplace_proxies
+            .get(marketplace)
+            .or(self.proxy.as_ref())
+    }
+
     /// Load the config from env vars. Returns a `VarError` if any are missing.
     pub(super) fn load() -> Result<Self, VarError> {
         Ok(
             Self {
-
+                max_retries: env_var_or("ITEM_SCRAPER_MAX_RETRIES", 3),
+                retry_base_delay_ms: env_var_or("ITEM_SCRAPER_RETRY_BASE_DELAY_MS", 1000),
+                max_concurrent_galleries: env_var_or("ITEM_SCRAPER_MAX_CONCURRENT_GALLERIES", 4),
+                breaker_failure_threshold: env_var_or("ITEM_SCRAPER_BREAKER_FAILURE_THRESHOLD", 5),
+                breaker_cooldown_secs: env_var_or("ITEM_SCRAPER_BREAKER_COOLDOWN_SECS", 300),
+                user_agents: env_var_list("SCRAPER_USER_AGENTS"),
+                proxy: env_proxy("SCRAPER_PROXY"),
+                marketplace_proxies: env_marketplace_proxies(),
+                base_currency: env_var_or("BASE_CURRENCY", "JPY".to_string()),
+                exchange_rate_api_endpoint: env_var_or("EXCHANGE_RATE_API_ENDPOINT", String::new()),
+                exchange_rate_cache_ttl_secs: env_var_or("EXCHANGE_RATE_CACHE_TTL_SECS", 3600),
+                item_refetch_ttl_secs: env_var_or("ITEM_SCRAPER_ITEM_REFETCH_TTL_SECS", 0),
+                message_buffer: env_var_or("ITEM_SCRAPER_MESSAGE_BUFFER", 1000)
             }
         )
     }
@@ -1,5 +1,6 @@
-use std::env::{self, VarError};
+use std::{collections::HashMap, env::{self, VarError}, str::FromStr};
 use serde::{Deserialize, Serialize};
+use crate::{galleries::domain_types::Marketplace, utils::proxy::ProxyConfig};
 
 pub use item_analysis::ItemAnalysisConfig;
 pub use image_classifier::ItemEmbedderConfig;
@@ -8,6 +9,8 @@ pub use item_scraper::ItemScraperConfig;
 pub use scraper_scheduler::ScraperSchedulerConfig;
 use state_tracker::StateTrackerConfig;
 pub use storage::StorageConfig;
+pub use notification::NotificationConfig;
+pub use http_client::HttpClientConfig;
 
 pub mod state_tracker;
 pub mod scraper_scheduler;
@@ -16,6 +19,8 @@ pub mod item_scraper;
 pub mod item_analysis;
 pub mod image_classifier;
 pub mod storage;
+pub mod notification;
+pub mod http_client;
 
 /// Holds all types of configs for the app.
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -27,7 +32,9 @@ pub struct AppConfig {
     pub item_scraper_config: ItemScraperConfig,
     pub item_analysis_config: ItemAnalysisConfig,
     pub img_classifier_config: ItemEmbedderConfig,
-    pub storage_config: StorageConfig
+    pub storage_config: StorageConfig,
+    pub notification_config: NotificationConfig,
+    pub http_client_config: HttpClientConfig
 }
 
 impl AppConfig {
@@ -43,16 +50,139 @@ impl AppConfig {
                 item_analysis_config: ItemAnalysisConfig::load()?,
                 img_classifier_config: ItemEmbedderConfig::load()?,
                 storage_config: StorageConfig::load()?,
+                notification_config: NotificationConfig::load(),
+                http_client_config: HttpClientConfig::load(),
             }
         )
     }
 }
 
+/// Load and parse an optional env var, falling back to `default` if it's missing or unparseable.
+fn env_var_or<T: FromStr>(key: &str, default: T) -> T {
+    match env::var(key) {
+        Ok(val) => val
+            .parse()
+            .unwrap_or_else(|_| {
+                tracing::warn!("Could not parse env var {key} ({val}); using default value");
+                default
+            }),
+        Err(_) => default
+    }
+}
+
+/// Load an optional comma-separated env var as a list, ignoring empty entries.
+/// 
+/// Returns an empty list if it's missing.
+fn env_var_list(key: &str) -> Vec<String> {
+    env::var(key)
+        .unwrap_or_default()
+        .split(',')
+        .map(|val| val.trim().to_string())
+        .filter(|val| !val.is_empty())
+        .collect()
+}
+
+/// Load an optional proxy from the `{prefix}_URL`, `{prefix}_USERNAME` and `{prefix}_PASSWORD` env vars.
+/// 
+/// Returns `None` if the URL is missing or empty.
+fn env_proxy(prefix: &str) -> Option<ProxyConfig> {
+    let url = env::var(format!("{prefix}_URL")).ok()?;
+    if url.trim().is_empty() {
+        return None;
+    }
+    Some(ProxyConfig {
+        url: url.trim().to_string(),
+        username: env::var(format!("{prefix}_USERNAME")).ok().filter(|val| !val.is_empty()),
+        password: env::var(format!("{prefix}_PASSWORD")).ok().filter(|val| !val.is_empty())
+    })
+}
+
+/// Load the proxy overrides for each marketplace, from the `{MARKETPLACE}_PROXY_*` env vars (ie `MERCARI_PROXY_URL`).
+fn env_marketplace_proxies() -> HashMap<Marketplace, ProxyConfig> {
+    Marketplace::all()
+        .into_iter()
+        .filter_map(|marketplace| {
+            let prefix = format!("{}_PROXY", marketplace.to_string().to_uppercase());
+            env_proxy(&prefix).map(|proxy| (marketplace, proxy))
+        })
+        .collect()
+}
+
+/// The format states and items are serialized in when persisted; shared by the state tracker store and storage.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SerializationFormat {
+    Json,
+    MessagePack
+}
+
+impl SerializationFormat {
+    /// The file extension for files in this format.
+    pub fn extension(&self) -> &'static str {
+        match self {
+            SerializationFormat::Json => "json",
+            SerializationFormat::MessagePack => "msgpack"
+        }
+    }
+}
+
+impl FromStr for SerializationFormat {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "json" => Ok(SerializationFormat::Json),
+            "msgpack" | "messagepack" => Ok(SerializationFormat::MessagePack),
+            other => Err(format!("Unknown serialization format: {other}"))
+        }
+    }
+}
+
 /// Config for the top-level Axum app:
 /// - `host_addr`: The address the app will run on
+/// - `shutdown_timeout_secs`: How long to wait for in-flight galleries to finish on shutdown
+/// - `idempotency_key_ttl_secs`: How long an `Idempotency-Key` is remembered for on gallery creation
+/// - `idempotency_cache_capacity`: The max number of `Idempotency-Key`s remembered at once
+/// - `request_log_success_level`: The level requests with a successful (non-4xx/5xx) response are logged at
+/// - `request_log_error_level`: The level requests with a 4xx/5xx response are logged at
+/// - `api_tokens`: The bearer tokens accepted by the API; if empty, every request to an authenticated path is rejected
+/// - `auth_disabled`: Whether to let every request through without a bearer token; only meant for local development
+/// - `unauthenticated_paths`: Paths which don't require a bearer token (ie health and metrics)
+/// - `max_create_gallery_body_bytes`: The max request body size for creating galleries; larger bodies are rejected with a 413
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct AxumConfig {
-    pub host_addr: String
+    pub host_addr: String,
+    pub shutdown_timeout_secs: u64,
+    pub idempotency_key_ttl_secs: u64,
+    pub idempotency_cache_capacity: usize,
+    pub request_log_success_level: LogLevel,
+    pub request_log_error_level: LogLevel,
+    pub api_tokens: Vec<String>,
+    pub auth_disabled: bool,
+    pub unauthenticated_paths: Vec<String>,
+    pub max_create_gallery_body_bytes: usize
+}
+
+/// A log level which can be set in the config.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+pub enum LogLevel {
+    Trace,
+    Debug,
+    Info,
+    Warn,
+    Error
+}
+
+impl FromStr for LogLevel {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "trace" => Ok(LogLevel::Trace),
+            "debug" => Ok(LogLevel::Debug),
+            "info" => Ok(LogLevel::Info),
+            "warn" => Ok(LogLevel::Warn),
+            "error" => Ok(LogLevel::Error),
+            other => Err(format!("Unknown log level: {other}"))
+        }
+    }
 }
 
 impl AxumConfig {
@@ -60,7 +190,19 @@ impl AxumConfig {
     pub(super) fn load() -> Result<Self, VarError> {
         Ok(
             AxumConfig {
-                host_addr: env::var("HOST_ADDR")?
+                host_addr: env::var("HOST_ADDR")?,
+                shutdown_timeout_secs: env_var_or("SHUTDOWN_TIMEOUT_SECS", 60),
+                idempotency_key_ttl_secs: env_var_or("IDEMPOTENCY_KEY_TTL_SECS", 86400),
+                idempotency_cache_capacity: env_var_or("IDEMPOTENCY_CACHE_CAPACITY", 10000),
+                request_log_success_level: env_var_or("REQUEST_LOG_SUCCESS_LEVEL", LogLevel::Info),
+                request_log_error_level: env_var_or("REQUEST_LOG_ERROR_LEVEL", LogLevel::Warn),
+                api_tokens: env_var_list("API_TOKENS"),
+                auth_disabled: env_var_or("API_AUTH_DISABLED", false),
+                unauthenticated_paths: match env::var("UNAUTHENTICATED_PATHS") {
+                    Ok(_) => env_var_list("UNAUTHENTICATED_PATHS"),
+                    Err(_) => vec!["/health".into(), "/metrics".into()]
+                },
+                max_create_gallery_body_bytes: env_var_or("MAX_CREATE_GALLERY_BODY_BYTES", 1048576)
             }
         )
     }
@@ -0,0 +1,67 @@
+use std::{env, str::FromStr};
+
+use serde::{Deserialize, Serialize};
+
+use super::{env_var_list, env_var_or};
+
+/// Config for the notification sinks which pipeline events are sent to:
+/// - `sinks`: The sinks events are sent to; unknown sinks are skipped
+/// - `webhook_url`: The URL events are POSTed to by the webhook sink
+/// - `webhook_secret`: If set, webhook bodies are signed with this as an HMAC-SHA256 key
+/// - `webhook_max_retries`: The max number of times a failed webhook is retried
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct NotificationConfig {
+    pub sinks: Vec<NotificationSinkKind>,
+    pub webhook_url: Option<String>,
+    pub webhook_secret: Option<String>,
+    pub webhook_max_retries: u32
+}
+
+/// The sinks available for pipeline events.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NotificationSinkKind {
+    Webhook,
+    Log
+}
+
+impl FromStr for NotificationSinkKind {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "webhook" => Ok(NotificationSinkKind::Webhook),
+            "log" => Ok(NotificationSinkKind::Log),
+            other => Err(format!("Unknown notification sink: {other}"))
+        }
+    }
+}
+
+impl NotificationConfig {
+    /// Load the config from env vars.
+    pub(super) fn load() -> Self {
+        NotificationConfig {
+            sinks: load_sinks(),
+            webhook_url: env::var("NOTIFICATION_WEBHOOK_URL")
+                .ok()
+                .filter(|url| !url.is_empty()),
+            webhook_secret: env::var("NOTIFICATION_WEBHOOK_SECRET")
+                .ok()
+                .filter(|secret| !secret.is_empty()),
+            webhook_max_retries: env_var_or("NOTIFICATION_WEBHOOK_MAX_RETRIES", 3)
+        }
+    }
+}
+
+/// Load the notification sinks, formatted as sink names separated by commas.
+/// 
+/// Unknown sinks are skipped.
+fn load_sinks() -> Vec<NotificationSinkKind> {
+    let mut sinks = vec![];
+    for name in env_var_list("NOTIFICATION_SINKS") {
+        match name.parse::<NotificationSinkKind>() {
+            Ok(sink) if !sinks.contains(&sink) => sinks.push(sink),
+            Ok(_) => (),
+            Err(err) => tracing::warn!("{err} in NOTIFICATION_SINKS; skipping it")
+        }
+    }
+    sinks
+}
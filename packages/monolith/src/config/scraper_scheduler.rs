@@ -1,10 +1,49 @@
-use std::env::VarError;
+use std::{env::VarError, str::FromStr};
 use serde::{Deserialize, Serialize};
 
-/// Config for the scraper scheduler module.
+use super::env_var_or;
+
+/// Config for the scraper scheduler module:
+/// - `min_scrape_interval_secs`: The minimum allowed interval between a gallery's scheduled scrapes
+/// - `jitter_window_secs`: The window within which each gallery's scrapes are offset, to stagger galleries sharing a schedule (0 disables this)
+/// - `rescrape_on_criteria_change`: Whether a gallery is immediately re-scraped when an update changes its search criteria
+/// - `concurrent_scrape_policy`: What happens to a scrape trigger (cron or manual) for a gallery which is already being scraped
+/// - `scrape_queue_poll_interval_secs`: How often a queued scrape checks whether the gallery's current scrape has finished
+/// - `dedup_window_secs`: A gallery's scheduled fire is skipped if it last fired within this window, ie around restarts (0 disables this)
+/// - `last_fired_path`: The JSON file galleries' last fired times are persisted to; if empty, they're only kept in memory
+/// - `sync_interval_secs`: How often the scheduled galleries are reconciled with those in storage, besides on startup (0 disables this)
+/// - `max_in_flight_galleries`: The max number of galleries in the pipeline at once; scheduled scrapes past this are deferred until there's room (0 disables this)
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct ScraperSchedulerConfig {
+    pub min_scrape_interval_secs: u64,
+    pub jitter_window_secs: u64,
+    pub rescrape_on_criteria_change: bool,
+    pub concurrent_scrape_policy: ConcurrentScrapePolicy,
+    pub scrape_queue_poll_interval_secs: u64,
+    pub dedup_window_secs: u64,
+    pub last_fired_path: String,
+    pub sync_interval_secs: u64,
+    pub max_in_flight_galleries: usize
+}
+
+/// What happens to a scrape trigger for a gallery which is already being scraped.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConcurrentScrapePolicy {
+    /// The trigger is rejected.
+    Reject,
+    /// The trigger is queued, and runs once the current scrape finishes; multiple queued triggers run as one scrape.
+    Queue
+}
 
+impl FromStr for ConcurrentScrapePolicy {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "reject" => Ok(ConcurrentScrapePolicy::Reject),
+            "queue" => Ok(ConcurrentScrapePolicy::Queue),
+            other => Err(format!("Unknown concurrent scrape policy: {other}"))
+        }
+    }
 }
 
 impl ScraperSchedulerConfig {
@@ -12,7 +51,15 @@ impl ScraperSchedulerConfig {
     pub(super) fn load() -> Result<Self, VarError> {
         Ok(
             ScraperSchedulerConfig {
-
+                min_scrape_interval_secs: env_var_or("SCHEDULER_MIN_SCRAPE_INTERVAL_SECS", 300),
+                jitter_window_secs: env_var_or("SCHEDULER_JITTER_WINDOW_SECS", 0),
+                rescrape_on_criteria_change: env_var_or("SCHEDULER_RESCRAPE_ON_CRITERIA_CHANGE", false),
+                concurrent_scrape_policy: env_var_or("SCHEDULER_CONCURRENT_SCRAPE_POLICY", ConcurrentScrapePolicy::Reject),
+                scrape_queue_poll_interval_secs: env_var_or("SCHEDULER_SCRAPE_QUEUE_POLL_INTERVAL_SECS", 30),
+                dedup_window_secs: env_var_or("SCHEDULER_DEDUP_WINDOW_SECS", 60),
+                last_fired_path: env_var_or("SCHEDULER_LAST_FIRED_PATH", "scheduler_last_fired.json".into()),
+                sync_interval_secs: env_var_or("SCHEDULER_SYNC_INTERVAL_SECS", 300),
+                max_in_flight_galleries: env_var_or("SCHEDULER_MAX_IN_FLIGHT_GALLERIES", 0)
             }
         )
     }
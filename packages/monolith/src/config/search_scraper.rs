@@ -1,18 +1,64 @@
-use std::env::{VarError};
+use std::{collections::HashMap, env::VarError};
 
 use serde::{Deserialize, Serialize};
+use crate::{galleries::domain_types::Marketplace, utils::{proxy::ProxyConfig, rate_limiter::RateLimit}};
+use super::{env_marketplace_proxies, env_proxy, env_var_list, env_var_or};
 
-/// Config for the scraper module.
+/// Config for the scraper module:
+/// - `marketplace_rate_limits`: The rate limit for search requests to each marketplace
+/// - `max_concurrent_galleries`: The max number of galleries being search-scraped at once
+/// - `max_concurrent_marketplaces`: The max number of marketplaces being searched at once, within a gallery
+/// - `breaker_failure_threshold`: The number of consecutive failed searches after which a marketplace is short-circuited (0 disables this)
+/// - `breaker_cooldown_secs`: How long a marketplace is short-circuited for, before a trial search is allowed
+/// - `user_agents`: The user agents rotated through for search requests (a default is used if empty)
+/// - `proxy`: The proxy that search requests are routed through, if any
+/// - `marketplace_proxies`: Proxies overriding `proxy` for specific marketplaces
+/// - `layout_check_window`: The number of recent searches per marketplace averaged for the layout check (0 disables this)
+/// - `layout_check_min_average`: The average number of items above which a search returning none is flagged as a probable layout change (0 disables this)
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct SearchScraperConfig {
+    pub marketplace_rate_limits: HashMap<Marketplace, RateLimit>,
+    pub max_concurrent_galleries: usize,
+    pub max_concurrent_marketplaces: usize,
+    pub breaker_failure_threshold: u32,
+    pub breaker_cooldown_secs: u64,
+    pub user_agents: Vec<String>,
+    pub proxy: Option<ProxyConfig>,
+    pub marketplace_proxies: HashMap<Marketplace, ProxyConfig>,
+    pub layout_check_window: usize,
+    pub layout_check_min_average: f64
 }
 
 impl SearchScraperConfig {
+    /// Returns the proxy to use for a marketplace; its override if it has one, else the default proxy (if any).
+    pub fn proxy_for(&self, marketplace: &Marketplace) -> Option<&ProxyConfig> {
+        self.marketplace_proxies
+            .get(marketplace)
+            .or(self.proxy.as_ref())
+    }
+
     /// Load the config from env vars. Returns a `VarError` if any are missing.
     pub(super) fn load() -> Result<Self, VarError> {
+        let mut marketplace_rate_limits = HashMap::new();
+        marketplace_rate_limits.insert(
+            Marketplace::Mercari,
+            RateLimit {
+                requests_per_sec: env_var_or("MERCARI_SEARCH_REQUESTS_PER_SEC", 1.0),
+                burst: env_var_or("MERCARI_SEARCH_BURST", 3)
+            }
+        );
         Ok(
             Self {
-
+                marketplace_rate_limits,
+                max_concurrent_galleries: env_var_or("SEARCH_SCRAPER_MAX_CONCURRENT_GALLERIES", 4),
+                max_concurrent_marketplaces: env_var_or("SEARCH_SCRAPER_MAX_CONCURRENT_MARKETPLACES", 4),
+                breaker_failure_threshold: env_var_or("SEARCH_SCRAPER_BREAKER_FAILURE_THRESHOLD", 5),
+                breaker_cooldown_secs: env_var_or("SEARCH_SCRAPER_BREAKER_COOLDOWN_SECS", 300),
+                user_agents: env_var_list("SCRAPER_USER_AGENTS"),
+                proxy: env_proxy("SCRAPER_PROXY"),
+                marketplace_proxies: env_marketplace_proxies(),
+                layout_check_window: env_var_or("SEARCH_SCRAPER_LAYOUT_CHECK_WINDOW", 20),
+                layout_check_min_average: env_var_or("SEARCH_SCRAPER_LAYOUT_CHECK_MIN_AVERAGE", 5.0)
             }
         )
     }
@@ -1,12 +1,66 @@
-use std::env::{self, VarError};
+use std::{collections::HashMap, env::{self, VarError}, str::FromStr};
 
 use serde::{Deserialize, Serialize};
 
+use crate::galleries::pipeline_states::GalleryPipelineStateTypes;
+
+use super::SerializationFormat;
+
+use super::{env_var_list, env_var_or};
+
 /// Config for the scraper module.
+/// 
+/// - `stage_timeouts_secs`: How long a gallery can stay in each stage before it's marked as stalled; stages without one are never marked
+/// - `watchdog_interval_secs`: How often galleries are checked for stalls
+/// - `max_stalled_galleries`: How many stalled galleries are kept for inspection; past this, the earliest marked are dropped
+/// - `final_retention_secs`: How long a gallery in the `Final` state is kept in the state tracker before it's compacted away
+/// - `compaction_interval_secs`: How often `Final` galleries past their retention are compacted
+/// - `send_timeout_ms`: How long modules wait for the state tracker to accept a message before giving up (0 waits indefinitely)
+/// - `serialization_format`: The format the file store persists states in
+/// - `audit_log_kind`: Where galleries' state transitions are recorded, if anywhere
+/// - `audit_log_path`: The JSONL file state transitions are appended to, for the file audit log
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct StateTrackerConfig {
     pub use_redis: bool,
-    pub redis_uri: String
+    pub redis_uri: String,
+    pub store_kind: StateTrackerStoreKind,
+    pub store_file_path: String,
+    pub stage_timeouts_secs: HashMap<GalleryPipelineStateTypes, u64>,
+    pub watchdog_interval_secs: u64,
+    pub max_stalled_galleries: usize,
+    pub final_retention_secs: u64,
+    pub compaction_interval_secs: u64,
+    pub send_timeout_ms: u64,
+    pub serialization_format: SerializationFormat,
+    pub audit_log_kind: AuditLogKind,
+    pub audit_log_path: String
+}
+
+/// The kind of backing store the state tracker persists states to.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum StateTrackerStoreKind {
+    Memory,
+    File
+}
+
+/// Where galleries' state transitions are recorded.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AuditLogKind {
+    /// Transitions aren't recorded.
+    None,
+    /// Transitions are appended to a JSONL file.
+    File
+}
+
+impl FromStr for AuditLogKind {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "none" => Ok(AuditLogKind::None),
+            "file" => Ok(AuditLogKind::File),
+            other => Err(format!("Unknown audit log kind: {other}"))
+        }
+    }
 }
 
 impl StateTrackerConfig {
@@ -17,11 +71,45 @@ impl StateTrackerConfig {
             "false" => false,
             _ => false
         };
+        let store_kind = match env::var("STATE_TRACKER_STORE").unwrap_or_default().as_str() {
+            "file" => StateTrackerStoreKind::File,
+            "memory" => StateTrackerStoreKind::Memory,
+            _ => StateTrackerStoreKind::Memory
+        };
         Ok(
             Self {
                 use_redis,
-                redis_uri: env::var("REDIS_URI")?
+                redis_uri: env::var("REDIS_URI")?,
+                store_kind,
+                store_file_path: env_var_or("STATE_TRACKER_STORE_FILE_PATH", "state_tracker_store.json".into()),
+                stage_timeouts_secs: load_stage_timeouts(),
+                watchdog_interval_secs: env_var_or("STATE_TRACKER_WATCHDOG_INTERVAL_SECS", 60),
+                max_stalled_galleries: env_var_or("STATE_TRACKER_MAX_STALLED_GALLERIES", 100),
+                final_retention_secs: env_var_or("STATE_TRACKER_FINAL_RETENTION_SECS", 86400),
+                compaction_interval_secs: env_var_or("STATE_TRACKER_COMPACTION_INTERVAL_SECS", 3600),
+                send_timeout_ms: env_var_or("STATE_TRACKER_SEND_TIMEOUT_MS", 10000),
+                serialization_format: env_var_or("SERIALIZATION_FORMAT", SerializationFormat::Json),
+                audit_log_kind: env_var_or("STATE_TRACKER_AUDIT_LOG", AuditLogKind::None),
+                audit_log_path: env_var_or("STATE_TRACKER_AUDIT_LOG_PATH", "state_transitions.jsonl".into())
             }
         )
     }
-}
\ No newline at end of file
+}
+
+/// Load the per-stage timeouts, formatted as `Stage=secs` entries separated by commas.
+/// 
+/// Unparseable entries are skipped.
+fn load_stage_timeouts() -> HashMap<GalleryPipelineStateTypes, u64> {
+    env_var_list("STATE_TRACKER_STAGE_TIMEOUTS_SECS")
+        .into_iter()
+        .filter_map(|entry| {
+            let parsed = entry
+                .split_once('=')
+                .and_then(|(stage, secs)| Some((stage.trim().parse().ok()?, secs.trim().parse().ok()?)));
+            if parsed.is_none() {
+                tracing::warn!("Could not parse stage timeout ({entry}) in STATE_TRACKER_STAGE_TIMEOUTS_SECS; skipping it");
+            }
+            parsed
+        })
+        .collect()
+}
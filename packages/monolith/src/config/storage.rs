@@ -1,20 +1,121 @@
-use std::env::VarError;
+use std::{env::{self, VarError}, str::FromStr};
 
 use serde::{Deserialize, Serialize};
 
-/// Config for the scraper module.
+use super::{env_var_list, env_var_or, SerializationFormat};
+
+/// Config for the storage module:
+/// - `compression`: The algorithm stored galleries are compressed with; `None` keeps them uncompressed
+/// - `compression_level`: The compression level; 0-9 for gzip, 1-22 for zstd
+/// - `serialization_format`: The format stored galleries are serialized in; uncompressed JSON galleries are kept deserialized
+/// - `analysis_retry_queue_path`: The JSON file the queue of items to retry analysis for is persisted to; if empty, it's only kept in memory
+/// - `diff_price_change_threshold`: The relative change (ie 0.01 for 1%) an item's price must exceed between scrapes to count as changed
+/// - `scheduler_states_path`: The JSON file galleries' scheduler states are persisted to; if empty, they're only kept in memory
+/// - `scraped_items_path`: The JSON file items scraped for galleries still in the pipeline are persisted to; if empty, they're only kept in memory
+/// - `history_max_runs`: The number of each gallery's latest runs kept for aggregating stats; 0 disables keeping them
+/// - `retry_max_attempts`: The max number of times a storage operation is attempted (including the first) if it fails with a retryable error
+/// - `retry_base_delay_ms`: The delay before the first retry, which doubles on each following retry
+/// - `retry_max_delay_ms`: The cap on the delay between retries, before jitter is added
+/// - `retryable_errors`: The kinds of storage errors which are retried; other errors are returned immediately
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct StorageConfig {
+    pub compression: StorageCompression,
+    pub compression_level: i32,
+    pub serialization_format: SerializationFormat,
+    pub analysis_retry_queue_path: String,
+    pub diff_price_change_threshold: f32,
+    pub scheduler_states_path: String,
+    pub scraped_items_path: String,
+    pub history_max_runs: usize,
+    pub retry_max_attempts: u32,
+    pub retry_base_delay_ms: u64,
+    pub retry_max_delay_ms: u64,
+    pub retryable_errors: Vec<StorageErrorKind>
+}
+
+/// The compression algorithms available for stored galleries.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum StorageCompression {
+    None,
+    Gzip,
+    Zstd
+}
+
+/// The kinds of errors storage operations can fail with.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StorageErrorKind {
+    NotFound,
+    AlreadyExists,
+    NoFailedAnalysis,
+    StateTracker,
+    Serialization,
+    Io,
+    Other
+}
 
+impl FromStr for StorageErrorKind {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "not_found" => Ok(StorageErrorKind::NotFound),
+            "already_exists" => Ok(StorageErrorKind::AlreadyExists),
+            "no_failed_analysis" => Ok(StorageErrorKind::NoFailedAnalysis),
+            "state_tracker" => Ok(StorageErrorKind::StateTracker),
+            "serialization" => Ok(StorageErrorKind::Serialization),
+            "io" => Ok(StorageErrorKind::Io),
+            "other" => Ok(StorageErrorKind::Other),
+            other => Err(format!("Unknown storage error kind: {other}"))
+        }
+    }
 }
 
 impl StorageConfig {
     /// Load the config from env vars. Returns a `VarError` if any are missing.
     pub(super) fn load() -> Result<Self, VarError> {
+        let compression = match env::var("STORAGE_COMPRESSION").unwrap_or_default().as_str() {
+            "gzip" => StorageCompression::Gzip,
+            "zstd" => StorageCompression::Zstd,
+            _ => StorageCompression::None
+        };
+        let default_level = match compression {
+            StorageCompression::None => 0,
+            StorageCompression::Gzip => 6,
+            StorageCompression::Zstd => 3
+        };
         Ok(
             StorageConfig {
-
+                compression,
+                compression_level: env_var_or("STORAGE_COMPRESSION_LEVEL", default_level),
+                serialization_format: env_var_or("SERIALIZATION_FORMAT", SerializationFormat::Json),
+                analysis_retry_queue_path: env_var_or("STORAGE_ANALYSIS_RETRY_QUEUE_PATH", "analysis_retry_queue.json".into()),
+                diff_price_change_threshold: env_var_or("STORAGE_DIFF_PRICE_CHANGE_THRESHOLD", 0.01),
+                scheduler_states_path: env_var_or("STORAGE_SCHEDULER_STATES_PATH", "scheduler_states.json".into()),
+                scraped_items_path: env_var_or("STORAGE_SCRAPED_ITEMS_PATH", "scraped_items.json".into()),
+                history_max_runs: env_var_or("STORAGE_HISTORY_MAX_RUNS", 100),
+                retry_max_attempts: env_var_or("STORAGE_RETRY_MAX_ATTEMPTS", 3),
+                retry_base_delay_ms: env_var_or("STORAGE_RETRY_BASE_DELAY_MS", 100),
+                retry_max_delay_ms: env_var_or("STORAGE_RETRY_MAX_DELAY_MS", 5000),
+                retryable_errors: load_retryable_errors()
             }
         )
     }
-}
\ No newline at end of file
+}
+
+/// Load the retryable storage error kinds, formatted as kind names separated by commas.
+/// 
+/// Defaults to only I/O errors if unset. Unknown kinds are skipped.
+fn load_retryable_errors() -> Vec<StorageErrorKind> {
+    let names = env_var_list("STORAGE_RETRYABLE_ERRORS");
+    if names.is_empty() {
+        return vec![StorageErrorKind::Io];
+    }
+    let mut kinds = vec![];
+    for name in names {
+        match name.parse::<StorageErrorKind>() {
+            Ok(kind) if !kinds.contains(&kind) => kinds.push(kind),
+            Ok(_) => (),
+            Err(err) => tracing::warn!("{err} in STORAGE_RETRYABLE_ERRORS; skipping it")
+        }
+    }
+    kinds
+}
@@ -1,7 +1,9 @@
 //! This module contains domain newtypes, mostly for gallery parameters.
 
+use std::collections::HashMap;
 use std::fmt::{self, Display};
-use std::ops::{Deref, DerefMut};
+use std::ops::{AddAssign, Deref, DerefMut};
+use std::time::Duration;
 use chrono::{DateTime, TimeZone, Utc};
 use croner::{errors::CronError, Cron};
 use serde::{Deserialize, Deserializer, Serialize, Serializer};
@@ -13,6 +15,13 @@ pub enum Marketplace {
     Mercari
 }
 
+impl Marketplace {
+    /// Returns every supported marketplace.
+    pub fn all() -> Vec<Self> {
+        vec![Marketplace::Mercari]
+    }
+}
+
 impl Display for Marketplace {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
@@ -38,6 +47,29 @@ impl ValidCronString {
         }
     } 
 
+    /// Instantiate, checking if the string is a valid Cron pattern,
+    /// and that it doesn't fire more often than `min_interval`.
+    /// 
+    /// The interval is checked over the next few fire times, so irregular patterns are covered too.
+    pub fn new_with_min_interval(expr: &str, min_interval: Duration) -> Result<Self, CronError> {
+        const NUM_CHECKED_FIRE_TIMES: usize = 10;
+        let cron = Cron::new(expr).parse()?;
+        let mut prev_time = cron.find_next_occurrence(&Utc::now(), false)?;
+        for _ in 0..NUM_CHECKED_FIRE_TIMES {
+            let next_time = cron.find_next_occurrence(&prev_time, false)?;
+            let interval = (next_time - prev_time)
+                .to_std()
+                .unwrap_or(Duration::ZERO);
+            if interval < min_interval {
+                return Err(CronError::InvalidPattern(
+                    format!("Pattern fires every {interval:?}, which is more often than the minimum of {min_interval:?}")
+                ));
+            }
+            prev_time = next_time;
+        }
+        Ok(Self(expr.to_string()))
+    }
+
     /// Get a (guaranteed valid) `Cron` from the string.
     pub fn get_cron(&mut self) -> Cron {
         Cron::new(&self.0)
@@ -49,6 +81,103 @@ impl ValidCronString {
     pub fn get_str(&self) -> &str {
         &self.0
     }
+
+    /// Get the first time the pattern fires strictly after `after`.
+    /// 
+    /// Returns `None` if it never fires again (ie it's for a specific date in the past).
+    pub fn next_fire_time(&self, after: &UnixUtcDateTime) -> Option<UnixUtcDateTime> {
+        Cron::new(&self.0)
+            .parse()
+            .ok()?
+            .find_next_occurrence(&after.0, false)
+            .ok()
+            .map(UnixUtcDateTime)
+    }
+
+    /// A plain-English description of the schedule, ie "Every day at 3 AM".
+    /// 
+    /// Covers common 5-field patterns (every N minutes, hourly, every N hours, daily, weekdays, weekly and monthly) and shorthands (ie `@daily`);
+    /// anything more complex falls back to the raw pattern.
+    pub fn describe(&self) -> String {
+        let expr = match self.0.trim().to_lowercase().as_str() {
+            "@hourly" => "0 * * * *".to_string(),
+            "@daily" | "@midnight" => "0 0 * * *".to_string(),
+            "@weekly" => "0 0 * * 0".to_string(),
+            "@monthly" => "0 0 1 * *".to_string(),
+            "@yearly" | "@annually" => return "Every year on January 1 at 12 AM".into(),
+            expr => expr.to_string()
+        };
+        let fields: Vec<&str> = expr.split_whitespace().collect();
+        let &[minute, hour, day_of_month, month, day_of_week] = fields.as_slice() else {
+            return self.0.clone();
+        };
+        if month != "*" {
+            return self.0.clone();
+        }
+        let step = |field: &str| field
+            .strip_prefix("*/")
+            .and_then(|step| step.parse::<u32>().ok())
+            .filter(|&step| step > 0);
+        let number = |field: &str, max: u32| field
+            .parse::<u32>()
+            .ok()
+            .filter(|&value| value <= max);
+        let description = match (day_of_month, day_of_week) {
+            ("*", "*") => match (minute, hour, number(minute, 59), number(hour, 23)) {
+                ("*", "*", _, _) => Some("Every minute".to_string()),
+                (_, "*", _, _) if step(minute).is_some() => step(minute).map(|step| format!("Every {step} minutes")),
+                (_, "*", Some(0), _) => Some("Every hour".to_string()),
+                (_, "*", Some(minute), _) => Some(format!("Every hour at {minute} minutes past")),
+                (_, _, Some(0), None) => step(hour).map(|step| format!("Every {step} hours")),
+                (_, _, Some(minute), None) => step(hour).map(|step| format!("Every {step} hours at {minute} minutes past")),
+                (_, _, Some(minute), Some(hour)) => Some(format!("Every day at {}", describe_time(hour, minute))),
+                _ => None
+            },
+            (_, "*") => match (number(day_of_month, 31).filter(|&day| day > 0), number(hour, 23), number(minute, 59)) {
+                (Some(day), Some(hour), Some(minute)) => Some(format!("On day {day} of every month at {}", describe_time(hour, minute))),
+                _ => None
+            },
+            ("*", _) => match (describe_days_of_week(day_of_week), number(hour, 23), number(minute, 59)) {
+                (Some(days), Some(hour), Some(minute)) => Some(format!("Every {days} at {}", describe_time(hour, minute))),
+                _ => None
+            },
+            _ => None
+        };
+        description.unwrap_or_else(|| self.0.clone())
+    }
+}
+
+/// Describes a time of day on a 12-hour clock, ie "3 AM" or "3:30 PM".
+fn describe_time(hour: u32, minute: u32) -> String {
+    let period = if hour < 12 { "AM" } else { "PM" };
+    let hour = match hour % 12 {
+        0 => 12,
+        hour => hour
+    };
+    match minute {
+        0 => format!("{hour} {period}"),
+        minute => format!("{hour}:{minute:02} {period}")
+    }
+}
+
+/// Describes a Cron day-of-week field which is a single day (by number or name) or a weekday/weekend range, ie "Monday" or "weekday".
+/// 
+/// Returns `None` for anything else.
+fn describe_days_of_week(field: &str) -> Option<String> {
+    const DAYS: [&str; 7] = ["Sunday", "Monday", "Tuesday", "Wednesday", "Thursday", "Friday", "Saturday"];
+    match field {
+        "1-5" | "mon-fri" => return Some("weekday".into()),
+        "0,6" | "6,0" | "sat,sun" | "sun,sat" => return Some("weekend day".into()),
+        _ => ()
+    }
+    let day = match field.parse::<usize>() {
+        Ok(day) if day <= 7 => day % 7,
+        Ok(_) => return None,
+        Err(_) => DAYS
+            .iter()
+            .position(|day| day[..3].eq_ignore_ascii_case(field))?
+    };
+    Some(DAYS[day].to_string())
 }
 
 // Custom implementation to check Cron validity before deserializing.
@@ -87,6 +216,31 @@ impl Display for GalleryId {
     }
 }
 
+impl From<String> for GalleryId {
+    fn from(value: String) -> Self {
+        Self(value)
+    }
+}
+
+/// A String wrapper for the ID of a single run of a gallery through the pipeline.
+/// 
+/// A new one is generated by the state tracker each time a gallery is added to it, so that logs from one scrape can be correlated.
+#[derive(Clone, Debug, Serialize, Deserialize, Eq, PartialEq, Hash)]
+pub struct RunId(String);
+
+impl RunId {
+    /// Generate a new, random run ID.
+    pub fn new() -> Self {
+        Self(uuid::Uuid::new_v4().to_string())
+    }
+}
+
+impl Display for RunId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
 /// A String wrapper for a marketplace item ID.
 /// 
 /// There is (currently) no special functionality or validation; this exists simply because the item ID is a heavily used domain type.
@@ -156,10 +310,20 @@ impl<'de> Deserialize<'de> for UnixUtcDateTime {
     fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
     where D: Deserializer<'de>,
     {
-        let string_timestamp = String::deserialize(deserializer)?;
-        let timestamp = string_timestamp
-            .parse::<i64>()
-            .map_err(|err| serde::de::Error::custom("Could not parse string to i64"))?;
+        /// Timestamps can come in as integers (ie, from our own serialization) or strings (ie, from marketplace APIs).
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum RawTimestamp {
+            Int(i64),
+            Str(String)
+        }
+
+        let timestamp = match RawTimestamp::deserialize(deserializer)? {
+            RawTimestamp::Int(timestamp) => timestamp,
+            RawTimestamp::Str(string_timestamp) => string_timestamp
+                .parse::<i64>()
+                .map_err(|err| serde::de::Error::custom("Could not parse string to i64"))?
+        };
         let datetime = chrono::Utc.timestamp_opt(timestamp, 0)
             .single()
             .ok_or_else(|| serde::de::Error::custom("Invalid timestamp"))?;
@@ -167,6 +331,12 @@ impl<'de> Deserialize<'de> for UnixUtcDateTime {
     }
 }
 
+impl From<DateTime<Utc>> for UnixUtcDateTime {
+    fn from(value: DateTime<Utc>) -> Self {
+        Self(value)
+    }
+}
+
 impl From<i64> for UnixUtcDateTime {
     fn from(value: i64) -> Self {
         let datetime = chrono::Utc.timestamp_opt(value, 0)
@@ -178,4 +348,92 @@ impl From<i64> for UnixUtcDateTime {
             });
         Self(datetime)
     }
-}
\ No newline at end of file
+}
+
+/// The number of tokens used by LLM requests.
+#[derive(Clone, Copy, Debug, Default, Serialize, Deserialize, PartialEq, Eq)]
+pub struct TokenUsage {
+    pub prompt_tokens: u64,
+    pub completion_tokens: u64
+}
+
+impl AddAssign for TokenUsage {
+    fn add_assign(&mut self, other: Self) {
+        self.prompt_tokens += other.prompt_tokens;
+        self.completion_tokens += other.completion_tokens;
+    }
+}
+
+/// The running token usage of a gallery's LLM requests, keyed by model.
+#[derive(Clone, Debug, Default, Serialize, Deserialize, PartialEq, Eq)]
+pub struct ModelTokenUsage(HashMap<String, TokenUsage>);
+
+impl ModelTokenUsage {
+    /// Add usage under a model to its running total.
+    pub fn record(&mut self, model: &str, usage: TokenUsage) {
+        *self.0
+            .entry(model.to_string())
+            .or_default() += usage;
+    }
+
+    /// Add all of another running total's usage to this one.
+    pub fn merge(&mut self, other: &ModelTokenUsage) {
+        for (model, usage) in other.iter() {
+            self.record(model, *usage);
+        }
+    }
+}
+
+impl Deref for ModelTokenUsage {
+    type Target = HashMap<String, TokenUsage>;
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn describe(expr: &str) -> String {
+        ValidCronString::new(expr.into())
+            .expect("The test cron string should be valid")
+            .describe()
+    }
+
+    #[test]
+    fn describes_minutely_and_hourly_schedules() {
+        assert_eq!(describe("* * * * *"), "Every minute");
+        assert_eq!(describe("*/15 * * * *"), "Every 15 minutes");
+        assert_eq!(describe("0 * * * *"), "Every hour");
+        assert_eq!(describe("@hourly"), "Every hour");
+        assert_eq!(describe("30 * * * *"), "Every hour at 30 minutes past");
+        assert_eq!(describe("0 */6 * * *"), "Every 6 hours");
+    }
+
+    #[test]
+    fn describes_daily_schedules() {
+        assert_eq!(describe("0 3 * * *"), "Every day at 3 AM");
+        assert_eq!(describe("30 15 * * *"), "Every day at 3:30 PM");
+        assert_eq!(describe("0 0 * * *"), "Every day at 12 AM");
+        assert_eq!(describe("@daily"), "Every day at 12 AM");
+        assert_eq!(describe("0 12 * * *"), "Every day at 12 PM");
+    }
+
+    #[test]
+    fn describes_weekly_and_monthly_schedules() {
+        assert_eq!(describe("30 9 * * 1"), "Every Monday at 9:30 AM");
+        assert_eq!(describe("0 9 * * MON"), "Every Monday at 9 AM");
+        assert_eq!(describe("0 9 * * 7"), "Every Sunday at 9 AM");
+        assert_eq!(describe("0 9 * * 1-5"), "Every weekday at 9 AM");
+        assert_eq!(describe("@weekly"), "Every Sunday at 12 AM");
+        assert_eq!(describe("0 8 1 * *"), "On day 1 of every month at 8 AM");
+    }
+
+    #[test]
+    fn falls_back_to_the_raw_pattern_for_complex_schedules() {
+        for expr in ["0 9 * 1 *", "0 9,17 * * *", "0 9 1 * 1", "5-10 * * * *"] {
+            assert_eq!(describe(expr), expr);
+        }
+    }
+}
@@ -2,20 +2,201 @@ use std::iter::zip;
 
 use serde::{Serialize, Deserialize};
 
+use super::{domain_types::ItemId, items::item_data::MarketplaceItemData};
+
+/// The placeholder in a prompt template which is substituted with the item listing.
+pub const PROMPT_ITEM_PLACEHOLDER: &str = "{item}";
+
+/// The placeholder in a prompt template which is substituted with the described criteria.
+pub const PROMPT_CRITERIA_PLACEHOLDER: &str = "{criteria}";
+
+/// The prompt template used for galleries which don't set their own.
+pub const DEFAULT_PROMPT_TEMPLATE: &str = "Here are the questions you must answer: \n {criteria} \n\n Here is the item listing: \n {item}";
+
 /// A Vec of user-defined questions to ask the LLM about each item in a gallery.
-#[derive(Clone, Debug, Serialize, Deserialize)]
+/// 
+/// Also holds optional deterministic filters, which are checked with `prefilter` before any item is sent to the LLM.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
 pub struct EvaluationCriteria {
-    criteria: Vec<Criterion>
+    criteria: Vec<Criterion>,
+    /// The inclusive (min, max) price range an item must fall in, in the base currency.
+    #[serde(default)]
+    price_range: Option<(f64, f64)>,
+    /// Keywords which must all appear (case-insensitively) in an item's name or description.
+    #[serde(default)]
+    required_keywords: Vec<String>,
+    /// A custom template for each item's prompt, which must contain the `{item}` and `{criteria}` placeholders.
+    /// 
+    /// If not set, `DEFAULT_PROMPT_TEMPLATE` is used.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    prompt_template: Option<String>,
+    /// The model used for analysis instead of the configured default, which must be in the allowed models.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    model_override: Option<String>,
+    /// If set, the gallery's analyzed items skip the item embedder, and go straight to the final state without embeddings.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    skip_embedding: bool,
+    /// If set, only this fraction (in (0, 1]) of the items which pass the prefilter are randomly sampled for analysis;
+    /// the rest are carried through unanalyzed.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    sample_rate: Option<f32>,
+    /// Items which are always analyzed, regardless of the prefilter and sampling.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pinned_item_ids: Vec<ItemId>
 }
 
 impl EvaluationCriteria {
-    /// Initialize with a list of criteria.
+    /// Initialize with a list of criteria, and no prefilters.
     pub fn new(criteria: Vec<Criterion>) -> Self {
         EvaluationCriteria {
-            criteria
+            criteria,
+            price_range: None,
+            required_keywords: vec![],
+            prompt_template: None,
+            model_override: None,
+            skip_embedding: false,
+            sample_rate: None,
+            pinned_item_ids: vec![]
+        }
+    }
+
+    /// Returns whether an item passes the deterministic filters,
+    /// ie its price (normalized into the base currency, if possible) is within the price range and it contains all required keywords.
+    /// 
+    /// If there are no filters, simply returns `true`.
+    pub fn prefilter(&self, item: &MarketplaceItemData) -> bool {
+        if let Some((min_price, max_price)) = self.price_range {
+            let price = item.comparable_price() as f64;
+            if price < min_price || price > max_price {
+                return false;
+            }
+        }
+        let item_text = format!("{} {}", item.name, item.description).to_lowercase();
+        self.required_keywords
+            .iter()
+            .all(|keyword| item_text.contains(&keyword.to_lowercase()))
+    }
+
+    /// Checks that the criteria make sense, ie:
+    /// - the price range's bounds are non-negative and in order
+    /// - no required keyword is empty
+    /// - the prompt template (if set) contains the `{item}` and `{criteria}` placeholders
+    /// - the sample rate (if set) is within (0, 1]
+    /// - each criterion is valid (see `Criterion::validate`)
+    /// 
+    /// Returns an `Err` describing the first problem found.
+    pub fn validate(&self) -> Result<(), String> {
+        if let Some((min_price, max_price)) = self.price_range {
+            if min_price < 0.0 || max_price < 0.0 {
+                return Err(format!("Price range ({min_price}, {max_price}) cannot be negative"));
+            }
+            if min_price > max_price {
+                return Err(format!("Price range minimum ({min_price}) is greater than its maximum ({max_price})"));
+            }
+        }
+        if self.required_keywords.iter().any(|keyword| keyword.trim().is_empty()) {
+            return Err("Required keywords cannot be empty".into());
+        }
+        if let Some(template) = &self.prompt_template {
+            for placeholder in [PROMPT_ITEM_PLACEHOLDER, PROMPT_CRITERIA_PLACEHOLDER] {
+                if !template.contains(placeholder) {
+                    return Err(format!("Prompt template must contain the {placeholder} placeholder"));
+                }
+            }
+        }
+        if let Some(sample_rate) = self.sample_rate {
+            if !(sample_rate > 0.0 && sample_rate <= 1.0) {
+                return Err(format!("Sample rate ({sample_rate}) must be within (0, 1]"));
+            }
+        }
+        for criterion in &self.criteria {
+            criterion.validate()?;
+        }
+        Ok(())
+    }
+
+    /// Checks that the model override (if set) is one of `allowed_models`.
+    pub fn validate_model_override(&self, allowed_models: &[String]) -> Result<(), String> {
+        match &self.model_override {
+            Some(model) if !allowed_models.contains(model) => Err(format!("Model override ({model}) is not an allowed model")),
+            _ => Ok(())
         }
     }
 
+    /// Returns whether the gallery's analyzed items skip embedding.
+    pub fn skip_embedding(&self) -> bool {
+        self.skip_embedding
+    }
+
+    /// Returns whether the item is pinned, ie it's always analyzed regardless of the prefilter and sampling.
+    pub fn is_pinned(&self, item_id: &ItemId) -> bool {
+        self.pinned_item_ids.contains(item_id)
+    }
+
+    /// Returns the fraction of items sampled for analysis, if set.
+    pub fn sample_rate(&self) -> Option<f32> {
+        self.sample_rate
+    }
+
+    /// Returns the model used for analysis instead of the configured default, if set.
+    pub fn model_override(&self) -> Option<&str> {
+        self.model_override.as_deref()
+    }
+
+    /// Fill each unset field (ie no criteria, no price range, no required keywords, no prompt template, no model override, no sample rate or no pinned items) from `defaults`.
+    /// 
+    /// `skip_embedding` is never filled, as it can't be told apart from being unset.
+    /// 
+    /// Returns the names of the fields which were filled.
+    pub fn apply_defaults(&mut self, defaults: &EvaluationCriteria) -> Vec<&'static str> {
+        let mut applied_fields = vec![];
+        if self.criteria.is_empty() && !defaults.criteria.is_empty() {
+            self.criteria = defaults.criteria.clone();
+            applied_fields.push("criteria");
+        }
+        if self.price_range.is_none() && defaults.price_range.is_some() {
+            self.price_range = defaults.price_range;
+            applied_fields.push("price_range");
+        }
+        if self.required_keywords.is_empty() && !defaults.required_keywords.is_empty() {
+            self.required_keywords = defaults.required_keywords.clone();
+            applied_fields.push("required_keywords");
+        }
+        if self.prompt_template.is_none() && defaults.prompt_template.is_some() {
+            self.prompt_template = defaults.prompt_template.clone();
+            applied_fields.push("prompt_template");
+        }
+        if self.model_override.is_none() && defaults.model_override.is_some() {
+            self.model_override = defaults.model_override.clone();
+            applied_fields.push("model_override");
+        }
+        if self.sample_rate.is_none() && defaults.sample_rate.is_some() {
+            self.sample_rate = defaults.sample_rate;
+            applied_fields.push("sample_rate");
+        }
+        if self.pinned_item_ids.is_empty() && !defaults.pinned_item_ids.is_empty() {
+            self.pinned_item_ids = defaults.pinned_item_ids.clone();
+            applied_fields.push("pinned_item_ids");
+        }
+        applied_fields
+    }
+
+    /// Returns a hash of the criteria, for keying cached analysis results;
+    /// any change to the criteria (including its prefilters and prompt template) changes the hash.
+    /// 
+    /// This is FNV-1a over the criteria's JSON, as std's hashers aren't guaranteed to be stable across Rust versions.
+    pub fn cache_hash(&self) -> u64 {
+        serde_json::to_vec(self)
+            .unwrap_or_default()
+            .iter()
+            .fold(0xcbf29ce484222325_u64, |hash, byte| (hash ^ *byte as u64).wrapping_mul(0x100000001b3))
+    }
+
+    /// The number of questions, ie the number of answers expected from the LLM.
+    pub fn num_criteria(&self) -> usize {
+        self.criteria.len()
+    }
+
     /// A string that describes each question and how to answer it.
     /// This is passed to the LLM in item analysis, to ensure a correctly structured response.
     /// 
@@ -46,6 +227,38 @@ impl EvaluationCriteria {
             .collect()
     }
 
+    /// Renders the prompt for an item, substituting the item listing and the described criteria into the prompt template
+    /// (or `DEFAULT_PROMPT_TEMPLATE` if there is none).
+    /// 
+    /// Placeholders are only substituted in the template itself, not in the substituted text.
+    pub fn render_prompt(&self, item_string: &str) -> String {
+        let template = self.prompt_template
+            .as_deref()
+            .unwrap_or(DEFAULT_PROMPT_TEMPLATE);
+        let criteria_string = self.describe_criteria();
+        let mut prompt = String::with_capacity(template.len() + item_string.len() + criteria_string.len());
+        let mut rest = template;
+        loop {
+            let next_item = rest.find(PROMPT_ITEM_PLACEHOLDER).map(|index| (index, PROMPT_ITEM_PLACEHOLDER, item_string));
+            let next_criteria = rest.find(PROMPT_CRITERIA_PLACEHOLDER).map(|index| (index, PROMPT_CRITERIA_PLACEHOLDER, criteria_string.as_str()));
+            let next = match (next_item, next_criteria) {
+                (Some(item), Some(criteria)) => Some(if item.0 < criteria.0 { item } else { criteria }),
+                (item, criteria) => item.or(criteria)
+            };
+            match next {
+                Some((index, placeholder, substitute)) => {
+                    prompt.push_str(&rest[..index]);
+                    prompt.push_str(substitute);
+                    rest = &rest[index + placeholder.len()..];
+                },
+                None => {
+                    prompt.push_str(rest);
+                    return prompt;
+                }
+            }
+        }
+    }
+
     /// Parses a list of answer strings into `CriterionAnswer`s and checks whether all answers satisfy hard criteria (if any).
     /// 
     /// This is just a combination of the `parse_answers` and `satisfies_hard_criteria` methods, for convenience.
@@ -110,6 +323,25 @@ pub struct Criterion {
 }
 
 impl Criterion {
+    /// Checks that the question isn't empty, and that the hard criterion (if any) fits the criterion type.
+    /// 
+    /// Returns an `Err` if not.
+    fn validate(&self) -> Result<(), String> {
+        if self.question.trim().is_empty() {
+            return Err("Criterion question cannot be empty".into());
+        }
+        match (&self.criterion_type, &self.hard_criterion) {
+            (_, None) |
+            (CriterionType::YesNo, Some(HardCriterion::YesNo(_))) => Ok(()),
+            (CriterionType::Int, Some(HardCriterion::Int(criterion))) => criterion.validate(),
+            (CriterionType::Float, Some(HardCriterion::Float(criterion))) => criterion.validate(),
+            (criterion_type, Some(hard_criterion)) => Err(format!(
+                "Hard criterion ({hard_criterion:?}) doesn't match type ({criterion_type:?}) of question: '{}'", 
+                self.question
+            ))
+        }
+    }
+
     /// Parses an answer string and returns the corresponding `CriterionAnswer`.
     /// 
     /// Returns an `Err` if the answer cannot be parsed into the criterion.
@@ -239,6 +471,16 @@ impl<T> NumericalHardCriterion<T>
 where 
     T: PartialOrd + Copy,
 {   
+    /// Checks that a `Between` criterion's bounds are in order.
+    /// 
+    /// Returns an `Err` if not.
+    fn validate(&self) -> Result<(), String> {
+        match self {
+            NumericalHardCriterion::Between(min, max) if min > max => Err("Hard criterion's minimum is greater than its maximum".into()),
+            _ => Ok(())
+        }
+    }
+
     /// Returns whether `answer` satisfies the hard criterion.
     pub fn is_satisfied(&self, answer: &T) -> bool {
         match self {
@@ -1,15 +1,29 @@
+use std::time::Duration;
 use serde::{Deserialize, Serialize};
 use crate::galleries::domain_types::{ItemId, UnixUtcDateTime};
 
+/// The relative width of the price buckets used in an item's canonical key (ie 10%).
+const CANONICAL_PRICE_BUCKET_RATIO: f32 = 1.1;
+
 /// This is the data for each item, common across all marketplaces.
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct MarketplaceItemData {
     pub id: ItemId,
     pub name: String,
+    /// The price in `currency`, as listed on the marketplace.
     pub price: f32,  
+    /// The ISO 4217 code of the price's currency; items from before this was added were all from Mercari, so default to JPY.
+    #[serde(default = "default_currency")]
+    pub currency: String,
+    /// The price converted into the configured base currency, if an exchange rate was available.
+    #[serde(default)]
+    pub normalized_price: Option<f32>,
     pub description: String,
     pub status: String,
     pub seller: MarketplaceSeller,
+    /// The seller's rating out of 5, if the marketplace provides one.
+    #[serde(default)]
+    pub seller_rating: Option<f32>,
     pub category: String,
     pub thumbnails: Vec<String>, 
     pub item_condition: String,
@@ -17,9 +31,92 @@ pub struct MarketplaceItemData {
     pub updated: UnixUtcDateTime,
 }
 
+fn default_currency() -> String {
+    "JPY".into()
+}
+
+impl MarketplaceItemData {
+    /// Returns the price to compare items by; the normalized price if there is one, else the listed price.
+    pub fn comparable_price(&self) -> f32 {
+        self.normalized_price.unwrap_or(self.price)
+    }
+
+    /// Returns a key identifying the underlying product, regardless of which marketplace it's listed on.
+    /// 
+    /// This is the item's name (lowercased, with only alphanumeric words kept) plus a bucket of its (comparable) price,
+    /// so near-identical listings at similar prices share a key.
+    pub fn canonical_key(&self) -> String {
+        let normalized_name = self.name
+            .to_lowercase()
+            .split(|c: char| !c.is_alphanumeric())
+            .filter(|word| !word.is_empty())
+            .collect::<Vec<_>>()
+            .join(" ");
+        let price_bucket = (self.comparable_price().max(1.0).ln() / CANONICAL_PRICE_BUCKET_RATIO.ln()).floor() as i64;
+        format!("{normalized_name}|{price_bucket}")
+    }
+
+    /// Returns whether the item's seller is rated at least `min_seller_rating`.
+    /// 
+    /// Items without a seller rating always pass.
+    pub fn meets_seller_rating(&self, min_seller_rating: Option<f32>) -> bool {
+        match (self.seller_rating, min_seller_rating) {
+            (Some(rating), Some(min_rating)) => rating >= min_rating,
+            _ => true
+        }
+    }
+
+    /// Returns whether the item was last updated more than `ttl` before `now`, ie whether its details should be re-fetched.
+    /// 
+    /// An item updated exactly `ttl` before `now` isn't stale yet. A `ttl` too large to subtract from `now` is never stale.
+    pub fn is_stale(&self, now: UnixUtcDateTime, ttl: Duration) -> bool {
+        chrono::Duration::from_std(ttl)
+            .ok()
+            .and_then(|ttl| now.checked_sub_signed(ttl))
+            .is_some_and(|cutoff| *self.updated < cutoff)
+    }
+}
+
 /// Data for the item's seller.
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct MarketplaceSeller {
     pub id: String, 
     pub name: String,
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::test_support::item_data;
+    use super::*;
+
+    fn rated(rating: Option<f32>) -> MarketplaceItemData {
+        MarketplaceItemData { seller_rating: rating, ..item_data("item", 100.0, 0) }
+    }
+
+    #[test]
+    fn sellers_rated_below_the_minimum_are_filtered() {
+        assert!(!rated(Some(3.9)).meets_seller_rating(Some(4.0)));
+        assert!(rated(Some(4.0)).meets_seller_rating(Some(4.0)));
+        assert!(rated(Some(4.5)).meets_seller_rating(Some(4.0)));
+    }
+
+    #[test]
+    fn unrated_sellers_and_galleries_without_a_minimum_always_pass() {
+        assert!(rated(None).meets_seller_rating(Some(4.0)));
+        assert!(rated(Some(1.0)).meets_seller_rating(None));
+    }
+
+    #[test]
+    fn an_item_is_only_stale_once_updated_more_than_the_ttl_ago() {
+        let item = item_data("item", 100.0, 1000);
+        let ttl = Duration::from_secs(60);
+        assert!(!item.is_stale(UnixUtcDateTime::from(1000 + 59), ttl));
+        assert!(!item.is_stale(UnixUtcDateTime::from(1000 + 60), ttl));
+        assert!(item.is_stale(UnixUtcDateTime::from(1000 + 61), ttl));
+    }
+
+    #[test]
+    fn a_ttl_too_large_to_subtract_is_never_stale() {
+        assert!(!item_data("item", 100.0, 0).is_stale(UnixUtcDateTime::from(1000), Duration::MAX));
+    }
+}
@@ -1,5 +1,7 @@
+use std::{cmp::Ordering, collections::HashSet};
+
 use serde::{Serialize, Deserialize};
-use crate::galleries::eval_criteria::{CriterionAnswer, EvaluationCriteria};
+use crate::galleries::{domain_types::{ItemId, Marketplace}, eval_criteria::{CriterionAnswer, EvaluationCriteria}};
 use super::item_data::MarketplaceItemData;
 
 /* 
@@ -16,7 +18,28 @@ pub struct ClassifiedItems {
 pub struct MarketplaceAnalyzedItems {
     pub relevant_items: Vec<AnalyzedMarketplaceItem>,
     pub irrelevant_items: Vec<AnalyzedMarketplaceItem>,
-    pub error_items: Vec<ErrorAnalyzedMarketplaceItem>
+    pub error_items: Vec<ErrorAnalyzedMarketplaceItem>,
+    /// Items which weren't analyzed, as they were left out of the gallery's analysis sample.
+    #[serde(default)]
+    pub unsampled_items: Vec<MarketplaceItemData>
+}
+
+impl MarketplaceAnalyzedItems {
+    /// Converts the items without embedding them, so each relevant item is recorded as skipped for `reason`.
+    pub fn without_embedding(self, reason: &str) -> MarketplaceEmbeddedAndAnalyzedItems {
+        let skipped_embedding_items = self.relevant_items
+            .into_iter()
+            .map(|item| SkippedEmbeddingMarketplaceItem { item, reason: reason.to_string() })
+            .collect();
+        MarketplaceEmbeddedAndAnalyzedItems {
+            embedded_items: Vec::new(),
+            irrelevant_analyzed_items: self.irrelevant_items,
+            error_analyzed_items: self.error_items,
+            error_embedded_items: Vec::new(),
+            skipped_embedding_items,
+            unsampled_items: self.unsampled_items
+        }
+    }
 }
 
 /// All embedded items under a marketplace, as well as irrelevant/error analyzed items.
@@ -25,7 +48,75 @@ pub struct MarketplaceEmbeddedAndAnalyzedItems {
     pub embedded_items: Vec<EmbeddedMarketplaceItem>,
     pub irrelevant_analyzed_items: Vec<AnalyzedMarketplaceItem>,
     pub error_analyzed_items: Vec<ErrorAnalyzedMarketplaceItem>,
-    pub error_embedded_items: Vec<ErrorEmbeddedMarketplaceItem>
+    pub error_embedded_items: Vec<ErrorEmbeddedMarketplaceItem>,
+    /// Relevant items which were deliberately not embedded.
+    #[serde(default)]
+    pub skipped_embedding_items: Vec<SkippedEmbeddingMarketplaceItem>,
+    /// Items which weren't analyzed, as they were left out of the gallery's analysis sample.
+    #[serde(default)]
+    pub unsampled_items: Vec<MarketplaceItemData>
+}
+
+impl MarketplaceEmbeddedAndAnalyzedItems {
+    /// Returns the embedded items with at least `min_confidence` (if set), most confident first.
+    /// 
+    /// Items without a confidence are sorted last, and are dropped if `min_confidence` is set.
+    pub fn embedded_items_by_confidence(&self, min_confidence: Option<f32>) -> Vec<&EmbeddedMarketplaceItem> {
+        let mut items: Vec<_> = self.embedded_items
+            .iter()
+            .filter(|item| match min_confidence {
+                Some(min_confidence) => item.confidence.is_some_and(|confidence| confidence >= min_confidence),
+                None => true
+            })
+            .collect();
+        items.sort_by(|a, b| match (a.confidence, b.confidence) {
+            (Some(a), Some(b)) => b.total_cmp(&a),
+            (Some(_), None) => Ordering::Less,
+            (None, Some(_)) => Ordering::Greater,
+            (None, None) => Ordering::Equal
+        });
+        items
+    }
+
+    /// The number of items, across all outcomes.
+    pub fn num_items(&self) -> usize {
+        self.embedded_items.len() 
+            + self.irrelevant_analyzed_items.len() 
+            + self.error_analyzed_items.len() 
+            + self.error_embedded_items.len()
+            + self.skipped_embedding_items.len()
+            + self.unsampled_items.len()
+    }
+
+    /// Merge another set of items under the marketplace into these, skipping items whose ID is already present (under any outcome).
+    /// 
+    /// Returns the number of items merged in.
+    pub fn merge(&mut self, other: MarketplaceEmbeddedAndAnalyzedItems) -> usize {
+        let mut item_ids: HashSet<ItemId> = self.embedded_items.iter().map(|item| item.item.id.clone())
+            .chain(self.irrelevant_analyzed_items.iter().map(|item| item.item.id.clone()))
+            .chain(self.error_analyzed_items.iter().map(|item| item.item.id.clone()))
+            .chain(self.error_embedded_items.iter().map(|item| item.item.item.id.clone()))
+            .chain(self.skipped_embedding_items.iter().map(|item| item.item.item.id.clone()))
+            .chain(self.unsampled_items.iter().map(|item| item.id.clone()))
+            .collect();
+        let num_item_ids = item_ids.len();
+        extend_new_items(&mut self.embedded_items, other.embedded_items, &mut item_ids, |item| &item.item.id);
+        extend_new_items(&mut self.irrelevant_analyzed_items, other.irrelevant_analyzed_items, &mut item_ids, |item| &item.item.id);
+        extend_new_items(&mut self.error_analyzed_items, other.error_analyzed_items, &mut item_ids, |item| &item.item.id);
+        extend_new_items(&mut self.error_embedded_items, other.error_embedded_items, &mut item_ids, |item| &item.item.item.id);
+        extend_new_items(&mut self.skipped_embedding_items, other.skipped_embedding_items, &mut item_ids, |item| &item.item.item.id);
+        extend_new_items(&mut self.unsampled_items, other.unsampled_items, &mut item_ids, |item| &item.id);
+        item_ids.len() - num_item_ids
+    }
+}
+
+/// Add the items of `new_items` whose ID isn't in `item_ids` to `items`, adding their IDs to `item_ids`.
+fn extend_new_items<T>(items: &mut Vec<T>, new_items: Vec<T>, item_ids: &mut HashSet<ItemId>, item_id: impl Fn(&T) -> &ItemId) {
+    items.extend(
+        new_items
+            .into_iter()
+            .filter(|item| item_ids.insert(item_id(item).clone()))
+    );
 }
 
 /// An item under a marketplace, whose description and image has been embedded.
@@ -35,7 +126,13 @@ pub struct EmbeddedMarketplaceItem {
     pub evaluation_answers: Vec<CriterionAnswer>,
     pub item_description: String,
     pub description_embedding: Vec<f32>,
-    pub image_embedding: Vec<f32>
+    pub image_embedding: Vec<f32>,
+    /// Carried over from analysis; see `AnalyzedMarketplaceItem::confidence`.
+    #[serde(default)]
+    pub confidence: Option<f32>,
+    /// Carried over from analysis; see `AnalyzedMarketplaceItem::analysis_provider`.
+    #[serde(default)]
+    pub analysis_provider: Option<String>
 }
 
 /// An analyzed item under a marketplace.
@@ -44,7 +141,18 @@ pub struct AnalyzedMarketplaceItem {
     pub item: MarketplaceItemData,
     pub evaluation_answers: Vec<CriterionAnswer>,
     pub item_description: String,
-    pub best_fit_image: usize
+    pub best_fit_image: usize,
+    /// Every marketplace this item was listed on, if it was merged from duplicate listings.
+    #[serde(default)]
+    pub source_marketplaces: Vec<Marketplace>,
+    /// How confident the model was in its analysis, from 0 to 1.
+    /// 
+    /// This is the model's self-reported score, so it's `None` for models which don't return one (or items analyzed before this was added).
+    #[serde(default)]
+    pub confidence: Option<f32>,
+    /// The name of the provider which analyzed this item (ie the primary or a fallback provider).
+    #[serde(default)]
+    pub analysis_provider: Option<String>
 }
 
 /// An item which encountered an error during analysis.
@@ -61,6 +169,13 @@ pub struct ErrorEmbeddedMarketplaceItem {
     pub error: String
 }
 
+/// A relevant item which was deliberately not embedded (ie it had too few images).
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct SkippedEmbeddingMarketplaceItem {
+    pub item: AnalyzedMarketplaceItem,
+    pub reason: String
+}
+
 /// Classified items under a marketplace.
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct ClassifiedMarketplaceItem {
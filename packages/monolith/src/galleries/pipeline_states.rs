@@ -1,10 +1,12 @@
 //! This module holds types related to each stage of the scraping pipeline.
-//! We can map each stage's state to the next stage using `map_to_next_stage`.
+//! We can map each stage's state to the next stage using its `to_next_stage`,
+//! or advance any `GalleryPipelineStates` using `advance`.
 
-use std::collections::HashMap;
+use std::{collections::HashMap, str::FromStr};
 use serde::{Serialize, Deserialize};
+use thiserror::Error;
 use super::{
-    domain_types::{GalleryId, ItemId, Marketplace, UnixUtcDateTime, ValidCronString}, eval_criteria::EvaluationCriteria, items::{item_data::MarketplaceItemData, pipeline_items::{MarketplaceAnalyzedItems, MarketplaceEmbeddedAndAnalyzedItems}}, search_criteria::GallerySearchCriteria
+    domain_types::{GalleryId, ItemId, Marketplace, ModelTokenUsage, UnixUtcDateTime, ValidCronString}, eval_criteria::EvaluationCriteria, items::{item_data::MarketplaceItemData, pipeline_items::{MarketplaceAnalyzedItems, MarketplaceEmbeddedAndAnalyzedItems}}, search_criteria::GallerySearchCriteria
 };
 
 /// The possible states of a gallery in the scraping pipeline.
@@ -43,12 +45,99 @@ impl GalleryPipelineStates {
             GalleryPipelineStates::Final(_) => GalleryPipelineStateTypes::Final,
         }
     }
+
+    /// Returns the ID of the gallery, regardless of its stage.
+    pub fn gallery_id(&self) -> &GalleryId {
+        match self {
+            GalleryPipelineStates::Initialization(state) => &state.gallery_id,
+            GalleryPipelineStates::SearchScraping(state) => &state.gallery_id,
+            GalleryPipelineStates::ItemScraping(state) => &state.gallery_id,
+            GalleryPipelineStates::ItemAnalysis(state) => &state.gallery_id,
+            GalleryPipelineStates::ItemEmbedding(state) => &state.gallery_id,
+            GalleryPipelineStates::Final(state) => &state.gallery_id,
+        }
+    }
+
+    /// Returns the reasons each marketplace failed so far, or `None` for stages which don't track failures yet
+    /// (ie `Initialization` and `SearchScraping`).
+    pub fn failed_marketplace_reasons(&self) -> Option<&HashMap<Marketplace, String>> {
+        match self {
+            GalleryPipelineStates::Initialization(_) | GalleryPipelineStates::SearchScraping(_) => None,
+            GalleryPipelineStates::ItemScraping(state) => Some(&state.failed_marketplace_reasons),
+            GalleryPipelineStates::ItemAnalysis(state) => Some(&state.failed_marketplace_reasons),
+            GalleryPipelineStates::ItemEmbedding(state) => Some(&state.failed_marketplace_reasons),
+            GalleryPipelineStates::Final(state) => Some(&state.failed_marketplace_reasons),
+        }
+    }
+
+    /// Advance the state to the next stage, using the data produced by the current stage.
+    /// 
+    /// Analyzed galleries which skip embedding advance straight to `Final`.
+    /// 
+    /// Returns an `Err` if the payload doesn't match the current stage, or the state is already `Final`.
+    pub fn advance(self, payload: StageAdvancePayload) -> Result<GalleryPipelineStates, StateTransitionError> {
+        match (self, payload) {
+            (GalleryPipelineStates::Initialization(state), StageAdvancePayload::Scheduled) => {
+                Ok(GalleryPipelineStates::SearchScraping(state.to_next_stage()))
+            },
+            (
+                GalleryPipelineStates::SearchScraping(state), 
+                StageAdvancePayload::SearchScraped { item_ids, marketplace_updated_datetimes, failed_marketplace_reasons }
+            ) => {
+                Ok(GalleryPipelineStates::ItemScraping(
+                    state.to_next_stage(item_ids, marketplace_updated_datetimes, failed_marketplace_reasons)
+                ))
+            },
+            (GalleryPipelineStates::ItemScraping(state), StageAdvancePayload::ItemsScraped(items)) => {
+                Ok(GalleryPipelineStates::ItemAnalysis(state.to_next_stage(items)))
+            },
+            (GalleryPipelineStates::ItemAnalysis(state), StageAdvancePayload::ItemsAnalyzed(items)) => {
+                match state.evaluation_criteria.skip_embedding() {
+                    true => Ok(GalleryPipelineStates::Final(state.to_final_stage_without_embedding(items))),
+                    false => Ok(GalleryPipelineStates::ItemEmbedding(state.to_next_stage(items)))
+                }
+            },
+            (GalleryPipelineStates::ItemEmbedding(state), StageAdvancePayload::ItemsEmbedded(items)) => {
+                Ok(GalleryPipelineStates::Final(state.to_next_stage(items)))
+            },
+            (GalleryPipelineStates::Final(_), _) => Err(StateTransitionError::AlreadyFinal),
+            (state, _) => Err(StateTransitionError::WrongPayload { state_type: state.state_type() })
+        }
+    }
+}
+
+/// The data produced by each stage, used for advancing a gallery to the next stage through `GalleryPipelineStates::advance`.
+#[derive(Clone, Debug)]
+pub enum StageAdvancePayload {
+    /// For advancing from `Initialization`; the scheduler produces no data.
+    Scheduled,
+    /// For advancing from `SearchScraping`.
+    SearchScraped {
+        item_ids: HashMap<Marketplace, Vec<ItemId>>,
+        marketplace_updated_datetimes: HashMap<Marketplace, UnixUtcDateTime>,
+        failed_marketplace_reasons: HashMap<Marketplace, String>
+    },
+    /// For advancing from `ItemScraping`.
+    ItemsScraped(HashMap<Marketplace, Vec<MarketplaceItemData>>),
+    /// For advancing from `ItemAnalysis`.
+    ItemsAnalyzed(HashMap<Marketplace, MarketplaceAnalyzedItems>),
+    /// For advancing from `ItemEmbedding`.
+    ItemsEmbedded(HashMap<Marketplace, MarketplaceEmbeddedAndAnalyzedItems>)
+}
+
+/// Possible errors from advancing a gallery's state.
+#[derive(Error, Debug, Serialize, Deserialize, Clone)]
+pub enum StateTransitionError {
+    #[error("The supplied payload cannot advance a gallery in the {state_type:?} state")]
+    WrongPayload { state_type: GalleryPipelineStateTypes },
+    #[error("The gallery is already in the final state")]
+    AlreadyFinal
 }
 
 /// A stateless enum of the possible states in the pipeline.
 /// 
 /// Used for matching on the stateful version using its `matches` function.
-#[derive(Clone, Debug, Serialize, Deserialize)]
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq, Hash)]
 pub enum GalleryPipelineStateTypes {
     Initialization, 
     SearchScraping, 
@@ -65,6 +154,21 @@ impl GalleryPipelineStateTypes {
     }
 }
 
+impl FromStr for GalleryPipelineStateTypes {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "Initialization" => Ok(GalleryPipelineStateTypes::Initialization),
+            "SearchScraping" => Ok(GalleryPipelineStateTypes::SearchScraping),
+            "ItemScraping" => Ok(GalleryPipelineStateTypes::ItemScraping),
+            "ItemAnalysis" => Ok(GalleryPipelineStateTypes::ItemAnalysis),
+            "ItemEmbedding" => Ok(GalleryPipelineStateTypes::ItemEmbedding),
+            "Final" => Ok(GalleryPipelineStateTypes::Final),
+            other => Err(format!("Unknown pipeline stage: {other}"))
+        }
+    }
+}
+
 /// This is the state of a gallery in the scheduler.
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct GallerySchedulerState {
@@ -73,6 +177,14 @@ pub struct GallerySchedulerState {
     pub search_criteria: GallerySearchCriteria,
     pub marketplace_previous_scraped_datetimes: HashMap<Marketplace, UnixUtcDateTime>,
     pub evaluation_criteria: EvaluationCriteria,
+    /// Whether the gallery's scheduled scrapes are fired; a disabled gallery stays registered in the scheduler.
+    #[serde(default = "default_enabled")]
+    pub enabled: bool,
+}
+
+/// Galleries persisted before `enabled` was added should stay enabled.
+fn default_enabled() -> bool {
+    true
 }
 
 impl GallerySchedulerState {
@@ -99,7 +211,25 @@ pub struct GallerySearchScrapingState {
 }
 
 impl GallerySearchScrapingState {
-
+    /// Convenience function for mapping to the next state.
+    pub fn to_next_stage(
+        self, 
+        item_ids: HashMap<Marketplace, Vec<ItemId>>,
+        marketplace_updated_datetimes: HashMap<Marketplace, UnixUtcDateTime>,
+        failed_marketplace_reasons: HashMap<Marketplace, String>
+    ) -> GalleryItemScrapingState {
+        GalleryItemScrapingState {
+            gallery_id: self.gallery_id,
+            item_ids,
+            marketplace_updated_datetimes,
+            failed_marketplace_reasons,
+            marketplace_retry_attempts: HashMap::new(),
+            completed_marketplaces: HashMap::new(),
+            max_items_per_marketplace: self.search_criteria.max_items_per_marketplace,
+            min_seller_rating: self.search_criteria.min_seller_rating,
+            evaluation_criteria: self.evaluation_criteria,
+        }
+    }
 }
 
 /// This is the state of a gallery after it has been search-scraped.
@@ -111,6 +241,20 @@ pub struct GalleryItemScrapingState {
     pub item_ids: HashMap<Marketplace, Vec<ItemId>>,
     pub marketplace_updated_datetimes: HashMap<Marketplace, UnixUtcDateTime>,
     pub failed_marketplace_reasons: HashMap<Marketplace, String>,
+    /// The number of item scrape retries so far, for marketplaces that failed.
+    #[serde(default)]
+    pub marketplace_retry_attempts: HashMap<Marketplace, u32>,
+    /// The item scrape results of marketplaces which have finished scraping so far.
+    /// 
+    /// This is checkpointed to the state tracker after each marketplace, so an interrupted scrape only resumes the rest.
+    #[serde(default)]
+    pub completed_marketplaces: HashMap<Marketplace, Vec<Result<MarketplaceItemData, String>>>,
+    /// If set, at most this many items are scraped for each marketplace.
+    #[serde(default)]
+    pub max_items_per_marketplace: Option<usize>,
+    /// If set, scraped items from sellers rated below this are dropped.
+    #[serde(default)]
+    pub min_seller_rating: Option<f32>,
     pub evaluation_criteria: EvaluationCriteria,
 }
 
@@ -123,6 +267,9 @@ impl GalleryItemScrapingState {
             marketplace_updated_datetimes: self.marketplace_updated_datetimes,
             failed_marketplace_reasons: self.failed_marketplace_reasons,
             evaluation_criteria: self.evaluation_criteria,
+            completed_items: HashMap::new(),
+            token_usage: ModelTokenUsage::default(),
+            sample_seed: rand::random(),
         }
     }
 }
@@ -137,18 +284,51 @@ pub struct GalleryItemAnalysisState {
     pub marketplace_updated_datetimes: HashMap<Marketplace, UnixUtcDateTime>,
     pub failed_marketplace_reasons: HashMap<Marketplace, String>,
     pub evaluation_criteria: EvaluationCriteria,
+    /// Marketplaces which already made it through the pipeline in a previous run, when only retrying failed analysis.
+    /// 
+    /// These are carried through untouched, and merged back in at the `Final` state.
+    #[serde(default)]
+    pub completed_items: HashMap<Marketplace, MarketplaceEmbeddedAndAnalyzedItems>,
+    /// The tokens used analyzing the gallery's items so far, including any previous runs.
+    #[serde(default)]
+    pub token_usage: ModelTokenUsage,
+    /// The seed for sampling items for analysis (if the criteria has a sample rate), which is fixed for the run.
+    #[serde(default)]
+    pub sample_seed: u64,
 }
 
 impl GalleryItemAnalysisState {
     /// Convenience function for mapping to the next state.
+    /// 
+    /// The scraped items of marketplaces missing from `items` (ie which failed analysis) are kept, so their analysis can be retried later.
     pub fn to_next_stage(self, items: HashMap<Marketplace, MarketplaceAnalyzedItems>) -> GalleryItemEmbedderState {
+        let unanalyzed_items = self.items
+            .into_iter()
+            .filter(|(marketplace, _)| !items.contains_key(marketplace))
+            .collect();
         GalleryItemEmbedderState {
             gallery_id: self.gallery_id,
             items,
             marketplace_updated_datetimes: self.marketplace_updated_datetimes,
             failed_marketplace_reasons: self.failed_marketplace_reasons,
+            unanalyzed_items,
+            completed_items: self.completed_items,
+            evaluation_criteria: self.evaluation_criteria,
+            token_usage: self.token_usage,
         }
     }
+
+    /// Convenience function for mapping straight to the final state, for galleries which skip embedding.
+    /// 
+    /// Relevant items are recorded as skipped, so the final state has no embedded items.
+    pub fn to_final_stage_without_embedding(self, items: HashMap<Marketplace, MarketplaceAnalyzedItems>) -> GalleryFinalState {
+        let mut embedder_state = self.to_next_stage(items);
+        let unembedded_items = std::mem::take(&mut embedder_state.items)
+            .into_iter()
+            .map(|(marketplace, items)| (marketplace, items.without_embedding("Embedding is skipped for this gallery")))
+            .collect();
+        embedder_state.to_next_stage(unembedded_items)
+    }
 }
 
 /// This is the state of a gallery after its items are embedded.
@@ -160,10 +340,39 @@ pub struct GalleryItemEmbedderState {
     pub items: HashMap<Marketplace, MarketplaceAnalyzedItems>,
     pub marketplace_updated_datetimes: HashMap<Marketplace, UnixUtcDateTime>,
     pub failed_marketplace_reasons: HashMap<Marketplace, String>,
+    /// The scraped items of marketplaces which failed analysis.
+    #[serde(default)]
+    pub unanalyzed_items: HashMap<Marketplace, Vec<MarketplaceItemData>>,
+    /// Marketplaces which already made it through the pipeline in a previous run.
+    #[serde(default)]
+    pub completed_items: HashMap<Marketplace, MarketplaceEmbeddedAndAnalyzedItems>,
+    /// Kept for retrying analysis of `unanalyzed_items`.
+    #[serde(default)]
+    pub evaluation_criteria: EvaluationCriteria,
+    /// The tokens used analyzing the gallery's items.
+    #[serde(default)]
+    pub token_usage: ModelTokenUsage,
 }
 
 impl GalleryItemEmbedderState {
-
+    /// Convenience function for mapping to the next state.
+    /// 
+    /// Any completed items from a previous run are merged into the embedded items.
+    pub fn to_next_stage(self, mut items: HashMap<Marketplace, MarketplaceEmbeddedAndAnalyzedItems>) -> GalleryFinalState {
+        for (marketplace, completed_items) in self.completed_items {
+            items.entry(marketplace).or_insert(completed_items);
+        }
+        GalleryFinalState {
+            gallery_id: self.gallery_id,
+            items,
+            marketplace_updated_datetimes: self.marketplace_updated_datetimes,
+            failed_marketplace_reasons: self.failed_marketplace_reasons,
+            unanalyzed_items: self.unanalyzed_items,
+            evaluation_criteria: self.evaluation_criteria,
+            token_usage: self.token_usage,
+            completed_at: UnixUtcDateTime::now(),
+        }
+    }
 }
 
 /// This is the state of a gallery after its items are classified into groups within the gallery.
@@ -176,8 +385,73 @@ pub struct GalleryFinalState {
     pub items: HashMap<Marketplace, MarketplaceEmbeddedAndAnalyzedItems>,
     pub marketplace_updated_datetimes: HashMap<Marketplace, UnixUtcDateTime>,
     pub failed_marketplace_reasons: HashMap<Marketplace, String>,
+    /// The scraped items of marketplaces which failed analysis.
+    #[serde(default)]
+    pub unanalyzed_items: HashMap<Marketplace, Vec<MarketplaceItemData>>,
+    /// Kept for retrying analysis of `unanalyzed_items`.
+    #[serde(default)]
+    pub evaluation_criteria: EvaluationCriteria,
+    /// The tokens used analyzing the gallery's items; carried into analysis retries, so they add to it.
+    #[serde(default)]
+    pub token_usage: ModelTokenUsage,
+    /// When the gallery reached this state.
+    /// 
+    /// This is required when loading, rather than defaulting, since it decides both when the gallery is compacted
+    /// and whether a stored run is superseded.
+    pub completed_at: UnixUtcDateTime,
 }
 
 impl GalleryFinalState {
+    /// Converts the gallery back into the item analysis state, for retrying only the marketplaces which failed analysis.
+    /// 
+    /// The rest of the marketplaces are kept as completed items, so they aren't analyzed again.
+    /// 
+    /// Returns `None` if no marketplaces failed analysis.
+    pub fn into_analysis_retry(mut self) -> Option<GalleryItemAnalysisState> {
+        if self.unanalyzed_items.is_empty() {
+            return None;
+        }
+        for marketplace in self.unanalyzed_items.keys() {
+            self.failed_marketplace_reasons.remove(marketplace);
+            self.items.remove(marketplace);
+        }
+        Some(GalleryItemAnalysisState {
+            gallery_id: self.gallery_id,
+            items: self.unanalyzed_items,
+            marketplace_updated_datetimes: self.marketplace_updated_datetimes,
+            failed_marketplace_reasons: self.failed_marketplace_reasons,
+            evaluation_criteria: self.evaluation_criteria,
+            completed_items: self.items,
+            token_usage: self.token_usage,
+            sample_seed: rand::random(),
+        })
+    }
 
-}
\ No newline at end of file
+    /// Returns the total number of items under each marketplace, regardless of their outcome.
+    /// 
+    /// Marketplaces which failed before reaching this state are included with a count of 0.
+    pub fn item_counts(&self) -> HashMap<Marketplace, usize> {
+        let mut counts: HashMap<Marketplace, usize> = self.failed_marketplace_reasons
+            .keys()
+            .map(|marketplace| (marketplace.clone(), 0))
+            .collect();
+        for (marketplace, items) in &self.items {
+            counts.insert(marketplace.clone(), items.num_items());
+        }
+        counts
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::test_support::final_state;
+    use super::*;
+
+    #[test]
+    fn a_final_state_without_a_completion_time_fails_to_load() {
+        let mut state_value = serde_json::to_value(final_state("gallery", 100, &["a"])).unwrap();
+        assert!(serde_json::from_value::<GalleryFinalState>(state_value.clone()).is_ok());
+        state_value.as_object_mut().unwrap().remove("completed_at");
+        assert!(serde_json::from_value::<GalleryFinalState>(state_value).is_err());
+    }
+}
@@ -1,6 +1,13 @@
+use std::collections::{HashMap, HashSet};
+
 use serde::{Serialize, Deserialize};
+use thiserror::Error;
+
+use super::domain_types::{Marketplace, UnixUtcDateTime};
 
 /// The search criteria used for all marketplaces within the gallery.
+/// 
+/// Marketplaces with an override in `per_marketplace` use its set fields instead of the global ones.
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct GallerySearchCriteria {
     pub keyword: String,
@@ -10,4 +17,224 @@ pub struct GallerySearchCriteria {
     pub min_price: Option<f32>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub max_price: Option<f32>,
-}
\ No newline at end of file
+    /// If set, only this many of the newest items are kept for each marketplace.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_items_per_marketplace: Option<usize>,
+    /// If set, items from sellers rated below this (out of 5) are dropped.
+    /// 
+    /// Items from marketplaces which don't provide seller ratings are kept.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub min_seller_rating: Option<f32>,
+    /// If set, only items updated after this are scraped; otherwise, the full search window is scraped.
+    /// 
+    /// This is set per marketplace by the search scraper, from the gallery's previous scraped datetimes.
+    #[serde(skip)]
+    pub updated_after: Option<UnixUtcDateTime>,
+    /// The marketplaces the gallery is searched on; defaults to every supported marketplace.
+    #[serde(default = "Marketplace::all")]
+    pub marketplaces: Vec<Marketplace>,
+    /// Overrides of the criteria for specific marketplaces.
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub per_marketplace: HashMap<Marketplace, MarketplaceSearchOverride>,
+}
+
+/// A problem with a gallery's search criteria.
+/// 
+/// `marketplace` is set if the problem is with the effective criteria of a marketplace with an override.
+#[derive(Error, Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum SearchCriteriaError {
+    #[error("keyword cannot be empty{}", marketplace_suffix(.marketplace))]
+    EmptyKeyword { marketplace: Option<Marketplace> },
+    #[error("{field} ({price}) cannot be negative{}", marketplace_suffix(.marketplace))]
+    NegativePrice { field: String, price: f32, marketplace: Option<Marketplace> },
+    #[error("min price ({min_price}) is greater than max price ({max_price}){}", marketplace_suffix(.marketplace))]
+    MinPriceAboveMaxPrice { min_price: f32, max_price: f32, marketplace: Option<Marketplace> },
+    #[error("keyword ({keyword}) is also excluded{}", marketplace_suffix(.marketplace))]
+    KeywordExcluded { keyword: String, marketplace: Option<Marketplace> },
+    #[error("min seller rating ({rating}) must be between 0 and 5")]
+    InvalidMinSellerRating { rating: f32 },
+    #[error("max items per marketplace cannot be 0")]
+    ZeroMaxItemsPerMarketplace,
+    #[error("marketplaces cannot be empty")]
+    NoMarketplaces
+}
+
+impl SearchCriteriaError {
+    /// Returns a copy of the error without the marketplace it applies to (if any).
+    fn without_marketplace(&self) -> Self {
+        let mut err = self.clone();
+        match &mut err {
+            SearchCriteriaError::EmptyKeyword { marketplace } |
+            SearchCriteriaError::NegativePrice { marketplace, .. } |
+            SearchCriteriaError::MinPriceAboveMaxPrice { marketplace, .. } |
+            SearchCriteriaError::KeywordExcluded { marketplace, .. } => *marketplace = None,
+            SearchCriteriaError::InvalidMinSellerRating { .. } | 
+            SearchCriteriaError::ZeroMaxItemsPerMarketplace |
+            SearchCriteriaError::NoMarketplaces => ()
+        }
+        err
+    }
+}
+
+/// Formats the marketplace a search criteria error applies to, if any.
+fn marketplace_suffix(marketplace: &Option<Marketplace>) -> String {
+    match marketplace {
+        Some(marketplace) => format!(" (for {marketplace})"),
+        None => String::new()
+    }
+}
+
+/// Overrides a gallery's search criteria for a specific marketplace; unset fields fall back to the global criteria.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct MarketplaceSearchOverride {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub keyword: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub exclude_keyword: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub min_price: Option<f32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_price: Option<f32>,
+}
+
+impl GallerySearchCriteria {
+    /// Returns a copy of the criteria, only matching items updated after `updated_after` (if any).
+    pub fn with_updated_after(&self, updated_after: Option<UnixUtcDateTime>) -> Self {
+        Self {
+            updated_after,
+            ..self.clone()
+        }
+    }
+
+    /// Returns the effective criteria for a marketplace, ie with its override (if any) applied.
+    /// 
+    /// The returned criteria has no overrides of its own.
+    pub fn for_marketplace(&self, marketplace: &Marketplace) -> Self {
+        let mut criteria = Self {
+            per_marketplace: HashMap::new(),
+            ..self.clone()
+        };
+        if let Some(marketplace_override) = self.per_marketplace.get(marketplace) {
+            if let Some(keyword) = &marketplace_override.keyword {
+                criteria.keyword = keyword.clone();
+            }
+            if let Some(exclude_keyword) = &marketplace_override.exclude_keyword {
+                criteria.exclude_keyword = exclude_keyword.clone();
+            }
+            if marketplace_override.min_price.is_some() {
+                criteria.min_price = marketplace_override.min_price;
+            }
+            if marketplace_override.max_price.is_some() {
+                criteria.max_price = marketplace_override.max_price;
+            }
+        }
+        criteria
+    }
+
+    /// Validates the criteria, returning every problem found (rather than only the first), so they can all be fixed at once.
+    /// 
+    /// The global criteria are checked, along with the effective criteria of each marketplace with an override.
+    /// Keywords are expected to already be trimmed.
+    pub fn validate(&self) -> Result<(), Vec<SearchCriteriaError>> {
+        let global_errors = self.effective_criteria_errors(None);
+        let mut errors = global_errors.clone();
+        if let Some(rating) = self.min_seller_rating.filter(|rating| !(0.0..=5.0).contains(rating)) {
+            errors.push(SearchCriteriaError::InvalidMinSellerRating { rating });
+        }
+        if self.max_items_per_marketplace == Some(0) {
+            errors.push(SearchCriteriaError::ZeroMaxItemsPerMarketplace);
+        }
+        if self.marketplaces.is_empty() {
+            errors.push(SearchCriteriaError::NoMarketplaces);
+        }
+        let mut marketplaces: Vec<_> = self.per_marketplace.keys().collect();
+        marketplaces.sort_by_key(|marketplace| marketplace.to_string());
+        for marketplace in marketplaces {
+            // Problems inherited from the global criteria are only reported once
+            let marketplace_errors = self
+                .for_marketplace(marketplace)
+                .effective_criteria_errors(Some(marketplace))
+                .into_iter()
+                .filter(|err| !global_errors.contains(&err.without_marketplace()));
+            errors.extend(marketplace_errors);
+        }
+        match errors.is_empty() {
+            true => Ok(()),
+            false => Err(errors)
+        }
+    }
+
+    /// Returns the problems with the keywords and prices of (effective) criteria, for the marketplace (if any).
+    fn effective_criteria_errors(&self, marketplace: Option<&Marketplace>) -> Vec<SearchCriteriaError> {
+        let mut errors = vec![];
+        let marketplace = marketplace.cloned();
+        if self.keyword.is_empty() {
+            errors.push(SearchCriteriaError::EmptyKeyword { marketplace: marketplace.clone() });
+        }
+        else if !self.exclude_keyword.is_empty() && self.keyword.to_lowercase() == self.exclude_keyword.to_lowercase() {
+            errors.push(SearchCriteriaError::KeywordExcluded { keyword: self.keyword.clone(), marketplace: marketplace.clone() });
+        }
+        for (field, price) in [("min price", self.min_price), ("max price", self.max_price)] {
+            if let Some(price) = price.filter(|price| *price < 0.0) {
+                errors.push(SearchCriteriaError::NegativePrice { field: field.into(), price, marketplace: marketplace.clone() });
+            }
+        }
+        if let (Some(min_price), Some(max_price)) = (self.min_price, self.max_price) {
+            if min_price > max_price {
+                errors.push(SearchCriteriaError::MinPriceAboveMaxPrice { min_price, max_price, marketplace });
+            }
+        }
+        errors
+    }
+
+    /// Returns the names of the fields which materially differ from `other`, ie which would change the scraped results.
+    /// 
+    /// Keywords are compared ignoring case and surrounding whitespace, and `updated_after` is ignored (as it's set per scrape).
+    pub fn diff(&self, other: &Self) -> Vec<&'static str> {
+        let normalize = |keyword: &str| keyword.trim().to_lowercase();
+        let mut changed_fields = vec![];
+        if normalize(&self.keyword) != normalize(&other.keyword) {
+            changed_fields.push("keyword");
+        }
+        if normalize(&self.exclude_keyword) != normalize(&other.exclude_keyword) {
+            changed_fields.push("exclude_keyword");
+        }
+        if self.min_price != other.min_price {
+            changed_fields.push("min_price");
+        }
+        if self.max_price != other.max_price {
+            changed_fields.push("max_price");
+        }
+        if self.max_items_per_marketplace != other.max_items_per_marketplace {
+            changed_fields.push("max_items_per_marketplace");
+        }
+        if self.min_seller_rating != other.min_seller_rating {
+            changed_fields.push("min_seller_rating");
+        }
+        if self.marketplaces.iter().collect::<HashSet<_>>() != other.marketplaces.iter().collect::<HashSet<_>>() {
+            changed_fields.push("marketplaces");
+        }
+        if self.per_marketplace != other.per_marketplace {
+            changed_fields.push("per_marketplace");
+        }
+        changed_fields
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn marketplaces_default_to_every_supported_marketplace() {
+        let criteria: GallerySearchCriteria = serde_json::from_str(r#"{"keyword": "test", "excludeKeyword": ""}"#).unwrap();
+        assert_eq!(criteria.marketplaces, Marketplace::all());
+    }
+
+    #[test]
+    fn empty_marketplaces_are_invalid() {
+        let mut criteria: GallerySearchCriteria = serde_json::from_str(r#"{"keyword": "test", "excludeKeyword": ""}"#).unwrap();
+        criteria.marketplaces = vec![];
+        assert_eq!(criteria.validate(), Err(vec![SearchCriteriaError::NoMarketplaces]));
+    }
+}
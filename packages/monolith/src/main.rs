@@ -3,12 +3,16 @@ mod galleries;
 mod scraping_pipeline;
 mod messages;
 mod routes;
+mod notifications;
 mod utils;
+#[cfg(any(test, feature = "test-support"))]
+mod test_support;
 
+use std::time::Duration;
 use axum::Router;
 use config::{AppConfig, AxumConfig};
 use scraping_pipeline::{AppModuleConnections, AppModules};
-use tokio::net::TcpListener;
+use tokio::{net::TcpListener, signal};
 use dotenv::dotenv;
 
 #[tokio::main]
@@ -18,16 +22,44 @@ async fn main() {
 
     let app_config = AppConfig::load().unwrap();
     let axum_config = app_config.axum_config.clone();
-    let module_connections = AppModuleConnections::new();
-    let router = routes::build_router(&app_config.axum_config, &module_connections);
+    let module_connections = AppModuleConnections::new(&app_config);
+    let router = routes::build_router(&app_config, &module_connections);
     let app_modules = AppModules::init(app_config, module_connections).await.run();
 
     tracing::info!("App started");
 
     start_app(router, &axum_config).await;
+    app_modules.shutdown(Duration::from_secs(axum_config.shutdown_timeout_secs)).await;
 }
 
 async fn start_app(router: Router, axum_config: &AxumConfig) {
     let listener = TcpListener::bind(axum_config.host_addr.clone()).await.unwrap();
-    axum::serve(listener, router).await.unwrap();
+    axum::serve(listener, router)
+        .with_graceful_shutdown(shutdown_signal())
+        .await
+        .unwrap();
+}
+
+/// Resolves once a SIGINT (ie Ctrl-C) or SIGTERM is received.
+async fn shutdown_signal() {
+    let ctrl_c = async {
+        signal::ctrl_c()
+            .await
+            .expect("Failed to install Ctrl-C handler");
+    };
+    #[cfg(unix)]
+    let terminate = async {
+        signal::unix::signal(signal::unix::SignalKind::terminate())
+            .expect("Failed to install SIGTERM handler")
+            .recv()
+            .await;
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => {},
+        _ = terminate => {},
+    }
+    tracing::info!("Received shutdown signal");
 }
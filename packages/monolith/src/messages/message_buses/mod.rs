@@ -1,8 +1,9 @@
 //! This module contains message buses, which are effectively just mpsc sender/receiver wrappers.
 use serde::{Deserialize, Serialize};
-use tokio::sync::{mpsc::{error::SendError, Receiver, Sender}, oneshot::error::RecvError};
-use std::fmt::Debug;
+use tokio::sync::{mpsc::{self, error::{self as mpsc_error, SendError}, Receiver, Sender}, oneshot::error::RecvError};
+use std::{fmt::{Debug, Write}, sync::{atomic::{AtomicU64, Ordering}, Arc}, time::{Duration, Instant}};
 use thiserror::Error;
+use tracing::Span;
 
 /// The errors that may arise from failure to send/receive a message.
 #[derive(Serialize, Deserialize, Error, Debug, Clone)]
@@ -10,7 +11,9 @@ pub enum MessageError {
     #[error("{0}")]
     SendError(String),
     #[error("{0}")]
-    RecvError(String)
+    RecvError(String),
+    #[error("Timed out after {0:?} waiting for the receiver to accept the message")]
+    SendTimeout(Duration)
 }
 
 impl<T> From<SendError<T>> for MessageError {
@@ -25,47 +28,416 @@ impl From<RecvError> for MessageError {
     }
 }
 
+impl<T: Debug> From<TrySendError<T>> for MessageError {
+    fn from(error: TrySendError<T>) -> Self {
+        MessageError::SendError(error.to_string())
+    }
+}
+
+/// The errors that may arise from failure to send a message without waiting; the unsent message is returned in either.
+#[derive(Error, Debug)]
+pub enum TrySendError<T: Debug> {
+    #[error("The bus is full")]
+    Full(T),
+    #[error("The bus is closed")]
+    Closed(T)
+}
+
+/// A message in transit, along with when it was sent and the span it was sent in.
+#[derive(Debug)]
+pub struct Envelope<T: Debug> {
+    message: T,
+    sent_at: Instant,
+    span: Span
+}
+
+impl<T: Debug> Envelope<T> {
+    /// Wrap a message being sent now, in the current span.
+    fn new(message: T) -> Self {
+        Self {
+            message,
+            sent_at: Instant::now(),
+            span: Span::current()
+        }
+    }
+}
+
+/// Metrics for a single message bus, shared between its senders and receiver.
+/// 
+/// These are only atomic counters, so updating them is cheap.
+#[derive(Debug)]
+pub struct BusMetrics {
+    message_type: &'static str,
+    queued: AtomicU64,
+    received: AtomicU64,
+    total_latency_micros: AtomicU64,
+    blocked_sends: AtomicU64,
+    dead_letters: AtomicU64
+}
+
+impl BusMetrics {
+    /// Instantiate the metrics for a bus carrying messages of type T, with all counters at 0.
+    pub fn new<T>() -> Self {
+        let type_name = std::any::type_name::<T>();
+        let message_type = type_name
+            .split('<')
+            .next()
+            .and_then(|path| path.rsplit("::").next())
+            .unwrap_or(type_name);
+        Self {
+            message_type,
+            queued: AtomicU64::new(0),
+            received: AtomicU64::new(0),
+            total_latency_micros: AtomicU64::new(0),
+            blocked_sends: AtomicU64::new(0),
+            dead_letters: AtomicU64::new(0)
+        }
+    }
+
+    /// Render the metrics of all buses in the Prometheus text exposition format.
+    pub fn render_prometheus(all_bus_metrics: &[Arc<BusMetrics>]) -> String {
+        let mut output = String::new();
+        let _ = writeln!(output, "# HELP itemtracker_bus_queued_messages Number of messages sent on a bus but not yet received.");
+        let _ = writeln!(output, "# TYPE itemtracker_bus_queued_messages gauge");
+        for metrics in all_bus_metrics {
+            let _ = writeln!(output, "itemtracker_bus_queued_messages{{message_type=\"{}\"}} {}", metrics.message_type, metrics.queued.load(Ordering::Relaxed));
+        }
+        let _ = writeln!(output, "# HELP itemtracker_bus_latency_seconds Time between a message being sent and received on a bus.");
+        let _ = writeln!(output, "# TYPE itemtracker_bus_latency_seconds summary");
+        for metrics in all_bus_metrics {
+            let latency_secs = metrics.total_latency_micros.load(Ordering::Relaxed) as f64 / 1_000_000.0;
+            let _ = writeln!(output, "itemtracker_bus_latency_seconds_sum{{message_type=\"{}\"}} {latency_secs}", metrics.message_type);
+            let _ = writeln!(output, "itemtracker_bus_latency_seconds_count{{message_type=\"{}\"}} {}", metrics.message_type, metrics.received.load(Ordering::Relaxed));
+        }
+        let _ = writeln!(output, "# HELP itemtracker_bus_blocked_sends_total Number of sends which had to wait for room on a full bus.");
+        let _ = writeln!(output, "# TYPE itemtracker_bus_blocked_sends_total counter");
+        for metrics in all_bus_metrics {
+            let _ = writeln!(output, "itemtracker_bus_blocked_sends_total{{message_type=\"{}\"}} {}", metrics.message_type, metrics.blocked_sends.load(Ordering::Relaxed));
+        }
+        let _ = writeln!(output, "# HELP itemtracker_bus_dead_letters_total Number of messages on a bus which couldn't be acted on.");
+        let _ = writeln!(output, "# TYPE itemtracker_bus_dead_letters_total counter");
+        for metrics in all_bus_metrics {
+            let _ = writeln!(output, "itemtracker_bus_dead_letters_total{{message_type=\"{}\"}} {}", metrics.message_type, metrics.dead_letters.load(Ordering::Relaxed));
+        }
+        output
+    }
+
+    /// Record a message being sent.
+    fn record_sent(&self) {
+        self.queued.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Record a send having to wait for room on the bus.
+    fn record_blocked_send(&self) {
+        self.blocked_sends.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Record a message failing to send, after it was recorded as sent.
+    fn record_send_failed(&self) {
+        self.queued.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    /// Record a message being received, along with when it was sent.
+    fn record_received(&self, sent_at: Instant) {
+        self.queued.fetch_sub(1, Ordering::Relaxed);
+        self.received.fetch_add(1, Ordering::Relaxed);
+        self.total_latency_micros.fetch_add(sent_at.elapsed().as_micros() as u64, Ordering::Relaxed);
+    }
+
+    /// Record a message being dead-lettered.
+    fn record_dead_letter(&self) {
+        self.dead_letters.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// Create a message bus holding up to `buffer` messages, returning its sender, receiver, and metrics.
+pub fn message_bus<T: Debug>(buffer: usize) -> (MessageSender<T>, MessageReceiver<T>, Arc<BusMetrics>) {
+    let (sender, receiver) = mpsc::channel(buffer);
+    let metrics = Arc::new(BusMetrics::new::<T>());
+    (
+        MessageSender::new(sender, metrics.clone()),
+        MessageReceiver::new(receiver, metrics.clone()),
+        metrics
+    )
+}
+
+/// Create a message bus like `message_bus`, whose receiver forwards messages which couldn't be acted on to a dead-letter bus.
+/// 
+/// The dead-letter bus is drained by a spawned task, which logs each dead letter and counts it in the bus's metrics,
+/// so this must be called within a Tokio runtime.
+pub fn message_bus_with_dead_letter<T: Debug + Send + 'static>(buffer: usize) -> (MessageSender<T>, MessageReceiver<T>, Arc<BusMetrics>) {
+    let (sender, receiver) = mpsc::channel(buffer);
+    let metrics = Arc::new(BusMetrics::new::<T>());
+    let (dead_letter_sender, dead_letter_receiver, _) = message_bus(buffer);
+    tokio::spawn(drain_dead_letters(dead_letter_receiver, metrics.clone()));
+    (
+        MessageSender::new(sender, metrics.clone()),
+        MessageReceiver::with_dead_letter(receiver, metrics.clone(), dead_letter_sender),
+        metrics
+    )
+}
+
+/// Log and count each dead letter until all of the bus's dead-letter senders are dropped.
+async fn drain_dead_letters<T: Debug>(mut dead_letter_receiver: MessageReceiver<DeadLetter<T>>, metrics: Arc<BusMetrics>) {
+    while let Some(dead_letter) = dead_letter_receiver.receive().await {
+        metrics.record_dead_letter();
+        tracing::warn!("Dead-lettered a {} message: {}", metrics.message_type, dead_letter.reason);
+        if let Some(message) = dead_letter.message {
+            tracing::debug!("Dead-lettered message: {message:?}");
+        }
+    }
+}
+
 /// A handle for sending messages of type T to a module.
 #[derive(Debug)]
 pub struct MessageSender<T: Debug> {
-    sender: Sender<T>
+    sender: Sender<Envelope<T>>,
+    metrics: Arc<BusMetrics>
 }
 
 impl<T: Debug> MessageSender<T> {
     /// Initialize the message sender.
-    pub fn new(sender: Sender<T>) -> Self {
-        Self { sender }
+    pub fn new(sender: Sender<Envelope<T>>, metrics: Arc<BusMetrics>) -> Self {
+        Self { sender, metrics }
     }
 
     /// Send a message through the sender.
+    /// 
+    /// If the bus is full, this waits for room on it, which is recorded in the bus's metrics;
+    /// this is how a slow receiver applies backpressure to its senders.
     pub async fn send(&mut self, message: T) -> Result<(), MessageError> {
+        self.metrics.record_sent();
+        let result = match self.sender.try_send(Envelope::new(message)) {
+            Ok(_) => Ok(()),
+            Err(mpsc_error::TrySendError::Full(envelope)) => {
+                self.metrics.record_blocked_send();
+                self.sender.send(envelope).await
+            },
+            Err(mpsc_error::TrySendError::Closed(envelope)) => Err(SendError(envelope))
+        };
+        result.map_err(|err| {
+            self.metrics.record_send_failed();
+            err.into()
+        })
+    }
+
+    /// Send a message through the sender, failing with a `SendTimeout` if the bus doesn't have room for it within `timeout`.
+    pub async fn send_with_timeout(&mut self, message: T, timeout: Duration) -> Result<(), MessageError> {
+        match tokio::time::timeout(timeout, self.send(message)).await {
+            Ok(result) => result,
+            Err(_) => {
+                self.metrics.record_send_failed();
+                Err(MessageError::SendTimeout(timeout))
+            }
+        }
+    }
+
+    /// Send a message through the sender without waiting, failing if the bus is full (or closed).
+    /// 
+    /// This is for producers which would rather drop a message than wait for room on the bus.
+    /// A rejected message isn't retried or dead-lettered; it's handed back in the `Err`, 
+    /// and if the caller drops it, the message is lost, so that's the caller's responsibility.
+    pub fn try_send(&self, message: T) -> Result<(), TrySendError<T>> {
+        self.metrics.record_sent();
         self.sender
-            .send(message)
-            .await
-            .map_err(Into::into)
+            .try_send(Envelope::new(message))
+            .map_err(|err| {
+                self.metrics.record_send_failed();
+                match err {
+                    mpsc_error::TrySendError::Full(envelope) => TrySendError::Full(envelope.message),
+                    mpsc_error::TrySendError::Closed(envelope) => TrySendError::Closed(envelope.message)
+                }
+            })
     }
 }   
 
 impl<T: Debug> Clone for MessageSender<T> {
     fn clone(&self) -> Self {
-        Self { sender: self.sender.clone() }
+        Self { 
+            sender: self.sender.clone(),
+            metrics: self.metrics.clone()
+        }
     }
 }
 
+/// A message which couldn't be acted on, along with the reason why.
+#[derive(Debug)]
+pub struct DeadLetter<T: Debug> {
+    /// The message, unless it was consumed by acting on it (ie if only its response couldn't be delivered).
+    pub message: Option<T>,
+    pub reason: String
+}
+
 /// A handle for a module to receive messages of type T.
+/// 
+/// If a dead-letter sender is configured, messages which couldn't be acted on are forwarded to it;
+/// this includes any messages still buffered when the receiver is dropped (ie if its module panics).
 #[derive(Debug)]
 pub struct MessageReceiver<T: Debug> {
-    receiver: Receiver<T>
+    receiver: Receiver<Envelope<T>>,
+    metrics: Arc<BusMetrics>,
+    dead_letter_sender: Option<MessageSender<DeadLetter<T>>>
 }
 
 impl <T: Debug> MessageReceiver<T> {
     /// Instantiate the message receiver.
-    pub fn new(receiver: Receiver<T>) -> Self {
-        Self { receiver }
+    pub fn new(receiver: Receiver<Envelope<T>>, metrics: Arc<BusMetrics>) -> Self {
+        Self { 
+            receiver,
+            metrics,
+            dead_letter_sender: None
+        }
+    }
+
+    /// Instantiate the message receiver, forwarding undeliverable messages to `dead_letter_sender`.
+    pub fn with_dead_letter(
+        receiver: Receiver<Envelope<T>>, 
+        metrics: Arc<BusMetrics>, 
+        dead_letter_sender: MessageSender<DeadLetter<T>>
+    ) -> Self {
+        Self {
+            receiver,
+            metrics,
+            dead_letter_sender: Some(dead_letter_sender)
+        }
     }
 
     /// Receive a message through the receiver.
     pub async fn receive(&mut self) -> Option<T> {
-        self.receiver.recv().await
+        self.receive_with_span()
+            .await
+            .map(|(message, _)| message)
+    }
+
+    /// Receive a message through the receiver, along with the span it was sent in.
+    /// 
+    /// Handling the message in (a child of) this span lets its logs be correlated with the sender's.
+    pub async fn receive_with_span(&mut self) -> Option<(T, Span)> {
+        let envelope = self.receiver.recv().await?;
+        self.metrics.record_received(envelope.sent_at);
+        Some((envelope.message, envelope.span))
+    }
+
+    /// Forward a message which couldn't be acted on to the dead-letter sender.
+    /// 
+    /// Does nothing if no dead-letter sender is configured.
+    pub async fn dead_letter(&mut self, message: T, reason: impl Into<String>) {
+        self.send_dead_letter(DeadLetter { message: Some(message), reason: reason.into() }).await;
+    }
+
+    /// Forward a response which couldn't be delivered (ie as its sender stopped waiting for it) to the dead-letter sender.
+    /// 
+    /// The message was consumed by acting on it, so only the reason is forwarded; it should identify the message.
+    /// Does nothing if no dead-letter sender is configured.
+    pub async fn dead_letter_response(&mut self, reason: impl Into<String>) {
+        self.send_dead_letter(DeadLetter { message: None, reason: reason.into() }).await;
+    }
+
+    /// Send a dead letter to the dead-letter sender, if one is configured.
+    async fn send_dead_letter(&mut self, dead_letter: DeadLetter<T>) {
+        if let Some(dead_letter_sender) = &mut self.dead_letter_sender {
+            if let Err(err) = dead_letter_sender.send(dead_letter).await {
+                tracing::warn!("Failed to forward a message to the dead-letter sender: {err}");
+            }
+        }
+    }
+}
+
+impl<T: Debug> Drop for MessageReceiver<T> {
+    fn drop(&mut self) {
+        if let Some(dead_letter_sender) = &self.dead_letter_sender {
+            self.receiver.close();
+            while let Ok(envelope) = self.receiver.try_recv() {
+                self.metrics.record_received(envelope.sent_at);
+                let dead_letter = DeadLetter { 
+                    message: Some(envelope.message), 
+                    reason: "Receiver was dropped before the message could be processed".into() 
+                };
+                if let Err(err) = dead_letter_sender.try_send(dead_letter) {
+                    tracing::warn!("Failed to forward a message to the dead-letter sender: {err}");
+                }
+            }
+        }
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TEST_TIMEOUT: Duration = Duration::from_secs(1);
+
+    /// A bus whose receiver forwards to the returned dead-letter receiver.
+    fn bus_with_dead_letter_receiver() -> (MessageSender<u32>, MessageReceiver<u32>, MessageReceiver<DeadLetter<u32>>) {
+        let (sender, receiver) = mpsc::channel(4);
+        let metrics = Arc::new(BusMetrics::new::<u32>());
+        let (dead_letter_sender, dead_letter_receiver, _) = message_bus(4);
+        (
+            MessageSender::new(sender, metrics.clone()),
+            MessageReceiver::with_dead_letter(receiver, metrics, dead_letter_sender),
+            dead_letter_receiver
+        )
+    }
+
+    #[tokio::test]
+    async fn forwards_dead_letters_with_their_reason() {
+        let (_sender, mut receiver, mut dead_letter_receiver) = bus_with_dead_letter_receiver();
+        receiver.dead_letter(1, "unhandled").await;
+        let dead_letter = dead_letter_receiver.receive().await.unwrap();
+        assert_eq!(dead_letter.message, Some(1));
+        assert_eq!(dead_letter.reason, "unhandled");
+    }
+
+    #[tokio::test]
+    async fn forwards_undelivered_responses_without_a_message() {
+        let (_sender, mut receiver, mut dead_letter_receiver) = bus_with_dead_letter_receiver();
+        receiver.dead_letter_response("undelivered").await;
+        let dead_letter = dead_letter_receiver.receive().await.unwrap();
+        assert_eq!(dead_letter.message, None);
+        assert_eq!(dead_letter.reason, "undelivered");
+    }
+
+    #[tokio::test]
+    async fn forwards_buffered_messages_when_dropped() {
+        let (mut sender, receiver, mut dead_letter_receiver) = bus_with_dead_letter_receiver();
+        sender.send(1).await.unwrap();
+        sender.send(2).await.unwrap();
+        drop(receiver);
+        assert_eq!(dead_letter_receiver.receive().await.unwrap().message, Some(1));
+        assert_eq!(dead_letter_receiver.receive().await.unwrap().message, Some(2));
+    }
+
+    #[tokio::test]
+    async fn a_message_rejected_by_a_full_bus_is_handed_back() {
+        let (sender, _receiver, metrics) = message_bus::<u32>(1);
+        sender.try_send(1).unwrap();
+        match sender.try_send(2) {
+            Err(TrySendError::Full(message)) => assert_eq!(message, 2),
+            other => panic!("Expected the message to be handed back as the bus is full, but got {other:?}")
+        }
+        assert_eq!(metrics.queued.load(Ordering::Relaxed), 1);
+    }
+
+    #[tokio::test]
+    async fn dead_letter_is_a_noop_without_a_sender() {
+        let (_, mut receiver, metrics) = message_bus::<u32>(4);
+        receiver.dead_letter(1, "unhandled").await;
+        assert_eq!(metrics.dead_letters.load(Ordering::Relaxed), 0);
+    }
+
+    #[tokio::test]
+    async fn drained_dead_letters_are_counted() {
+        let (_, mut receiver, metrics) = message_bus_with_dead_letter::<u32>(4);
+        receiver.dead_letter(1, "unhandled").await;
+        receiver.dead_letter(2, "unhandled").await;
+        tokio::time::timeout(TEST_TIMEOUT, async {
+            while metrics.dead_letters.load(Ordering::Relaxed) < 2 {
+                tokio::task::yield_now().await;
+            }
+        })
+            .await
+            .expect("Both dead letters should be drained");
+        let rendered = BusMetrics::render_prometheus(&[metrics]);
+        assert!(rendered.contains("itemtracker_bus_dead_letters_total{message_type=\"u32\"} 2"));
+    }
+}
@@ -1,7 +1,7 @@
 use serde::{Serialize, Deserialize};
 use thiserror::Error;
-use crate::{galleries::{domain_types::GalleryId, pipeline_states::GalleryItemAnalysisState}, messages::message_buses::MessageError};
-use super::state_tracker::StateTrackerError;
+use crate::{galleries::{domain_types::{GalleryId, TokenUsage}, eval_criteria::{CriterionAnswer, EvaluationCriteria}, items::item_data::MarketplaceItemData, pipeline_states::GalleryItemAnalysisState}, messages::message_buses::MessageError};
+use super::{state_tracker::StateTrackerError, ModuleMessageWithReturn};
 
 /// Possible errors emitted from the item analysis module.
 #[derive(Error, Debug, Serialize, Deserialize, Clone)]
@@ -22,4 +22,32 @@ pub enum ItemAnalysisMessage {
     AnalyzeGallery { gallery_id: GalleryId },
     /// Message for starting analysis of a new gallery.
     AnalyzeGalleryNew { gallery: GalleryItemAnalysisState },
+    /// Message for analyzing a sample item against proposed evaluation criteria, without touching the pipeline.
+    PreviewCriteria(PreviewCriteriaMessage),
+}
+
+/// Message for previewing evaluation criteria on a sample item.
+pub type PreviewCriteriaMessage = ModuleMessageWithReturn<CriteriaPreviewRequest, Result<CriteriaPreview, String>>;
+
+/// A sample item, and the evaluation criteria to preview on it.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct CriteriaPreviewRequest {
+    pub item: MarketplaceItemData,
+    pub evaluation_criteria: EvaluationCriteria
+}
+
+/// The model's verdict on a sample item under the previewed evaluation criteria.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct CriteriaPreview {
+    /// Whether the item would be kept as relevant.
+    pub relevant: bool,
+    /// Whether the item passes the criteria's prefilters; items which don't are never sent to the model in the pipeline.
+    pub passes_prefilter: bool,
+    pub evaluation_answers: Vec<CriterionAnswer>,
+    pub item_description: String,
+    pub confidence: Option<f32>,
+    /// The provider and model which analyzed the item.
+    pub provider: String,
+    pub model: String,
+    pub token_usage: TokenUsage
 }
\ No newline at end of file
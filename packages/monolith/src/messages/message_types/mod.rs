@@ -50,6 +50,11 @@ where
         (message, receiver)
     }
 
+    /// Get a reference to the message, without acting on it.
+    pub fn message(&self) -> &Message {
+        &self.message
+    }
+
     /// Act upon the message and provide a response to it.
     /// 
     /// Returns an `Err` with the response value if it couldn't be successfully delivered.
@@ -1,17 +1,21 @@
 use thiserror::Error;
 use serde::{Deserialize, Serialize};
-use crate::{galleries::{domain_types::GalleryId, pipeline_states::GallerySchedulerState}, messages::message_buses::MessageError};
+use crate::{galleries::{domain_types::{GalleryId, UnixUtcDateTime, ValidCronString}, pipeline_states::GallerySchedulerState}, messages::message_buses::MessageError};
 use super::{state_tracker::StateTrackerError, ModuleMessageWithReturn};
 
 /// Possible errors emitted from the scraper scheduler.
 #[derive(Error, Debug, Serialize, Deserialize, Clone)]
 pub enum SchedulerError {
-    #[error("Gallery {gallery_id} not found and cannot be deleted")]
+    #[error("Gallery {gallery_id} is not scheduled")]
     GalleryNotFound { gallery_id: GalleryId },
     #[error("Gallery {gallery_id} already exists and cannot be added again")]
     GalleryAlreadyExists { gallery_id: GalleryId },
     #[error("Update for gallery {gallery_id} has the wrong gallery ID")]
     GalleryUpdateHasWrongId { gallery_id: GalleryId },
+    #[error("Gallery {gallery_id} has an invalid scraping schedule: {message}")]
+    InvalidSchedule { gallery_id: GalleryId, message: String },
+    #[error("A scrape of gallery {gallery_id} is already in progress")]
+    ScrapeInProgress { gallery_id: GalleryId },
     #[error("Error from state tracker for gallery {gallery_id}: {err}")]
     StateErr { gallery_id: GalleryId, err: StateTrackerError },
     #[error("Error while sending a message for gallery {gallery_id}: {err}")]
@@ -26,7 +30,44 @@ pub enum SchedulerError {
 pub enum SchedulerMessage {
     NewGallery(NewGalleryMessage),
     DeleteGallery(DeleteGalleryMessage),
-    UpdateGallery(UpdateGalleryMessage)
+    UpdateGallery(UpdateGalleryMessage),
+    /// Get when a gallery will next be scraped (including its jitter), or `None` if its schedule never fires again.
+    /// 
+    /// Returns an `Err` if the gallery isn't scheduled.
+    GetNextRun(GetNextRunMessage),
+    /// Immediately send a gallery to the search scraper, without affecting its schedule.
+    /// 
+    /// If the gallery is already being scraped, the scrape is queued or rejected, depending on the concurrent scrape policy.
+    /// 
+    /// Returns an `Err` if the gallery isn't scheduled, or the scrape was rejected.
+    ForceScrape(ForceScrapeMessage),
+    /// Enable or disable a gallery's scheduled scrapes; a disabled gallery stays in the scheduler, but its schedule doesn't fire.
+    /// 
+    /// Returns an `Err` if the gallery isn't scheduled.
+    SetEnabled(SetEnabledMessage),
+    /// Get a snapshot of every scheduled gallery's schedule, sorted by ID.
+    GetSchedule(GetScheduleMessage)
+}
+
+/// What happened to a scrape trigger for a gallery.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ScrapeStart {
+    /// The gallery was sent into the pipeline.
+    Started,
+    /// The gallery was already being scraped, so it'll be scraped once that finishes.
+    Queued
+}
+
+/// A snapshot of a scheduled gallery's schedule.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ScheduledGallerySnapshot {
+    pub gallery_id: GalleryId,
+    pub scraping_periodicity: ValidCronString,
+    /// A plain-English description of the schedule (see `ValidCronString::describe`).
+    pub schedule_description: String,
+    pub enabled: bool,
+    /// When the gallery will next be scraped, including its jitter; not set if its schedule never fires again.
+    pub next_fire_time: Option<UnixUtcDateTime>
 }
 
 /// Message for adding a new gallery to the scheduler.
@@ -36,4 +77,16 @@ pub type NewGalleryMessage = ModuleMessageWithReturn<GallerySchedulerState, Resu
 pub type DeleteGalleryMessage = ModuleMessageWithReturn<GalleryId, Result<(), SchedulerError>>;
 
 /// Message for editing a gallery in the scheduler.
-pub type UpdateGalleryMessage = ModuleMessageWithReturn<GallerySchedulerState, Result<(), SchedulerError>>;
\ No newline at end of file
+pub type UpdateGalleryMessage = ModuleMessageWithReturn<GallerySchedulerState, Result<(), SchedulerError>>;
+
+/// Message for getting when a gallery will next be scraped.
+pub type GetNextRunMessage = ModuleMessageWithReturn<GalleryId, Result<Option<UnixUtcDateTime>, SchedulerError>>;
+
+/// Message for immediately scraping a gallery.
+pub type ForceScrapeMessage = ModuleMessageWithReturn<GalleryId, Result<ScrapeStart, SchedulerError>>;
+
+/// Message for enabling or disabling a gallery's scheduled scrapes.
+pub type SetEnabledMessage = ModuleMessageWithReturn<(GalleryId, bool), Result<(), SchedulerError>>;
+
+/// Message for getting a snapshot of the scheduler's galleries.
+pub type GetScheduleMessage = ModuleMessageWithReturn<(), Vec<ScheduledGallerySnapshot>>;
@@ -1,6 +1,7 @@
-use crate::galleries::{domain_types::GalleryId, pipeline_states::{GalleryPipelineStateTypes, GalleryPipelineStates}};
+use crate::galleries::{domain_types::{GalleryId, RunId, UnixUtcDateTime}, pipeline_states::{GalleryPipelineStateTypes, GalleryPipelineStates}};
 use super::ModuleMessageWithReturn;
 use redis::RedisError;
+use tokio_util::sync::CancellationToken;
 use serde::{Deserialize, Serialize};
 use thiserror::Error;
 
@@ -13,6 +14,14 @@ pub enum StateTrackerError {
     GalleryDoesntExist,
     #[error("Gallery has the wrong state")]
     GalleryHasWrongState,
+    #[error("Gallery's state is currently taken")]
+    GalleryStateTaken,
+    #[error("Gallery was cancelled")]
+    GalleryCancelled,
+    #[error("Gallery has no stored snapshot for that stage")]
+    SnapshotNotFound,
+    #[error("Gallery is in the {actual:?} stage, not the expected {expected:?} stage")]
+    UnexpectedStage { expected: GalleryPipelineStateTypes, actual: GalleryPipelineStateTypes },
     #[error("{0}")]
     Other(String)
 }
@@ -42,10 +51,18 @@ pub enum StateTrackerMessage {
     /// 
     /// Returns an `Err` if it isn't (not intuitive, but allows one to use the returned `StateTrackerError`)
     CheckGalleryDoesntExist(CheckGalleryDoesntExistMessage),
+    /// Check that a gallery is in state, returning its current state type.
+    /// 
+    /// Returns an `Err` if it isn't.
+    CheckGalleryExists(CheckGalleryExistsMessage),
     /// Take the gallery's state (leaving the stored state as `None`).
     /// 
     /// Returns an `Err` if the gallery doesn't exist, its state has already been taken, or the requested state type doesn't match the stored state.
     GetGalleryState(GetGalleryStateMessage),
+    /// Get a copy of the gallery's state, leaving the stored state untouched.
+    /// 
+    /// Returns an `Err` if the gallery doesn't exist, its state is currently taken, or the requested state type doesn't match the stored state.
+    PeekGalleryState(PeekGalleryStateMessage),
     /// Update a gallery's state, overwriting its old state.
     /// 
     /// Returns an `Err` if the gallery doesn't exist, or its state has not been taken.
@@ -53,25 +70,156 @@ pub enum StateTrackerMessage {
     /// Remove a gallery from the state.
     /// 
     /// Returns an `Err` if the gallery doesn't exist.
-    RemoveGallery(RemoveGalleryMessage)
+    RemoveGallery(RemoveGalleryMessage),
+    /// Cancel a gallery's current run and remove it from the state, rejecting any later adds or updates for it.
+    /// 
+    /// Returns an `Err` if the gallery doesn't exist in the state, though it's still cancelled.
+    CancelGallery(CancelGalleryMessage),
+    /// Get the cancellation token for a gallery's current run, which is cancelled if the gallery is cancelled.
+    /// 
+    /// Returns an `Err` if the gallery doesn't exist.
+    GetCancellationToken(GetCancellationTokenMessage),
+    /// Remove a gallery from the state, only if it's in the expected stage and its state isn't taken.
+    /// 
+    /// Returns an `Err` if the gallery doesn't exist, is in another stage, or is currently being processed.
+    RemoveGalleryIfState(RemoveGalleryIfStateMessage),
+    /// Get the IDs of all galleries which haven't reached the `Final` state.
+    GetInFlightGalleries(GetInFlightGalleriesMessage),
+    /// Get the IDs and state types of all galleries in the state, sorted by ID.
+    ListGalleries(ListGalleriesMessage),
+    /// Write all gallery states through to the backing store, returning how many were persisted.
+    PersistAll(PersistAllMessage),
+    /// Get all galleries which were marked as stalled, sorted by ID.
+    GetStalledGalleries(GetStalledGalleriesMessage),
+    /// Get the latest stored snapshot of a gallery's state in a stage, even if it's no longer in the state.
+    /// 
+    /// Returns an `Err` if the gallery never reached the stage.
+    GetStageSnapshot(GetStageSnapshotMessage),
+    /// Get a gallery's state transitions from the audit log, oldest first, even if it's no longer in the state.
+    /// 
+    /// Returns an empty list if the gallery has none, or the audit log is disabled.
+    GetAuditLog(GetAuditLogMessage)
 }
 
-/// Message for adding a new gallery to the state.
-pub type AddGalleryMessage = ModuleMessageWithReturn<(GalleryId, GalleryPipelineStates), Result<(), StateTrackerError>>;
+impl StateTrackerMessage {
+    /// The name of the message's variant, for identifying it once its payload has been consumed.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            StateTrackerMessage::AddGallery(_) => "AddGallery",
+            StateTrackerMessage::CheckGalleryDoesntExist(_) => "CheckGalleryDoesntExist",
+            StateTrackerMessage::CheckGalleryExists(_) => "CheckGalleryExists",
+            StateTrackerMessage::GetGalleryState(_) => "GetGalleryState",
+            StateTrackerMessage::PeekGalleryState(_) => "PeekGalleryState",
+            StateTrackerMessage::UpdateGalleryState(_) => "UpdateGalleryState",
+            StateTrackerMessage::RemoveGallery(_) => "RemoveGallery",
+            StateTrackerMessage::CancelGallery(_) => "CancelGallery",
+            StateTrackerMessage::GetCancellationToken(_) => "GetCancellationToken",
+            StateTrackerMessage::RemoveGalleryIfState(_) => "RemoveGalleryIfState",
+            StateTrackerMessage::GetInFlightGalleries(_) => "GetInFlightGalleries",
+            StateTrackerMessage::ListGalleries(_) => "ListGalleries",
+            StateTrackerMessage::PersistAll(_) => "PersistAll",
+            StateTrackerMessage::GetStalledGalleries(_) => "GetStalledGalleries",
+            StateTrackerMessage::GetStageSnapshot(_) => "GetStageSnapshot",
+            StateTrackerMessage::GetAuditLog(_) => "GetAuditLog"
+        }
+    }
+
+    /// The ID of the gallery the message is about, if it's about a single gallery.
+    pub fn gallery_id(&self) -> Option<GalleryId> {
+        match self {
+            StateTrackerMessage::AddGallery(msg) => Some(msg.message().0.clone()),
+            StateTrackerMessage::CheckGalleryDoesntExist(msg) => Some(msg.message().clone()),
+            StateTrackerMessage::CheckGalleryExists(msg) => Some(msg.message().clone()),
+            StateTrackerMessage::GetGalleryState(msg) => Some(msg.message().0.clone()),
+            StateTrackerMessage::PeekGalleryState(msg) => Some(msg.message().0.clone()),
+            StateTrackerMessage::UpdateGalleryState(msg) => Some(msg.message().0.clone()),
+            StateTrackerMessage::RemoveGallery(msg) => Some(msg.message().clone()),
+            StateTrackerMessage::CancelGallery(msg) => Some(msg.message().clone()),
+            StateTrackerMessage::GetCancellationToken(msg) => Some(msg.message().clone()),
+            StateTrackerMessage::RemoveGalleryIfState(msg) => Some(msg.message().0.clone()),
+            StateTrackerMessage::GetStageSnapshot(msg) => Some(msg.message().0.clone()),
+            StateTrackerMessage::GetAuditLog(msg) => Some(msg.message().clone()),
+            StateTrackerMessage::GetInFlightGalleries(_)
+                | StateTrackerMessage::ListGalleries(_)
+                | StateTrackerMessage::PersistAll(_)
+                | StateTrackerMessage::GetStalledGalleries(_) => None
+        }
+    }
+}
+
+/// Message for adding a new gallery to the state, returning the ID generated for this run of the gallery.
+pub type AddGalleryMessage = ModuleMessageWithReturn<(GalleryId, GalleryPipelineStates), Result<RunId, StateTrackerError>>;
 
 /// Message for checking a gallery's existence in the state.
 pub type CheckGalleryDoesntExistMessage = ModuleMessageWithReturn<GalleryId, Result<(), StateTrackerError>>;
 
+/// Message for checking a gallery's existence in the state, returning its state type.
+pub type CheckGalleryExistsMessage = ModuleMessageWithReturn<GalleryId, Result<GalleryPipelineStateTypes, StateTrackerError>>;
+
 /// Message for checking a gallery's state.
 pub type CheckGalleryStateMessage = ModuleMessageWithReturn<(GalleryId, GalleryPipelineStateTypes), Result<(), StateTrackerError>>;
 
 /// Message for taking a gallery's state, leaving it set as `None`.
 pub type GetGalleryStateMessage = ModuleMessageWithReturn<(GalleryId, GalleryPipelineStateTypes), Result<GalleryPipelineStates, StateTrackerError>>;
 
+/// Message for getting a copy of a gallery's state, without taking it.
+pub type PeekGalleryStateMessage = ModuleMessageWithReturn<(GalleryId, GalleryPipelineStateTypes), Result<GalleryPipelineStates, StateTrackerError>>;
+
 /// Message for updating and overwriting a gallery's state. 
 pub type UpdateGalleryStateMessage = ModuleMessageWithReturn<(GalleryId, GalleryPipelineStates), Result<(), StateTrackerError>>;
 
 /// Message for removing a gallery from the state.
 pub type RemoveGalleryMessage = ModuleMessageWithReturn<GalleryId, Result<(), StateTrackerError>>;
 
+/// Message for cancelling a gallery's run and removing it from the state.
+pub type CancelGalleryMessage = ModuleMessageWithReturn<GalleryId, Result<(), StateTrackerError>>;
+
+/// Message for getting the cancellation token of a gallery's current run.
+pub type GetCancellationTokenMessage = ModuleMessageWithReturn<GalleryId, Result<CancellationToken, StateTrackerError>>;
+
+/// Message for removing a gallery from the state, only if it's in the expected stage.
+pub type RemoveGalleryIfStateMessage = ModuleMessageWithReturn<(GalleryId, GalleryPipelineStateTypes), Result<(), StateTrackerError>>;
+
+/// Message for getting the IDs of all galleries still in the pipeline.
+pub type GetInFlightGalleriesMessage = ModuleMessageWithReturn<(), Result<Vec<GalleryId>, StateTrackerError>>;
+
+/// Message for listing all galleries in the state along with their state types.
+pub type ListGalleriesMessage = ModuleMessageWithReturn<(), Result<Vec<(GalleryId, GalleryPipelineStateTypes)>, StateTrackerError>>;
+
+/// Message for persisting all gallery states to the backing store.
+pub type PersistAllMessage = ModuleMessageWithReturn<(), Result<usize, StateTrackerError>>;
+
+/// Message for getting a gallery's stored snapshot for a stage.
+pub type GetStageSnapshotMessage = ModuleMessageWithReturn<(GalleryId, GalleryPipelineStateTypes), Result<GalleryPipelineStates, StateTrackerError>>;
+
+/// Message for getting all stalled galleries.
+pub type GetStalledGalleriesMessage = ModuleMessageWithReturn<(), Result<Vec<StalledGallery>, StateTrackerError>>;
+
+/// Message for getting a gallery's state transitions from the audit log.
+pub type GetAuditLogMessage = ModuleMessageWithReturn<GalleryId, Result<Vec<StateTransition>, StateTrackerError>>;
+
+/// A change to a gallery's state, as recorded in the audit log.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct StateTransition {
+    pub gallery_id: GalleryId,
+    /// The gallery's stage before the transition; `None` if it was just added.
+    pub from_stage: Option<GalleryPipelineStateTypes>,
+    /// The gallery's stage after the transition; `None` if it was removed.
+    pub to_stage: Option<GalleryPipelineStateTypes>,
+    pub timestamp: UnixUtcDateTime
+}
+
+/// A gallery which didn't advance within its stage's timeout.
+/// 
+/// It's removed from the state once marked, so its last state is kept here for inspection.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct StalledGallery {
+    pub gallery_id: GalleryId,
+    pub stage: GalleryPipelineStateTypes,
+    /// How long the gallery had been in its stage when it was marked.
+    pub stalled_for_secs: u64,
+    pub marked_at: UnixUtcDateTime,
+    pub last_state: GalleryPipelineStates
+}
+
 
@@ -1,18 +1,44 @@
+use std::collections::HashMap;
 use serde::{Deserialize, Serialize};
-use crate::galleries::{domain_types::GalleryId, pipeline_states::GalleryFinalState};
+use crate::{config::storage::StorageErrorKind, galleries::{domain_types::{GalleryId, ItemId, Marketplace, ModelTokenUsage, UnixUtcDateTime}, eval_criteria::EvaluationCriteria, items::{item_data::MarketplaceItemData, pipeline_items::{AnalyzedMarketplaceItem, EmbeddedMarketplaceItem, MarketplaceAnalyzedItems, MarketplaceEmbeddedAndAnalyzedItems}}, pipeline_states::{GalleryFinalState, GalleryItemAnalysisState, GallerySchedulerState}}};
 use thiserror::Error;
 
-use super::state_tracker::StateTrackerError;
+use super::{state_tracker::StateTrackerError, ModuleMessageWithReturn};
 
 /// Possible errors emitted from the scraper.
 #[derive(Error, Debug, Serialize, Deserialize, Clone)]
 pub enum StorageError {
+    #[error("Gallery {gallery_id} is not in storage")]
+    GalleryNotFound { gallery_id: GalleryId },
+    #[error("A later run of gallery {gallery_id} is already in storage")]
+    GalleryAlreadyExists { gallery_id: GalleryId },
+    #[error("Gallery {gallery_id} has no marketplaces which failed analysis")]
+    NoFailedAnalysis { gallery_id: GalleryId },
     #[error("Error from state tracker for gallery {gallery_id}: {err}")]
     StateErr { gallery_id: GalleryId, err: StateTrackerError },
+    #[error("Failed to (de)serialize data for gallery {gallery_id}: {message}")]
+    Serialization { gallery_id: GalleryId, message: String },
+    #[error("I/O error for gallery {gallery_id}: {message}")]
+    Io { gallery_id: GalleryId, message: String },
     #[error("Encountered a different error for gallery {gallery_id}: {message}")]
     Other { gallery_id: GalleryId, message: String }
 }
 
+impl StorageError {
+    /// The kind of the error, for deciding whether it's retryable.
+    pub fn kind(&self) -> StorageErrorKind {
+        match self {
+            StorageError::GalleryNotFound { .. } => StorageErrorKind::NotFound,
+            StorageError::GalleryAlreadyExists { .. } => StorageErrorKind::AlreadyExists,
+            StorageError::NoFailedAnalysis { .. } => StorageErrorKind::NoFailedAnalysis,
+            StorageError::StateErr { .. } => StorageErrorKind::StateTracker,
+            StorageError::Serialization { .. } => StorageErrorKind::Serialization,
+            StorageError::Io { .. } => StorageErrorKind::Io,
+            StorageError::Other { .. } => StorageErrorKind::Other
+        }
+    }
+}
+
 
 /// The messages the storage module can take.
 #[derive(Debug)]
@@ -26,5 +52,380 @@ pub enum StorageMessage {
     /// Stores a gallery in state which encountered an error.
     /// If the gallery isn't in state, an error is logged and nothing happens.
     /// TODO: make the error an enum so it can be logged properly?
-    StoreGalleryError { gallery_id: GalleryId, error: String } 
-}
\ No newline at end of file
+    StoreGalleryError { gallery_id: GalleryId, error: String },
+    /// Fetches a page of a stored gallery's items under a marketplace.
+    GetItemsPaginated(GetItemsPaginatedMessage),
+    /// Returns a stored gallery in the item analysis state, for retrying its marketplaces which failed analysis.
+    /// 
+    /// The gallery stays stored until the retried run replaces it. Returns an `Err` if it isn't stored, or no marketplaces failed analysis.
+    GetGalleryForAnalysisRetry(GetGalleryForAnalysisRetryMessage),
+    /// Upserts a gallery's freshly scraped items under a marketplace, keyed by item ID, returning how many were newly inserted.
+    /// 
+    /// These are kept until the gallery is stored, so later stages can recover them if the in-memory state is lost.
+    UpsertScrapedItems(UpsertScrapedItemsMessage),
+    /// Fetches all scraped items stored for a gallery, by marketplace.
+    /// 
+    /// Returns an empty map if none are stored.
+    GetScrapedItems(GetScrapedItemsMessage),
+    /// Drops a gallery's scraped items, ie when it's cancelled.
+    /// 
+    /// If the gallery has no scraped items, nothing happens.
+    ClearScrapedItems { gallery_id: GalleryId },
+    /// Fetches the tokens used analyzing a stored gallery, by model.
+    GetTokenUsage(GetTokenUsageMessage),
+    /// Stores a gallery if it isn't stored yet; used before compacting it out of the state tracker.
+    /// 
+    /// Succeeds without changing anything if the gallery is already stored.
+    EnsureGalleryStored(EnsureGalleryStoredMessage),
+    /// Finds a stored gallery's embedded items most similar to a query embedding, most similar first.
+    /// 
+    /// Returns an `Err` if the gallery isn't stored, or the query embedding is empty.
+    FindSimilarItems(FindSimilarItemsMessage),
+    /// Fetches the cached analyses of items under an evaluation criteria's hash, by item ID.
+    /// 
+    /// Items without a cached analysis are left out.
+    GetCachedAnalyses(GetCachedAnalysesMessage),
+    /// Caches items' analyses under an evaluation criteria's hash, replacing any existing analyses of the items.
+    CacheAnalyses { criteria_hash: u64, analyses: Vec<CachedItemAnalysis> },
+    /// Queues a gallery's items under a marketplace which failed analysis, to be retried later.
+    /// 
+    /// If the marketplace's items are already queued, the new items are added to them.
+    EnqueueAnalysisRetry(EnqueueAnalysisRetryMessage),
+    /// Fetches the queued analysis retries of stored galleries; those of galleries still in the pipeline are left out until they're stored.
+    GetAnalysisRetries(GetAnalysisRetriesMessage),
+    /// Merges the outcome of retrying a queued marketplace's analysis into its stored gallery.
+    /// 
+    /// Items which still failed stay queued, until they've been retried `max_attempts` times;
+    /// then they're dropped from the queue, and recorded in the gallery's `failed_marketplace_reasons`.
+    /// 
+    /// Returns an `Err` if the marketplace isn't queued, or the gallery isn't stored.
+    ResolveAnalysisRetry(ResolveAnalysisRetryMessage),
+    /// Diffs a gallery's freshly scraped items against those of its previous scrape, keeping the diff and replacing the previous items.
+    /// 
+    /// Only the given marketplaces are diffed, so marketplaces which failed to scrape don't show all their items as removed.
+    RecordScrapeDiff { gallery_id: GalleryId, items: HashMap<Marketplace, Vec<MarketplaceItemData>> },
+    /// Fetches the diff of a gallery's latest scrape against its previous one.
+    /// 
+    /// Returns an `Err` if the gallery hasn't been scraped yet.
+    GetScrapeDiff(GetScrapeDiffMessage),
+    /// Stores a gallery's scheduler state, replacing its existing state (if any).
+    PutSchedulerState { gallery: GallerySchedulerState },
+    /// Removes a gallery's scheduler state.
+    /// 
+    /// If the gallery's state isn't stored, nothing happens.
+    DeleteSchedulerState { gallery_id: GalleryId },
+    /// Fetches every stored gallery scheduler state, which the scheduler reconciles its galleries with.
+    GetSchedulerStates(GetSchedulerStatesMessage),
+    /// Aggregates a gallery's item prices under each marketplace over its latest runs.
+    /// 
+    /// Returns an `Err` if the gallery has no recorded runs.
+    GetGalleryStats(GetGalleryStatsMessage),
+    /// Merges a stored gallery's items into another stored gallery (skipping items it already has, by ID), then removes the source from storage.
+    /// 
+    /// Returns an `Err` (changing nothing) if either gallery isn't stored, or they're the same gallery.
+    MergeGalleries(MergeGalleriesMessage)
+}
+
+impl StorageMessage {
+    /// The name of the message's variant, for identifying it once its payload has been consumed.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            StorageMessage::StoreGallery { .. } => "StoreGallery",
+            StorageMessage::StoreGalleryNew { .. } => "StoreGalleryNew",
+            StorageMessage::StoreGalleryError { .. } => "StoreGalleryError",
+            StorageMessage::GetItemsPaginated(_) => "GetItemsPaginated",
+            StorageMessage::GetGalleryForAnalysisRetry(_) => "GetGalleryForAnalysisRetry",
+            StorageMessage::UpsertScrapedItems(_) => "UpsertScrapedItems",
+            StorageMessage::GetScrapedItems(_) => "GetScrapedItems",
+            StorageMessage::ClearScrapedItems { .. } => "ClearScrapedItems",
+            StorageMessage::GetTokenUsage(_) => "GetTokenUsage",
+            StorageMessage::EnsureGalleryStored(_) => "EnsureGalleryStored",
+            StorageMessage::FindSimilarItems(_) => "FindSimilarItems",
+            StorageMessage::GetCachedAnalyses(_) => "GetCachedAnalyses",
+            StorageMessage::CacheAnalyses { .. } => "CacheAnalyses",
+            StorageMessage::EnqueueAnalysisRetry(_) => "EnqueueAnalysisRetry",
+            StorageMessage::GetAnalysisRetries(_) => "GetAnalysisRetries",
+            StorageMessage::ResolveAnalysisRetry(_) => "ResolveAnalysisRetry",
+            StorageMessage::RecordScrapeDiff { .. } => "RecordScrapeDiff",
+            StorageMessage::GetScrapeDiff(_) => "GetScrapeDiff",
+            StorageMessage::PutSchedulerState { .. } => "PutSchedulerState",
+            StorageMessage::DeleteSchedulerState { .. } => "DeleteSchedulerState",
+            StorageMessage::GetSchedulerStates(_) => "GetSchedulerStates",
+            StorageMessage::GetGalleryStats(_) => "GetGalleryStats",
+            StorageMessage::MergeGalleries(_) => "MergeGalleries"
+        }
+    }
+
+    /// The ID of the gallery the message is about, if it's about a single gallery.
+    /// 
+    /// A merge is about its target gallery, which the source gallery is merged into.
+    pub fn gallery_id(&self) -> Option<GalleryId> {
+        match self {
+            StorageMessage::StoreGallery { gallery_id }
+                | StorageMessage::StoreGalleryError { gallery_id, .. }
+                | StorageMessage::ClearScrapedItems { gallery_id }
+                | StorageMessage::RecordScrapeDiff { gallery_id, .. }
+                | StorageMessage::DeleteSchedulerState { gallery_id } => Some(gallery_id.clone()),
+            StorageMessage::StoreGalleryNew { gallery } => Some(gallery.gallery_id.clone()),
+            StorageMessage::PutSchedulerState { gallery } => Some(gallery.gallery_id.clone()),
+            StorageMessage::GetItemsPaginated(msg) => Some(msg.message().gallery_id.clone()),
+            StorageMessage::GetGalleryForAnalysisRetry(msg) => Some(msg.message().clone()),
+            StorageMessage::UpsertScrapedItems(msg) => Some(msg.message().0.clone()),
+            StorageMessage::GetScrapedItems(msg) => Some(msg.message().clone()),
+            StorageMessage::GetTokenUsage(msg) => Some(msg.message().clone()),
+            StorageMessage::EnsureGalleryStored(msg) => Some(msg.message().gallery_id.clone()),
+            StorageMessage::FindSimilarItems(msg) => Some(msg.message().gallery_id.clone()),
+            StorageMessage::EnqueueAnalysisRetry(msg) => Some(msg.message().gallery_id.clone()),
+            StorageMessage::ResolveAnalysisRetry(msg) => Some(msg.message().gallery_id.clone()),
+            StorageMessage::GetScrapeDiff(msg) => Some(msg.message().clone()),
+            StorageMessage::GetGalleryStats(msg) => Some(msg.message().gallery_id.clone()),
+            StorageMessage::MergeGalleries(msg) => Some(msg.message().target_gallery_id.clone()),
+            StorageMessage::GetCachedAnalyses(_)
+                | StorageMessage::CacheAnalyses { .. }
+                | StorageMessage::GetAnalysisRetries(_)
+                | StorageMessage::GetSchedulerStates(_) => None
+        }
+    }
+}
+
+/// Message for fetching a page of a stored gallery's items under a marketplace.
+pub type GetItemsPaginatedMessage = ModuleMessageWithReturn<ItemsPageRequest, Result<ItemsPage, StorageError>>;
+
+/// Message for getting a stored gallery to retry its failed analysis.
+pub type GetGalleryForAnalysisRetryMessage = ModuleMessageWithReturn<GalleryId, Result<GalleryItemAnalysisState, StorageError>>;
+
+/// Message for upserting a gallery's scraped items under a marketplace.
+pub type UpsertScrapedItemsMessage = ModuleMessageWithReturn<(GalleryId, Marketplace, Vec<MarketplaceItemData>), Result<usize, StorageError>>;
+
+/// Message for fetching a gallery's stored scraped items.
+pub type GetScrapedItemsMessage = ModuleMessageWithReturn<GalleryId, Result<HashMap<Marketplace, Vec<MarketplaceItemData>>, StorageError>>;
+
+/// Message for fetching a stored gallery's token usage.
+pub type GetTokenUsageMessage = ModuleMessageWithReturn<GalleryId, Result<ModelTokenUsage, StorageError>>;
+
+/// Message for storing a gallery if it isn't stored yet.
+pub type EnsureGalleryStoredMessage = ModuleMessageWithReturn<GalleryFinalState, Result<(), StorageError>>;
+
+/// Message for finding a stored gallery's items most similar to a query embedding.
+pub type FindSimilarItemsMessage = ModuleMessageWithReturn<SimilarItemsRequest, Result<Vec<SimilarItem>, StorageError>>;
+
+/// Message for fetching the cached analyses of items.
+pub type GetCachedAnalysesMessage = ModuleMessageWithReturn<(u64, Vec<ItemId>), HashMap<ItemId, CachedItemAnalysis>>;
+
+/// Message for queuing items which failed analysis.
+pub type EnqueueAnalysisRetryMessage = ModuleMessageWithReturn<AnalysisRetryEntry, Result<(), StorageError>>;
+
+/// Message for fetching the queued analysis retries.
+pub type GetAnalysisRetriesMessage = ModuleMessageWithReturn<(), Vec<AnalysisRetryEntry>>;
+
+/// Message for merging the outcome of an analysis retry.
+pub type ResolveAnalysisRetryMessage = ModuleMessageWithReturn<AnalysisRetryOutcome, Result<(), StorageError>>;
+
+/// Message for fetching a gallery's latest scrape diff.
+pub type GetScrapeDiffMessage = ModuleMessageWithReturn<GalleryId, Result<ScrapeDiff, StorageError>>;
+
+/// Message for fetching every stored gallery scheduler state.
+pub type GetSchedulerStatesMessage = ModuleMessageWithReturn<(), Vec<GallerySchedulerState>>;
+
+/// Message for aggregating a gallery's stats over its latest runs.
+pub type GetGalleryStatsMessage = ModuleMessageWithReturn<GalleryStatsRequest, Result<GalleryStats, StorageError>>;
+
+/// Message for merging a stored gallery into another.
+pub type MergeGalleriesMessage = ModuleMessageWithReturn<MergeGalleriesRequest, Result<GalleryMergeSummary, StorageError>>;
+
+/// A gallery's items under a marketplace which failed analysis, queued to be retried.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct AnalysisRetryEntry {
+    pub gallery_id: GalleryId,
+    pub marketplace: Marketplace,
+    pub items: Vec<MarketplaceItemData>,
+    pub evaluation_criteria: EvaluationCriteria,
+    /// The number of times these items have been retried so far.
+    pub attempts: u32,
+    pub last_error: String
+}
+
+/// The outcome of retrying a queued marketplace's analysis.
+/// 
+/// Items which were successfully analyzed are under the relevant/irrelevant items, and those which failed again are under the error items.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct AnalysisRetryOutcome {
+    pub gallery_id: GalleryId,
+    pub marketplace: Marketplace,
+    pub analyzed_items: MarketplaceAnalyzedItems,
+    pub token_usage: ModelTokenUsage,
+    pub max_attempts: u32
+}
+
+/// An item's cached analysis, which can be reused for the item under the same evaluation criteria while it's unchanged.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct CachedItemAnalysis {
+    pub item: AnalyzedMarketplaceItem,
+    pub relevant: bool,
+    pub cached_at: UnixUtcDateTime
+}
+
+/// The parameters for finding similar items.
+/// 
+/// `query_embedding` is compared against both the description and image embedding of each item,
+/// so it must come from the same embedder (and have the same dimensions) as the stored items.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct SimilarItemsRequest {
+    pub gallery_id: GalleryId,
+    pub query_embedding: Vec<f32>,
+    pub top_k: usize
+}
+
+/// An item found to be similar to a query embedding, along with its cosine similarity to it.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct SimilarItem {
+    pub marketplace: Marketplace,
+    pub item: EmbeddedMarketplaceItem,
+    pub similarity: f32
+}
+
+/// The parameters for fetching a page of items.
+/// 
+/// If `sort_by_confidence` or `min_confidence` is set, items are paginated most confident first,
+/// and items without a confidence are dropped if `min_confidence` is set.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ItemsPageRequest {
+    pub gallery_id: GalleryId,
+    pub marketplace: Marketplace,
+    pub offset: usize,
+    pub limit: usize,
+    #[serde(default)]
+    pub min_confidence: Option<f32>,
+    #[serde(default)]
+    pub sort_by_confidence: bool
+}
+
+/// A page of a gallery's items under a marketplace, along with the total number of items.
+/// 
+/// If the offset is past the end of the items, `items` is empty but `total` is still set.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ItemsPage {
+    pub items: Vec<EmbeddedMarketplaceItem>,
+    pub total: usize
+}
+
+impl ItemsPage {
+    /// Get the requested page of a marketplace's embedded items; no items returns an empty page.
+    pub fn paginate(marketplace_items: Option<&MarketplaceEmbeddedAndAnalyzedItems>, request: &ItemsPageRequest) -> Self {
+        let items: Vec<_> = match marketplace_items {
+            Some(marketplace_items) if request.sort_by_confidence || request.min_confidence.is_some() => marketplace_items
+                .embedded_items_by_confidence(request.min_confidence),
+            Some(marketplace_items) => marketplace_items.embedded_items
+                .iter()
+                .collect(),
+            None => vec![]
+        };
+        let page = items
+            .iter()
+            .skip(request.offset)
+            .take(request.limit)
+            .map(|&item| item.clone())
+            .collect();
+        Self {
+            items: page,
+            total: items.len()
+        }
+    }
+}
+
+/// The changes in a gallery's scraped items between its latest scrape and the one before it.
+/// 
+/// On a gallery's first scrape, all of its items are added.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ScrapeDiff {
+    pub scraped_at: UnixUtcDateTime,
+    pub added: Vec<ScrapeDiffItem>,
+    pub price_changed: Vec<PriceChangedItem>,
+    pub removed: Vec<ScrapeDiffItem>
+}
+
+/// An item which was added or removed between scrapes, at its comparable (ie normalized, if available) price.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ScrapeDiffItem {
+    pub marketplace: Marketplace,
+    pub item_id: ItemId,
+    pub name: String,
+    pub price: f32
+}
+
+/// An item whose comparable price changed between scrapes.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct PriceChangedItem {
+    pub marketplace: Marketplace,
+    pub item_id: ItemId,
+    pub name: String,
+    pub old_price: f32,
+    pub new_price: f32
+}
+
+/// The parameters for merging the source gallery into the target gallery.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct MergeGalleriesRequest {
+    pub source_gallery_id: GalleryId,
+    pub target_gallery_id: GalleryId
+}
+
+/// The number of items under each of the source gallery's marketplaces which were merged into the target gallery,
+/// and which were skipped as the target already had them.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct GalleryMergeSummary {
+    pub source_gallery_id: GalleryId,
+    pub target_gallery_id: GalleryId,
+    pub merged_items: HashMap<Marketplace, usize>,
+    pub duplicate_items: HashMap<Marketplace, usize>,
+    /// The number of the source's marketplaces queued for retrying analysis, which were moved to the target.
+    pub moved_analysis_retries: usize
+}
+
+/// The parameters for aggregating a gallery's stats, over its latest `window` runs.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct GalleryStatsRequest {
+    pub gallery_id: GalleryId,
+    pub window: usize
+}
+
+/// A gallery's relevant item prices aggregated over its latest runs, under each marketplace.
+/// 
+/// `num_runs` may be less than the requested window, if fewer runs are recorded.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct GalleryStats {
+    pub gallery_id: GalleryId,
+    pub num_runs: usize,
+    pub earliest_run_at: UnixUtcDateTime,
+    pub latest_run_at: UnixUtcDateTime,
+    pub marketplaces: HashMap<Marketplace, MarketplacePriceStats>
+}
+
+/// Aggregates of a marketplace's item prices, using their comparable (ie normalized, if available) price.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct MarketplacePriceStats {
+    pub count: usize,
+    pub min_price: f32,
+    pub max_price: f32,
+    pub average_price: f32
+}
+
+impl MarketplacePriceStats {
+    /// Initialize with a single price.
+    pub fn new(price: f32) -> Self {
+        Self {
+            count: 1,
+            min_price: price,
+            max_price: price,
+            average_price: price
+        }
+    }
+
+    /// Add a price to the aggregates, keeping a running average.
+    pub fn add(&mut self, price: f32) {
+        self.count += 1;
+        self.min_price = self.min_price.min(price);
+        self.max_price = self.max_price.max(price);
+        self.average_price += (price - self.average_price) / self.count as f32;
+    }
+}
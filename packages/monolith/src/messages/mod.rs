@@ -1,9 +1,11 @@
 use message_buses::{MessageError, MessageReceiver, MessageSender};
 use message_types::{
-    item_analysis::ItemAnalysisMessage, item_embedder::ItemEmbedderMessage, item_scraper::ItemScraperMessage, scraper_scheduler::SchedulerMessage, search_scraper::SearchScraperMessage, state_tracker::{AddGalleryMessage, CheckGalleryDoesntExistMessage, RemoveGalleryMessage, StateTrackerError, StateTrackerMessage, GetGalleryStateMessage, UpdateGalleryStateMessage}, storage::StorageMessage
+    item_analysis::ItemAnalysisMessage, item_embedder::ItemEmbedderMessage, item_scraper::ItemScraperMessage, scraper_scheduler::SchedulerMessage, search_scraper::SearchScraperMessage, state_tracker::{AddGalleryMessage, CancelGalleryMessage, CheckGalleryDoesntExistMessage, GetCancellationTokenMessage, CheckGalleryExistsMessage, GetAuditLogMessage, RemoveGalleryIfStateMessage, RemoveGalleryMessage, StateTrackerError, StateTrackerMessage, GetGalleryStateMessage, GetInFlightGalleriesMessage, GetStageSnapshotMessage, GetStalledGalleriesMessage, ListGalleriesMessage, PeekGalleryStateMessage, PersistAllMessage, StalledGallery, StateTransition, UpdateGalleryStateMessage}, storage::{FindSimilarItemsMessage, SimilarItem, SimilarItemsRequest, StorageError, StorageMessage}
 };
 
-use crate::galleries::{domain_types::GalleryId, pipeline_states::{GalleryPipelineStateTypes, GalleryPipelineStates}};
+use std::time::Duration;
+use tokio_util::sync::CancellationToken;
+use crate::galleries::{domain_types::{GalleryId, RunId}, pipeline_states::{GalleryPipelineStateTypes, GalleryPipelineStates}};
 
 pub mod message_buses;
 pub mod message_types;
@@ -44,27 +46,39 @@ pub type StateTrackerReceiver = MessageReceiver<StateTrackerMessage>;
 /// Handle for sending the scraper scheduler messages.
 /// 
 /// Wraps messaging with functions for ease of use.
+/// 
+/// If a send timeout is set, each function returns a `MessageError::SendTimeout` if the state tracker doesn't accept
+/// its message in time (ie if it's overwhelmed), instead of waiting indefinitely.
 #[derive(Clone, Debug)]
-pub struct StateTrackerSender { sender: MessageSender<StateTrackerMessage> }
+pub struct StateTrackerSender { 
+    sender: MessageSender<StateTrackerMessage>,
+    send_timeout: Option<Duration>
+}
 
 impl StateTrackerSender {
-    /// Initialize the message sender.
+    /// Initialize the message sender, without a send timeout.
     pub fn new(sender: MessageSender<StateTrackerMessage>) -> Self {
-        Self { sender }
+        Self { 
+            sender,
+            send_timeout: None
+        }
+    }
+
+    /// Set the send timeout used by all functions.
+    pub fn with_send_timeout(mut self, send_timeout: Duration) -> Self {
+        self.send_timeout = Some(send_timeout);
+        self
     }
 
-    /// Add a gallery to the state.
+    /// Add a gallery to the state, returning the ID generated for this run of the gallery (see `gallery_run_span`).
     /// 
     /// Returns an `Err` if the gallery already exists.
     pub async fn add_gallery(
         &mut self,
-        gallery_id: GalleryId, 
         state: GalleryPipelineStates
-    ) -> Result<Result<(), StateTrackerError>, MessageError> {
-        let (msg, receiver) = AddGalleryMessage::new((gallery_id, state));
-        self.sender
-            .send(StateTrackerMessage::AddGallery(msg))
-            .await?;
+    ) -> Result<Result<RunId, StateTrackerError>, MessageError> {
+        let (msg, receiver) = AddGalleryMessage::new((state.gallery_id().clone(), state));
+        self.send(StateTrackerMessage::AddGallery(msg)).await?;
         receiver.await
             .map_err(Into::into)
     }
@@ -78,9 +92,20 @@ impl StateTrackerSender {
         gallery_id: GalleryId
     ) -> Result<Result<(), StateTrackerError>, MessageError> {
         let (msg, receiver) = CheckGalleryDoesntExistMessage::new(gallery_id);
-        self.sender
-            .send(StateTrackerMessage::CheckGalleryDoesntExist(msg))
-            .await?;
+        self.send(StateTrackerMessage::CheckGalleryDoesntExist(msg)).await?;
+        receiver.await
+            .map_err(Into::into)
+    }
+
+    /// Verify that a gallery exists, returning its current state type.
+    /// 
+    /// Returns an `Err` if it doesn't.
+    pub async fn check_gallery_exists(
+        &mut self,
+        gallery_id: GalleryId
+    ) -> Result<Result<GalleryPipelineStateTypes, StateTrackerError>, MessageError> {
+        let (msg, receiver) = CheckGalleryExistsMessage::new(gallery_id);
+        self.send(StateTrackerMessage::CheckGalleryExists(msg)).await?;
         receiver.await
             .map_err(Into::into)
     }
@@ -94,9 +119,21 @@ impl StateTrackerSender {
         state_type: GalleryPipelineStateTypes
     ) -> Result<Result<GalleryPipelineStates, StateTrackerError>, MessageError> {
         let (msg, receiver) = GetGalleryStateMessage::new((gallery_id, state_type));
-        self.sender
-            .send(StateTrackerMessage::GetGalleryState(msg))
-            .await?;
+        self.send(StateTrackerMessage::GetGalleryState(msg)).await?;
+        receiver.await
+            .map_err(Into::into)
+    }
+
+    /// Get a copy of a gallery's state, without taking it.
+    /// 
+    /// Returns an `Err` if it doesn't exist, its state is wrong, or its state is currently taken.
+    pub async fn peek_gallery_state(
+        &mut self,
+        gallery_id: GalleryId,
+        state_type: GalleryPipelineStateTypes
+    ) -> Result<Result<GalleryPipelineStates, StateTrackerError>, MessageError> {
+        let (msg, receiver) = PeekGalleryStateMessage::new((gallery_id, state_type));
+        self.send(StateTrackerMessage::PeekGalleryState(msg)).await?;
         receiver.await
             .map_err(Into::into)
     }
@@ -106,13 +143,10 @@ impl StateTrackerSender {
     /// Returns an `Err` if it doesn't exist, its state is wrong, or its state isn't taken.
     pub async fn update_gallery_state(
         &mut self,
-        gallery_id: GalleryId,
         state: GalleryPipelineStates
     ) -> Result<Result<(), StateTrackerError>, MessageError> {
-        let (msg, receiver) = UpdateGalleryStateMessage::new((gallery_id, state));
-        self.sender
-            .send(StateTrackerMessage::UpdateGalleryState(msg))
-            .await?;
+        let (msg, receiver) = UpdateGalleryStateMessage::new((state.gallery_id().clone(), state));
+        self.send(StateTrackerMessage::UpdateGalleryState(msg)).await?;
         receiver
             .await
             .map_err(Into::into)
@@ -126,11 +160,131 @@ impl StateTrackerSender {
         gallery_id: GalleryId
     ) -> Result<Result<(), StateTrackerError>, MessageError> {
         let (msg, receiver) = RemoveGalleryMessage::new(gallery_id);
-        self.sender
-            .send(StateTrackerMessage::RemoveGallery(msg))
-            .await?;
+        self.send(StateTrackerMessage::RemoveGallery(msg)).await?;
         receiver
             .await
             .map_err(Into::into)
     }
+
+    /// Cancel a gallery's current run and remove it from state; modules processing it abort, and their later writes are rejected.
+    /// 
+    /// Returns an `Err` if it doesn't exist in state, though it's still cancelled.
+    pub async fn cancel_gallery(
+        &mut self,
+        gallery_id: GalleryId
+    ) -> Result<Result<(), StateTrackerError>, MessageError> {
+        let (msg, receiver) = CancelGalleryMessage::new(gallery_id);
+        self.send(StateTrackerMessage::CancelGallery(msg)).await?;
+        receiver
+            .await
+            .map_err(Into::into)
+    }
+
+    /// Get the cancellation token for a gallery's current run, which is cancelled if the gallery is cancelled.
+    /// 
+    /// If the gallery was already cancelled, the token is returned already cancelled.
+    /// Returns an `Err` if it doesn't exist.
+    pub async fn get_cancellation_token(
+        &mut self,
+        gallery_id: GalleryId
+    ) -> Result<Result<CancellationToken, StateTrackerError>, MessageError> {
+        let (msg, receiver) = GetCancellationTokenMessage::new(gallery_id);
+        self.send(StateTrackerMessage::GetCancellationToken(msg)).await?;
+        receiver
+            .await
+            .map_err(Into::into)
+    }
+
+    /// Remove a gallery from state, only if it's in the expected stage and isn't currently being processed.
+    /// 
+    /// Unlike `remove_gallery`, this can't clobber a gallery which a module just advanced (or took).
+    /// 
+    /// Returns an `Err` if it doesn't exist, is in another stage (`UnexpectedStage`), or its state is taken.
+    pub async fn remove_gallery_if_state(
+        &mut self,
+        gallery_id: GalleryId,
+        expected: GalleryPipelineStateTypes
+    ) -> Result<Result<(), StateTrackerError>, MessageError> {
+        let (msg, receiver) = RemoveGalleryIfStateMessage::new((gallery_id, expected));
+        self.send(StateTrackerMessage::RemoveGalleryIfState(msg)).await?;
+        receiver
+            .await
+            .map_err(Into::into)
+    }
+
+    /// Get the IDs of all galleries which haven't reached the `Final` state.
+    pub async fn get_in_flight_galleries(&mut self) -> Result<Result<Vec<GalleryId>, StateTrackerError>, MessageError> {
+        let (msg, receiver) = GetInFlightGalleriesMessage::new(());
+        self.send(StateTrackerMessage::GetInFlightGalleries(msg)).await?;
+        receiver.await
+            .map_err(Into::into)
+    }
+
+    /// Get the IDs and state types of all galleries in the state, sorted by ID.
+    pub async fn list_galleries(&mut self) -> Result<Result<Vec<(GalleryId, GalleryPipelineStateTypes)>, StateTrackerError>, MessageError> {
+        let (msg, receiver) = ListGalleriesMessage::new(());
+        self.send(StateTrackerMessage::ListGalleries(msg)).await?;
+        receiver.await
+            .map_err(Into::into)
+    }
+
+    /// Write all gallery states through to the state tracker's backing store.
+    /// 
+    /// Returns the number of persisted states.
+    pub async fn persist_all(&mut self) -> Result<Result<usize, StateTrackerError>, MessageError> {
+        let (msg, receiver) = PersistAllMessage::new(());
+        self.send(StateTrackerMessage::PersistAll(msg)).await?;
+        receiver.await
+            .map_err(Into::into)
+    }
+
+    /// Get the latest stored snapshot of a gallery's state in a stage.
+    /// 
+    /// Returns an `Err` if the gallery never reached the stage.
+    pub async fn get_stage_snapshot(
+        &mut self,
+        gallery_id: GalleryId,
+        stage: GalleryPipelineStateTypes
+    ) -> Result<Result<GalleryPipelineStates, StateTrackerError>, MessageError> {
+        let (msg, receiver) = GetStageSnapshotMessage::new((gallery_id, stage));
+        self.send(StateTrackerMessage::GetStageSnapshot(msg)).await?;
+        receiver.await
+            .map_err(Into::into)
+    }
+
+    /// Get all galleries which were marked as stalled, sorted by ID.
+    pub async fn get_stalled_galleries(&mut self) -> Result<Result<Vec<StalledGallery>, StateTrackerError>, MessageError> {
+        let (msg, receiver) = GetStalledGalleriesMessage::new(());
+        self.send(StateTrackerMessage::GetStalledGalleries(msg)).await?;
+        receiver.await
+            .map_err(Into::into)
+    }
+
+    /// Get a gallery's state transitions from the audit log, oldest first.
+    pub async fn get_audit_log(&mut self, gallery_id: GalleryId) -> Result<Result<Vec<StateTransition>, StateTrackerError>, MessageError> {
+        let (msg, receiver) = GetAuditLogMessage::new(gallery_id);
+        self.send(StateTrackerMessage::GetAuditLog(msg)).await?;
+        receiver.await
+            .map_err(Into::into)
+    }
+
+    /// Send a message to the state tracker, applying the send timeout if there is one.
+    async fn send(&mut self, msg: StateTrackerMessage) -> Result<(), MessageError> {
+        match self.send_timeout {
+            Some(send_timeout) => self.sender.send_with_timeout(msg, send_timeout).await,
+            None => self.sender.send(msg).await
+        }
+    }
+}
+
+impl StorageSender {
+    /// Find a stored gallery's `top_k` embedded items most similar to a query embedding, most similar first.
+    /// 
+    /// Returns an `Err` if the gallery isn't stored, skips embedding, or the query embedding is empty.
+    pub async fn find_similar_items(&mut self, request: SimilarItemsRequest) -> Result<Result<Vec<SimilarItem>, StorageError>, MessageError> {
+        let (msg, receiver) = FindSimilarItemsMessage::new(request);
+        self.send(StorageMessage::FindSimilarItems(msg)).await?;
+        receiver.await
+            .map_err(Into::into)
+    }
 }
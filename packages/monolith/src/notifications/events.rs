@@ -0,0 +1,93 @@
+use std::collections::HashMap;
+use serde::{Deserialize, Serialize};
+use crate::galleries::{domain_types::{GalleryId, Marketplace}, pipeline_states::{GalleryFinalState, GalleryPipelineStateTypes}};
+
+/// An event in a gallery's run through the pipeline, which is sent to the notification sinks.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum PipelineEvent {
+    /// The gallery reached the final state.
+    GalleryCompleted(FinalStateSummary),
+    /// The gallery didn't advance within its stage's timeout, so was removed from the pipeline.
+    GalleryStalled(StalledGallerySummary),
+    /// A marketplace's search returned no items for the gallery, when it usually returns plenty; its layout probably changed.
+    LayoutBreakSuspected(LayoutBreakSummary)
+}
+
+impl PipelineEvent {
+    /// The ID of the gallery the event is for.
+    pub fn gallery_id(&self) -> &GalleryId {
+        match self {
+            PipelineEvent::GalleryCompleted(summary) => &summary.gallery_id,
+            PipelineEvent::GalleryStalled(summary) => &summary.gallery_id,
+            PipelineEvent::LayoutBreakSuspected(summary) => &summary.gallery_id
+        }
+    }
+}
+
+/// A summary of a gallery which reached the final state.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct FinalStateSummary {
+    pub gallery_id: GalleryId,
+    pub item_counts: HashMap<Marketplace, usize>,
+    pub marketplace_counts: HashMap<Marketplace, MarketplaceItemCounts>,
+    pub failed_marketplaces: Vec<Marketplace>
+}
+
+/// The number of items under a marketplace, by their outcome.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct MarketplaceItemCounts {
+    pub embedded: usize,
+    pub irrelevant: usize,
+    pub analysis_errors: usize,
+    pub embedding_errors: usize,
+    pub skipped_embedding: usize,
+    /// Items left out of the gallery's analysis sample.
+    pub unsampled: usize
+}
+
+impl FinalStateSummary {
+    /// Summarize a gallery in the final state.
+    pub fn new(gallery: &GalleryFinalState) -> Self {
+        let marketplace_counts = gallery.items
+            .iter()
+            .map(|(marketplace, items)| {
+                let counts = MarketplaceItemCounts {
+                    embedded: items.embedded_items.len(),
+                    irrelevant: items.irrelevant_analyzed_items.len(),
+                    analysis_errors: items.error_analyzed_items.len(),
+                    embedding_errors: items.error_embedded_items.len(),
+                    skipped_embedding: items.skipped_embedding_items.len(),
+                    unsampled: items.unsampled_items.len()
+                };
+                (marketplace.clone(), counts)
+            })
+            .collect();
+        Self {
+            gallery_id: gallery.gallery_id.clone(),
+            item_counts: gallery.item_counts(),
+            marketplace_counts,
+            failed_marketplaces: gallery.failed_marketplace_reasons.keys().cloned().collect()
+        }
+    }
+}
+
+/// A summary of a gallery which stalled mid-pipeline.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct StalledGallerySummary {
+    pub gallery_id: GalleryId,
+    pub stage: GalleryPipelineStateTypes,
+    /// How long the gallery had been in its stage when it was marked as stalled.
+    pub stalled_for_secs: u64,
+    /// Marketplaces which had already failed before the gallery stalled.
+    pub failed_marketplaces: Vec<Marketplace>
+}
+
+/// A summary of a search which was flagged as a probable marketplace layout change.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct LayoutBreakSummary {
+    pub gallery_id: GalleryId,
+    pub marketplace: Marketplace,
+    /// The average number of items the marketplace's recent searches returned.
+    pub average_item_count: f64
+}
@@ -0,0 +1,31 @@
+use async_trait::async_trait;
+use super::{NotificationSink, PipelineEvent};
+
+/// Logs pipeline events; useful for debugging, or for shipping events through the log pipeline.
+pub(super) struct LoggingSink;
+
+#[async_trait]
+impl NotificationSink for LoggingSink {
+    async fn notify(&self, event: PipelineEvent) {
+        match &event {
+            PipelineEvent::GalleryCompleted(summary) => tracing::info!(
+                "Gallery {} completed with {} items ({} failed marketplaces)",
+                summary.gallery_id,
+                summary.item_counts.values().sum::<usize>(),
+                summary.failed_marketplaces.len()
+            ),
+            PipelineEvent::GalleryStalled(summary) => tracing::warn!(
+                "Gallery {} stalled in the {:?} stage after {}s",
+                summary.gallery_id,
+                summary.stage,
+                summary.stalled_for_secs
+            ),
+            PipelineEvent::LayoutBreakSuspected(summary) => tracing::warn!(
+                "Search of {} for gallery {} returned no items (recent average: {:.1}); its layout probably changed",
+                summary.marketplace,
+                summary.gallery_id,
+                summary.average_item_count
+            )
+        }
+    }
+}
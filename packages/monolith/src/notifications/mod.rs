@@ -0,0 +1,65 @@
+//! Contains the sinks which pipeline events (ie galleries completing or stalling) are sent to.
+//! 
+//! Adding a new sink only requires implementing `NotificationSink` in its own file, and selecting it in `Notifier::new`.
+use std::sync::Arc;
+use async_trait::async_trait;
+use futures::future::join_all;
+use logging::LoggingSink;
+use webhook::WebhookSink;
+use crate::{config::notification::{NotificationConfig, NotificationSinkKind}, utils::http_client::HttpClientFactory};
+
+pub use events::{FinalStateSummary, LayoutBreakSummary, PipelineEvent, StalledGallerySummary};
+
+mod events;
+mod logging;
+mod webhook;
+
+/// A destination for pipeline events.
+#[async_trait]
+pub trait NotificationSink: Send + Sync {
+    /// Deliver an event.
+    /// 
+    /// Failures are handled by the sink itself (ie by retrying or logging them), so they don't affect other sinks.
+    async fn notify(&self, event: PipelineEvent);
+}
+
+/// Emits pipeline events to every configured sink.
+/// 
+/// This is cheaply cloneable, so each module emitting events can hold its own copy.
+#[derive(Clone)]
+pub struct Notifier {
+    sinks: Arc<Vec<Box<dyn NotificationSink>>>
+}
+
+impl Notifier {
+    /// Build the sinks chosen in the config.
+    /// 
+    /// Sinks missing required config (ie a webhook without a URL) are skipped with a warning.
+    pub fn new(config: &NotificationConfig, http_clients: &HttpClientFactory) -> Self {
+        let mut sinks: Vec<Box<dyn NotificationSink>> = vec![];
+        for sink_kind in &config.sinks {
+            match sink_kind {
+                NotificationSinkKind::Webhook => match WebhookSink::new(config, http_clients) {
+                    Some(sink) => sinks.push(Box::new(sink)),
+                    None => tracing::warn!("The webhook notification sink is enabled, but NOTIFICATION_WEBHOOK_URL isn't set; skipping it")
+                },
+                NotificationSinkKind::Log => sinks.push(Box::new(LoggingSink))
+            }
+        }
+        Self { sinks: Arc::new(sinks) }
+    }
+
+    /// Send an event to all sinks in the background.
+    pub fn emit(&self, event: PipelineEvent) {
+        if self.sinks.is_empty() {
+            return;
+        }
+        let sinks = self.sinks.clone();
+        tokio::spawn(async move {
+            let notifications = sinks
+                .iter()
+                .map(|sink| sink.notify(event.clone()));
+            join_all(notifications).await;
+        });
+    }
+}
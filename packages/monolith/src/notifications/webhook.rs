@@ -0,0 +1,93 @@
+use std::time::Duration;
+use async_trait::async_trait;
+use hmac::{Hmac, Mac};
+use reqwest::Client;
+use sha2::Sha256;
+use crate::{config::notification::NotificationConfig, utils::http_client::HttpClientFactory};
+use super::{NotificationSink, PipelineEvent};
+
+/// The delay before the first webhook retry, doubled for each subsequent retry.
+const RETRY_BASE_DELAY: Duration = Duration::from_secs(1);
+
+/// The header the body's HMAC signature is sent in.
+const SIGNATURE_HEADER: &str = "X-Signature";
+
+/// POSTs pipeline events to a webhook as JSON, tagged with their `event` type.
+pub(super) struct WebhookSink {
+    webhook_url: String,
+    signing_secret: Option<String>,
+    max_retries: u32,
+    request_client: Client
+}
+
+#[async_trait]
+impl NotificationSink for WebhookSink {
+    async fn notify(&self, event: PipelineEvent) {
+        self.send_with_retries(event).await;
+    }
+}
+
+impl WebhookSink {
+    /// Initialize the sink; returns `None` if no webhook URL is configured.
+    pub fn new(config: &NotificationConfig, http_clients: &HttpClientFactory) -> Option<Self> {
+        Some(Self {
+            webhook_url: config.webhook_url.clone()?,
+            signing_secret: config.webhook_secret.clone(),
+            max_retries: config.webhook_max_retries,
+            request_client: http_clients.client()
+        })
+    }
+
+    /// Send the event to the webhook, retrying with exponential backoff up to the max number of retries.
+    async fn send_with_retries(&self, event: PipelineEvent) {
+        let gallery_id = event.gallery_id();
+        let body = match serde_json::to_vec(&event) {
+            Ok(body) => body,
+            Err(err) => {
+                tracing::error!("Unable to serialize pipeline event for gallery {gallery_id}: {err}");
+                return;
+            }
+        };
+        let mut attempt = 0;
+        loop {
+            let mut request = self.request_client
+                .post(&self.webhook_url)
+                .header(reqwest::header::CONTENT_TYPE, "application/json")
+                .body(body.clone());
+            if let Some(secret) = &self.signing_secret {
+                request = request.header(SIGNATURE_HEADER, Self::sign(secret, &body));
+            }
+            let error = match request.send().await {
+                Ok(res) if res.status().is_success() => {
+                    tracing::debug!("Notified webhook of pipeline event for gallery {gallery_id}");
+                    return;
+                },
+                Ok(res) => format!("Received unexpected status code {}", res.status()),
+                Err(err) => format!("Error while sending request: {err}")
+            };
+            if attempt >= self.max_retries {
+                tracing::error!(
+                    "Failed to notify webhook of pipeline event for gallery {gallery_id} after {} attempts: {error}",
+                    attempt + 1
+                );
+                return;
+            }
+            tracing::warn!("Failed to notify webhook for gallery {gallery_id} (attempt {}): {error}", attempt + 1);
+            tokio::time::sleep(RETRY_BASE_DELAY.saturating_mul(2u32.saturating_pow(attempt))).await;
+            attempt += 1;
+        }
+    }
+
+    /// Computes the hex-encoded HMAC-SHA256 signature of the body.
+    fn sign(secret: &str, body: &[u8]) -> String {
+        let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes())
+            .expect("HMAC should be able to take a key of any size");
+        mac.update(body);
+        let signature = mac.finalize().into_bytes();
+        let hex_signature: String = signature
+            .iter()
+            .map(|byte| format!("{byte:02x}"))
+            .collect();
+        format!("sha256={hex_signature}")
+    }
+}
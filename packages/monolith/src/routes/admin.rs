@@ -0,0 +1,30 @@
+use axum::{routing::get, Json, Router};
+use crate::{config::AxumConfig, messages::{message_types::scraper_scheduler::{GetScheduleMessage, ScheduledGallerySnapshot, SchedulerMessage}, ScraperSchedulerSender}, scraping_pipeline::AppModuleConnections};
+use super::api_error::ApiError;
+
+/// Build the router for operational debugging.
+pub(super) fn build(config: &AxumConfig, module_connections: &AppModuleConnections) -> Router {
+    let mut router = Router::new();
+
+    let scheduler_sender = module_connections.scraper_scheduler.0.clone();
+    router = router.route("/scheduler", get(
+        move || get_schedule(scheduler_sender)
+    ));
+
+    router
+}
+
+/// Get every gallery registered in the scheduler, with its Cron schedule and next fire time (including its jitter), sorted by ID.
+async fn get_schedule(
+    mut scheduler_sender: ScraperSchedulerSender
+) -> Result<Json<Vec<ScheduledGallerySnapshot>>, ApiError> {
+    let (msg, receiver) = GetScheduleMessage::new(());
+    scheduler_sender
+        .send(SchedulerMessage::GetSchedule(msg))
+        .await
+        .map_err(|err| ApiError::Internal(format!("Failed to message the scheduler: {err}")))?;
+    receiver
+        .await
+        .map(Json)
+        .map_err(|err| ApiError::Internal(format!("Failed to receive a response from the scheduler: {err}")))
+}
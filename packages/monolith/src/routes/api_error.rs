@@ -0,0 +1,55 @@
+//! Contains the error type returned by the HTTP API's handlers.
+use axum::{response::{IntoResponse, Response}, Json};
+use reqwest::StatusCode;
+use serde_json::json;
+use thiserror::Error;
+
+/// The errors returned by the HTTP API.
+/// 
+/// Each variant maps to a status code, and is returned with a JSON `{ "error": ... }` body describing it.
+#[derive(Error, Debug, Clone)]
+pub(super) enum ApiError {
+    /// The request was invalid (ie failed validation); maps to a 400.
+    #[error("{0}")]
+    BadRequest(String),
+    /// The request had several problems, which are all listed so they can be fixed at once; maps to a 400.
+    /// 
+    /// The problems are returned in an `errors` array alongside the `error`.
+    #[error("{message}: {}", errors.join("; "))]
+    InvalidFields { message: String, errors: Vec<String> },
+    /// The request had no or an invalid bearer token; maps to a 401.
+    #[error("{0}")]
+    Unauthorized(String),
+    /// The gallery (or other resource) doesn't exist; maps to a 404.
+    #[error("{0}")]
+    NotFound(String),
+    /// The request conflicts with the gallery's current state (ie it's still in the pipeline); maps to a 409.
+    #[error("{0}")]
+    Conflict(String),
+    /// A module couldn't be messaged, or failed unexpectedly; maps to a 500.
+    #[error("{0}")]
+    Internal(String)
+}
+
+impl ApiError {
+    /// The status code of the error.
+    pub fn status_code(&self) -> StatusCode {
+        match self {
+            ApiError::BadRequest(_) | ApiError::InvalidFields { .. } => StatusCode::BAD_REQUEST,
+            ApiError::Unauthorized(_) => StatusCode::UNAUTHORIZED,
+            ApiError::NotFound(_) => StatusCode::NOT_FOUND,
+            ApiError::Conflict(_) => StatusCode::CONFLICT,
+            ApiError::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR
+        }
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        let body = match &self {
+            ApiError::InvalidFields { message, errors } => Json(json!({ "error": message, "errors": errors })),
+            _ => Json(json!({ "error": self.to_string() }))
+        };
+        (self.status_code(), body).into_response()
+    }
+}
@@ -0,0 +1,78 @@
+//! Contains the middleware for authenticating requests with a bearer token.
+use std::sync::Arc;
+use axum::{extract::{Request, State}, http::header::AUTHORIZATION, middleware::Next, response::{IntoResponse, Response}};
+use sha2::{Digest, Sha256};
+use crate::config::AxumConfig;
+use super::api_error::ApiError;
+
+/// The accepted bearer tokens, and the paths which don't require one.
+/// 
+/// If authentication isn't explicitly disabled, it fails closed: with no tokens configured, no token is accepted.
+#[derive(Clone, Debug)]
+pub(super) struct BearerAuth {
+    disabled: bool,
+    token_digests: Arc<Vec<[u8; 32]>>,
+    unauthenticated_paths: Arc<Vec<String>>
+}
+
+impl BearerAuth {
+    /// Get the tokens and unauthenticated paths from the config.
+    /// 
+    /// Tokens are stored as SHA-256 digests, so that comparing them doesn't leak their length.
+    pub(super) fn new(config: &AxumConfig) -> Self {
+        if config.auth_disabled {
+            tracing::warn!("API authentication is disabled; the API is unauthenticated");
+        } else if config.api_tokens.is_empty() {
+            tracing::error!("No API tokens are configured; every request to an authenticated path will be rejected");
+        }
+        let token_digests = config.api_tokens
+            .iter()
+            .map(|token| Sha256::digest(token.as_bytes()).into())
+            .collect();
+        Self {
+            disabled: config.auth_disabled,
+            token_digests: Arc::new(token_digests),
+            unauthenticated_paths: Arc::new(config.unauthenticated_paths.clone())
+        }
+    }
+
+    /// Whether the token is one of the accepted tokens.
+    /// 
+    /// Every accepted token is compared in constant time, so the comparison doesn't leak which (or how much of a) token matched.
+    fn accepts(&self, token: &str) -> bool {
+        let digest: [u8; 32] = Sha256::digest(token.as_bytes()).into();
+        self.token_digests
+            .iter()
+            .fold(false, |accepted, token_digest| accepted | constant_time_eq(&digest, token_digest))
+    }
+}
+
+/// Rejects requests without an accepted `Authorization: Bearer <token>` header with a 401.
+/// 
+/// Requests to unauthenticated paths are let through, as is every request if authentication is disabled.
+pub(super) async fn require_bearer_token(
+    State(auth): State<BearerAuth>,
+    request: Request,
+    next: Next
+) -> Response {
+    if auth.disabled || auth.unauthenticated_paths.iter().any(|path| path == request.uri().path()) {
+        return next.run(request).await;
+    }
+    let token = request
+        .headers()
+        .get(AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "));
+    match token {
+        Some(token) if auth.accepts(token.trim()) => next.run(request).await,
+        Some(_) => ApiError::Unauthorized("Invalid bearer token".into()).into_response(),
+        None => ApiError::Unauthorized("Missing bearer token".into()).into_response()
+    }
+}
+
+/// Compares two digests without short-circuiting on the first differing byte.
+fn constant_time_eq(a: &[u8; 32], b: &[u8; 32]) -> bool {
+    a.iter()
+        .zip(b.iter())
+        .fold(0u8, |diff, (x, y)| diff | (x ^ y)) == 0
+}
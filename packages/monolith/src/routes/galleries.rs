@@ -0,0 +1,1464 @@
+mod export;
+
+use std::{collections::HashMap, sync::Arc, time::Duration};
+use axum::{extract::{DefaultBodyLimit, Path, Query, State}, http::HeaderMap, response::{IntoResponse, Response}, routing::{delete, get, patch, post}, Json, Router};
+use reqwest::StatusCode;
+use serde::{Deserialize, Serialize};
+use tokio::sync::Mutex;
+use uuid::Uuid;
+use super::api_error::ApiError;
+use crate::{config::{item_analysis::ModelPrice, AxumConfig, ItemAnalysisConfig, ScraperSchedulerConfig}, galleries::{domain_types::{GalleryId, Marketplace, ModelTokenUsage, UnixUtcDateTime, ValidCronString}, eval_criteria::EvaluationCriteria, pipeline_states::{GalleryPipelineStateTypes, GalleryPipelineStates, GallerySchedulerState}, search_criteria::GallerySearchCriteria}, messages::{message_types::{item_analysis::{CriteriaPreview, CriteriaPreviewRequest, ItemAnalysisMessage, PreviewCriteriaMessage}, item_embedder::ItemEmbedderMessage, item_scraper::ItemScraperMessage, search_scraper::SearchScraperMessage, scraper_scheduler::{DeleteGalleryMessage, ForceScrapeMessage, GetNextRunMessage, GetScheduleMessage, NewGalleryMessage, SchedulerError, SchedulerMessage, ScrapeStart, SetEnabledMessage, UpdateGalleryMessage}, state_tracker::{StalledGallery, StateTrackerError, StateTransition}, storage::{GalleryMergeSummary, GalleryStats, GalleryStatsRequest, GetGalleryStatsMessage, GetItemsPaginatedMessage, GetScrapeDiffMessage, GetTokenUsageMessage, ItemsPage, ItemsPageRequest, MergeGalleriesMessage, MergeGalleriesRequest, ScrapeDiff, SimilarItem, SimilarItemsRequest, StorageError, StorageMessage, GetGalleryForAnalysisRetryMessage}}, ItemAnalysisSender, ItemEmbedderSender, ItemScraperSender, ScraperSchedulerSender, SearchScraperSender, StateTrackerSender, StorageSender}, scraping_pipeline::AppModuleConnections, utils::idempotency_cache::IdempotencyCache};
+
+/// The header a client can set to safely retry gallery creation.
+const IDEMPOTENCY_KEY_HEADER: &str = "Idempotency-Key";
+
+/// The default number of galleries returned per page when listing galleries.
+const DEFAULT_LIST_LIMIT: usize = 50;
+
+/// The maximum number of galleries returned per page when listing galleries.
+const MAX_LIST_LIMIT: usize = 500;
+
+/// The default number of items returned per marketplace when fetching a gallery's items.
+const DEFAULT_ITEMS_LIMIT: usize = 50;
+
+/// The maximum number of items returned per marketplace when fetching a gallery's items.
+const MAX_ITEMS_LIMIT: usize = 500;
+
+/// The default number of a gallery's latest runs its stats are aggregated over.
+const DEFAULT_STATS_WINDOW: usize = 10;
+
+/// The default number of items returned when finding a gallery's items most similar to a query embedding.
+const DEFAULT_SIMILAR_ITEMS: usize = 10;
+
+/// The config and connections shared by the gallery creation routes.
+#[derive(Clone)]
+struct GalleryCreationState {
+    min_scrape_interval: Duration,
+    allowed_models: Arc<Vec<String>>,
+    /// If set, fills the unset fields of new galleries' evaluation criteria.
+    default_criteria: Option<Arc<EvaluationCriteria>>,
+    scheduler_sender: ScraperSchedulerSender,
+    /// Remembers recently seen `Idempotency-Key`s, and the galleries they created.
+    idempotency_cache: Arc<Mutex<IdempotencyCache>>
+}
+
+/// The request for creating a gallery. Its ID is generated on creation.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct CreateGalleryRequest {
+    scraping_periodicity: ValidCronString,
+    search_criteria: GallerySearchCriteria,
+    evaluation_criteria: EvaluationCriteria
+}
+
+/// The query parameters for creating a gallery.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct CreateGalleryParams {
+    /// If set, the gallery is only validated, and returned in its normalized form without being created.
+    #[serde(default)]
+    dry_run: bool
+}
+
+/// The query parameters for listing galleries.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct ListGalleriesParams {
+    /// If set, only galleries in this stage are listed.
+    stage: Option<GalleryPipelineStateTypes>,
+    /// The number of galleries to skip.
+    #[serde(default)]
+    offset: usize,
+    /// The maximum number of galleries to return; defaults to `DEFAULT_LIST_LIMIT`, and is capped at `MAX_LIST_LIMIT`.
+    limit: Option<usize>
+}
+
+/// The response for listing galleries.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct ListGalleriesResponse {
+    galleries: Vec<GallerySummary>,
+    /// The total number of galleries matching the filter, across all pages.
+    total: usize
+}
+
+/// A gallery's ID and current stage.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct GallerySummary {
+    gallery_id: GalleryId,
+    stage: GalleryPipelineStateTypes,
+    /// A plain-English description of the gallery's schedule; not set if it isn't scheduled.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    schedule_description: Option<String>
+}
+
+/// The response for creating a gallery.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct CreateGalleryResponse {
+    gallery_id: GalleryId
+}
+
+/// The result of creating a single gallery in a batch.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "status", rename_all = "snake_case")]
+enum BatchCreateGalleryResult {
+    Created { gallery_id: GalleryId },
+    Failed { error: String }
+}
+
+/// The request for immediately re-scraping galleries.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct RescrapeRequest {
+    gallery_ids: Vec<GalleryId>
+}
+
+/// The result of triggering a re-scrape for a single gallery.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "status", rename_all = "snake_case")]
+enum RescrapeResult {
+    Triggered { gallery_id: GalleryId },
+    /// The gallery was already being scraped, so it'll be re-scraped once that finishes.
+    Queued { gallery_id: GalleryId },
+    Failed { gallery_id: GalleryId, error: String }
+}
+
+/// The response for deleting a gallery, stating which subsystems it was removed from.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct DeleteGalleryResponse {
+    removed_from_scheduler: bool,
+    removed_from_state_tracker: bool
+}
+
+/// The response for a gallery's status in the pipeline.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct GalleryStatusResponse {
+    gallery_id: GalleryId,
+    stage: GalleryPipelineStateTypes,
+    /// Whether a module is currently processing the gallery; if so, `failed_marketplaces` is not available yet.
+    in_progress: bool,
+    failed_marketplaces: Vec<FailedMarketplace>,
+    /// The number of items under each marketplace; only set once the gallery reaches the `Final` stage.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    item_counts: Option<HashMap<Marketplace, usize>>,
+    /// A plain-English description of the gallery's schedule; not set if it isn't scheduled.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    schedule_description: Option<String>
+}
+
+/// The request for enabling or disabling a gallery's scheduled scrapes.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct SetEnabledRequest {
+    enabled: bool
+}
+
+/// The response for updating a gallery.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct UpdateGalleryResponse {
+    gallery_id: GalleryId
+}
+
+/// The response for enabling or disabling a gallery's scheduled scrapes.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct SetEnabledResponse {
+    gallery_id: GalleryId,
+    enabled: bool
+}
+
+/// The response for when a gallery will next be scraped.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct NextRunResponse {
+    gallery_id: GalleryId,
+    /// Not set if the gallery's schedule never fires again.
+    next_run: Option<UnixUtcDateTime>
+}
+
+/// The response for a gallery's token usage from analysis.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct TokenUsageResponse {
+    gallery_id: GalleryId,
+    models: Vec<ModelUsage>,
+    prompt_tokens: u64,
+    completion_tokens: u64,
+    /// The estimated cost of the models with a configured price.
+    estimated_cost: f64,
+    /// The models without a configured price, which aren't included in the estimated cost.
+    unpriced_models: Vec<String>
+}
+
+/// The tokens used under a single model, and their estimated cost in USD.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct ModelUsage {
+    model: String,
+    prompt_tokens: u64,
+    completion_tokens: u64,
+    /// Not set if the model has no configured price.
+    estimated_cost: Option<f64>
+}
+
+/// The query parameters for replaying a gallery.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct ReplayParams {
+    /// The stage to replay the gallery from.
+    from: GalleryPipelineStateTypes
+}
+
+/// The response for replaying a gallery.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct ReplayResponse {
+    gallery_id: GalleryId,
+    from: GalleryPipelineStateTypes
+}
+
+/// The senders of each module a gallery can be replayed into.
+#[derive(Clone)]
+struct ReplaySenders {
+    search_scraper: SearchScraperSender,
+    item_scraper: ItemScraperSender,
+    item_analysis: ItemAnalysisSender,
+    item_embedder: ItemEmbedderSender,
+    storage: StorageSender
+}
+
+/// The response for retrying a gallery's failed analysis.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct RetryAnalysisResponse {
+    retried_marketplaces: Vec<Marketplace>
+}
+
+/// The query parameters for fetching a gallery's items.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct GalleryItemsParams {
+    /// If set, only this marketplace's items are returned; otherwise, each marketplace's are.
+    marketplace: Option<Marketplace>,
+    /// The number of items to skip under each marketplace.
+    #[serde(default)]
+    offset: usize,
+    /// The maximum number of items to return under each marketplace; defaults to `DEFAULT_ITEMS_LIMIT`, and is capped at `MAX_ITEMS_LIMIT`.
+    limit: Option<usize>,
+    /// If set, only items with at least this confidence are returned.
+    min_confidence: Option<f32>,
+    /// If set, items are returned most confident first.
+    #[serde(default)]
+    sort_by_confidence: bool
+}
+
+/// The request for finding a gallery's items most similar to a query embedding.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct SimilarItemsParams {
+    query_embedding: Vec<f32>,
+    /// The maximum number of items to return; defaults to `DEFAULT_SIMILAR_ITEMS`, and is capped at `MAX_ITEMS_LIMIT`.
+    top_k: Option<usize>
+}
+
+/// The query parameters for fetching a gallery's stats.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct GalleryStatsParams {
+    /// The number of the gallery's latest runs to aggregate over; defaults to `DEFAULT_STATS_WINDOW`.
+    window: Option<usize>
+}
+
+/// The response for fetching a gallery's items.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct GalleryItemsResponse {
+    gallery_id: GalleryId,
+    /// Whether the gallery is still in the pipeline, so only the items of marketplaces it's already finished are returned.
+    partial: bool,
+    /// The gallery's stage, if it's still in the pipeline.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    stage: Option<GalleryPipelineStateTypes>,
+    marketplaces: Vec<MarketplaceItemsPage>
+}
+
+/// A page of a gallery's embedded items under a marketplace.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct MarketplaceItemsPage {
+    marketplace: Marketplace,
+    #[serde(flatten)]
+    page: ItemsPage
+}
+
+/// A marketplace which failed somewhere in the pipeline, and why.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct FailedMarketplace {
+    marketplace: Marketplace,
+    reason: String
+}
+
+/// Build the router for managing galleries.
+pub(super) fn build(
+    config: &AxumConfig, 
+    scheduler_config: &ScraperSchedulerConfig, 
+    analysis_config: &ItemAnalysisConfig,
+    module_connections: &AppModuleConnections
+) -> Router {
+    let mut router = Router::new();
+    let min_scrape_interval = Duration::from_secs(scheduler_config.min_scrape_interval_secs);
+    let model_prices = Arc::new(analysis_config.model_prices.clone());
+    let default_criteria = analysis_config.default_evaluation_criteria.clone().map(Arc::new);
+    let allowed_models = Arc::new(analysis_config.allowed_model_overrides.clone());
+    // Bodies past this are rejected with a 413 while being read, before they're deserialized
+    let creation_body_limit = DefaultBodyLimit::max(config.max_create_gallery_body_bytes);
+
+    let creation_state = GalleryCreationState {
+        min_scrape_interval,
+        allowed_models: allowed_models.clone(),
+        default_criteria: default_criteria.clone(),
+        scheduler_sender: module_connections.scraper_scheduler.0.clone(),
+        idempotency_cache: Arc::new(Mutex::new(IdempotencyCache::new(
+            config.idempotency_cache_capacity,
+            Duration::from_secs(config.idempotency_key_ttl_secs)
+        )))
+    };
+    let state_tracker_sender = module_connections.state_tracker.0.clone();
+    let list_scheduler_sender = module_connections.scraper_scheduler.0.clone();
+    router = router.route("/", 
+        post(create_gallery)
+        .with_state(creation_state.clone())
+        .layer(creation_body_limit)
+        .get(
+            move |query| list_galleries(query, state_tracker_sender, list_scheduler_sender)
+        )
+    );
+
+    let preview_allowed_models = allowed_models.clone();
+    let preview_default_criteria = default_criteria.clone();
+    let item_analysis_sender = module_connections.item_analysis.0.clone();
+    router = router.route("/:id/criteria/preview", post(
+        move |path, body| preview_gallery_criteria(path, body, preview_allowed_models, preview_default_criteria, item_analysis_sender)
+    ));
+
+    router = router.route("/batch", post(batch_create_galleries)
+        .with_state(creation_state)
+        .layer(creation_body_limit)
+    );
+
+    let scheduler_sender = module_connections.scraper_scheduler.0.clone();
+    router = router.route("/rescrape", post(
+        move |body| rescrape_galleries(body, scheduler_sender)
+    ));
+
+    let scheduler_sender = module_connections.scraper_scheduler.0.clone();
+    let state_tracker_sender = module_connections.state_tracker.0.clone();
+    let storage_sender = module_connections.storage.0.clone();
+    router = router.route("/merge", post(
+        move |body| merge_galleries(body, scheduler_sender, state_tracker_sender, storage_sender)
+    ));
+
+    let state_tracker_sender = module_connections.state_tracker.0.clone();
+    router = router.route("/stalled", get(
+        move || list_stalled_galleries(state_tracker_sender)
+    ));
+
+    let scheduler_sender = module_connections.scraper_scheduler.0.clone();
+    let state_tracker_sender = module_connections.state_tracker.0.clone();
+    let update_scheduler_sender = module_connections.scraper_scheduler.0.clone();
+    router = router.route("/:id", delete(
+        move |path| delete_gallery(path, scheduler_sender, state_tracker_sender)
+    ).put(
+        move |path, body| update_gallery(path, body, min_scrape_interval, allowed_models, update_scheduler_sender)
+    ));
+
+    let state_tracker_sender = module_connections.state_tracker.0.clone();
+    let scheduler_sender = module_connections.scraper_scheduler.0.clone();
+    router = router.route("/:id/status", get(
+        move |path| get_gallery_status(path, state_tracker_sender, scheduler_sender)
+    ));
+
+    let state_tracker_sender = module_connections.state_tracker.0.clone();
+    router = router.route("/:id/audit", get(
+        move |path| get_gallery_audit_log(path, state_tracker_sender)
+    ));
+
+    let scheduler_sender = module_connections.scraper_scheduler.0.clone();
+    router = router.route("/:id/next-run", get(
+        move |path| get_gallery_next_run(path, scheduler_sender)
+    ));
+
+    let scheduler_sender = module_connections.scraper_scheduler.0.clone();
+    router = router.route("/:id/enabled", patch(
+        move |path, body| set_gallery_enabled(path, body, scheduler_sender)
+    ));
+
+    let state_tracker_sender = module_connections.state_tracker.0.clone();
+    let storage_sender = module_connections.storage.0.clone();
+    router = router.route("/:id/usage", get(
+        move |path| get_gallery_usage(path, state_tracker_sender, storage_sender, model_prices)
+    ));
+
+    let state_tracker_sender = module_connections.state_tracker.0.clone();
+    let storage_sender = module_connections.storage.0.clone();
+    router = router.route("/:id/items", get(
+        move |path, query| get_gallery_items(path, query, state_tracker_sender, storage_sender)
+    ));
+
+    let storage_sender = module_connections.storage.0.clone();
+    router = router.route("/:id/diff", get(
+        move |path| get_gallery_scrape_diff(path, storage_sender)
+    ));
+
+    let storage_sender = module_connections.storage.0.clone();
+    router = router.route("/:id/similar", post(
+        move |path, body| find_similar_items(path, body, storage_sender)
+    ));
+
+    let storage_sender = module_connections.storage.0.clone();
+    router = router.route("/:id/stats", get(
+        move |path, query| get_gallery_stats(path, query, storage_sender)
+    ));
+
+    let storage_sender = module_connections.storage.0.clone();
+    router = router.route("/:id/export", get(
+        move |path, query| export::export_gallery_items(path, query, storage_sender)
+    ));
+
+    let state_tracker_sender = module_connections.state_tracker.0.clone();
+    let replay_senders = ReplaySenders {
+        search_scraper: module_connections.search_scraper.0.clone(),
+        item_scraper: module_connections.item_scraper.0.clone(),
+        item_analysis: module_connections.item_analysis.0.clone(),
+        item_embedder: module_connections.image_classifier.0.clone(),
+        storage: module_connections.storage.0.clone()
+    };
+    router = router.route("/:id/replay", post(
+        move |path, query| replay_gallery(path, query, state_tracker_sender, replay_senders)
+    ));
+
+    let state_tracker_sender = module_connections.state_tracker.0.clone();
+    let storage_sender = module_connections.storage.0.clone();
+    let item_analysis_sender = module_connections.item_analysis.0.clone();
+    router = router.route("/:id/retry-analysis", post(
+        move |path| retry_gallery_analysis(path, state_tracker_sender, storage_sender, item_analysis_sender)
+    ));
+
+    router
+}
+
+/// Create a gallery and add it to the scheduler.
+/// 
+/// If an `Idempotency-Key` header is set and was recently seen, the originally created gallery's ID is returned instead.
+/// 
+/// If `dry_run` is set, the gallery is only validated, and its normalized form (including any default evaluation criteria) is returned with a 200.
+async fn create_gallery(
+    State(mut state): State<GalleryCreationState>,
+    headers: HeaderMap,
+    Query(params): Query<CreateGalleryParams>,
+    Json(mut request): Json<CreateGalleryRequest>
+) -> Result<Response, ApiError> {
+    if params.dry_run {
+        apply_default_criteria(&mut request, state.default_criteria.as_deref());
+        let request = validate_gallery(request, state.min_scrape_interval, &state.allowed_models)?;
+        return Ok((StatusCode::OK, Json(request)).into_response());
+    }
+
+    let idempotency_key = match headers.get(IDEMPOTENCY_KEY_HEADER) {
+        Some(value) => Some(
+            value
+                .to_str()
+                .map_err(|_| ApiError::BadRequest(format!("{IDEMPOTENCY_KEY_HEADER} header is not valid ASCII")))?
+                .to_string()
+        ),
+        None => None
+    };
+    // Held for the whole creation, so that concurrent retries with the same key can't both create a gallery
+    let idempotency_cache = state.idempotency_cache.clone();
+    let mut idempotency_cache = match idempotency_key {
+        Some(_) => Some(idempotency_cache.lock().await),
+        None => None
+    };
+    if let (Some(key), Some(cache)) = (&idempotency_key, &mut idempotency_cache) {
+        if let Some(gallery_id) = cache.get(key) {
+            tracing::info!("Got repeated {IDEMPOTENCY_KEY_HEADER} for gallery {gallery_id}; returning original response");
+            return Ok((StatusCode::CREATED, Json(CreateGalleryResponse { gallery_id })).into_response());
+        }
+    }
+
+    let gallery_id = add_new_gallery(request, &mut state).await?;
+
+    if let (Some(key), Some(cache)) = (idempotency_key, &mut idempotency_cache) {
+        cache.insert(key, gallery_id.clone());
+    }
+    tracing::info!("Created gallery {gallery_id}");
+    Ok((StatusCode::CREATED, Json(CreateGalleryResponse { gallery_id })).into_response())
+}
+
+/// Create multiple galleries at once.
+/// 
+/// Each gallery is validated and created independently, so some can succeed while others fail;
+/// the response is always a 207, with a result for each gallery in the same order as the request.
+async fn batch_create_galleries(
+    State(mut state): State<GalleryCreationState>,
+    Json(requests): Json<Vec<serde_json::Value>>
+) -> (StatusCode, Json<Vec<BatchCreateGalleryResult>>) {
+    let mut results = Vec::with_capacity(requests.len());
+    for request in requests {
+        let result = match serde_json::from_value::<CreateGalleryRequest>(request) {
+            Ok(request) => match add_new_gallery(request, &mut state).await {
+                Ok(gallery_id) => BatchCreateGalleryResult::Created { gallery_id },
+                Err(err) => BatchCreateGalleryResult::Failed { error: err.to_string() }
+            },
+            Err(err) => BatchCreateGalleryResult::Failed { error: format!("Invalid gallery: {err}") }
+        };
+        results.push(result);
+    }
+    let num_created = results
+        .iter()
+        .filter(|result| matches!(result, BatchCreateGalleryResult::Created { .. }))
+        .count();
+    tracing::info!("Batch created {num_created}/{} galleries", results.len());
+    (StatusCode::MULTI_STATUS, Json(results))
+}
+
+/// Immediately send each gallery to the search scraper, regardless of (and without affecting) its schedule.
+/// 
+/// Responds with a 207 and the result for each gallery; galleries which aren't scheduled fail,
+/// and galleries which are already being scraped are queued or fail, depending on the concurrent scrape policy.
+async fn rescrape_galleries(
+    Json(request): Json<RescrapeRequest>,
+    mut scheduler_sender: ScraperSchedulerSender
+) -> (StatusCode, Json<Vec<RescrapeResult>>) {
+    let mut results = Vec::with_capacity(request.gallery_ids.len());
+    for gallery_id in request.gallery_ids {
+        let (msg, receiver) = ForceScrapeMessage::new(gallery_id.clone());
+        let result = match scheduler_sender.send(SchedulerMessage::ForceScrape(msg)).await {
+            Ok(_) => match receiver.await {
+                Ok(Ok(ScrapeStart::Started)) => RescrapeResult::Triggered { gallery_id },
+                Ok(Ok(ScrapeStart::Queued)) => RescrapeResult::Queued { gallery_id },
+                Ok(Err(SchedulerError::ScrapeInProgress { .. })) => RescrapeResult::Failed { 
+                    gallery_id, 
+                    error: "Scrape already in progress".into() 
+                },
+                Ok(Err(err)) => RescrapeResult::Failed { gallery_id, error: err.to_string() },
+                Err(err) => RescrapeResult::Failed { gallery_id, error: format!("Failed to receive a response from the scheduler: {err}") }
+            },
+            Err(err) => RescrapeResult::Failed { gallery_id, error: format!("Failed to message the scheduler: {err}") }
+        };
+        results.push(result);
+    }
+    let num_triggered = results
+        .iter()
+        .filter(|result| matches!(result, RescrapeResult::Triggered { .. }))
+        .count();
+    tracing::info!("Triggered re-scrapes for {num_triggered}/{} galleries", results.len());
+    (StatusCode::MULTI_STATUS, Json(results))
+}
+
+/// Validates a gallery's schedule, search criteria and evaluation criteria (including that its model override is in `allowed_models`),
+/// returning it normalized (ie with trimmed keywords).
+/// 
+/// Returns an `Err` with a 400 describing the first problem found,
+/// except for the search criteria, whose problems are all listed.
+fn validate_gallery(
+    mut request: CreateGalleryRequest,
+    min_scrape_interval: Duration,
+    allowed_models: &[String]
+) -> Result<CreateGalleryRequest, ApiError> {
+    ValidCronString::new_with_min_interval(request.scraping_periodicity.get_str(), min_scrape_interval)
+        .map_err(|err| ApiError::BadRequest(format!("Invalid scraping periodicity: {err}")))?;
+
+    let search_criteria = &mut request.search_criteria;
+    search_criteria.keyword = search_criteria.keyword.trim().to_string();
+    search_criteria.exclude_keyword = search_criteria.exclude_keyword.trim().to_string();
+    for marketplace_override in search_criteria.per_marketplace.values_mut() {
+        marketplace_override.keyword = marketplace_override.keyword.as_ref().map(|keyword| keyword.trim().to_string());
+        marketplace_override.exclude_keyword = marketplace_override.exclude_keyword.as_ref().map(|keyword| keyword.trim().to_string());
+    }
+    search_criteria
+        .validate()
+        .map_err(|errors| ApiError::InvalidFields { 
+            message: "Invalid search criteria".into(), 
+            errors: errors
+                .iter()
+                .map(|err| err.to_string())
+                .collect()
+        })?;
+
+    request.evaluation_criteria
+        .validate()
+        .and_then(|_| request.evaluation_criteria.validate_model_override(allowed_models))
+        .map_err(|err| ApiError::BadRequest(format!("Invalid evaluation criteria: {err}")))?;
+    Ok(request)
+}
+
+/// Fill the unset fields of a gallery's evaluation criteria from the default criteria (if any),
+/// returning the names of the fields which were filled.
+fn apply_default_criteria(request: &mut CreateGalleryRequest, default_criteria: Option<&EvaluationCriteria>) -> Vec<&'static str> {
+    match default_criteria {
+        Some(default_criteria) => request.evaluation_criteria.apply_defaults(default_criteria),
+        None => vec![]
+    }
+}
+
+/// Apply the default evaluation criteria to a new gallery, validate it, generate an ID for it and add it to the scheduler, returning the ID.
+/// 
+/// Returns an `Err` with a 400 if the gallery is invalid, or a 500 if the scheduler couldn't add it.
+async fn add_new_gallery(
+    mut request: CreateGalleryRequest,
+    state: &mut GalleryCreationState
+) -> Result<GalleryId, ApiError> {
+    let applied_defaults = apply_default_criteria(&mut request, state.default_criteria.as_deref());
+    let request = validate_gallery(request, state.min_scrape_interval, &state.allowed_models)?;
+    let gallery_id = GalleryId::from(Uuid::new_v4().to_string());
+    if !applied_defaults.is_empty() {
+        tracing::info!("Applied default evaluation criteria to gallery {gallery_id}, as these were unset: {}", applied_defaults.join(", "));
+    }
+    let gallery = GallerySchedulerState {
+        gallery_id: gallery_id.clone(),
+        scraping_periodicity: request.scraping_periodicity,
+        search_criteria: request.search_criteria,
+        marketplace_previous_scraped_datetimes: HashMap::new(),
+        evaluation_criteria: request.evaluation_criteria,
+        enabled: true
+    };
+    let (msg, receiver) = NewGalleryMessage::new(gallery);
+    state.scheduler_sender
+        .send(SchedulerMessage::NewGallery(msg))
+        .await
+        .map_err(|err| ApiError::Internal(format!("Failed to message the scheduler: {err}")))?;
+    match receiver.await {
+        Ok(Ok(_)) => Ok(gallery_id),
+        Ok(Err(err @ SchedulerError::InvalidSchedule { .. })) => Err(ApiError::BadRequest(err.to_string())),
+        Ok(Err(err)) => Err(ApiError::Internal(format!("Scheduler failed to add gallery: {err}"))),
+        Err(err) => Err(ApiError::Internal(format!("Failed to receive a response from the scheduler: {err}")))
+    }
+}
+
+/// Update a gallery's schedule, search criteria and evaluation criteria in the scheduler.
+/// 
+/// The update is validated like a new gallery (without applying the default evaluation criteria).
+/// It carries no previous scraped datetimes, so the scheduler keeps the gallery's (ie its next scrape doesn't start over).
+/// Responds with a 400 if it's invalid (including a malformed schedule), in which case the gallery keeps its previous schedule,
+/// or a 404 if the gallery isn't scheduled.
+async fn update_gallery(
+    Path(gallery_id): Path<String>,
+    Json(request): Json<CreateGalleryRequest>,
+    min_scrape_interval: Duration,
+    allowed_models: Arc<Vec<String>>,
+    mut scheduler_sender: ScraperSchedulerSender
+) -> Result<Json<UpdateGalleryResponse>, ApiError> {
+    let gallery_id = GalleryId::from(gallery_id);
+    let request = validate_gallery(request, min_scrape_interval, &allowed_models)?;
+    let gallery = GallerySchedulerState {
+        gallery_id: gallery_id.clone(),
+        scraping_periodicity: request.scraping_periodicity,
+        search_criteria: request.search_criteria,
+        marketplace_previous_scraped_datetimes: HashMap::new(),
+        evaluation_criteria: request.evaluation_criteria,
+        enabled: true
+    };
+
+    let (msg, receiver) = UpdateGalleryMessage::new(gallery);
+    scheduler_sender
+        .send(SchedulerMessage::UpdateGallery(msg))
+        .await
+        .map_err(|err| ApiError::Internal(format!("Failed to message the scheduler: {err}")))?;
+    match receiver.await {
+        Ok(Ok(_)) => Ok(Json(UpdateGalleryResponse { gallery_id })),
+        Ok(Err(err @ SchedulerError::InvalidSchedule { .. })) => Err(ApiError::BadRequest(err.to_string())),
+        Ok(Err(err @ SchedulerError::GalleryNotFound { .. })) => Err(ApiError::NotFound(err.to_string())),
+        Ok(Err(err)) => Err(ApiError::Internal(format!("Scheduler failed to update gallery: {err}"))),
+        Err(err) => Err(ApiError::Internal(format!("Failed to receive a response from the scheduler: {err}")))
+    }
+}
+
+/// Delete a gallery from both the scheduler and the state tracker.
+/// 
+/// Any in-flight run of the gallery is cancelled, so modules processing it abort and their later writes are rejected.
+/// 
+/// Responds with a 404 if the gallery exists in neither.
+async fn delete_gallery(
+    Path(gallery_id): Path<String>,
+    mut scheduler_sender: ScraperSchedulerSender,
+    mut state_tracker_sender: StateTrackerSender
+) -> Result<Json<DeleteGalleryResponse>, ApiError> {
+    let gallery_id = GalleryId::from(gallery_id);
+
+    let (msg, receiver) = DeleteGalleryMessage::new(gallery_id.clone());
+    scheduler_sender
+        .send(SchedulerMessage::DeleteGallery(msg))
+        .await
+        .map_err(|err| ApiError::Internal(format!("Failed to message the scheduler: {err}")))?;
+    let removed_from_scheduler = match receiver.await {
+        Ok(Ok(_)) => true,
+        Ok(Err(SchedulerError::GalleryNotFound { .. })) => false,
+        Ok(Err(err)) => return Err(ApiError::Internal(format!("Scheduler failed to delete gallery: {err}"))),
+        Err(err) => return Err(ApiError::Internal(format!("Failed to receive a response from the scheduler: {err}")))
+    };
+
+    let removed_from_state_tracker = match state_tracker_sender.cancel_gallery(gallery_id.clone()).await {
+        Ok(Ok(_)) => true,
+        Ok(Err(StateTrackerError::GalleryDoesntExist)) => false,
+        Ok(Err(err)) => return Err(ApiError::Internal(format!("State tracker failed to cancel gallery: {err}"))),
+        Err(err) => return Err(ApiError::Internal(format!("Failed to message the state tracker: {err}")))
+    };
+
+    if !removed_from_scheduler && !removed_from_state_tracker {
+        return Err(ApiError::NotFound(format!("Gallery {gallery_id} not found")));
+    }
+    tracing::info!("Deleted gallery {gallery_id} (scheduler: {removed_from_scheduler}, state tracker: {removed_from_state_tracker})");
+    Ok(Json(DeleteGalleryResponse {
+        removed_from_scheduler,
+        removed_from_state_tracker
+    }))
+}
+
+/// Merge a duplicate gallery's stored items into another stored gallery, skipping items the target already has (by ID),
+/// then delete the source from the scheduler and state tracker.
+/// 
+/// Responds with a 400 if the galleries are the same, a 409 if either is still in the pipeline, or a 404 if either isn't stored.
+async fn merge_galleries(
+    Json(request): Json<MergeGalleriesRequest>,
+    mut scheduler_sender: ScraperSchedulerSender,
+    mut state_tracker_sender: StateTrackerSender,
+    mut storage_sender: StorageSender
+) -> Result<Json<GalleryMergeSummary>, ApiError> {
+    let source_gallery_id = request.source_gallery_id.clone();
+    if source_gallery_id == request.target_gallery_id {
+        return Err(ApiError::BadRequest(format!("Can't merge gallery {source_gallery_id} into itself")));
+    }
+
+    for gallery_id in [&request.source_gallery_id, &request.target_gallery_id] {
+        match state_tracker_sender.check_gallery_doesnt_exist(gallery_id.clone()).await {
+            Ok(Ok(_)) => (),
+            Ok(Err(StateTrackerError::GalleryAlreadyExists)) => return Err(ApiError::Conflict(format!("Gallery {gallery_id} is still in the pipeline"))),
+            Ok(Err(err)) => return Err(ApiError::Internal(format!("State tracker failed to check gallery: {err}"))),
+            Err(err) => return Err(ApiError::Internal(format!("Failed to message the state tracker: {err}")))
+        }
+    }
+
+    let (msg, receiver) = MergeGalleriesMessage::new(request);
+    storage_sender
+        .send(StorageMessage::MergeGalleries(msg))
+        .await
+        .map_err(|err| ApiError::Internal(format!("Failed to message storage: {err}")))?;
+    let summary = match receiver.await {
+        Ok(Ok(summary)) => summary,
+        Ok(Err(err @ StorageError::GalleryNotFound { .. })) => return Err(ApiError::NotFound(err.to_string())),
+        Ok(Err(err)) => return Err(ApiError::Internal(format!("Storage failed to merge galleries: {err}"))),
+        Err(err) => return Err(ApiError::Internal(format!("Failed to receive a response from storage: {err}")))
+    };
+
+    // The source's items are merged by now, so failing to delete it only leaves it scheduled to be scraped again
+    let (msg, receiver) = DeleteGalleryMessage::new(source_gallery_id.clone());
+    scheduler_sender
+        .send(SchedulerMessage::DeleteGallery(msg))
+        .await
+        .map_err(|err| ApiError::Internal(format!("Merged gallery {source_gallery_id}, but failed to message the scheduler to delete it: {err}")))?;
+    match receiver.await {
+        Ok(Ok(_)) | Ok(Err(SchedulerError::GalleryNotFound { .. })) => (),
+        Ok(Err(err)) => return Err(ApiError::Internal(format!("Merged gallery {source_gallery_id}, but the scheduler failed to delete it: {err}"))),
+        Err(err) => return Err(ApiError::Internal(format!("Merged gallery {source_gallery_id}, but failed to receive a response from the scheduler: {err}")))
+    }
+    // Cancelling also rejects the writes of any run which started since it was checked
+    match state_tracker_sender.cancel_gallery(source_gallery_id.clone()).await {
+        Ok(Ok(_)) | Ok(Err(StateTrackerError::GalleryDoesntExist)) => (),
+        Ok(Err(err)) => return Err(ApiError::Internal(format!("Merged gallery {source_gallery_id}, but the state tracker failed to cancel it: {err}"))),
+        Err(err) => return Err(ApiError::Internal(format!("Merged gallery {source_gallery_id}, but failed to message the state tracker: {err}")))
+    }
+
+    tracing::info!(
+        "Merged gallery {source_gallery_id} into gallery {} ({} items merged, {} duplicates skipped)", 
+        summary.target_gallery_id,
+        summary.merged_items.values().sum::<usize>(),
+        summary.duplicate_items.values().sum::<usize>()
+    );
+    Ok(Json(summary))
+}
+
+/// Get a gallery's current stage in the pipeline, the reasons for any failed marketplaces, and a description of its schedule.
+/// 
+/// Responds with a 404 if the gallery isn't in the pipeline (including if it's already been stored and removed).
+async fn get_gallery_status(
+    Path(gallery_id): Path<String>,
+    mut state_tracker_sender: StateTrackerSender,
+    mut scheduler_sender: ScraperSchedulerSender
+) -> Result<Json<GalleryStatusResponse>, ApiError> {
+    let gallery_id = GalleryId::from(gallery_id);
+
+    let stage = match state_tracker_sender.check_gallery_exists(gallery_id.clone()).await {
+        Ok(Ok(stage)) => stage,
+        Ok(Err(StateTrackerError::GalleryDoesntExist)) => return Err(ApiError::NotFound(format!("Gallery {gallery_id} not found"))),
+        Ok(Err(err)) => return Err(ApiError::Internal(format!("State tracker failed to check gallery: {err}"))),
+        Err(err) => return Err(ApiError::Internal(format!("Failed to message the state tracker: {err}")))
+    };
+
+    let (in_progress, failed_marketplace_reasons, item_counts) = match state_tracker_sender.peek_gallery_state(gallery_id.clone(), stage.clone()).await {
+        Ok(Ok(state)) => {
+            let failed_marketplace_reasons = state
+                .failed_marketplace_reasons()
+                .cloned()
+                .unwrap_or_default();
+            let item_counts = match &state {
+                GalleryPipelineStates::Final(state) => Some(state.item_counts()),
+                _ => None
+            };
+            (false, failed_marketplace_reasons, item_counts)
+        },
+        // The gallery may also have moved to the next stage between both requests
+        Ok(Err(StateTrackerError::GalleryStateTaken | StateTrackerError::GalleryHasWrongState)) => (true, HashMap::new(), None),
+        Ok(Err(StateTrackerError::GalleryDoesntExist)) => return Err(ApiError::NotFound(format!("Gallery {gallery_id} not found"))),
+        Ok(Err(err)) => return Err(ApiError::Internal(format!("State tracker failed to get gallery state: {err}"))),
+        Err(err) => return Err(ApiError::Internal(format!("Failed to message the state tracker: {err}")))
+    };
+
+    let failed_marketplaces = failed_marketplace_reasons
+        .into_iter()
+        .map(|(marketplace, reason)| FailedMarketplace { marketplace, reason })
+        .collect();
+    let schedule_description = fetch_schedule_descriptions(&mut scheduler_sender)
+        .await?
+        .remove(&gallery_id);
+    Ok(Json(GalleryStatusResponse {
+        gallery_id,
+        stage,
+        in_progress,
+        failed_marketplaces,
+        item_counts,
+        schedule_description
+    }))
+}
+
+/// List the galleries in the pipeline along with their current stage and a description of their schedule, sorted by ID.
+/// 
+/// Supports filtering by `stage`, and paginating with `offset` and `limit`.
+async fn list_galleries(
+    Query(params): Query<ListGalleriesParams>,
+    mut state_tracker_sender: StateTrackerSender,
+    mut scheduler_sender: ScraperSchedulerSender
+) -> Result<Json<ListGalleriesResponse>, ApiError> {
+    let galleries = match state_tracker_sender.list_galleries().await {
+        Ok(Ok(galleries)) => galleries,
+        Ok(Err(err)) => return Err(ApiError::Internal(format!("State tracker failed to list galleries: {err}"))),
+        Err(err) => return Err(ApiError::Internal(format!("Failed to message the state tracker: {err}")))
+    };
+
+    let galleries: Vec<GallerySummary> = galleries
+        .into_iter()
+        .filter(|(_, stage)| params.stage.as_ref().is_none_or(|filter| filter == stage))
+        .map(|(gallery_id, stage)| GallerySummary { gallery_id, stage, schedule_description: None })
+        .collect();
+    let total = galleries.len();
+    let limit = params.limit
+        .unwrap_or(DEFAULT_LIST_LIMIT)
+        .min(MAX_LIST_LIMIT);
+    let mut schedule_descriptions = fetch_schedule_descriptions(&mut scheduler_sender).await?;
+    let galleries = galleries
+        .into_iter()
+        .skip(params.offset)
+        .take(limit)
+        .map(|summary| GallerySummary {
+            schedule_description: schedule_descriptions.remove(&summary.gallery_id),
+            ..summary
+        })
+        .collect();
+    Ok(Json(ListGalleriesResponse { galleries, total }))
+}
+
+/// Fetch a plain-English description of each scheduled gallery's schedule, by gallery ID.
+async fn fetch_schedule_descriptions(scheduler_sender: &mut ScraperSchedulerSender) -> Result<HashMap<GalleryId, String>, ApiError> {
+    let (msg, receiver) = GetScheduleMessage::new(());
+    scheduler_sender
+        .send(SchedulerMessage::GetSchedule(msg))
+        .await
+        .map_err(|err| ApiError::Internal(format!("Failed to message the scheduler: {err}")))?;
+    let schedule = receiver
+        .await
+        .map_err(|err| ApiError::Internal(format!("Failed to receive a response from the scheduler: {err}")))?;
+    Ok(
+        schedule
+            .into_iter()
+            .map(|gallery| (gallery.gallery_id, gallery.schedule_description))
+            .collect()
+    )
+}
+
+/// List the galleries which were removed from the pipeline for not advancing within their stage's timeout, sorted by ID.
+/// 
+/// Each includes its last state for inspection; a stalled gallery can be retried through `POST /galleries/rescrape`.
+async fn list_stalled_galleries(
+    mut state_tracker_sender: StateTrackerSender
+) -> Result<Json<Vec<StalledGallery>>, ApiError> {
+    match state_tracker_sender.get_stalled_galleries().await {
+        Ok(Ok(stalled_galleries)) => Ok(Json(stalled_galleries)),
+        Ok(Err(err)) => Err(ApiError::Internal(format!("State tracker failed to get stalled galleries: {err}"))),
+        Err(err) => Err(ApiError::Internal(format!("Failed to message the state tracker: {err}")))
+    }
+}
+
+/// Get a gallery's recorded state transitions, oldest first.
+/// 
+/// These are kept after the gallery leaves the pipeline, so this responds with an empty list (rather than a 404)
+/// if the gallery has no recorded transitions, or the audit log is disabled.
+async fn get_gallery_audit_log(
+    Path(gallery_id): Path<String>,
+    mut state_tracker_sender: StateTrackerSender
+) -> Result<Json<Vec<StateTransition>>, ApiError> {
+    let gallery_id = GalleryId::from(gallery_id);
+    match state_tracker_sender.get_audit_log(gallery_id).await {
+        Ok(Ok(transitions)) => Ok(Json(transitions)),
+        Ok(Err(err)) => Err(ApiError::Internal(format!("State tracker failed to get audit log: {err}"))),
+        Err(err) => Err(ApiError::Internal(format!("Failed to message the state tracker: {err}")))
+    }
+}
+
+/// Get when a gallery will next be scraped, including its jitter.
+/// 
+/// Responds with a 404 if the gallery isn't scheduled.
+async fn get_gallery_next_run(
+    Path(gallery_id): Path<String>,
+    mut scheduler_sender: ScraperSchedulerSender
+) -> Result<Json<NextRunResponse>, ApiError> {
+    let gallery_id = GalleryId::from(gallery_id);
+
+    let (msg, receiver) = GetNextRunMessage::new(gallery_id.clone());
+    scheduler_sender
+        .send(SchedulerMessage::GetNextRun(msg))
+        .await
+        .map_err(|err| ApiError::Internal(format!("Failed to message the scheduler: {err}")))?;
+    let next_run = match receiver.await {
+        Ok(Ok(next_run)) => next_run,
+        Ok(Err(err @ SchedulerError::GalleryNotFound { .. })) => return Err(ApiError::NotFound(err.to_string())),
+        Ok(Err(err)) => return Err(ApiError::Internal(format!("Scheduler failed to get the next run: {err}"))),
+        Err(err) => return Err(ApiError::Internal(format!("Failed to receive a response from the scheduler: {err}")))
+    };
+    Ok(Json(NextRunResponse { gallery_id, next_run }))
+}
+
+/// Enable or disable a gallery's scheduled scrapes, without removing it from the scheduler.
+/// 
+/// Responds with a 404 if the gallery isn't scheduled.
+async fn set_gallery_enabled(
+    Path(gallery_id): Path<String>,
+    Json(request): Json<SetEnabledRequest>,
+    mut scheduler_sender: ScraperSchedulerSender
+) -> Result<Json<SetEnabledResponse>, ApiError> {
+    let gallery_id = GalleryId::from(gallery_id);
+
+    let (msg, receiver) = SetEnabledMessage::new((gallery_id.clone(), request.enabled));
+    scheduler_sender
+        .send(SchedulerMessage::SetEnabled(msg))
+        .await
+        .map_err(|err| ApiError::Internal(format!("Failed to message the scheduler: {err}")))?;
+    match receiver.await {
+        Ok(Ok(_)) => Ok(Json(SetEnabledResponse { gallery_id, enabled: request.enabled })),
+        Ok(Err(err @ SchedulerError::GalleryNotFound { .. })) => Err(ApiError::NotFound(err.to_string())),
+        Ok(Err(err)) => Err(ApiError::Internal(format!("Scheduler failed to set the gallery's enabled flag: {err}"))),
+        Err(err) => Err(ApiError::Internal(format!("Failed to receive a response from the scheduler: {err}")))
+    }
+}
+
+/// Get the tokens a gallery has used in analysis so far (including any retries), by model, along with their estimated cost.
+/// 
+/// The gallery is looked up in the pipeline first, then in storage.
+/// Responds with a 404 if it's in neither, or a 409 if a module is currently processing it.
+async fn get_gallery_usage(
+    Path(gallery_id): Path<String>,
+    mut state_tracker_sender: StateTrackerSender,
+    mut storage_sender: StorageSender,
+    model_prices: Arc<HashMap<String, ModelPrice>>
+) -> Result<Json<TokenUsageResponse>, ApiError> {
+    let gallery_id = GalleryId::from(gallery_id);
+
+    let token_usage = match state_tracker_sender.check_gallery_exists(gallery_id.clone()).await {
+        Ok(Ok(stage)) => match state_tracker_sender.peek_gallery_state(gallery_id.clone(), stage).await {
+            Ok(Ok(state)) => match state {
+                GalleryPipelineStates::Initialization(_) | 
+                GalleryPipelineStates::SearchScraping(_) | 
+                GalleryPipelineStates::ItemScraping(_) => ModelTokenUsage::default(),
+                GalleryPipelineStates::ItemAnalysis(state) => state.token_usage,
+                GalleryPipelineStates::ItemEmbedding(state) => state.token_usage,
+                GalleryPipelineStates::Final(state) => state.token_usage,
+            },
+            Ok(Err(StateTrackerError::GalleryStateTaken | StateTrackerError::GalleryHasWrongState)) => {
+                return Err(ApiError::Conflict(format!("Gallery {gallery_id} is currently being processed")))
+            },
+            Ok(Err(err)) => return Err(ApiError::Internal(format!("State tracker failed to get gallery state: {err}"))),
+            Err(err) => return Err(ApiError::Internal(format!("Failed to message the state tracker: {err}")))
+        },
+        Ok(Err(StateTrackerError::GalleryDoesntExist)) => {
+            let (msg, receiver) = GetTokenUsageMessage::new(gallery_id.clone());
+            storage_sender
+                .send(StorageMessage::GetTokenUsage(msg))
+                .await
+                .map_err(|err| ApiError::Internal(format!("Failed to message storage: {err}")))?;
+            match receiver.await {
+                Ok(Ok(token_usage)) => token_usage,
+                Ok(Err(StorageError::GalleryNotFound { .. })) => return Err(ApiError::NotFound(format!("Gallery {gallery_id} not found"))),
+                Ok(Err(err)) => return Err(ApiError::Internal(format!("Storage failed to get token usage: {err}"))),
+                Err(err) => return Err(ApiError::Internal(format!("Failed to receive a response from storage: {err}")))
+            }
+        },
+        Ok(Err(err)) => return Err(ApiError::Internal(format!("State tracker failed to check gallery: {err}"))),
+        Err(err) => return Err(ApiError::Internal(format!("Failed to message the state tracker: {err}")))
+    };
+
+    let mut models: Vec<ModelUsage> = token_usage
+        .iter()
+        .map(|(model, usage)| ModelUsage {
+            model: model.clone(),
+            prompt_tokens: usage.prompt_tokens,
+            completion_tokens: usage.completion_tokens,
+            estimated_cost: model_prices
+                .get(model)
+                .map(|price| usage.prompt_tokens as f64 * price.prompt_price + usage.completion_tokens as f64 * price.completion_price)
+        })
+        .collect();
+    models.sort_by(|a, b| a.model.cmp(&b.model));
+    let estimated_cost = models
+        .iter()
+        .filter_map(|model| model.estimated_cost)
+        .sum();
+    let unpriced_models = models
+        .iter()
+        .filter(|model| model.estimated_cost.is_none())
+        .map(|model| model.model.clone())
+        .collect();
+    Ok(Json(TokenUsageResponse {
+        gallery_id,
+        prompt_tokens: models.iter().map(|model| model.prompt_tokens).sum(),
+        completion_tokens: models.iter().map(|model| model.completion_tokens).sum(),
+        estimated_cost,
+        unpriced_models,
+        models
+    }))
+}
+
+/// Get a page of a gallery's embedded items under each marketplace (or only `marketplace`, if set).
+/// 
+/// The gallery is looked up in the pipeline first, then in storage. If it's still in the pipeline, the response is flagged as `partial`,
+/// and only has the items of marketplaces finished so far (ie in a previous run); it has none while a module is processing the gallery.
+/// 
+/// Responds with a 404 if the gallery is in neither.
+async fn get_gallery_items(
+    Path(gallery_id): Path<String>,
+    Query(params): Query<GalleryItemsParams>,
+    mut state_tracker_sender: StateTrackerSender,
+    mut storage_sender: StorageSender
+) -> Result<Json<GalleryItemsResponse>, ApiError> {
+    let gallery_id = GalleryId::from(gallery_id);
+    let marketplaces = match &params.marketplace {
+        Some(marketplace) => vec![marketplace.clone()],
+        None => Marketplace::all()
+    };
+    let page_request = |marketplace: Marketplace| ItemsPageRequest {
+        gallery_id: gallery_id.clone(),
+        marketplace,
+        offset: params.offset,
+        limit: params.limit
+            .unwrap_or(DEFAULT_ITEMS_LIMIT)
+            .min(MAX_ITEMS_LIMIT),
+        min_confidence: params.min_confidence,
+        sort_by_confidence: params.sort_by_confidence
+    };
+
+    match state_tracker_sender.check_gallery_exists(gallery_id.clone()).await {
+        Ok(Ok(stage)) => {
+            let (partial, items) = match state_tracker_sender.peek_gallery_state(gallery_id.clone(), stage.clone()).await {
+                Ok(Ok(state)) => match state {
+                    GalleryPipelineStates::Initialization(_) | 
+                    GalleryPipelineStates::SearchScraping(_) | 
+                    GalleryPipelineStates::ItemScraping(_) => (true, HashMap::new()),
+                    GalleryPipelineStates::ItemAnalysis(state) => (true, state.completed_items),
+                    GalleryPipelineStates::ItemEmbedding(state) => (true, state.completed_items),
+                    GalleryPipelineStates::Final(state) => (false, state.items),
+                },
+                Ok(Err(StateTrackerError::GalleryStateTaken | StateTrackerError::GalleryHasWrongState)) => (true, HashMap::new()),
+                Ok(Err(StateTrackerError::GalleryDoesntExist)) => return Err(ApiError::NotFound(format!("Gallery {gallery_id} not found"))),
+                Ok(Err(err)) => return Err(ApiError::Internal(format!("State tracker failed to get gallery state: {err}"))),
+                Err(err) => return Err(ApiError::Internal(format!("Failed to message the state tracker: {err}")))
+            };
+            let marketplaces = marketplaces
+                .into_iter()
+                .map(|marketplace| MarketplaceItemsPage {
+                    page: ItemsPage::paginate(items.get(&marketplace), &page_request(marketplace.clone())),
+                    marketplace
+                })
+                .collect();
+            Ok(Json(GalleryItemsResponse {
+                gallery_id: gallery_id.clone(),
+                partial,
+                stage: Some(stage),
+                marketplaces
+            }))
+        },
+        Ok(Err(StateTrackerError::GalleryDoesntExist)) => {
+            let mut pages = vec![];
+            for marketplace in marketplaces {
+                let (msg, receiver) = GetItemsPaginatedMessage::new(page_request(marketplace.clone()));
+                storage_sender
+                    .send(StorageMessage::GetItemsPaginated(msg))
+                    .await
+                    .map_err(|err| ApiError::Internal(format!("Failed to message storage: {err}")))?;
+                match receiver.await {
+                    Ok(Ok(page)) => pages.push(MarketplaceItemsPage { marketplace, page }),
+                    Ok(Err(StorageError::GalleryNotFound { .. })) => return Err(ApiError::NotFound(format!("Gallery {gallery_id} not found"))),
+                    Ok(Err(err)) => return Err(ApiError::Internal(format!("Storage failed to get items: {err}"))),
+                    Err(err) => return Err(ApiError::Internal(format!("Failed to receive a response from storage: {err}")))
+                }
+            }
+            Ok(Json(GalleryItemsResponse {
+                gallery_id: gallery_id.clone(),
+                partial: false,
+                stage: None,
+                marketplaces: pages
+            }))
+        },
+        Ok(Err(err)) => Err(ApiError::Internal(format!("State tracker failed to check gallery: {err}"))),
+        Err(err) => Err(ApiError::Internal(format!("Failed to message the state tracker: {err}")))
+    }
+}
+
+/// Get which items were added, changed price or were removed in a gallery's latest scrape, compared to its previous one.
+/// 
+/// Responds with a 404 if the gallery hasn't been scraped yet.
+async fn get_gallery_scrape_diff(
+    Path(gallery_id): Path<String>,
+    mut storage_sender: StorageSender
+) -> Result<Json<ScrapeDiff>, ApiError> {
+    let gallery_id = GalleryId::from(gallery_id);
+
+    let (msg, receiver) = GetScrapeDiffMessage::new(gallery_id.clone());
+    storage_sender
+        .send(StorageMessage::GetScrapeDiff(msg))
+        .await
+        .map_err(|err| ApiError::Internal(format!("Failed to message storage: {err}")))?;
+    match receiver.await {
+        Ok(Ok(diff)) => Ok(Json(diff)),
+        Ok(Err(StorageError::GalleryNotFound { .. })) => Err(ApiError::NotFound(format!("Gallery {gallery_id} hasn't been scraped yet"))),
+        Ok(Err(err)) => Err(ApiError::Internal(format!("Storage failed to get the scrape diff: {err}"))),
+        Err(err) => Err(ApiError::Internal(format!("Failed to receive a response from storage: {err}")))
+    }
+}
+
+/// Find a stored gallery's embedded items most similar to a query embedding, most similar first.
+/// 
+/// Responds with a 400 if the query embedding is empty, `top_k` is 0 or the gallery skips embedding,
+/// or a 404 if the gallery isn't stored.
+async fn find_similar_items(
+    Path(gallery_id): Path<String>,
+    Json(params): Json<SimilarItemsParams>,
+    mut storage_sender: StorageSender
+) -> Result<Json<Vec<SimilarItem>>, ApiError> {
+    let gallery_id = GalleryId::from(gallery_id);
+    if params.query_embedding.is_empty() {
+        return Err(ApiError::BadRequest("Query embedding must not be empty".into()));
+    }
+    let top_k = params.top_k.unwrap_or(DEFAULT_SIMILAR_ITEMS).min(MAX_ITEMS_LIMIT);
+    if top_k == 0 {
+        return Err(ApiError::BadRequest("top_k must be at least 1".into()));
+    }
+
+    let request = SimilarItemsRequest { gallery_id: gallery_id.clone(), query_embedding: params.query_embedding, top_k };
+    match storage_sender.find_similar_items(request).await {
+        Ok(Ok(items)) => Ok(Json(items)),
+        Ok(Err(StorageError::GalleryNotFound { .. })) => Err(ApiError::NotFound(format!("Gallery {gallery_id} is not in storage"))),
+        Ok(Err(StorageError::Other { message, .. })) => Err(ApiError::BadRequest(message)),
+        Ok(Err(err)) => Err(ApiError::Internal(format!("Storage failed to find similar items: {err}"))),
+        Err(err) => Err(ApiError::Internal(format!("Failed to message storage: {err}")))
+    }
+}
+
+/// Get the count, min, max and average price of a gallery's relevant items under each marketplace, over its latest `window` runs.
+/// 
+/// Responds with a 400 if the window is 0, or a 404 if the gallery has no recorded runs.
+async fn get_gallery_stats(
+    Path(gallery_id): Path<String>,
+    Query(params): Query<GalleryStatsParams>,
+    mut storage_sender: StorageSender
+) -> Result<Json<GalleryStats>, ApiError> {
+    let gallery_id = GalleryId::from(gallery_id);
+    let window = params.window.unwrap_or(DEFAULT_STATS_WINDOW);
+    if window == 0 {
+        return Err(ApiError::BadRequest("Window must be at least 1".into()));
+    }
+
+    let (msg, receiver) = GetGalleryStatsMessage::new(GalleryStatsRequest { gallery_id: gallery_id.clone(), window });
+    storage_sender
+        .send(StorageMessage::GetGalleryStats(msg))
+        .await
+        .map_err(|err| ApiError::Internal(format!("Failed to message storage: {err}")))?;
+    match receiver.await {
+        Ok(Ok(stats)) => Ok(Json(stats)),
+        Ok(Err(StorageError::GalleryNotFound { .. })) => Err(ApiError::NotFound(format!("Gallery {gallery_id} has no recorded runs"))),
+        Ok(Err(err)) => Err(ApiError::Internal(format!("Storage failed to get the gallery's stats: {err}"))),
+        Err(err) => Err(ApiError::Internal(format!("Failed to receive a response from storage: {err}")))
+    }
+}
+
+/// Re-run a gallery from a stage, using the latest snapshot of its state in that stage from the state tracker store.
+/// 
+/// The snapshot is sent to the stage's module as a new gallery, so only that stage and those after it are re-run;
+/// replaying from `Initialization` re-runs the search scrape.
+/// 
+/// Responds with a 409 if the gallery is still in the pipeline, or never reached the stage.
+async fn replay_gallery(
+    Path(gallery_id): Path<String>,
+    Query(params): Query<ReplayParams>,
+    mut state_tracker_sender: StateTrackerSender,
+    mut senders: ReplaySenders
+) -> Result<(StatusCode, Json<ReplayResponse>), ApiError> {
+    let gallery_id = GalleryId::from(gallery_id);
+
+    match state_tracker_sender.check_gallery_doesnt_exist(gallery_id.clone()).await {
+        Ok(Ok(_)) => (),
+        Ok(Err(StateTrackerError::GalleryAlreadyExists)) => return Err(ApiError::Conflict(format!("Gallery {gallery_id} is still in the pipeline"))),
+        Ok(Err(err)) => return Err(ApiError::Internal(format!("State tracker failed to check gallery: {err}"))),
+        Err(err) => return Err(ApiError::Internal(format!("Failed to message the state tracker: {err}")))
+    }
+
+    let snapshot = match state_tracker_sender.get_stage_snapshot(gallery_id.clone(), params.from.clone()).await {
+        Ok(Ok(snapshot)) => snapshot,
+        Ok(Err(StateTrackerError::SnapshotNotFound)) => {
+            return Err(ApiError::Conflict(format!("Gallery {gallery_id} has no stored state for the {:?} stage", params.from)))
+        },
+        Ok(Err(err)) => return Err(ApiError::Internal(format!("State tracker failed to get stage snapshot: {err}"))),
+        Err(err) => return Err(ApiError::Internal(format!("Failed to message the state tracker: {err}")))
+    };
+
+    let send_result = match snapshot {
+        GalleryPipelineStates::Initialization(gallery) => senders.search_scraper
+            .send(SearchScraperMessage::ScrapeSearchNew { gallery: gallery.to_next_stage() })
+            .await,
+        GalleryPipelineStates::SearchScraping(gallery) => senders.search_scraper
+            .send(SearchScraperMessage::ScrapeSearchNew { gallery })
+            .await,
+        GalleryPipelineStates::ItemScraping(gallery) => senders.item_scraper
+            .send(ItemScraperMessage::ScrapeItemsNew { gallery })
+            .await,
+        GalleryPipelineStates::ItemAnalysis(gallery) => senders.item_analysis
+            .send(ItemAnalysisMessage::AnalyzeGalleryNew { gallery })
+            .await,
+        GalleryPipelineStates::ItemEmbedding(gallery) => senders.item_embedder
+            .send(ItemEmbedderMessage::ClassifyNew { gallery })
+            .await,
+        GalleryPipelineStates::Final(gallery) => senders.storage
+            .send(StorageMessage::StoreGalleryNew { gallery })
+            .await,
+    };
+    send_result.map_err(|err| ApiError::Internal(format!("Failed to message the {:?} module: {err}", params.from)))?;
+    tracing::info!("Replaying gallery {gallery_id} from the {:?} stage", params.from);
+    Ok((StatusCode::ACCEPTED, Json(ReplayResponse { gallery_id, from: params.from })))
+}
+
+/// Re-run analysis for only the marketplaces of a stored gallery which failed it.
+/// 
+/// The gallery is sent back into the pipeline, with its other marketplaces carried through untouched;
+/// its stored run is kept until the retried run reaches the `Final` stage and replaces it.
+/// 
+/// Responds with a 404 if the gallery isn't stored, or a 409 if it's still in the pipeline or has no failed analysis.
+async fn retry_gallery_analysis(
+    Path(gallery_id): Path<String>,
+    mut state_tracker_sender: StateTrackerSender,
+    mut storage_sender: StorageSender,
+    mut item_analysis_sender: ItemAnalysisSender
+) -> Result<(StatusCode, Json<RetryAnalysisResponse>), ApiError> {
+    let gallery_id = GalleryId::from(gallery_id);
+
+    match state_tracker_sender.check_gallery_doesnt_exist(gallery_id.clone()).await {
+        Ok(Ok(_)) => (),
+        Ok(Err(StateTrackerError::GalleryAlreadyExists)) => return Err(ApiError::Conflict(format!("Gallery {gallery_id} is still in the pipeline"))),
+        Ok(Err(err)) => return Err(ApiError::Internal(format!("State tracker failed to check gallery: {err}"))),
+        Err(err) => return Err(ApiError::Internal(format!("Failed to message the state tracker: {err}")))
+    }
+
+    let (msg, receiver) = GetGalleryForAnalysisRetryMessage::new(gallery_id.clone());
+    storage_sender
+        .send(StorageMessage::GetGalleryForAnalysisRetry(msg))
+        .await
+        .map_err(|err| ApiError::Internal(format!("Failed to message storage: {err}")))?;
+    let gallery = match receiver.await {
+        Ok(Ok(gallery)) => gallery,
+        Ok(Err(err @ StorageError::GalleryNotFound { .. })) => return Err(ApiError::NotFound(err.to_string())),
+        Ok(Err(err @ StorageError::NoFailedAnalysis { .. })) => return Err(ApiError::Conflict(err.to_string())),
+        Ok(Err(err)) => return Err(ApiError::Internal(format!("Storage failed to get gallery: {err}"))),
+        Err(err) => return Err(ApiError::Internal(format!("Failed to receive a response from storage: {err}")))
+    };
+
+    let retried_marketplaces: Vec<_> = gallery.items.keys().cloned().collect();
+    item_analysis_sender
+        .send(ItemAnalysisMessage::AnalyzeGalleryNew { gallery })
+        .await
+        .map_err(|err| ApiError::Internal(format!("Failed to message item analysis: {err}")))?;
+    tracing::info!("Retrying analysis of gallery {gallery_id} for marketplaces {retried_marketplaces:?}");
+    Ok((StatusCode::ACCEPTED, Json(RetryAnalysisResponse { retried_marketplaces })))
+}
+
+/// Analyze a sample item against proposed evaluation criteria, returning the model's verdict; useful for tuning prompts.
+/// 
+/// The criteria are filled from the default criteria (if any) and validated as on gallery creation.
+/// Nothing is looked up or changed in the pipeline or storage, so the gallery doesn't need to exist yet.
+/// 
+/// Responds with a 400 if the criteria are invalid, or a 500 if the item couldn't be analyzed.
+async fn preview_gallery_criteria(
+    Path(gallery_id): Path<String>,
+    Json(mut request): Json<CriteriaPreviewRequest>,
+    allowed_models: Arc<Vec<String>>,
+    default_criteria: Option<Arc<EvaluationCriteria>>,
+    mut item_analysis_sender: ItemAnalysisSender
+) -> Result<Json<CriteriaPreview>, ApiError> {
+    if let Some(default_criteria) = default_criteria.as_deref() {
+        request.evaluation_criteria.apply_defaults(default_criteria);
+    }
+    request.evaluation_criteria
+        .validate()
+        .and_then(|_| request.evaluation_criteria.validate_model_override(&allowed_models))
+        .map_err(|err| ApiError::BadRequest(format!("Invalid evaluation criteria: {err}")))?;
+
+    tracing::debug!("Previewing evaluation criteria for gallery {gallery_id} on item {}", request.item.id);
+    let (msg, receiver) = PreviewCriteriaMessage::new(request);
+    item_analysis_sender
+        .send(ItemAnalysisMessage::PreviewCriteria(msg))
+        .await
+        .map_err(|err| ApiError::Internal(format!("Failed to message item analysis: {err}")))?;
+    match receiver.await {
+        Ok(Ok(preview)) => Ok(Json(preview)),
+        Ok(Err(err)) => Err(ApiError::Internal(format!("Failed to analyze the sample item: {err}"))),
+        Err(err) => Err(ApiError::Internal(format!("Failed to receive a response from item analysis: {err}")))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        config::{item_analysis::{AnalysisParseStrictness, AnalysisProviderKind}, scraper_scheduler::ConcurrentScrapePolicy, LogLevel}, 
+        messages::message_buses::message_bus, 
+        scraping_pipeline::{module_health::ModuleHealth, pipeline_metrics::PipelineMetrics}
+    };
+    use super::*;
+
+    /// The body limit of the creation routes in these tests.
+    const BODY_LIMIT: usize = 1024;
+
+    fn axum_config() -> AxumConfig {
+        AxumConfig {
+            host_addr: String::new(),
+            shutdown_timeout_secs: 0,
+            idempotency_key_ttl_secs: 60,
+            idempotency_cache_capacity: 1,
+            request_log_success_level: LogLevel::Info,
+            request_log_error_level: LogLevel::Warn,
+            api_tokens: vec![],
+            auth_disabled: true,
+            unauthenticated_paths: vec![],
+            max_create_gallery_body_bytes: BODY_LIMIT
+        }
+    }
+
+    fn scheduler_config() -> ScraperSchedulerConfig {
+        ScraperSchedulerConfig {
+            min_scrape_interval_secs: 0,
+            jitter_window_secs: 0,
+            rescrape_on_criteria_change: false,
+            concurrent_scrape_policy: ConcurrentScrapePolicy::Reject,
+            scrape_queue_poll_interval_secs: 0,
+            dedup_window_secs: 0,
+            last_fired_path: String::new(),
+            sync_interval_secs: 0,
+            max_in_flight_galleries: 0
+        }
+    }
+
+    fn analysis_config() -> ItemAnalysisConfig {
+        ItemAnalysisConfig {
+            provider: AnalysisProviderKind::Anthropic,
+            fallback_providers: vec![],
+            anthropic_api_endpoint: String::new(),
+            anthropic_api_key: String::new(),
+            anthropic_model: String::new(),
+            anthropic_version: String::new(),
+            anthropic_timeout_secs: 0,
+            openai_api_endpoint: String::new(),
+            openai_api_key: String::new(),
+            openai_model: String::new(),
+            openai_timeout_secs: 0,
+            gemini_api_endpoint: String::new(),
+            gemini_api_key: String::new(),
+            gemini_model: String::new(),
+            gemini_timeout_secs: 0,
+            max_concurrent_galleries: 1,
+            model_prices: HashMap::new(),
+            structured_output: false,
+            parse_strictness: AnalysisParseStrictness::Strict,
+            default_evaluation_criteria: None,
+            cache_ttl_secs: 0,
+            retry_queue_interval_secs: 0,
+            retry_queue_max_attempts: 0,
+            allowed_model_overrides: vec![]
+        }
+    }
+
+    fn module_connections() -> AppModuleConnections {
+        let (state_tracker_sender, state_tracker_receiver, _) = message_bus(1);
+        let (scheduler_sender, scheduler_receiver, _) = message_bus(1);
+        let (search_scraper_sender, search_scraper_receiver, _) = message_bus(1);
+        let (item_scraper_sender, item_scraper_receiver, _) = message_bus(1);
+        let (item_analysis_sender, item_analysis_receiver, _) = message_bus(1);
+        let (item_embedder_sender, item_embedder_receiver, _) = message_bus(1);
+        let (storage_sender, storage_receiver, _) = message_bus(1);
+        AppModuleConnections {
+            state_tracker: (StateTrackerSender::new(state_tracker_sender), state_tracker_receiver),
+            scraper_scheduler: (scheduler_sender, scheduler_receiver),
+            search_scraper: (search_scraper_sender, search_scraper_receiver),
+            item_scraper: (item_scraper_sender, item_scraper_receiver),
+            item_analysis: (item_analysis_sender, item_analysis_receiver),
+            image_classifier: (item_embedder_sender, item_embedder_receiver),
+            storage: (storage_sender, storage_receiver),
+            module_health: Arc::new(ModuleHealth::new()),
+            pipeline_metrics: Arc::new(PipelineMetrics::new()),
+            bus_metrics: Arc::new(vec![])
+        }
+    }
+
+    /// Serve the galleries router on a local port, returning its address.
+    async fn serve(module_connections: &AppModuleConnections) -> String {
+        let router = build(&axum_config(), &scheduler_config(), &analysis_config(), module_connections);
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0")
+            .await
+            .expect("Should be able to bind a local port");
+        let addr = listener
+            .local_addr()
+            .expect("The listener should have an address");
+        tokio::spawn(async move { axum::serve(listener, router).await });
+        format!("http://{addr}")
+    }
+
+    #[tokio::test]
+    async fn an_over_limit_creation_body_is_rejected_before_being_deserialized() {
+        let module_connections = module_connections();
+        let addr = serve(&module_connections).await;
+        let client = reqwest::Client::new();
+        let over_limit_body = format!("{{\"padding\": \"{}\"}}", "a".repeat(BODY_LIMIT));
+        for path in ["/", "/batch"] {
+            let response = client
+                .post(format!("{addr}{path}"))
+                .header("Content-Type", "application/json")
+                .body(over_limit_body.clone())
+                .send()
+                .await
+                .expect("The request should be answered");
+            assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE, "{path}");
+        }
+    }
+
+    #[tokio::test]
+    async fn a_within_limit_creation_body_is_deserialized() {
+        let module_connections = module_connections();
+        let addr = serve(&module_connections).await;
+        let response = reqwest::Client::new()
+            .post(format!("{addr}/"))
+            .header("Content-Type", "application/json")
+            .body("{}")
+            .send()
+            .await
+            .expect("The request should be answered");
+        assert_eq!(response.status(), StatusCode::UNPROCESSABLE_ENTITY);
+    }
+
+    #[tokio::test]
+    async fn an_empty_query_embedding_is_rejected_without_messaging_storage() {
+        let module_connections = module_connections();
+        let addr = serve(&module_connections).await;
+        let response = reqwest::Client::new()
+            .post(format!("{addr}/gallery/similar"))
+            .json(&serde_json::json!({ "query_embedding": [] }))
+            .send()
+            .await
+            .expect("The request should be answered");
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+}
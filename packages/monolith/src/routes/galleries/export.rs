@@ -0,0 +1,241 @@
+use std::{borrow::Cow, collections::VecDeque};
+use axum::{body::Body, extract::{Path, Query}, http::header, response::{IntoResponse, Response}};
+use serde::{Deserialize, Serialize};
+use crate::{galleries::{domain_types::{GalleryId, ItemId, Marketplace}, items::pipeline_items::EmbeddedMarketplaceItem}, messages::{message_types::storage::{GetItemsPaginatedMessage, ItemsPage, ItemsPageRequest, StorageError, StorageMessage}, StorageSender}, routes::api_error::ApiError};
+
+/// The number of items fetched from storage at a time while exporting.
+const EXPORT_PAGE_SIZE: usize = 200;
+
+/// The formats a gallery's items can be exported in.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+enum ExportFormat {
+    #[default]
+    Csv,
+    Json
+}
+
+impl ExportFormat {
+    /// The response's content type.
+    fn content_type(&self) -> &'static str {
+        match self {
+            ExportFormat::Csv => "text/csv; charset=utf-8",
+            ExportFormat::Json => "application/json"
+        }
+    }
+
+    /// The file extension of the exported file.
+    fn extension(&self) -> &'static str {
+        match self {
+            ExportFormat::Csv => "csv",
+            ExportFormat::Json => "json"
+        }
+    }
+
+    /// Written before any items.
+    fn header(&self) -> &'static str {
+        match self {
+            ExportFormat::Csv => "marketplace,item_id,title,normalized_price,analysis_summary\n",
+            ExportFormat::Json => "["
+        }
+    }
+
+    /// Written after all items.
+    fn footer(&self) -> &'static str {
+        match self {
+            ExportFormat::Csv => "",
+            ExportFormat::Json => "]"
+        }
+    }
+}
+
+/// The query parameters for exporting a gallery's items.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub(super) struct ExportItemsParams {
+    /// Defaults to CSV.
+    #[serde(default)]
+    format: ExportFormat
+}
+
+/// A gallery's (embedded) item, as exported.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct ExportedItem {
+    marketplace: Marketplace,
+    item_id: ItemId,
+    title: String,
+    /// Not set if no exchange rate was available for the item's currency.
+    normalized_price: Option<f32>,
+    /// The item's description from analysis.
+    analysis_summary: String
+}
+
+impl ExportedItem {
+    fn new(marketplace: &Marketplace, item: EmbeddedMarketplaceItem) -> Self {
+        Self {
+            marketplace: marketplace.clone(),
+            item_id: item.item.id,
+            title: item.item.name,
+            normalized_price: item.item.normalized_price,
+            analysis_summary: item.item_description
+        }
+    }
+
+    /// Formats the item as a CSV row, including its trailing newline.
+    fn to_csv_row(&self) -> String {
+        let fields = [
+            self.marketplace.to_string(),
+            self.item_id.to_string(),
+            self.title.clone(),
+            self.normalized_price
+                .map(|price| price.to_string())
+                .unwrap_or_default(),
+            self.analysis_summary.clone()
+        ];
+        let row: Vec<_> = fields
+            .iter()
+            .map(|field| csv_field(field))
+            .collect();
+        format!("{}\n", row.join(","))
+    }
+}
+
+/// Quotes a CSV field if it contains a comma, quote or newline, doubling any quotes inside it.
+fn csv_field(field: &str) -> Cow<'_, str> {
+    match field.contains([',', '"', '\n', '\r']) {
+        true => Cow::Owned(format!("\"{}\"", field.replace('"', "\"\""))),
+        false => Cow::Borrowed(field)
+    }
+}
+
+/// A stored gallery's items export, which is streamed a page of items at a time,
+/// going through each marketplace in turn.
+struct ItemsExport {
+    gallery_id: GalleryId,
+    format: ExportFormat,
+    storage_sender: StorageSender,
+    /// The marketplaces left to export; the front is the one currently being exported.
+    marketplaces: VecDeque<Marketplace>,
+    /// The offset of the next page under the current marketplace.
+    offset: usize,
+    num_exported: usize,
+    started: bool,
+    finished: bool
+}
+
+impl ItemsExport {
+    fn new(gallery_id: GalleryId, format: ExportFormat, storage_sender: StorageSender) -> Self {
+        Self {
+            gallery_id,
+            format,
+            storage_sender,
+            marketplaces: Marketplace::all().into(),
+            offset: 0,
+            num_exported: 0,
+            started: false,
+            finished: false
+        }
+    }
+
+    /// Returns the next chunk of the export, or `None` once it's finished.
+    /// 
+    /// If a page can't be fetched, the error is returned and the export finishes there.
+    async fn next_chunk(&mut self) -> Option<Result<String, String>> {
+        if self.finished {
+            return None;
+        }
+        if !self.started {
+            self.started = true;
+            return Some(Ok(self.format.header().to_string()));
+        }
+        loop {
+            let Some(marketplace) = self.marketplaces.front().cloned() else {
+                self.finished = true;
+                tracing::info!("Exported {} items for gallery {}", self.num_exported, self.gallery_id);
+                return Some(Ok(self.format.footer().to_string()));
+            };
+            let page = match self.fetch_page(marketplace.clone(), self.offset, EXPORT_PAGE_SIZE).await {
+                Ok(page) => page,
+                Err(err) => {
+                    self.finished = true;
+                    tracing::warn!("Failed to export items for gallery {}: {err}", self.gallery_id);
+                    return Some(Err(err.to_string()));
+                }
+            };
+            self.offset += page.items.len();
+            if page.items.is_empty() || self.offset >= page.total {
+                self.marketplaces.pop_front();
+                self.offset = 0;
+            }
+            if page.items.is_empty() {
+                continue;
+            }
+            let mut chunk = String::new();
+            for item in page.items {
+                let item = ExportedItem::new(&marketplace, item);
+                match self.format {
+                    ExportFormat::Csv => chunk.push_str(&item.to_csv_row()),
+                    ExportFormat::Json => {
+                        if self.num_exported > 0 {
+                            chunk.push(',');
+                        }
+                        match serde_json::to_string(&item) {
+                            Ok(json) => chunk.push_str(&json),
+                            Err(err) => tracing::warn!("Failed to serialize item {} for export; skipping it: {err}", item.item_id)
+                        }
+                    }
+                }
+                self.num_exported += 1;
+            }
+            return Some(Ok(chunk));
+        }
+    }
+
+    /// Fetch a page of the gallery's items under a marketplace from storage.
+    async fn fetch_page(&mut self, marketplace: Marketplace, offset: usize, limit: usize) -> Result<ItemsPage, ApiError> {
+        let (msg, receiver) = GetItemsPaginatedMessage::new(ItemsPageRequest {
+            gallery_id: self.gallery_id.clone(),
+            marketplace,
+            offset,
+            limit,
+            min_confidence: None,
+            sort_by_confidence: false
+        });
+        self.storage_sender
+            .send(StorageMessage::GetItemsPaginated(msg))
+            .await
+            .map_err(|err| ApiError::Internal(format!("Failed to message storage: {err}")))?;
+        match receiver.await {
+            Ok(Ok(page)) => Ok(page),
+            Ok(Err(StorageError::GalleryNotFound { .. })) => Err(ApiError::NotFound(format!("Gallery {} not found", self.gallery_id))),
+            Ok(Err(err)) => Err(ApiError::Internal(format!("Storage failed to get items: {err}"))),
+            Err(err) => Err(ApiError::Internal(format!("Failed to receive a response from storage: {err}")))
+        }
+    }
+}
+
+/// Export a stored gallery's (embedded) items as CSV or JSON, streamed a page at a time so large galleries aren't buffered in memory.
+/// 
+/// Responds with a 404 if the gallery isn't stored. As the response is streamed, a failure partway through truncates it.
+pub(super) async fn export_gallery_items(
+    Path(gallery_id): Path<String>,
+    Query(params): Query<ExportItemsParams>,
+    storage_sender: StorageSender
+) -> Result<Response, ApiError> {
+    let gallery_id = GalleryId::from(gallery_id);
+    let mut export = ItemsExport::new(gallery_id.clone(), params.format, storage_sender);
+    // Checked upfront, since the status can't be changed once streaming starts
+    if let Some(marketplace) = export.marketplaces.front().cloned() {
+        export.fetch_page(marketplace, 0, 0).await?;
+    }
+    let stream = futures::stream::unfold(export, |mut export| async move {
+        export
+            .next_chunk()
+            .await
+            .map(|chunk| (chunk, export))
+    });
+    let headers = [
+        (header::CONTENT_TYPE, params.format.content_type().to_string()),
+        (header::CONTENT_DISPOSITION, format!("attachment; filename=\"{gallery_id}.{}\"", params.format.extension()))
+    ];
+    Ok((headers, Body::from_stream(stream)).into_response())
+}
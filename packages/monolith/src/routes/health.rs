@@ -0,0 +1,28 @@
+use std::sync::Arc;
+use axum::{routing::get, Json, Router};
+use reqwest::StatusCode;
+use crate::{config::AxumConfig, scraping_pipeline::{module_health::{ModuleHealth, ModuleHealthReport}, AppModuleConnections}};
+
+/// Build the router for checking the app's health.
+pub(super) fn build(config: &AxumConfig, module_connections: &AppModuleConnections) -> Router {
+    let mut router = Router::new();
+
+    let module_health = module_connections.module_health.clone();
+    router = router.route("/health", get(
+        move || health(module_health)
+    ));
+
+    router
+}
+
+/// Reports whether each module is alive.
+/// 
+/// Responds with a 503 if any module is unhealthy.
+async fn health(module_health: Arc<ModuleHealth>) -> (StatusCode, Json<ModuleHealthReport>) {
+    let report = module_health.report();
+    let status = match report.all_healthy() {
+        true => StatusCode::OK,
+        false => StatusCode::SERVICE_UNAVAILABLE
+    };
+    (status, Json(report))
+}
@@ -0,0 +1,26 @@
+use std::sync::Arc;
+use axum::{http::header, response::IntoResponse, routing::get, Router};
+use crate::{config::AxumConfig, messages::message_buses::BusMetrics, scraping_pipeline::{pipeline_metrics::PipelineMetrics, AppModuleConnections}};
+
+/// Build the router for exposing pipeline metrics.
+pub(super) fn build(config: &AxumConfig, module_connections: &AppModuleConnections) -> Router {
+    let mut router = Router::new();
+
+    let pipeline_metrics = module_connections.pipeline_metrics.clone();
+    let bus_metrics = module_connections.bus_metrics.clone();
+    router = router.route("/metrics", get(
+        move || metrics(pipeline_metrics, bus_metrics)
+    ));
+
+    router
+}
+
+/// Reports the pipeline's throughput counters and message bus metrics in Prometheus text format.
+async fn metrics(pipeline_metrics: Arc<PipelineMetrics>, bus_metrics: Arc<Vec<Arc<BusMetrics>>>) -> impl IntoResponse {
+    let mut output = pipeline_metrics.render_prometheus();
+    output.push_str(&BusMetrics::render_prometheus(&bus_metrics));
+    (
+        [(header::CONTENT_TYPE, "text/plain; version=0.0.4")],
+        output
+    )
+}
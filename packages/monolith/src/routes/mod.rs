@@ -1,11 +1,35 @@
 mod search_scraper;
+mod health;
+mod galleries;
+mod metrics;
+mod request_logging;
+mod auth;
+mod api_error;
+mod admin;
 
-use axum::Router;
-use crate::{config::AxumConfig, scraping_pipeline::AppModuleConnections};
+use axum::{middleware, Router};
+use crate::{config::AppConfig, scraping_pipeline::AppModuleConnections};
 
-pub fn build_router(config: &AxumConfig, module_connections: &AppModuleConnections) -> Router {
-    let search_scraper_router = search_scraper::build(config, module_connections);
+pub fn build_router(config: &AppConfig, module_connections: &AppModuleConnections) -> Router {
+    let axum_config = &config.axum_config;
+    let search_scraper_router = search_scraper::build(axum_config, module_connections);
+    let health_router = health::build(axum_config, module_connections);
+    let galleries_router = galleries::build(axum_config, &config.scraper_scheduler_config, &config.item_analysis_config, module_connections);
+    let metrics_router = metrics::build(axum_config, module_connections);
+    let admin_router = admin::build(axum_config, module_connections);
 
     Router::new()
         .nest("/scraper", search_scraper_router)
+        .nest("/galleries", galleries_router)
+        .nest("/admin", admin_router)
+        .merge(health_router)
+        .merge(metrics_router)
+        .layer(middleware::from_fn_with_state(
+            auth::BearerAuth::new(axum_config),
+            auth::require_bearer_token
+        ))
+        .layer(middleware::from_fn_with_state(
+            request_logging::RequestLogLevels::new(axum_config),
+            request_logging::log_requests
+        ))
 }
\ No newline at end of file
@@ -0,0 +1,82 @@
+//! Contains the middleware for logging each HTTP request and its response.
+use std::time::Instant;
+use axum::{extract::{Request, State}, http::HeaderMap, middleware::Next, response::Response};
+use tracing::Instrument;
+use crate::config::{AxumConfig, LogLevel};
+
+/// Headers whose values are never logged.
+const SENSITIVE_HEADERS: [&str; 5] = ["authorization", "proxy-authorization", "cookie", "set-cookie", "x-api-key"];
+
+/// Fragments of header names which mark them as sensitive (ie custom API key headers).
+const SENSITIVE_HEADER_FRAGMENTS: [&str; 5] = ["api-key", "apikey", "token", "secret", "password"];
+
+/// The levels that requests are logged at, depending on their response's status.
+#[derive(Clone, Copy, Debug)]
+pub(super) struct RequestLogLevels {
+    success: LogLevel,
+    error: LogLevel
+}
+
+impl RequestLogLevels {
+    /// Get the levels from the config.
+    pub(super) fn new(config: &AxumConfig) -> Self {
+        Self {
+            success: config.request_log_success_level,
+            error: config.request_log_error_level
+        }
+    }
+}
+
+/// Logs the method, path, (redacted) headers, status and latency of each request, within a span for the request.
+/// 
+/// Responses with a 4xx/5xx status are logged at the error level; all others at the success level.
+pub(super) async fn log_requests(
+    State(levels): State<RequestLogLevels>,
+    request: Request,
+    next: Next
+) -> Response {
+    let method = request.method().clone();
+    let path = request.uri().path().to_string();
+    let headers = redact_headers(request.headers());
+    let span = tracing::info_span!("http_request", %method, %path);
+    let start = Instant::now();
+    let response = next
+        .run(request)
+        .instrument(span.clone())
+        .await;
+    let latency = start.elapsed();
+    let status = response.status();
+    let level = match status.is_client_error() || status.is_server_error() {
+        true => levels.error,
+        false => levels.success
+    };
+    let _entered = span.enter();
+    match level {
+        LogLevel::Trace => tracing::trace!(status = status.as_u16(), ?latency, ?headers, "Handled request"),
+        LogLevel::Debug => tracing::debug!(status = status.as_u16(), ?latency, ?headers, "Handled request"),
+        LogLevel::Info => tracing::info!(status = status.as_u16(), ?latency, ?headers, "Handled request"),
+        LogLevel::Warn => tracing::warn!(status = status.as_u16(), ?latency, ?headers, "Handled request"),
+        LogLevel::Error => tracing::error!(status = status.as_u16(), ?latency, ?headers, "Handled request")
+    }
+    response
+}
+
+/// Returns the headers as (name, value) pairs, with the values of sensitive headers redacted.
+fn redact_headers(headers: &HeaderMap) -> Vec<(String, String)> {
+    headers
+        .iter()
+        .map(|(name, value)| {
+            let name = name.as_str().to_lowercase();
+            let is_sensitive = SENSITIVE_HEADERS.contains(&name.as_str())
+                || SENSITIVE_HEADER_FRAGMENTS.iter().any(|fragment| name.contains(fragment));
+            let value = match is_sensitive {
+                true => "[REDACTED]".to_string(),
+                false => value
+                    .to_str()
+                    .unwrap_or("[non-UTF8]")
+                    .to_string()
+            };
+            (name, value)
+        })
+        .collect()
+}
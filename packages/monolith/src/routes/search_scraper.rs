@@ -1,6 +1,7 @@
 use axum::{routing::post, Json, Router};
 use reqwest::StatusCode;
 use crate::{config::AxumConfig, galleries::pipeline_states::GallerySearchScrapingState, messages::{message_types::search_scraper::SearchScraperMessage, SearchScraperSender}, scraping_pipeline::AppModuleConnections};
+use super::api_error::ApiError;
 
 /// Build the router for ingesting data from the scraper. 
 /// 
@@ -22,9 +23,10 @@ pub(super) fn build(config: &AxumConfig, module_connections: &AppModuleConnectio
 async fn start_scrape(
     Json(gallery): Json<GallerySearchScrapingState>,
     mut sender: SearchScraperSender
-) -> Result<StatusCode, (StatusCode, String)> {
-    tokio::spawn(async move {
-        sender.send(SearchScraperMessage::ScrapeSearchNew { gallery }).await.unwrap();
-    });
+) -> Result<StatusCode, ApiError> {
+    sender
+        .send(SearchScraperMessage::ScrapeSearchNew { gallery })
+        .await
+        .map_err(|err| ApiError::Internal(format!("Failed to message the search scraper: {err}")))?;
     Ok(StatusCode::OK)
 }
\ No newline at end of file
@@ -0,0 +1,121 @@
+//! Contains the compactor for galleries which stay in the `Final` state.
+use std::time::Duration;
+use crate::{config::state_tracker::StateTrackerConfig, galleries::{domain_types::{GalleryId, UnixUtcDateTime}, pipeline_states::{GalleryFinalState, GalleryPipelineStateTypes, GalleryPipelineStates}}, messages::{message_types::storage::{EnsureGalleryStoredMessage, StorageMessage}, StateTrackerSender, StorageSender}};
+
+/// Periodically removes galleries which have been in the `Final` state for longer than the retention period from the state tracker.
+///
+/// Galleries normally leave the state tracker once they're stored, but can be left behind (ie if storing failed).
+/// Each gallery is made sure to be stored before it's removed, so its results aren't lost.
+///
+/// This runs as its own task (rather than in the state tracker) since it needs to wait on the storage module,
+/// which itself waits on the state tracker.
+pub struct FinalStateCompactor {
+    state_tracker_sender: StateTrackerSender,
+    storage_sender: StorageSender,
+    retention: Duration,
+    sweep_interval: Duration
+}
+
+impl FinalStateCompactor {
+    /// Instantiate the compactor.
+    pub fn new(config: &StateTrackerConfig, state_tracker_sender: StateTrackerSender, storage_sender: StorageSender) -> Self {
+        Self {
+            state_tracker_sender,
+            storage_sender,
+            retention: Duration::from_secs(config.final_retention_secs),
+            sweep_interval: Duration::from_secs(config.compaction_interval_secs.max(1))
+        }
+    }
+
+    /// Sweep for stale `Final` galleries every `sweep_interval`.
+    pub async fn run(&mut self) {
+        tracing::info!("FinalStateCompactor is running...");
+        let mut interval = tokio::time::interval(self.sweep_interval);
+        loop {
+            interval.tick().await;
+            let num_compacted = self.sweep().await;
+            if num_compacted > 0 {
+                tracing::info!("Compacted {num_compacted} galleries in the Final state");
+            }
+        }
+    }
+
+    /// Store and remove all `Final` galleries which completed longer than the retention period ago, returning how many were removed.
+    ///
+    /// Galleries which are currently being processed (ie by the storage module) are skipped.
+    async fn sweep(&mut self) -> usize {
+        let galleries = match self.state_tracker_sender.list_galleries().await {
+            Ok(Ok(galleries)) => galleries,
+            Ok(Err(err)) => {
+                tracing::error!("Compactor failed to list galleries: {err}");
+                return 0;
+            },
+            Err(err) => {
+                tracing::error!("Compactor failed to message the state tracker: {err}");
+                return 0;
+            }
+        };
+        let cutoff = match chrono::Duration::from_std(self.retention) {
+            Ok(retention) => *UnixUtcDateTime::now() - retention,
+            Err(_) => return 0
+        };
+        let mut num_compacted = 0;
+        for (gallery_id, stage) in galleries {
+            if stage != GalleryPipelineStateTypes::Final {
+                continue;
+            }
+            let gallery = match self.state_tracker_sender.peek_gallery_state(gallery_id.clone(), GalleryPipelineStateTypes::Final).await {
+                Ok(Ok(GalleryPipelineStates::Final(gallery))) => gallery,
+                Ok(_) => continue,
+                Err(err) => {
+                    tracing::error!("Compactor failed to message the state tracker: {err}");
+                    return num_compacted;
+                }
+            };
+            if *gallery.completed_at > cutoff {
+                continue;
+            }
+            if self.compact_gallery(gallery_id, gallery).await {
+                num_compacted += 1;
+            }
+        }
+        num_compacted
+    }
+
+    /// Make sure the gallery is stored, then remove it from the state tracker.
+    ///
+    /// Returns whether it was removed; it's left in the state tracker if it couldn't be stored,
+    /// or if it left the `Final` state in the meantime (ie it was re-added for a new scrape).
+    async fn compact_gallery(&mut self, gallery_id: GalleryId, gallery: GalleryFinalState) -> bool {
+        let (msg, receiver) = EnsureGalleryStoredMessage::new(gallery);
+        if let Err(err) = self.storage_sender.send(StorageMessage::EnsureGalleryStored(msg)).await {
+            tracing::error!("Compactor failed to message storage for gallery {gallery_id}: {err}");
+            return false;
+        }
+        match receiver.await {
+            Ok(Ok(_)) => (),
+            Ok(Err(err)) => {
+                tracing::error!("Storage failed to store gallery {gallery_id} before compaction; keeping it: {err}");
+                return false;
+            },
+            Err(err) => {
+                tracing::error!("Compactor failed to receive a response from storage for gallery {gallery_id}: {err}");
+                return false;
+            }
+        }
+        match self.state_tracker_sender.remove_gallery_if_state(gallery_id.clone(), GalleryPipelineStateTypes::Final).await {
+            Ok(Ok(_)) => {
+                tracing::debug!("Compacted gallery {gallery_id} out of the state tracker");
+                true
+            },
+            Ok(Err(err)) => {
+                tracing::error!("State tracker failed to remove gallery {gallery_id} during compaction: {err}");
+                false
+            },
+            Err(err) => {
+                tracing::error!("Compactor failed to message the state tracker: {err}");
+                false
+            }
+        }
+    }
+}
@@ -1,5 +1,6 @@
 //! API-specific types are derived from the docs: https://docs.anthropic.com/en/api/messages
 use serde::{Deserialize, Serialize};
+use serde_json::Value;
 
 /// The request form for querying Anthropic API.
 /// 
@@ -10,7 +11,28 @@ pub struct AnthropicRequestForm {
     pub model: String,
     pub max_tokens: usize,
     pub messages: Vec<AnthropicMessage>,
-    pub system: String
+    pub system: String,
+    /// Only set when using structured output, in which case the model is forced to call the single tool.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tools: Option<Vec<AnthropicTool>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tool_choice: Option<AnthropicToolChoice>
+}
+
+/// A tool the model can call, whose input must follow `input_schema`: https://docs.anthropic.com/en/docs/build-with-claude/tool-use
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct AnthropicTool {
+    pub name: String,
+    pub description: String,
+    pub input_schema: Value
+}
+
+/// Which tool the model must call.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct AnthropicToolChoice {
+    #[serde(rename = "type")] // API expects `type` but it's a keyword
+    pub choice_type: String,
+    pub name: String
 }
 
 /// A single message in the content of a Anthropic API request.
@@ -28,7 +50,10 @@ pub struct AnthropicMessageContent {
     #[serde(skip_serializing_if = "Option::is_none")] 
     pub text: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
-    pub source: Option<AnthropicImageMessageContent>
+    pub source: Option<AnthropicImageMessageContent>,
+    /// The input of a `tool_use` block in a response.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub input: Option<Value>
 }   
 
 /// Used to send image blocks in an Anthropic API message.
@@ -63,5 +88,8 @@ pub struct AnthropicUsage {
 pub struct EvaluationAnswers {
     pub answers: Vec<String>,
     pub item_description: String,
-    pub best_fit_image: usize
+    pub best_fit_image: usize,
+    /// The model's self-reported confidence in its answers, from 0 to 1; not all models reliably return one.
+    #[serde(default)]
+    pub confidence: Option<f32>
 }
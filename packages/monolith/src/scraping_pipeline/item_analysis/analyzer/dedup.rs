@@ -0,0 +1,144 @@
+//! Contains the deduplication of items listed on multiple marketplaces.
+use std::collections::{HashMap, VecDeque};
+use crate::galleries::{domain_types::{ItemId, Marketplace}, items::{item_data::MarketplaceItemData, pipeline_items::MarketplaceAnalyzedItems}};
+
+/// The marketplaces each merged item was listed on, keyed by the marketplace and ID of the item that was kept.
+pub(super) type SourceMarketplaces = HashMap<(Marketplace, ItemId), Vec<Marketplace>>;
+
+/// Merges items sharing a canonical key across marketplaces into a single item.
+///
+/// The cheapest listing is kept under its own marketplace, with the union of all listings' images;
+/// the rest are dropped. Items are only merged across marketplaces, never within one: if a marketplace has several items
+/// sharing a key, each is merged with (at most) one item from every other marketplace, and any left over are kept as-is.
+///
+/// Returns the deduplicated items, along with the source marketplaces of every merged item.
+pub(super) fn dedup_across_marketplaces(
+    items: HashMap<Marketplace, Vec<MarketplaceItemData>>
+) -> (HashMap<Marketplace, Vec<MarketplaceItemData>>, SourceMarketplaces) {
+    let mut groups: HashMap<String, Vec<(Marketplace, MarketplaceItemData)>> = HashMap::new();
+    let mut deduped_items: HashMap<Marketplace, Vec<MarketplaceItemData>> = HashMap::new();
+    for (marketplace, marketplace_items) in items {
+        deduped_items.entry(marketplace.clone()).or_default();
+        for item in marketplace_items {
+            groups
+                .entry(item.canonical_key())
+                .or_default()
+                .push((marketplace.clone(), item));
+        }
+    }
+
+    let mut source_marketplaces = HashMap::new();
+    for (_, group) in groups {
+        let mut listings_by_marketplace: Vec<(Marketplace, VecDeque<MarketplaceItemData>)> = vec![];
+        for (marketplace, item) in group {
+            match listings_by_marketplace.iter_mut().find(|(listed_marketplace, _)| *listed_marketplace == marketplace) {
+                Some((_, listings)) => listings.push_back(item),
+                None => listings_by_marketplace.push((marketplace, VecDeque::from([item])))
+            }
+        }
+        // Each round takes the next listing from every marketplace with one left, so no two are from the same marketplace
+        loop {
+            let listings: Vec<_> = listings_by_marketplace
+                .iter_mut()
+                .filter_map(|(marketplace, listings)| Some((marketplace.clone(), listings.pop_front()?)))
+                .collect();
+            match listings.len() {
+                0 => break,
+                1 => for (marketplace, item) in listings {
+                    deduped_items.entry(marketplace).or_default().push(item);
+                },
+                _ => {
+                    let marketplaces = listings
+                        .iter()
+                        .map(|(marketplace, _)| marketplace.clone())
+                        .collect();
+                    let (marketplace, merged_item) = merge_listings(listings);
+                    tracing::trace!("Merged item {} listed on {marketplaces:?}", merged_item.id);
+                    source_marketplaces.insert((marketplace.clone(), merged_item.id.clone()), marketplaces);
+                    deduped_items.entry(marketplace).or_default().push(merged_item);
+                }
+            }
+        }
+    }
+    (deduped_items, source_marketplaces)
+}
+
+/// Merges listings of the same item into the cheapest one, with the union of all of their images,
+/// returning it along with its marketplace.
+/// 
+/// Panics if there are no listings.
+fn merge_listings(listings: Vec<(Marketplace, MarketplaceItemData)>) -> (Marketplace, MarketplaceItemData) {
+    let mut thumbnails: Vec<String> = vec![];
+    for (_, item) in &listings {
+        for thumbnail in &item.thumbnails {
+            if !thumbnails.contains(thumbnail) {
+                thumbnails.push(thumbnail.clone());
+            }
+        }
+    }
+    let (marketplace, mut cheapest_item) = listings
+        .into_iter()
+        .min_by(|(_, a), (_, b)| a.comparable_price().total_cmp(&b.comparable_price()))
+        .expect("There should be at least 1 listing to merge");
+    cheapest_item.thumbnails = thumbnails;
+    (marketplace, cheapest_item)
+}
+
+/// Records the source marketplaces of merged items on their analyzed counterparts.
+pub(super) fn apply_source_marketplaces(
+    marketplace: &Marketplace,
+    analyzed_items: &mut MarketplaceAnalyzedItems,
+    source_marketplaces: &SourceMarketplaces
+) {
+    let all_items = analyzed_items.relevant_items
+        .iter_mut()
+        .chain(analyzed_items.irrelevant_items.iter_mut());
+    for analyzed_item in all_items {
+        if let Some(marketplaces) = source_marketplaces.get(&(marketplace.clone(), analyzed_item.item.id.clone())) {
+            analyzed_item.source_marketplaces = marketplaces.clone();
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use crate::test_support::item_data;
+    use super::*;
+
+    fn listing(id: &str, price: f32, thumbnail: &str) -> MarketplaceItemData {
+        let mut item = item_data(id, price, 100);
+        item.name = "Vintage Camera".into();
+        item.thumbnails = vec![thumbnail.into()];
+        item
+    }
+
+    #[test]
+    fn keeps_every_item_sharing_a_key_within_a_marketplace() {
+        let items = HashMap::from([(
+            Marketplace::Mercari,
+            vec![listing("a", 1000.0, "a.jpg"), listing("b", 1001.0, "b.jpg")]
+        )]);
+        assert_eq!(items[&Marketplace::Mercari][0].canonical_key(), items[&Marketplace::Mercari][1].canonical_key());
+        let (deduped_items, source_marketplaces) = dedup_across_marketplaces(items);
+        let mut ids: Vec<_> = deduped_items[&Marketplace::Mercari]
+            .iter()
+            .map(|item| item.id.to_string())
+            .collect();
+        ids.sort();
+        assert_eq!(ids, vec!["a", "b"]);
+        assert!(source_marketplaces.is_empty());
+    }
+
+    #[test]
+    fn merging_keeps_the_cheapest_listing_with_every_image() {
+        let listings = vec![
+            (Marketplace::Mercari, listing("expensive", 1200.0, "a.jpg")),
+            (Marketplace::Mercari, listing("cheap", 1000.0, "b.jpg"))
+        ];
+        let (marketplace, merged_item) = merge_listings(listings);
+        assert_eq!(marketplace, Marketplace::Mercari);
+        assert_eq!(merged_item.id.to_string(), "cheap");
+        assert_eq!(merged_item.thumbnails, vec!["a.jpg", "b.jpg"]);
+    }
+}
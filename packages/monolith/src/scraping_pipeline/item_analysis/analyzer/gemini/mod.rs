@@ -0,0 +1,249 @@
+use async_trait::async_trait;
+use futures::future::join_all;
+use reqwest::{Client, RequestBuilder, StatusCode};
+use types::{GeminiContent, GeminiGenerationConfig, GeminiInlineData, GeminiPart, GeminiRequestForm, GeminiResponse};
+use crate::{config::ItemAnalysisConfig, galleries::{domain_types::TokenUsage, eval_criteria::EvaluationCriteria, items::{item_data::MarketplaceItemData, pipeline_items::{ErrorAnalyzedMarketplaceItem, MarketplaceAnalyzedItems}}}};
+use super::{build_system_prompt, check_marketplace_results, collect_item_outcomes, is_unavailable_status, fetch_item_images, parse_item_answers, AnalysisError, AnalysisProvider, ItemOutcome, UsageTally};
+
+mod types;
+
+pub(super) struct GeminiRequester {
+    config: ItemAnalysisConfig,
+    request_client: Client
+}
+
+#[async_trait]
+impl AnalysisProvider for GeminiRequester {
+    async fn analyze(
+        &self, 
+        items: &[MarketplaceItemData], 
+        eval_criteria: &EvaluationCriteria, 
+        model: &str, 
+        usage: &UsageTally
+    ) -> Result<MarketplaceAnalyzedItems, AnalysisError> {
+        let (item_requests, mut failed_image_items) = self
+            .build_requests(items, eval_criteria, model)
+            .await;
+        let (mut analyzed_items, num_unavailable) = self
+            .execute_and_handle_requests(eval_criteria, item_requests, usage)
+            .await;
+        analyzed_items.error_items.append(&mut failed_image_items);
+        check_marketplace_results(analyzed_items, num_unavailable)
+    }
+}
+
+impl GeminiRequester {
+    /// Instantiate the requester.
+    pub fn new(config: ItemAnalysisConfig, request_client: Client) -> Self {
+        Self {
+            config,
+            request_client
+        }
+    }
+
+    /// Build the requests for a marketplace's items.
+    ///
+    /// Returns the requests, as well as items whose images could not be fetched (as `ErrorAnalyzedMarketplaceItem`).
+    async fn build_requests(
+        &self,
+        items: &[MarketplaceItemData],
+        eval_criteria: &EvaluationCriteria,
+        model: &str
+    ) -> (
+        Vec<(MarketplaceItemData, RequestBuilder)>,
+        Vec<ErrorAnalyzedMarketplaceItem>
+    ) {
+        let mut failed_image_items = Vec::new();
+        let item_requests = items
+                .iter()
+                .map(|item| async {
+                    let item_request = self
+                        .build_item_request(item, eval_criteria, model)
+                        .await;
+                    (item.clone(), item_request)
+                });
+        let item_requests = join_all(item_requests).await;
+        let item_requests = item_requests
+            .into_iter()
+            .filter_map(|(item, request)| match request {
+                Ok(req) => Some((item, req)),
+                Err(error) => {
+                    let err_item = ErrorAnalyzedMarketplaceItem { item, error };
+                    failed_image_items.push(err_item);
+                    None
+                }
+            })
+            .collect();
+        (item_requests, failed_image_items)
+    }
+
+    /// Executes and handles the requests for a marketplace's items, processing each item's response as soon as it arrives.
+    /// 
+    /// Returns the analyzed items, along with the number of items which failed because the provider was unavailable.
+    async fn execute_and_handle_requests(
+        &self,
+        eval_criteria: &EvaluationCriteria,
+        item_requests: Vec<(MarketplaceItemData, RequestBuilder)>,
+        usage: &UsageTally
+    ) -> (MarketplaceAnalyzedItems, usize) {
+        let outcomes = item_requests
+            .into_iter()
+            .map(|(item, request)| async {
+                let result = request.send().await;
+                self.process_item_result(eval_criteria, item, result, usage).await
+            });
+        collect_item_outcomes(join_all(outcomes).await)
+    }
+
+    /// Process the raw LLM output for an item, adding the tokens its response used to `usage`.
+    async fn process_item_result(
+        &self,
+        eval_criteria: &EvaluationCriteria,
+        item: MarketplaceItemData,
+        result: Result<reqwest::Response, reqwest::Error>,
+        usage: &UsageTally
+    ) -> ItemOutcome {
+        let mut unavailable = false;
+        let answers = match result {
+            Ok(res) => {
+                match res.status() {
+                    StatusCode::OK => {
+                        match res.json::<GeminiResponse>().await {
+                            Ok(response) => {
+                                tracing::trace!("Successful response: {response:#?}");
+                                if let Some(usage_metadata) = &response.usage_metadata {
+                                    usage.add(TokenUsage {
+                                        prompt_tokens: usage_metadata.prompt_token_count as u64,
+                                        completion_tokens: usage_metadata.candidates_token_count as u64
+                                    });
+                                }
+                                Self::extract_response_text(&response)
+                                    .and_then(|text| parse_item_answers(&item, &text, eval_criteria, self.config.parse_strictness))
+                            },
+                            Err(err) => Err(format!("Unable to parse Gemini response: {err:#?}"))
+                        }
+                    },
+                    other => {
+                        unavailable = is_unavailable_status(other);
+                        let res = res.text().await;
+                        Err(format!("Received unexpected status code ({other}) from Gemini API; response: {res:#?}"))
+                    }
+                }
+            },
+            Err(err) => {
+                unavailable = true;
+                Err(format!("Error while querying the Gemini API: {err}"))
+            }
+        };
+        match answers {
+            Ok((analyzed_item, relevant)) => ItemOutcome::Analyzed(analyzed_item, relevant),
+            Err(error) => {
+                tracing::warn!("Item {} had an error during item analysis: {}", item.id, error);
+                ItemOutcome::Failed(ErrorAnalyzedMarketplaceItem { item, error }, unavailable)
+            }
+        }
+    }
+
+    /// Extracts the text of the first candidate in a Gemini response, concatenating its text parts.
+    ///
+    /// Returns an `Err` if there are no candidates, or the candidate has no text.
+    fn extract_response_text(response: &GeminiResponse) -> Result<String, String> {
+        if response.candidates.len() > 1 {
+            tracing::warn!("Unexpectedly received >1 candidate in Gemini response; using the first...");
+        }
+        let candidate = response.candidates
+            .first()
+            .ok_or("Expected 1 candidate in Gemini response but found none".to_string())?;
+        let text: String = candidate.content
+            .as_ref()
+            .ok_or(format!("Gemini candidate contained no content (finish reason: {:?})", candidate.finish_reason))?
+            .parts
+            .iter()
+            .filter_map(|part| part.text.clone())
+            .collect();
+        match text.is_empty() {
+            true => Err("Gemini candidate content contained no text".into()),
+            false => Ok(text)
+        }
+    }
+
+    /// Builds the request for a single item.
+    /// Follows the request format specified here: https://ai.google.dev/api/generate-content
+    ///
+    /// Returns an `Err` if no images could be successfully fetched for the item.
+    async fn build_item_request(
+        &self,
+        item: &MarketplaceItemData,
+        eval_criteria: &EvaluationCriteria,
+        model: &str
+    ) -> Result<RequestBuilder, String> {
+        let item_image_strings = fetch_item_images(&self.request_client, &item.thumbnails).await;
+        if item_image_strings.is_empty() {
+            return Err("No images fetched; either all requests to fetch them failed, or errors occurred during parsing".to_string());
+        }
+        let req_form = self.build_request_form(item, item_image_strings, eval_criteria);
+        let req = self.request_client
+            .post(format!("{}/{}:generateContent", self.config.gemini_api_endpoint, model))
+            .header("x-goog-api-key", &self.config.gemini_api_key)
+            .json(&req_form);
+        Ok(req)
+    }
+
+    /// Builds the entire request form for an item.
+    fn build_request_form(
+        &self,
+        item: &MarketplaceItemData,
+        item_image_strings: Vec<String>,
+        eval_criteria: &EvaluationCriteria
+    ) -> GeminiRequestForm {
+        let system_prompt = build_system_prompt();
+        let item_string = serde_json::to_string_pretty(&item)
+            .expect("Serializing MarketplaceItemData should have no reason to fail");
+        let mut parts: Vec<GeminiPart> = item_image_strings
+            .into_iter()
+            .enumerate()
+            .flat_map(|(index, image_string)| {
+                vec![
+                    GeminiPart {
+                        text: Some(format!("Item image {}: ", index + 1)),
+                        inline_data: None
+                    },
+                    GeminiPart {
+                        text: None,
+                        inline_data: Some(GeminiInlineData {
+                            mime_type: "image/png".into(),
+                            data: image_string
+                        })
+                    }
+                ]
+            })
+            .collect();
+        parts.push(
+            GeminiPart {
+                text: Some(eval_criteria.render_prompt(&item_string)),
+                inline_data: None
+            }
+        );
+        GeminiRequestForm {
+            system_instruction: GeminiContent {
+                role: None,
+                parts: vec![
+                    GeminiPart {
+                        text: Some(system_prompt),
+                        inline_data: None
+                    }
+                ]
+            },
+            contents: vec![
+                GeminiContent {
+                    role: Some("user".into()),
+                    parts
+                }
+            ],
+            generation_config: GeminiGenerationConfig {
+                max_output_tokens: 1000, // TODO: Figure out a good number for this
+                response_mime_type: "application/json".into()
+            }
+        }
+    }
+}
\ No newline at end of file
@@ -0,0 +1,81 @@
+//! API-specific types are derived from the docs: https://ai.google.dev/api/generate-content
+//! 
+//! **NOTE**: Some of these structs don't fully describe the actual data shapes,
+//! leaving out data that we don't use. Check the docs for what they are
+//! if you're expecting/need any of it.
+use serde::{Deserialize, Serialize};
+
+/// The request form for querying the Gemini API.
+/// 
+/// **NOTE**: There are other optional parameters, but they're left out as we don't (currently) use them.
+/// Check the docs for what they are.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct GeminiRequestForm {
+    pub system_instruction: GeminiContent,
+    pub contents: Vec<GeminiContent>,
+    pub generation_config: GeminiGenerationConfig
+}
+
+/// A single message in a Gemini API request or response.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct GeminiContent {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub role: Option<String>,
+    pub parts: Vec<GeminiPart>
+}
+
+/// A part of a Gemini message; either text or inline data (ie an image).
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct GeminiPart {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub text: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub inline_data: Option<GeminiInlineData>
+}
+
+/// Used to send base64-encoded images in a Gemini API message.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct GeminiInlineData {
+    pub mime_type: String,
+    pub data: String
+}
+
+/// Config for the generation of a Gemini API response.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct GeminiGenerationConfig {
+    pub max_output_tokens: usize,
+    pub response_mime_type: String
+}
+
+/// The response received from a Gemini API request.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct GeminiResponse {
+    #[serde(default)]
+    pub candidates: Vec<GeminiCandidate>,
+    pub usage_metadata: Option<GeminiUsage>
+}
+
+/// A candidate response from the Gemini API.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct GeminiCandidate {
+    pub content: Option<GeminiContent>,
+    pub finish_reason: Option<String>
+}
+
+/// The usage data for this query.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct GeminiUsage {
+    #[serde(default)]
+    pub prompt_token_count: usize,
+    #[serde(default)]
+    pub candidates_token_count: usize,
+    #[serde(default)]
+    pub total_token_count: usize
+}
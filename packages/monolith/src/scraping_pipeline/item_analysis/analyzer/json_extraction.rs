@@ -0,0 +1,68 @@
+//! Contains the extraction of JSON objects from LLM responses with extra text around them.
+use serde_json::{Map, Value};
+
+/// Extract the first valid JSON object from an LLM response, ie one wrapped in prose or a markdown code fence.
+/// 
+/// The contents of the first code fence (if any) are searched first, falling back to the whole text.
+/// 
+/// Returns `None` if the text contains no valid JSON object.
+pub(super) fn extract_json_object(text: &str) -> Option<Value> {
+    fenced_block(text)
+        .and_then(first_json_object)
+        .or_else(|| first_json_object(text))
+        .map(Value::Object)
+}
+
+/// The contents of the first closed markdown code fence in the text (including any language tag).
+fn fenced_block(text: &str) -> Option<&str> {
+    let (_, after_opening_fence) = text.split_once("```")?;
+    let (contents, _) = after_opening_fence.split_once("```")?;
+    Some(contents)
+}
+
+/// The first JSON object in the text, parsed from the first `{` which starts a valid one; any text after it is ignored.
+fn first_json_object(text: &str) -> Option<Map<String, Value>> {
+    text.match_indices('{')
+        .find_map(|(start, _)| {
+            serde_json::Deserializer::from_str(&text[start..])
+                .into_iter::<Map<String, Value>>()
+                .next()?
+                .ok()
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+    use super::*;
+
+    #[test]
+    fn extracts_an_object_from_a_code_fence() {
+        let text = "Here are my answers:\n```json\n{\"answers\": [\"Yes\"]}\n```\nLet me know if you need anything else.";
+        assert_eq!(extract_json_object(text), Some(json!({"answers": ["Yes"]})));
+    }
+
+    #[test]
+    fn extracts_an_object_surrounded_by_prose() {
+        let text = "Sure! {\"answers\": [\"No\"], \"note\": \"a } in a string\"} Hope that helps.";
+        assert_eq!(extract_json_object(text), Some(json!({"answers": ["No"], "note": "a } in a string"})));
+    }
+
+    #[test]
+    fn skips_braces_which_dont_start_a_valid_object() {
+        let text = "Using {curly braces} as asked: {\"answers\": []}";
+        assert_eq!(extract_json_object(text), Some(json!({"answers": []})));
+    }
+
+    #[test]
+    fn falls_back_to_the_whole_text_if_the_code_fence_has_no_object() {
+        let text = "```\nno JSON here\n```\n{\"answers\": [\"Yes\"]}";
+        assert_eq!(extract_json_object(text), Some(json!({"answers": ["Yes"]})));
+    }
+
+    #[test]
+    fn returns_none_without_a_valid_object() {
+        assert_eq!(extract_json_object("I can't answer that."), None);
+        assert_eq!(extract_json_object("{\"answers\": [\"Yes\""), None);
+    }
+}
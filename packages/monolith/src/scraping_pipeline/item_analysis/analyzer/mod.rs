@@ -1,40 +1,780 @@
-use std::collections::HashMap;
+use std::{collections::HashMap, io::Cursor, sync::{Arc, Mutex}, time::Duration};
 
-use anthropic::AnthropicRequester;
+use anthropic::{types::EvaluationAnswers, AnthropicRequester};
+use async_trait::async_trait;
+use base64::{engine::general_purpose::STANDARD, Engine};
+use gemini::GeminiRequester;
+use image::ImageFormat;
 use openai::OpenAIRequester;
+use reqwest::{Client, StatusCode};
+use serde_json::{json, Value};
+use thiserror::Error;
 
-use crate::{config::ItemAnalysisConfig, galleries::{domain_types::Marketplace, eval_criteria::EvaluationCriteria, items::{item_data::MarketplaceItemData, pipeline_items::MarketplaceAnalyzedItems}}};
+use crate::{config::{item_analysis::{AnalysisParseStrictness, AnalysisProviderKind}, ItemAnalysisConfig}, galleries::{domain_types::{ItemId, Marketplace, ModelTokenUsage, TokenUsage}, eval_criteria::EvaluationCriteria, items::{item_data::MarketplaceItemData, pipeline_items::{AnalyzedMarketplaceItem, ErrorAnalyzedMarketplaceItem, MarketplaceAnalyzedItems}}}, messages::message_types::{item_analysis::CriteriaPreview, storage::CachedItemAnalysis}, utils::http_client::HttpClientFactory};
 
 mod anthropic;
 mod openai;
+mod gemini;
+mod dedup;
+mod json_extraction;
+mod sampling;
+
+/// The interface for an LLM backend which can analyze items.
+/// 
+/// Adding a new provider only requires implementing this, and selecting it in `ProviderEntry::new`.
+#[async_trait]
+pub(super) trait AnalysisProvider {
+    /// Analyze a marketplace's items against the evaluation criteria.
+    /// 
+    /// Returns an `Err` if the marketplace's items couldn't be analyzed as a whole.
+    /// The tokens used are added to `usage` as each response arrives, as failed (or timed out) analysis may still have used some.
+    async fn analyze(
+        &self, 
+        items: &[MarketplaceItemData], 
+        eval_criteria: &EvaluationCriteria, 
+        model: &str, 
+        usage: &UsageTally
+    ) -> Result<MarketplaceAnalyzedItems, AnalysisError>;
+}
+
+/// The running total of tokens used by a provider's requests.
+/// 
+/// This is shared with the provider rather than returned by it, so the tokens used by the responses which arrived
+/// are still known if the analysis times out.
+#[derive(Default)]
+pub(super) struct UsageTally(Mutex<TokenUsage>);
+
+impl UsageTally {
+    /// Add a response's usage to the total.
+    pub fn add(&self, usage: TokenUsage) {
+        *self.0.lock().expect("Usage tally lock should never be poisoned") += usage;
+    }
+
+    /// Get the total usage so far.
+    pub fn total(&self) -> TokenUsage {
+        *self.0.lock().expect("Usage tally lock should never be poisoned")
+    }
+}
+
+/// The outcome of a single item's analysis request.
+pub(super) enum ItemOutcome {
+    /// The item's analysis, and whether it's relevant.
+    Analyzed(AnalyzedMarketplaceItem, bool),
+    /// The item's error, and whether it's due to the provider being unavailable (see `is_unavailable_status`).
+    Failed(ErrorAnalyzedMarketplaceItem, bool)
+}
+
+/// Possible errors emitted from an analysis provider.
+#[derive(Error, Debug, Clone)]
+pub(super) enum AnalysisError {
+    #[error("All {num_items} items failed analysis (first error: {first_error})")]
+    AllItemsFailed { num_items: usize, first_error: String },
+    #[error("Analysis timed out after {timeout:?}")]
+    Timeout { timeout: Duration },
+    #[error("All {num_items} items failed analysis, {num_unavailable} due to the provider being unavailable (first error: {first_error})")]
+    ProviderUnavailable { num_items: usize, num_unavailable: usize, first_error: String }
+}
+
+impl AnalysisError {
+    /// Whether the error is due to the provider rather than the items, so another provider may succeed.
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            AnalysisError::AllItemsFailed { .. } => false,
+            AnalysisError::Timeout { .. } | AnalysisError::ProviderUnavailable { .. } => true
+        }
+    }
+}
+
+/// An analysis provider, along with the model and timeout it's used with.
+#[derive(Clone)]
+struct ProviderEntry {
+    kind: AnalysisProviderKind,
+    provider: Arc<dyn AnalysisProvider + Send + Sync>,
+    model: String,
+    timeout: Duration
+}
+
+impl ProviderEntry {
+    /// Initialize a provider, using its model and timeout from the config.
+    fn new(kind: AnalysisProviderKind, config: &ItemAnalysisConfig, http_clients: &HttpClientFactory) -> Self {
+        if config.structured_output && kind == AnalysisProviderKind::Gemini {
+            tracing::warn!("Structured output isn't supported for Gemini; falling back to parsing free-text responses");
+        }
+        let (provider, timeout_secs): (Arc<dyn AnalysisProvider + Send + Sync>, u64) = match kind {
+            AnalysisProviderKind::Anthropic => (Arc::new(AnthropicRequester::new(config.clone(), http_clients.client())), config.anthropic_timeout_secs),
+            AnalysisProviderKind::OpenAI => (Arc::new(OpenAIRequester::new(config.clone(), http_clients.client())), config.openai_timeout_secs),
+            AnalysisProviderKind::Gemini => (Arc::new(GeminiRequester::new(config.clone(), http_clients.client())), config.gemini_timeout_secs),
+        };
+        Self {
+            kind,
+            provider,
+            model: config.model_for(kind).to_string(),
+            timeout: Duration::from_secs(timeout_secs)
+        }
+    }
+}
 
 /// Orchestrates requesting of the LLM for a gallery's items.
+#[derive(Clone)]
 pub(super) struct Analyzer {
-    config: ItemAnalysisConfig,
-    anthropic_requester: AnthropicRequester,
-    openai_requester: OpenAIRequester
+    /// The primary provider, followed by the fallback providers in the order they're tried; never empty.
+    providers: Vec<ProviderEntry>,
+    /// Models which a gallery's evaluation criteria may use instead of the primary provider's model.
+    allowed_model_overrides: Arc<Vec<String>>
 }
 
 impl Analyzer {
-    /// Initialize the analyzer.
-    pub fn new(config: ItemAnalysisConfig) -> Self {
-        let anthropic_requester = AnthropicRequester::new(config.clone());
-        let openai_requester = OpenAIRequester::new(config.clone());
+    /// Initialize the analyzer, using the primary and fallback providers chosen in the config.
+    pub fn new(config: ItemAnalysisConfig, http_clients: &HttpClientFactory) -> Self {
+        let providers = std::iter::once(config.provider)
+            .chain(config.fallback_providers.iter().copied())
+            .map(|kind| ProviderEntry::new(kind, &config, http_clients))
+            .collect();
         Self { 
-            config,
-            anthropic_requester,
-            openai_requester
-        }   
+            providers,
+            allowed_model_overrides: Arc::new(config.allowed_model_overrides)
+        }
     }
 
-    /// Request analysis of a gallery's items, and sends the items to the next stage.
+    /// Request analysis of a gallery's items.
+    /// 
+    /// Items which don't pass the evaluation criteria's prefilter are dropped before being sent to the provider, unless they're pinned,
+    /// and items listed on multiple marketplaces are merged into one (see `dedup_across_marketplaces`).
+    /// 
+    /// If the evaluation criteria has a sample rate and `sample_seed` is set, only that fraction of each marketplace's uncached items
+    /// are sent to the provider (see `sample_items`); the rest are recorded as unsampled. Pinned items are always sent, on top of the sample.
+    /// Retries pass no seed, so aren't sampled again.
+    /// 
+    /// Items in `cached_analyses` aren't sent to the provider, and their cached analysis is used instead;
+    /// these are expected to already be checked as fresh.
+    /// 
+    /// If a provider is unavailable for a marketplace (see `AnalysisError::is_retryable`), the next provider is tried,
+    /// and is used for the rest of the gallery's marketplaces. The provider which analyzed each item is recorded on it.
+    /// 
+    /// Marketplaces which fail analysis as a whole (including by timing out on every provider) are left out, 
+    /// with their error recorded in `failed_marketplace_reasons`.
+    /// 
+    /// The primary provider uses the evaluation criteria's model override if it's set (see `model_for`), while fallback providers use their own model.
+    /// 
+    /// The tokens used are added to `token_usage` under the model used; for a timed out marketplace, that's the usage of the responses which arrived in time.
     pub async fn analyze_gallery(
-        &mut self, 
+        &mut self,
         items: HashMap<Marketplace, Vec<MarketplaceItemData>>,
-        eval_criteria: &EvaluationCriteria
+        eval_criteria: &EvaluationCriteria,
+        sample_seed: Option<u64>,
+        cached_analyses: &HashMap<ItemId, CachedItemAnalysis>,
+        failed_marketplace_reasons: &mut HashMap<Marketplace, String>,
+        token_usage: &mut ModelTokenUsage
     ) -> HashMap<Marketplace, MarketplaceAnalyzedItems> {
-        self.anthropic_requester
-            .analyze_gallery(items, eval_criteria)
+        let mut analyzed_items = HashMap::new();
+        let mut provider_index = 0;
+        let (items, source_marketplaces) = dedup::dedup_across_marketplaces(items);
+        for (marketplace, items) in items {
+            let num_items = items.len();
+            let items: Vec<_> = items
+                .into_iter()
+                .filter(|item| eval_criteria.is_pinned(&item.id) || eval_criteria.prefilter(item))
+                .collect();
+            tracing::debug!("Prefiltered out {}/{num_items} items for marketplace {marketplace}", num_items - items.len());
+            let (cached_items, items): (Vec<_>, Vec<_>) = items
+                .into_iter()
+                .partition(|item| cached_analyses.contains_key(&item.id));
+            if !cached_items.is_empty() {
+                tracing::debug!("Reusing cached analyses of {} items for marketplace {marketplace}", cached_items.len());
+            }
+            let (items, unsampled_items) = match (eval_criteria.sample_rate(), sample_seed) {
+                (Some(sample_rate), Some(seed)) => {
+                    let (mut pinned_items, items): (Vec<_>, Vec<_>) = items
+                        .into_iter()
+                        .partition(|item| eval_criteria.is_pinned(&item.id));
+                    let (mut sampled_items, unsampled_items) = sampling::sample_items(items, sample_rate, seed, &marketplace);
+                    sampled_items.append(&mut pinned_items);
+                    (sampled_items, unsampled_items)
+                },
+                _ => (items, vec![])
+            };
+            if !unsampled_items.is_empty() {
+                tracing::debug!("Left {} items out of the analysis sample for marketplace {marketplace}", unsampled_items.len());
+            }
+            let analysis_result = match items.is_empty() {
+                true => Ok(MarketplaceAnalyzedItems {
+                    relevant_items: vec![],
+                    irrelevant_items: vec![],
+                    error_items: vec![],
+                    unsampled_items: vec![]
+                }),
+                false => self.analyze_with_fallback(&items, eval_criteria, &mut provider_index, token_usage).await
+            };
+            match analysis_result {
+                Ok(mut marketplace_items) => {
+                    marketplace_items.unsampled_items = unsampled_items;
+                    add_cached_items(&mut marketplace_items, cached_items, cached_analyses);
+                    dedup::apply_source_marketplaces(&marketplace, &mut marketplace_items, &source_marketplaces);
+                    analyzed_items.insert(marketplace, marketplace_items);
+                },
+                Err(err) => {
+                    tracing::warn!("Item analysis failed for marketplace {marketplace}: {err}");
+                    failed_marketplace_reasons.insert(marketplace, format!("Item analysis failed: {err}"));
+                }
+            }
+        }
+        analyzed_items
+    }
+
+    /// Analyze a marketplace's items, starting from the provider at `provider_index` 
+    /// and falling through to the next provider on a retryable error.
+    /// 
+    /// `provider_index` is left at the last provider tried, and the successful provider's name is recorded on each analyzed item.
+    async fn analyze_with_fallback(
+        &self,
+        items: &[MarketplaceItemData],
+        eval_criteria: &EvaluationCriteria,
+        provider_index: &mut usize,
+        token_usage: &mut ModelTokenUsage
+    ) -> Result<MarketplaceAnalyzedItems, AnalysisError> {
+        loop {
+            let entry = &self.providers[*provider_index];
+            let model = self.model_for(*provider_index, eval_criteria);
+            let usage = UsageTally::default();
+            let analysis_result = tokio::time::timeout(entry.timeout, entry.provider.analyze(items, eval_criteria, model, &usage))
+                .await
+                .unwrap_or(Err(AnalysisError::Timeout { timeout: entry.timeout }));
+            token_usage.record(model, usage.total());
+            match analysis_result {
+                Ok(mut marketplace_items) => {
+                    for item in marketplace_items.relevant_items.iter_mut().chain(marketplace_items.irrelevant_items.iter_mut()) {
+                        item.analysis_provider = Some(entry.kind.name().to_string());
+                    }
+                    return Ok(marketplace_items);
+                },
+                Err(err) if err.is_retryable() && *provider_index + 1 < self.providers.len() => {
+                    let next = self.providers[*provider_index + 1].kind.name();
+                    tracing::warn!("Analysis provider {} failed ({err}); falling back to {next}", entry.kind.name());
+                    *provider_index += 1;
+                },
+                Err(err) => return Err(err)
+            }
+        }
+    }
+
+    /// Analyze a single sample item against the evaluation criteria with the primary provider, for previewing the criteria.
+    /// 
+    /// The item is analyzed even if it doesn't pass the prefilter (which is reported instead), and no fallback providers are tried.
+    /// 
+    /// Returns an `Err` if the item couldn't be analyzed.
+    pub async fn preview(&self, item: MarketplaceItemData, eval_criteria: &EvaluationCriteria) -> Result<CriteriaPreview, String> {
+        let entry = &self.providers[0];
+        let model = self.model_for(0, eval_criteria);
+        let passes_prefilter = eval_criteria.prefilter(&item);
+        let usage = UsageTally::default();
+        let analysis_result = tokio::time::timeout(entry.timeout, entry.provider.analyze(std::slice::from_ref(&item), eval_criteria, model, &usage))
             .await
+            .unwrap_or(Err(AnalysisError::Timeout { timeout: entry.timeout }));
+        let token_usage = usage.total();
+        let mut analyzed_items = analysis_result.map_err(|err| err.to_string())?;
+        let (relevant, analyzed_item) = match (analyzed_items.relevant_items.pop(), analyzed_items.irrelevant_items.pop()) {
+            (Some(item), _) => (true, item),
+            (None, Some(item)) => (false, item),
+            (None, None) => return Err("The provider returned no analysis for the item".into())
+        };
+        Ok(CriteriaPreview {
+            relevant,
+            passes_prefilter,
+            evaluation_answers: analyzed_item.evaluation_answers,
+            item_description: analyzed_item.item_description,
+            confidence: analyzed_item.confidence,
+            provider: entry.kind.name().to_string(),
+            model: model.to_string(),
+            token_usage
+        })
+    }
+
+    /// Returns the model to use for the provider at `provider_index`.
+    /// 
+    /// This is the evaluation criteria's model override for the primary provider, if it's set and allowed;
+    /// otherwise, it's the provider's configured model.
+    fn model_for<'a>(&'a self, provider_index: usize, eval_criteria: &'a EvaluationCriteria) -> &'a str {
+        let entry = &self.providers[provider_index];
+        match eval_criteria.model_override() {
+            Some(model) if provider_index == 0 && self.allowed_model_overrides.iter().any(|allowed| allowed == model) => model,
+            Some(model) if provider_index == 0 => {
+                tracing::warn!("Model override ({model}) isn't in the allowed models; using {} instead", entry.model);
+                &entry.model
+            },
+            _ => &entry.model
+        }
+    }
+}
+
+/// Adds items' cached analyses to a marketplace's analyzed items, using the items' current data.
+fn add_cached_items(
+    analyzed_items: &mut MarketplaceAnalyzedItems,
+    cached_items: Vec<MarketplaceItemData>,
+    cached_analyses: &HashMap<ItemId, CachedItemAnalysis>
+) {
+    for item in cached_items {
+        if let Some(cached_analysis) = cached_analyses.get(&item.id) {
+            let analyzed_item = AnalyzedMarketplaceItem {
+                item,
+                source_marketplaces: vec![],
+                ..cached_analysis.item.clone()
+            };
+            match cached_analysis.relevant {
+                true => analyzed_items.relevant_items.push(analyzed_item),
+                false => analyzed_items.irrelevant_items.push(analyzed_item)
+            }
+        }
+    }
+}
+
+/// Collects a marketplace's item outcomes into its analyzed items.
+/// 
+/// Returns the analyzed items, along with the number of items which failed because the provider was unavailable.
+fn collect_item_outcomes(outcomes: Vec<ItemOutcome>) -> (MarketplaceAnalyzedItems, usize) {
+    let mut analyzed_items = MarketplaceAnalyzedItems {
+        relevant_items: vec![],
+        irrelevant_items: vec![],
+        error_items: vec![],
+        unsampled_items: vec![]
+    };
+    let mut num_unavailable = 0;
+    for outcome in outcomes {
+        match outcome {
+            ItemOutcome::Analyzed(item, true) => analyzed_items.relevant_items.push(item),
+            ItemOutcome::Analyzed(item, false) => analyzed_items.irrelevant_items.push(item),
+            ItemOutcome::Failed(item, unavailable) => {
+                num_unavailable += unavailable as usize;
+                analyzed_items.error_items.push(item);
+            }
+        }
     }
-}
\ No newline at end of file
+    tracing::debug!(
+        "Item analysis results: {} relevant items, {} irrelevant items, and {} error items",
+        analyzed_items.relevant_items.len(),
+        analyzed_items.irrelevant_items.len(),
+        analyzed_items.error_items.len()
+    );
+    (analyzed_items, num_unavailable)
+}
+
+/// Returns an `Err` if every item in a (non-empty) marketplace failed analysis,
+/// as this usually indicates an issue with the provider itself (ie a bad API key).
+/// 
+/// If any of these failures were due to the provider being unavailable (`num_unavailable`), 
+/// the error is a (retryable) `ProviderUnavailable`.
+fn check_marketplace_results(analyzed_items: MarketplaceAnalyzedItems, num_unavailable: usize) -> Result<MarketplaceAnalyzedItems, AnalysisError> {
+    let num_items = analyzed_items.relevant_items.len() + analyzed_items.irrelevant_items.len() + analyzed_items.error_items.len();
+    match analyzed_items.error_items.first() {
+        Some(err_item) if num_items == analyzed_items.error_items.len() && num_unavailable > 0 => Err(
+            AnalysisError::ProviderUnavailable { 
+                num_items, 
+                num_unavailable,
+                first_error: err_item.error.clone() 
+            }
+        ),
+        Some(err_item) if num_items == analyzed_items.error_items.len() => Err(
+            AnalysisError::AllItemsFailed { 
+                num_items, 
+                first_error: err_item.error.clone() 
+            }
+        ),
+        _ => Ok(analyzed_items)
+    }
+}
+
+/// Builds the system prompt shared by all providers.
+/// 
+/// The questions and item listing are sent separately in each item's prompt (see `EvaluationCriteria::render_prompt`).
+fn build_system_prompt() -> String {
+    String::from("
+        You're an Item Listings Analysis AI.
+
+        You will help to evaluate an item listing, consisting of its listed images and a JSON of its information, by answering some structured questions about it.
+        Next to each question is the format that MUST be used when answering the question.
+
+        If the question is unanswerable, nonsensical, or not even a question, you are allowed to give a reasonable 'default' answer,
+        such as N for Y/N questions, U for Y/N/U questions, 0 for numerical questions, or 'I cannot answer this.' for open-ended questions.
+        However, YOU MUST ALWAYS FOLLOW THE GIVEN FORMAT WHEN ANSWERING.
+
+        Output your answers in JSON format, with a key 'answers' containing the list of answers in asked order.
+        If there are no questions, return this list empty.
+
+        Additionally, return a detailed description of the item in as few words as possible.
+        Only include information useful in distinguishing this item from other items; information specific to the item (such as size, condition etc) must be omitted.
+        Output this description with the key 'item_description' in the JSON.
+
+        Finally, pick the image (from index 0) which best describes this item and/or shows the most recognizable feature of this item.
+        If there is only 1 image, just return 0.
+        Output this as a number with the key 'best_fit_image' in the JSON.
+
+        Lastly, rate how confident you are in your answers, from 0 (mostly guessing) to 1 (certain).
+        Output this as a number with the key 'confidence' in the JSON.
+
+        Do NOT output anything outside of the above JSON format.
+    ")
+}
+
+/// The name given to the schema of an item's analysis, for providers which require one.
+const ANALYSIS_SCHEMA_NAME: &str = "item_analysis";
+
+/// Builds the JSON schema of an item's analysis, for providers which support constraining their output to one.
+/// 
+/// This describes the parts of an `AnalyzedMarketplaceItem` filled in by the LLM (see `build_system_prompt`),
+/// with exactly one answer per question in the evaluation criteria.
+fn build_analysis_schema(eval_criteria: &EvaluationCriteria) -> Value {
+    let num_answers = eval_criteria.num_criteria();
+    json!({
+        "type": "object",
+        "properties": {
+            "answers": {
+                "type": "array",
+                "description": "The answers to each question, in asked order and following each question's format",
+                "items": { "type": "string" },
+                "minItems": num_answers,
+                "maxItems": num_answers
+            },
+            "item_description": {
+                "type": "string",
+                "description": "A short description of the item, only including information useful in distinguishing it from other items"
+            },
+            "best_fit_image": {
+                "type": "integer",
+                "description": "The index (from 0) of the image which best describes the item",
+                "minimum": 0
+            },
+            "confidence": {
+                "type": "number",
+                "description": "How confident you are in your answers, from 0 (mostly guessing) to 1 (certain)",
+                "minimum": 0,
+                "maximum": 1
+            }
+        },
+        "required": ["answers", "item_description", "best_fit_image", "confidence"],
+        "additionalProperties": false
+    })
+}
+
+/// Parses the LLM's text output for an item into an analyzed item,
+/// along with whether it satisfies the hard criteria.
+/// 
+/// The item's confidence is the model's self-reported score (clamped to 0-1), or `None` if it didn't give one.
+///
+/// Returns an `Err` if the text couldn't be parsed into answers (see `parse_evaluation_answers`), or the answers don't fit the evaluation criteria.
+fn parse_item_answers(
+    item: &MarketplaceItemData,
+    text: &str,
+    eval_criteria: &EvaluationCriteria,
+    strictness: AnalysisParseStrictness
+) -> Result<(AnalyzedMarketplaceItem, bool), String> {
+    let parsed_message = parse_evaluation_answers(item, text, strictness)?;
+    let (answers, satisfies_hard_criteria) = eval_criteria
+        .parse_answers_and_check_hard_criteria(parsed_message.answers)
+        .map_err(|err| format!("Unable to parse answers into evaluation criteria: {err}"))?;
+    let analyzed_item = AnalyzedMarketplaceItem {
+        item: item.clone(),
+        evaluation_answers: answers,
+        item_description: parsed_message.item_description,
+        best_fit_image: parsed_message.best_fit_image,
+        source_marketplaces: vec![],
+        confidence: parsed_message.confidence.map(|confidence| confidence.clamp(0.0, 1.0)),
+        analysis_provider: None
+    };
+    Ok((analyzed_item, satisfies_hard_criteria))
+}
+
+/// Parses the LLM's text output for an item into answers.
+/// 
+/// If parsing is lenient and the text isn't clean JSON, the first valid JSON object in it 
+/// (ie inside a markdown code fence, or surrounded by prose) is parsed instead.
+fn parse_evaluation_answers(
+    item: &MarketplaceItemData, 
+    text: &str, 
+    strictness: AnalysisParseStrictness
+) -> Result<EvaluationAnswers, String> {
+    let err = match serde_json::from_str::<EvaluationAnswers>(text) {
+        Ok(answers) => return Ok(answers),
+        Err(err) => err
+    };
+    if strictness == AnalysisParseStrictness::Lenient {
+        let recovered_answers = json_extraction::extract_json_object(text)
+            .and_then(|object| serde_json::from_value::<EvaluationAnswers>(object).ok());
+        if let Some(answers) = recovered_answers {
+            tracing::warn!("LLM message content for item {} wasn't clean JSON; recovered its answers from the first JSON object in it", item.id);
+            return Ok(answers);
+        }
+    }
+    Err(format!("Unable to parse LLM message content into answers: {err}"))
+}
+
+/// Whether a non-OK status code means the provider is unavailable (ie rate limited or erroring),
+/// rather than the request being bad.
+fn is_unavailable_status(status: StatusCode) -> bool {
+    status == StatusCode::TOO_MANY_REQUESTS || status.is_server_error()
+}
+
+/// Fetches images from image URLs, converts them to PNG,
+/// and converts their content into base64 strings.
+///
+/// Discards unsuccessful image URLs.
+async fn fetch_item_images(request_client: &Client, image_urls: &Vec<String>) -> Vec<String> {
+    let mut encoded_images = vec![];
+    for url in image_urls {
+        match request_client
+            .get(url)
+            .send()
+            .await {
+                Ok(res) => {
+                    match res.bytes().await {
+                        Ok(bytes) => {
+                            match image::load_from_memory(&bytes) {
+                                Ok(image) => {
+                                    let mut cursor = Cursor::new(Vec::new());
+                                    match image.write_to(&mut cursor, ImageFormat::Png) {
+                                        Ok(_) => {
+                                            let encoded_image = STANDARD.encode(cursor.into_inner());
+                                            encoded_images.push(encoded_image);
+                                        },
+                                        Err(err) => tracing::warn!("Failed to write fetched image URL to buffer: {err}")
+                                    }
+                                },
+                                Err(err) => tracing::warn!("Failed to decode fetched image URL bytes into an image: {err}")
+                            }
+                        },
+                        Err(err) => tracing::warn!("Failed to decode fetched image URL into bytes: {err}")
+                    }
+                },
+                Err(err) => tracing::warn!("Failed to fetch an image URL: {err}")
+            }
+    }
+    tracing::trace!(
+        "Successfully fetched and encoded {}/{} image URLs",
+        encoded_images.len(),
+        image_urls.len()
+    );
+    encoded_images
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::item_data;
+
+    /// A provider which returns a canned analysis, marking every item relevant, or fails with `error` if it's set.
+    struct MockProvider {
+        error: Option<AnalysisError>
+    }
+
+    #[async_trait]
+    impl AnalysisProvider for MockProvider {
+        async fn analyze(
+            &self, 
+            items: &[MarketplaceItemData], 
+            _eval_criteria: &EvaluationCriteria, 
+            model: &str, 
+            usage: &UsageTally
+        ) -> Result<MarketplaceAnalyzedItems, AnalysisError> {
+            usage.add(TokenUsage { prompt_tokens: 10, completion_tokens: 5 });
+            if let Some(err) = &self.error {
+                return Err(err.clone());
+            }
+            let relevant_items = items
+                .iter()
+                .map(|item| AnalyzedMarketplaceItem {
+                    item: item.clone(),
+                    evaluation_answers: vec![],
+                    item_description: format!("Analyzed by {model}"),
+                    best_fit_image: 0,
+                    source_marketplaces: vec![],
+                    confidence: Some(1.0),
+                    analysis_provider: None
+                })
+                .collect();
+            let analyzed_items = MarketplaceAnalyzedItems {
+                relevant_items,
+                irrelevant_items: vec![],
+                error_items: vec![],
+                unsampled_items: vec![]
+            };
+            Ok(analyzed_items)
+        }
+    }
+
+    /// A provider which gets one response, then never finishes.
+    struct HangingProvider;
+
+    #[async_trait]
+    impl AnalysisProvider for HangingProvider {
+        async fn analyze(
+            &self, 
+            _items: &[MarketplaceItemData], 
+            _eval_criteria: &EvaluationCriteria, 
+            _model: &str, 
+            usage: &UsageTally
+        ) -> Result<MarketplaceAnalyzedItems, AnalysisError> {
+            usage.add(TokenUsage { prompt_tokens: 10, completion_tokens: 5 });
+            std::future::pending().await
+        }
+    }
+
+    fn mock_entry(kind: AnalysisProviderKind, error: Option<AnalysisError>) -> ProviderEntry {
+        ProviderEntry {
+            kind,
+            provider: Arc::new(MockProvider { error }),
+            model: format!("{}-model", kind.name()),
+            timeout: Duration::from_secs(5)
+        }
+    }
+
+    fn analyzer(providers: Vec<ProviderEntry>) -> Analyzer {
+        Analyzer {
+            providers,
+            allowed_model_overrides: Arc::new(vec![])
+        }
+    }
+
+    async fn analyze(analyzer: &mut Analyzer) -> (HashMap<Marketplace, MarketplaceAnalyzedItems>, HashMap<Marketplace, String>, ModelTokenUsage) {
+        analyze_with(analyzer, &EvaluationCriteria::default(), None).await
+    }
+
+    /// Analyze item "a" (priced at 100) and item "b" (priced at 5000) against the evaluation criteria.
+    async fn analyze_with(
+        analyzer: &mut Analyzer, 
+        eval_criteria: &EvaluationCriteria, 
+        sample_seed: Option<u64>
+    ) -> (HashMap<Marketplace, MarketplaceAnalyzedItems>, HashMap<Marketplace, String>, ModelTokenUsage) {
+        let items = HashMap::from([(Marketplace::Mercari, vec![item_data("a", 100.0, 0), item_data("b", 5000.0, 0)])]);
+        let mut failed_marketplace_reasons = HashMap::new();
+        let mut token_usage = ModelTokenUsage::default();
+        let analyzed_items = analyzer
+            .analyze_gallery(items, eval_criteria, sample_seed, &HashMap::new(), &mut failed_marketplace_reasons, &mut token_usage)
+            .await;
+        (analyzed_items, failed_marketplace_reasons, token_usage)
+    }
+
+    /// The IDs of a marketplace's items which were sent to the provider.
+    fn analyzed_ids(analyzed_items: &HashMap<Marketplace, MarketplaceAnalyzedItems>) -> Vec<String> {
+        let mut ids: Vec<_> = analyzed_items[&Marketplace::Mercari].relevant_items
+            .iter()
+            .map(|item| item.item.id.to_string())
+            .collect();
+        ids.sort();
+        ids
+    }
+
+    #[tokio::test]
+    async fn uses_the_providers_canned_analysis() {
+        let mut analyzer = analyzer(vec![mock_entry(AnalysisProviderKind::Anthropic, None)]);
+        let (analyzed_items, failed_marketplace_reasons, token_usage) = analyze(&mut analyzer).await;
+
+        let mercari_items = &analyzed_items[&Marketplace::Mercari];
+        assert_eq!(mercari_items.relevant_items.len(), 2);
+        assert!(mercari_items.relevant_items
+            .iter()
+            .all(|item| item.analysis_provider.as_deref() == Some("anthropic") && item.item_description == "Analyzed by anthropic-model"));
+        assert!(failed_marketplace_reasons.is_empty());
+        assert_eq!(token_usage.get("anthropic-model"), Some(&TokenUsage { prompt_tokens: 10, completion_tokens: 5 }));
+    }
+
+    #[tokio::test]
+    async fn a_pinned_item_below_the_price_filter_is_still_analyzed() {
+        let eval_criteria: EvaluationCriteria = serde_json::from_value(json!({
+            "criteria": [],
+            "price_range": [1000.0, 10000.0],
+            "pinned_item_ids": ["a"]
+        })).unwrap();
+        let mut analyzer = analyzer(vec![mock_entry(AnalysisProviderKind::Anthropic, None)]);
+        let (analyzed_items, _, _) = analyze_with(&mut analyzer, &eval_criteria, None).await;
+        assert_eq!(analyzed_ids(&analyzed_items), ["a", "b"]);
+
+        let unpinned_criteria: EvaluationCriteria = serde_json::from_value(json!({
+            "criteria": [],
+            "price_range": [1000.0, 10000.0]
+        })).unwrap();
+        let (analyzed_items, _, _) = analyze_with(&mut analyzer, &unpinned_criteria, None).await;
+        assert_eq!(analyzed_ids(&analyzed_items), ["b"]);
+    }
+
+    #[tokio::test]
+    async fn a_pinned_item_is_analyzed_on_top_of_the_sample() {
+        let eval_criteria: EvaluationCriteria = serde_json::from_value(json!({
+            "criteria": [],
+            "sample_rate": 0.01,
+            "pinned_item_ids": ["a"]
+        })).unwrap();
+        let mut analyzer = analyzer(vec![mock_entry(AnalysisProviderKind::Anthropic, None)]);
+        for seed in 0..10 {
+            let (analyzed_items, _, _) = analyze_with(&mut analyzer, &eval_criteria, Some(seed)).await;
+            assert!(analyzed_ids(&analyzed_items).contains(&"a".to_string()));
+        }
+    }
+
+    #[tokio::test]
+    async fn falls_back_when_the_provider_is_unavailable() {
+        let unavailable = AnalysisError::ProviderUnavailable { num_items: 2, num_unavailable: 2, first_error: "429".into() };
+        let mut analyzer = analyzer(vec![
+            mock_entry(AnalysisProviderKind::Anthropic, Some(unavailable)),
+            mock_entry(AnalysisProviderKind::OpenAI, None)
+        ]);
+        let (analyzed_items, failed_marketplace_reasons, token_usage) = analyze(&mut analyzer).await;
+
+        assert!(analyzed_items[&Marketplace::Mercari].relevant_items
+            .iter()
+            .all(|item| item.analysis_provider.as_deref() == Some("openai")));
+        assert!(failed_marketplace_reasons.is_empty());
+        assert!(token_usage.get("anthropic-model").is_some());
+    }
+
+    #[tokio::test]
+    async fn records_the_usage_of_a_timed_out_analysis() {
+        let mut analyzer = analyzer(vec![ProviderEntry {
+            kind: AnalysisProviderKind::Anthropic,
+            provider: Arc::new(HangingProvider),
+            model: "anthropic-model".into(),
+            timeout: Duration::from_millis(10)
+        }]);
+        let (analyzed_items, failed_marketplace_reasons, token_usage) = analyze(&mut analyzer).await;
+
+        assert!(analyzed_items.is_empty());
+        assert!(failed_marketplace_reasons[&Marketplace::Mercari].contains("timed out"));
+        assert_eq!(token_usage.get("anthropic-model"), Some(&TokenUsage { prompt_tokens: 10, completion_tokens: 5 }));
+    }
+
+    /// A response wrapped in prose and a markdown code fence.
+    const MESSY_RESPONSE: &str = "Here is my evaluation:\n```json\n{\"answers\": [\"Yes\"], \"item_description\": \"A camera\", \"best_fit_image\": 0}\n```";
+
+    #[test]
+    fn strict_parsing_requires_clean_json() {
+        let item = item_data("a", 100.0, 0);
+        assert!(parse_evaluation_answers(&item, MESSY_RESPONSE, AnalysisParseStrictness::Strict).is_err());
+        let clean_response = r#"{"answers": ["Yes"], "item_description": "A camera", "best_fit_image": 0}"#;
+        let answers = parse_evaluation_answers(&item, clean_response, AnalysisParseStrictness::Strict).unwrap();
+        assert_eq!(answers.answers, ["Yes"]);
+    }
+
+    #[test]
+    fn lenient_parsing_recovers_answers_from_messy_responses() {
+        let item = item_data("a", 100.0, 0);
+        let answers = parse_evaluation_answers(&item, MESSY_RESPONSE, AnalysisParseStrictness::Lenient).unwrap();
+        assert_eq!(answers.answers, ["Yes"]);
+        assert_eq!(answers.item_description, "A camera");
+        let prose_response = r#"Sure! {"answers": ["No"], "item_description": "A lens", "best_fit_image": 1} Hope that helps."#;
+        let answers = parse_evaluation_answers(&item, prose_response, AnalysisParseStrictness::Lenient).unwrap();
+        assert_eq!(answers.best_fit_image, 1);
+        // An object which isn't a valid set of answers isn't recovered
+        let invalid_response = r#"Sure! {"answers": "No"}"#;
+        assert!(parse_evaluation_answers(&item, invalid_response, AnalysisParseStrictness::Lenient).is_err());
+    }
+
+    #[tokio::test]
+    async fn records_a_failed_marketplace_without_falling_back() {
+        let failed = AnalysisError::AllItemsFailed { num_items: 2, first_error: "unparseable".into() };
+        let mut analyzer = analyzer(vec![
+            mock_entry(AnalysisProviderKind::Anthropic, Some(failed)),
+            mock_entry(AnalysisProviderKind::OpenAI, None)
+        ]);
+        let (analyzed_items, failed_marketplace_reasons, _) = analyze(&mut analyzer).await;
+
+        assert!(analyzed_items.is_empty());
+        assert!(failed_marketplace_reasons.contains_key(&Marketplace::Mercari));
+    }
+}
@@ -1,9 +1,9 @@
-use std::{collections::HashMap, iter::zip};
-
+use async_trait::async_trait;
 use futures::future::join_all;
 use reqwest::{Client, RequestBuilder, StatusCode};
-use types::{OpenAIImageURLMessage, OpenAIMessage, OpenAIMessageContent, OpenAIRequestForm, OpenAIResponse};
-use crate::{config::ItemAnalysisConfig, galleries::{domain_types::Marketplace, eval_criteria::EvaluationCriteria, items::{item_data::MarketplaceItemData, pipeline_items::{AnalyzedMarketplaceItem, ErrorAnalyzedMarketplaceItem, MarketplaceAnalyzedItems}}, pipeline_states::{GalleryItemEmbedderState, GalleryItemAnalysisState}}, scraping_pipeline::item_analysis::analyzer::anthropic::types::EvaluationAnswers};
+use types::{OpenAIImageURLMessage, OpenAIJsonSchema, OpenAIMessage, OpenAIMessageContent, OpenAIRequestForm, OpenAIResponse, OpenAIResponseFormat};
+use crate::{config::ItemAnalysisConfig, galleries::{domain_types::TokenUsage, eval_criteria::EvaluationCriteria, items::{item_data::MarketplaceItemData, pipeline_items::{ErrorAnalyzedMarketplaceItem, MarketplaceAnalyzedItems}}}};
+use super::{build_analysis_schema, build_system_prompt, check_marketplace_results, collect_item_outcomes, is_unavailable_status, parse_item_answers, AnalysisError, AnalysisProvider, ItemOutcome, UsageTally, ANALYSIS_SCHEMA_NAME};
 
 mod types;
 
@@ -12,167 +12,128 @@ pub(super) struct OpenAIRequester {
     request_client: Client
 }
 
+#[async_trait]
+impl AnalysisProvider for OpenAIRequester {
+    async fn analyze(
+        &self, 
+        items: &[MarketplaceItemData], 
+        eval_criteria: &EvaluationCriteria, 
+        model: &str, 
+        usage: &UsageTally
+    ) -> Result<MarketplaceAnalyzedItems, AnalysisError> {
+        let item_requests = self.build_requests(items, eval_criteria, model);
+        let (analyzed_items, num_unavailable) = self.execute_and_handle_requests(eval_criteria, item_requests, usage).await;
+        check_marketplace_results(analyzed_items, num_unavailable)
+    }
+}
+
 impl OpenAIRequester {
     /// Instantiate the requester.
-    pub fn new(config: ItemAnalysisConfig) -> Self {
+    pub fn new(config: ItemAnalysisConfig, request_client: Client) -> Self {
         Self {
-            config: config,
-            request_client: Client::new()
+            config,
+            request_client
         }
     }
 
-    /// Perform analysis of a gallery's items.
-    pub async fn analyze_gallery(&mut self, mut gallery: GalleryItemAnalysisState) -> GalleryItemEmbedderState {
-        let items = gallery.items;
-        let eval_criteria_string = gallery.evaluation_criteria.describe_criteria();
-        let gallery_requests = self.build_requests(items, eval_criteria_string);
-        let analyzed_items = self.execute_and_handle_requests(
-            &mut gallery.evaluation_criteria, 
-            gallery_requests
-        ).await;
-        GalleryItemEmbedderState {
-            gallery_id: gallery.gallery_id,
-            items: analyzed_items,
-            failed_marketplace_reasons: gallery.failed_marketplace_reasons,
-            marketplace_updated_datetimes: gallery.marketplace_updated_datetimes
-        }
-    }
-
-    /// Executes and handles the requests for a gallery.
+    /// Executes and handles the requests for a marketplace's items, processing each item's response as soon as it arrives.
+    /// 
+    /// Returns the analyzed items, along with the number of items which failed because the provider was unavailable.
     async fn execute_and_handle_requests(
-        &self, 
-        eval_criteria: &mut EvaluationCriteria,
-        gallery_requests: HashMap<Marketplace, Vec<(MarketplaceItemData, RequestBuilder)>>
-    ) -> HashMap<Marketplace, MarketplaceAnalyzedItems> {
-        let mut gallery_items = HashMap::new();
-        for (marketplace, items_and_requests) in gallery_requests {
-            let (items, item_requests): (Vec<_>, Vec<_>) = items_and_requests
-                .into_iter()
-                .unzip();
-            let request_futures = item_requests
-                .into_iter()
-                .map(|request| request.send());
-            let results = join_all(request_futures).await;
-            let items_and_results = zip(items, results).collect();
-            let marketplace_items = self
-                .process_marketplace_results(eval_criteria, items_and_results)
-                .await;
-            gallery_items.insert(marketplace, marketplace_items);
-        }
-        gallery_items
+        &self,
+        eval_criteria: &EvaluationCriteria,
+        items_and_requests: Vec<(MarketplaceItemData, RequestBuilder)>,
+        usage: &UsageTally
+    ) -> (MarketplaceAnalyzedItems, usize) {
+        let outcomes = items_and_requests
+            .into_iter()
+            .map(|(item, request)| async {
+                let result = request.send().await;
+                self.process_item_result(eval_criteria, item, result, usage).await
+            });
+        collect_item_outcomes(join_all(outcomes).await)
     }
 
-    /// Process the raw LLM output for all items in a gallery's marketplace.
-    async fn process_marketplace_results(
+    /// Process the raw LLM output for an item, adding the tokens its response used to `usage`.
+    async fn process_item_result(
         &self,
-        eval_criteria: &mut EvaluationCriteria,
-        results: Vec<(MarketplaceItemData, Result<reqwest::Response, reqwest::Error>)>,
-    ) -> MarketplaceAnalyzedItems {
-        let mut relevant_items = vec![];
-        let mut irrelevant_items = vec![];
-        let mut error_items = vec![];
-        for (item, result) in results {
-            let mut err_str = None;
-            match result {
-                Ok(res) => {
-                    match res.status() {
-                        StatusCode::OK => {
-                            match res.json::<OpenAIResponse>().await {
-                                Ok(response) => {
-                                    tracing::trace!("Successful response: {response:#?}"); // TODO: delete this later on
-                                    if response.choices.len() == 0 {
-                                        err_str = Some("Expected 1 message in Anthropic response but found none".into());
-                                    }
-                                    else if response.choices.len() > 1 {
-                                        tracing::warn!("Unexpectedly received >1 messages in Anthropic response; using the first...");
+        eval_criteria: &EvaluationCriteria,
+        item: MarketplaceItemData,
+        result: Result<reqwest::Response, reqwest::Error>,
+        usage: &UsageTally
+    ) -> ItemOutcome {
+        let mut unavailable = false;
+        let answers = match result {
+            Ok(res) => {
+                match res.status() {
+                    StatusCode::OK => {
+                        match res.json::<OpenAIResponse>().await {
+                            Ok(response) => {
+                                tracing::trace!("Successful response: {response:#?}"); // TODO: delete this later on
+                                usage.add(TokenUsage {
+                                    prompt_tokens: response.usage.prompt_tokens as u64,
+                                    completion_tokens: response.usage.completion_tokens as u64
+                                });
+                                if response.choices.is_empty() {
+                                    Err("Expected 1 choice in OpenAI response but found none".into())
+                                }
+                                else {
+                                    if response.choices.len() > 1 {
+                                        tracing::warn!("Unexpectedly received >1 choices in OpenAI response; using the first...");
                                     }
-                                    match &response.choices[0].text {
-                                        Some(text) => {
-                                            match serde_json::from_str::<EvaluationAnswers>(text) {
-                                                Ok(parsed_message) => {
-                                                    match eval_criteria.parse_answers_and_check_hard_criteria(parsed_message.answers) {
-                                                        Ok((answers, satisfies_hard_criteria)) => {
-                                                            let analyzed_item = AnalyzedMarketplaceItem {
-                                                                item: item.clone(), 
-                                                                evaluation_answers: answers,
-                                                                item_description: parsed_message.item_description,
-                                                                best_fit_image: parsed_message.best_fit_image
-                                                            };
-                                                            if satisfies_hard_criteria {
-                                                                relevant_items.push(analyzed_item);
-                                                            } else {
-                                                                irrelevant_items.push(analyzed_item);
-                                                            }
-                                                        },
-                                                        Err(err) => err_str = Some(format!("Unable to parse answers into evaluation criteria: {err}"))
-                                                    }
-                                                },
-                                                Err(err) => err_str = Some(format!("Unable to parse Anthropic message content into answers: {err}"))
-                                            }
-                                        },
-                                        None => err_str = Some("Anthropic message content contained no `text` key".into())
-                                    }
-                                },
-                                Err(err) => err_str = Some(format!("Unable to parse Anthropic response: {err:#?}"))
-                            }
-                        },
-                        other => {
-                            let res = res.text().await;
-                            err_str = Some(format!("Received unexpected status code {other} from Anthropic API; response: {res:#?}"));
+                                    parse_item_answers(&item, &response.choices[0].message.content, eval_criteria, self.config.parse_strictness)
+                                }
+                            },
+                            Err(err) => Err(format!("Unable to parse OpenAI response: {err:#?}"))
                         }
+                    },
+                    other => {
+                        unavailable = is_unavailable_status(other);
+                        let res = res.text().await;
+                        Err(format!("Received unexpected status code {other} from OpenAI API; response: {res:#?}"))
                     }
-                },
-                Err(err) => err_str = Some(format!("Error while querying the Anthropic API: {err}"))
+                }
+            },
+            Err(err) => {
+                unavailable = true;
+                Err(format!("Error while querying the OpenAI API: {err}"))
             }
-            if let Some(error) = err_str {
+        };
+        match answers {
+            Ok((analyzed_item, relevant)) => ItemOutcome::Analyzed(analyzed_item, relevant),
+            Err(error) => {
                 tracing::trace!("Item {} had an error during item analysis: {}", item.id, error);
-                let err_item = ErrorAnalyzedMarketplaceItem { item, error };
-                error_items.push(err_item);
+                ItemOutcome::Failed(ErrorAnalyzedMarketplaceItem { item, error }, unavailable)
             }
         }
-        tracing::debug!(
-            "Item analysis results: {} relevant items, {} irrelevant items, and {} error items",
-            relevant_items.len(), 
-            irrelevant_items.len(), 
-            error_items.len()
-        );
-        MarketplaceAnalyzedItems {
-            relevant_items,
-            irrelevant_items,
-            error_items
-        }
     }
 
-    /// Build the requests for all the gallery's items.
+    /// Build the requests for a marketplace's items.
     fn build_requests(
-        &self, 
-        items: HashMap<Marketplace, Vec<MarketplaceItemData>>,
-        eval_criteria_string: String
-    ) -> HashMap<Marketplace, Vec<(MarketplaceItemData, RequestBuilder)>> {
+        &self,
+        items: &[MarketplaceItemData],
+        eval_criteria: &EvaluationCriteria,
+        model: &str
+    ) -> Vec<(MarketplaceItemData, RequestBuilder)> {
         items
-            .into_iter()
-            .map(|(marketplace, items)| {
-                let items_and_requests = items
-                    .into_iter()
-                    .map(|item| {
-                        let item_request = self.build_item_request(&item, &eval_criteria_string);
-                        (item, item_request)
-                    })
-                    .collect();
-                (marketplace, items_and_requests)
+            .iter()
+            .map(|item| {
+                let item_request = self.build_item_request(item, eval_criteria, model);
+                (item.clone(), item_request)
             })
             .collect()
     }
 
     /// Builds the request for a single item.
-    /// 
+    ///
     /// Follows the request format specified here: https://platform.openai.com/docs/api-reference/chat
     fn build_item_request(
-        &self, 
+        &self,
         item: &MarketplaceItemData,
-        eval_criteria_string: &String
+        eval_criteria: &EvaluationCriteria,
+        model: &str
     ) -> RequestBuilder {
-        let req_form = self.build_request_form(item, eval_criteria_string);
+        let req_form = self.build_request_form(item, eval_criteria, model);
         self.request_client
             .post(&self.config.openai_api_endpoint)
             .bearer_auth(&self.config.openai_api_key)
@@ -180,32 +141,15 @@ impl OpenAIRequester {
     }
 
     /// Builds the entire request form for an item.
+    /// 
+    /// If structured output is enabled, the response is constrained to the analysis schema.
     fn build_request_form(
-        &self, 
+        &self,
         item: &MarketplaceItemData,
-        eval_criteria_string: &String
+        eval_criteria: &EvaluationCriteria,
+        model: &str
     ) -> OpenAIRequestForm {
-        let system_prompt = format!("
-            You're an Item Listings Analysis AI. 
-            
-            You will help to evaluate an item listing, consisting of its listed images and a JSON of its information, by answering some structured questions about it. 
-            
-            There are the following question types:
-            - YesNo: Answer with 'Y'/'N' only
-            - YesNoUncertain: Answer with 'Y'/'N'/'U' only
-            - Int: Answer with an integer within a string
-            - Float: Answer with a float within a string
-            - OpenEnded: Answer as best as you can, under 200 characters
-
-            If the question is unanswerable, nonsensical, or not even a question, you are allowed to give a reasonable 'default' answer, 
-            such as N for Y/N questions, U for Y/N/U questions, 0 for numerical questions, or 'I cannot answer this.' for open-ended questions.
-            However, YOU MUST ALWAYS FOLLOW THE GIVEN FORMAT WHEN ANSWERING.
-
-            Output your answers in JSON format, with a key 'answers' containing the list of answers in asked order.
-            If there are no questions, return the list empty.
-
-            Here are the questions you must answer:\n {eval_criteria_string}
-        ");
+        let system_prompt = build_system_prompt();
         let system_message = OpenAIMessage {
             role: "developer".to_string(),
             content: vec![
@@ -221,7 +165,7 @@ impl OpenAIRequester {
             .into_iter()
             .enumerate()
             .map(|(index, url)| {
-                // Follows the recommended format for sending multiple images: https://docs.anthropic.com/en/docs/build-with-claude/vision#example-multiple-images
+                // Follows the recommended format for sending images: https://platform.openai.com/docs/guides/vision
                 vec![
                     OpenAIMessageContent {
                         content_type: "text".into(),
@@ -229,7 +173,7 @@ impl OpenAIRequester {
                         image_url: None
                     },
                     OpenAIMessageContent {
-                        content_type: "image".into(),
+                        content_type: "image_url".into(),
                         text: None,
                         image_url: Some(OpenAIImageURLMessage { url })
                     }
@@ -242,18 +186,30 @@ impl OpenAIRequester {
         message_contents.push(
             OpenAIMessageContent {
                 content_type: "text".into(),
-                text: Some(format!("Here is the item listing: \n {item_string}")),
+                text: Some(eval_criteria.render_prompt(&item_string)),
                 image_url: None
-            }   
+            }
         );
         let user_messages = OpenAIMessage {
             role: "user".into(),
             content: message_contents
         };
+        let response_format = match self.config.structured_output {
+            true => Some(OpenAIResponseFormat {
+                format_type: "json_schema".into(),
+                json_schema: OpenAIJsonSchema {
+                    name: ANALYSIS_SCHEMA_NAME.into(),
+                    strict: true,
+                    schema: build_analysis_schema(eval_criteria)
+                }
+            }),
+            false => None
+        };
         OpenAIRequestForm {
-            model: self.config.anthropic_model.clone(),
+            model: model.to_string(),
             max_completion_tokens: 1000, // TODO: Figure out a good number for this
-            messages: vec![system_message, user_messages]
+            messages: vec![system_message, user_messages],
+            response_format
         }
     }
 }
\ No newline at end of file
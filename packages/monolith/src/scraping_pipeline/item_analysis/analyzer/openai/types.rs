@@ -1,9 +1,10 @@
-//! API-specific types are derived from the docs: https://docs.OpenAI.com/en/api/messages
+//! API-specific types are derived from the docs: https://platform.openai.com/docs/api-reference/chat
 //! 
 //! **NOTE**: Some of these structs don't fully describe the actual data shapes,
 //! leaving out data that we don't use. Check the docs for what they are
 //! if you're expecting/need any of it.
 use serde::{Deserialize, Serialize};
+use serde_json::Value;
 
 /// The request form for querying OpenAI API.
 /// 
@@ -13,7 +14,26 @@ use serde::{Deserialize, Serialize};
 pub struct OpenAIRequestForm {
     pub model: String,
     pub max_completion_tokens: usize,
-    pub messages: Vec<OpenAIMessage>
+    pub messages: Vec<OpenAIMessage>,
+    /// Only set when using structured outputs.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub response_format: Option<OpenAIResponseFormat>
+}
+
+/// Constrains an OpenAI API response to a JSON schema: https://platform.openai.com/docs/guides/structured-outputs
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct OpenAIResponseFormat {
+    #[serde(rename = "type")] // API expects `type` but it's a keyword
+    pub format_type: String,
+    pub json_schema: OpenAIJsonSchema
+}
+
+/// The JSON schema a structured output must follow.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct OpenAIJsonSchema {
+    pub name: String,
+    pub strict: bool,
+    pub schema: Value
 }
 
 /// A single message in the content of a OpenAI API request.
@@ -45,14 +65,14 @@ pub struct OpenAIImageURLMessage {
 pub struct OpenAIResponse {
     pub id: String,
     pub usage: OpenAIUsage,
-    pub choices: Vec<OpenAIMessageContent>,
+    pub choices: Vec<OpenAIResponseMessage>,
 }
 
 /// The usage data for this query.
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct OpenAIUsage {
     pub prompt_tokens: usize,
-    pub output_tokens: usize,
+    pub completion_tokens: usize,
     pub total_tokens: usize
 }
 
@@ -65,6 +85,6 @@ pub struct OpenAIResponseMessage {
 /// The content of a message of an OpenAI API response.
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct OpenAIResponseMessageContent {
-    role: String,
-    content: String
+    pub role: String,
+    pub content: String
 }
@@ -0,0 +1,43 @@
+//! Contains the sampling of items sent to analysis, for galleries which only analyze a fraction of their items.
+use std::collections::HashSet;
+use rand::{rngs::StdRng, seq::index, SeedableRng};
+use crate::galleries::{domain_types::Marketplace, items::item_data::MarketplaceItemData};
+
+/// Randomly samples `sample_rate` of a marketplace's items (rounded up, so a non-empty marketplace keeps at least 1 item).
+///
+/// The sample is deterministic for a given seed and marketplace, so a replayed run samples the same items.
+///
+/// Returns the sampled items, followed by the rest; both keep their original order.
+pub(super) fn sample_items(
+    items: Vec<MarketplaceItemData>,
+    sample_rate: f32,
+    seed: u64,
+    marketplace: &Marketplace
+) -> (Vec<MarketplaceItemData>, Vec<MarketplaceItemData>) {
+    let num_items = items.len();
+    let sample_size = ((num_items as f64 * sample_rate as f64).ceil() as usize).min(num_items);
+    if sample_size == num_items {
+        return (items, vec![]);
+    }
+    let mut rng = StdRng::seed_from_u64(marketplace_seed(seed, marketplace));
+    let sampled_indices: HashSet<usize> = index::sample(&mut rng, num_items, sample_size)
+        .into_iter()
+        .collect();
+    let (sampled_items, unsampled_items): (Vec<_>, Vec<_>) = items
+        .into_iter()
+        .enumerate()
+        .partition(|(index, _)| sampled_indices.contains(index));
+    (
+        sampled_items.into_iter().map(|(_, item)| item).collect(),
+        unsampled_items.into_iter().map(|(_, item)| item).collect()
+    )
+}
+
+/// Mixes the marketplace into the seed with FNV-1a, so each marketplace is sampled independently
+/// (and regardless of the order marketplaces are iterated in).
+fn marketplace_seed(seed: u64, marketplace: &Marketplace) -> u64 {
+    marketplace
+        .to_string()
+        .bytes()
+        .fold(seed ^ 0xcbf29ce484222325_u64, |hash, byte| (hash ^ byte as u64).wrapping_mul(0x100000001b3))
+}
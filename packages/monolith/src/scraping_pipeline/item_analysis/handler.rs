@@ -1,79 +1,375 @@
-use std::collections::HashMap;
+use std::{collections::HashMap, sync::Arc, time::Duration};
+use tokio_util::sync::CancellationToken;
+use tracing::Instrument;
 use crate::{
     config::ItemAnalysisConfig, 
-    galleries::{domain_types::{GalleryId, Marketplace, UnixUtcDateTime}, items::pipeline_items::MarketplaceAnalyzedItems, pipeline_states::{GalleryItemAnalysisState, GalleryItemEmbedderState, GalleryPipelineStateTypes, GalleryPipelineStates}}, 
+    galleries::{domain_types::{GalleryId, ItemId, Marketplace, ModelTokenUsage, RunId, UnixUtcDateTime}, items::pipeline_items::{ErrorAnalyzedMarketplaceItem, MarketplaceAnalyzedItems}, pipeline_states::{GalleryItemAnalysisState, GalleryPipelineStateTypes, GalleryPipelineStates, StageAdvancePayload}}, 
     messages::{
-        message_types::{item_analysis::ItemAnalysisError, item_embedder::ItemEmbedderMessage
-        }, ItemEmbedderSender, StateTrackerSender
-    }
+        message_types::{item_analysis::{CriteriaPreview, CriteriaPreviewRequest, ItemAnalysisError}, item_embedder::ItemEmbedderMessage, storage::{AnalysisRetryEntry, AnalysisRetryOutcome, CachedItemAnalysis, EnqueueAnalysisRetryMessage, GetAnalysisRetriesMessage, GetCachedAnalysesMessage, GetScrapedItemsMessage, ResolveAnalysisRetryMessage, StorageMessage}
+        }, ItemEmbedderSender, StateTrackerSender, StorageSender
+    },
+    notifications::{FinalStateSummary, Notifier, PipelineEvent},
+    scraping_pipeline::pipeline_metrics::PipelineMetrics,
+    utils::{http_client::HttpClientFactory, tracing_context::gallery_run_span}
 };
 
 use super::analyzer::Analyzer;
 
 /// Coordinates the internal workings of the module.
+/// 
+/// This is cloned for each gallery being processed, so its fields must be cheaply cloneable.
+#[derive(Clone)]
 pub(super) struct Handler {
     state_tracker_sender: StateTrackerSender,
     item_embedder_sender: ItemEmbedderSender,
-    analyzer: Analyzer
+    storage_sender: StorageSender,
+    analyzer: Analyzer,
+    /// Used to emit completions of galleries which skip embedding, as they reach the final state here.
+    notifier: Notifier,
+    pipeline_metrics: Arc<PipelineMetrics>,
+    /// How long an unchanged item's cached analysis is reused for; zero disables caching.
+    cache_ttl: Duration,
+    /// Whether items which failed analysis are queued to be retried.
+    retry_enabled: bool,
+    /// How many times queued items are retried before giving up on them.
+    retry_max_attempts: u32
 }
 
 impl Handler {
     /// Instantiate the state.
     pub fn new(
         config: &ItemAnalysisConfig,
+        http_clients: &HttpClientFactory,
         state_tracker_sender: StateTrackerSender,
-        item_embedder_sender: ItemEmbedderSender
+        item_embedder_sender: ItemEmbedderSender,
+        storage_sender: StorageSender,
+        notifier: Notifier,
+        pipeline_metrics: Arc<PipelineMetrics>
     ) -> Self {
-        let analyzer = Analyzer::new(config.clone());
+        let analyzer = Analyzer::new(config.clone(), http_clients);
         Self {
             state_tracker_sender,
             item_embedder_sender,
-            analyzer
+            storage_sender,
+            analyzer,
+            notifier,
+            pipeline_metrics,
+            cache_ttl: Duration::from_secs(config.cache_ttl_secs),
+            retry_enabled: config.retry_queue_interval_secs > 0,
+            retry_max_attempts: config.retry_queue_max_attempts
         }
     }
     
     /// Perform the entire scraping of a new gallery.
     pub async fn analyze_new_gallery(&mut self, gallery: GalleryItemAnalysisState) -> Result<(), ItemAnalysisError> {
         let gallery_id = gallery.gallery_id.clone();
-        self.add_gallery_to_state(gallery_id.clone(), gallery).await?;
-        self.analyze_gallery_in_state(gallery_id).await
+        let run_id = self.add_gallery_to_state(gallery_id.clone(), gallery).await?;
+        self.analyze_gallery_in_state(gallery_id.clone())
+            .instrument(gallery_run_span(&gallery_id, &run_id))
+            .await
     }
 
     /// Perform the scraping of a gallery in state.
+    /// 
+    /// Aborts if the gallery's run is cancelled (ie it's deleted) in the meantime.
     pub async fn analyze_gallery_in_state(&mut self, gallery_id: GalleryId) -> Result<(), ItemAnalysisError> {
-        let gallery = self.fetch_gallery_state(gallery_id).await?;
-        self.analyze_gallery(gallery).await
+        let cancellation = self.fetch_cancellation_token(gallery_id.clone()).await?;
+        let gallery = self.fetch_gallery_state(gallery_id.clone()).await?;
+        match cancellation.run_until_cancelled(self.analyze_gallery(gallery)).await {
+            Some(result) => result,
+            None => {
+                tracing::info!("Gallery {gallery_id} was cancelled; aborted its analysis");
+                Ok(())
+            }
+        }
+    }
+
+    /// Fetch the cancellation token for the gallery's current run.
+    async fn fetch_cancellation_token(&mut self, gallery_id: GalleryId) -> Result<CancellationToken, ItemAnalysisError> {
+        self.state_tracker_sender
+            .get_cancellation_token(gallery_id.clone())
+            .await
+            .map_err(|err| ItemAnalysisError::Other { 
+                gallery_id: gallery_id.clone(), 
+                message: format!("Could not receive response from state tracker: {err}") 
+            })?
+            .map_err(|err| ItemAnalysisError::StateErr { 
+                gallery_id, 
+                err 
+            })
     }
 
-    /// Scrapes the search for a gallery and sends it to the item scraper.
-    async fn analyze_gallery(&mut self, gallery: GalleryItemAnalysisState) -> Result<(), ItemAnalysisError> {
+    /// Analyzes a gallery's items and sends it to the item embedder,
+    /// or straight to storage if the gallery skips embedding.
+    async fn analyze_gallery(&mut self, mut gallery: GalleryItemAnalysisState) -> Result<(), ItemAnalysisError> {
+        self.load_stored_items(&mut gallery).await;
+        let criteria_hash = gallery.evaluation_criteria.cache_hash();
+        let cached_analyses = self.fetch_cached_analyses(&gallery, criteria_hash).await;
+        let num_previously_failed = gallery.failed_marketplace_reasons.len();
         let analyzed_items = self.analyzer
-            .analyze_gallery(gallery.items, &gallery.evaluation_criteria)
+            .analyze_gallery(
+                gallery.items.clone(), 
+                &gallery.evaluation_criteria, 
+                Some(gallery.sample_seed),
+                &cached_analyses,
+                &mut gallery.failed_marketplace_reasons,
+                &mut gallery.token_usage
+            )
             .await;
+        self.cache_analyses(criteria_hash, &analyzed_items, &cached_analyses).await;
+        self.enqueue_analysis_retries(&gallery, &analyzed_items).await;
+        self.pipeline_metrics.record_failed_marketplaces(
+            &GalleryPipelineStateTypes::ItemAnalysis, 
+            gallery.failed_marketplace_reasons.len().saturating_sub(num_previously_failed)
+        );
         let gallery_id = gallery.gallery_id.clone();
-        self.update_gallery_state(
-            gallery.gallery_id,
-            analyzed_items,
-            gallery.marketplace_updated_datetimes,
-            gallery.failed_marketplace_reasons,
-        ).await?;
-        self.item_embedder_sender
-            .send(ItemEmbedderMessage::Classify { gallery_id: gallery_id.clone() })
-            .await
-            .map_err(|err| ItemAnalysisError::MessageErr { gallery_id, err })?;
+        let skip_embedding = gallery.evaluation_criteria.skip_embedding();
+        self.update_gallery_state(gallery, analyzed_items).await?;
+        let send_result = match skip_embedding {
+            true => self.storage_sender
+                .send(StorageMessage::StoreGallery { gallery_id: gallery_id.clone() })
+                .await,
+            false => self.item_embedder_sender
+                .send(ItemEmbedderMessage::Classify { gallery_id: gallery_id.clone() })
+                .await
+        };
+        send_result.map_err(|err| ItemAnalysisError::MessageErr { gallery_id, err })?;
             Ok(())
     }
     
-    /// Add a new gallery to the state.
+    /// Analyze a sample item against proposed evaluation criteria, without touching the pipeline or storage.
+    pub async fn preview_criteria(&self, request: CriteriaPreviewRequest) -> Result<CriteriaPreview, String> {
+        self.analyzer
+            .preview(request.item, &request.evaluation_criteria)
+            .await
+    }
+    
+    /// Fills in the items of marketplaces missing (or empty) in the gallery's state from the scraped items in storage.
+    /// 
+    /// Marketplaces which already failed or were completed in a previous run are left alone.
+    /// This is best-effort; failures to reach storage are logged, and the gallery's state is used as is.
+    async fn load_stored_items(&mut self, gallery: &mut GalleryItemAnalysisState) {
+        let gallery_id = gallery.gallery_id.clone();
+        let (msg, receiver) = GetScrapedItemsMessage::new(gallery_id.clone());
+        if let Err(err) = self.storage_sender.send(StorageMessage::GetScrapedItems(msg)).await {
+            tracing::warn!("Failed to message storage for scraped items of gallery {gallery_id}: {err}");
+            return;
+        }
+        let stored_items = match receiver.await {
+            Ok(Ok(stored_items)) => stored_items,
+            Ok(Err(err)) => {
+                tracing::warn!("Failed to fetch scraped items of gallery {gallery_id} from storage: {err}");
+                return;
+            },
+            Err(err) => {
+                tracing::warn!("Failed to receive a response from storage for scraped items of gallery {gallery_id}: {err}");
+                return;
+            }
+        };
+        for (marketplace, items) in stored_items {
+            if gallery.failed_marketplace_reasons.contains_key(&marketplace) || gallery.completed_items.contains_key(&marketplace) {
+                continue;
+            }
+            let state_items = gallery.items
+                .entry(marketplace.clone())
+                .or_default();
+            if state_items.is_empty() && !items.is_empty() {
+                tracing::debug!("Loaded {} scraped {marketplace} items for gallery {gallery_id} from storage", items.len());
+                *state_items = items;
+            }
+        }
+    }
+
+    /// Fetches the fresh cached analyses of the gallery's items from storage, ie of items which haven't been updated since,
+    /// and were cached within the cache TTL.
+    /// 
+    /// This is best-effort; failures to reach storage are logged, and no cached analyses are used.
+    async fn fetch_cached_analyses(&mut self, gallery: &GalleryItemAnalysisState, criteria_hash: u64) -> HashMap<ItemId, CachedItemAnalysis> {
+        if self.cache_ttl.is_zero() {
+            return HashMap::new();
+        }
+        let items: HashMap<_, _> = gallery.items
+            .values()
+            .flatten()
+            .map(|item| (item.id.clone(), item.updated.clone()))
+            .collect();
+        let (msg, receiver) = GetCachedAnalysesMessage::new((criteria_hash, items.keys().cloned().collect()));
+        if let Err(err) = self.storage_sender.send(StorageMessage::GetCachedAnalyses(msg)).await {
+            tracing::warn!("Failed to message storage for cached analyses of gallery {}: {err}", gallery.gallery_id);
+            return HashMap::new();
+        }
+        let cached_analyses = match receiver.await {
+            Ok(cached_analyses) => cached_analyses,
+            Err(err) => {
+                tracing::warn!("Failed to receive a response from storage for cached analyses of gallery {}: {err}", gallery.gallery_id);
+                return HashMap::new();
+            }
+        };
+        let cache_ttl = chrono::Duration::from_std(self.cache_ttl).unwrap_or(chrono::Duration::MAX);
+        let now = UnixUtcDateTime::now();
+        cached_analyses
+            .into_iter()
+            .filter(|(item_id, cached_analysis)| {
+                items.get(item_id) == Some(&cached_analysis.item.item.updated)
+                    && *now - *cached_analysis.cached_at < cache_ttl
+            })
+            .collect()
+    }
+
+    /// Caches the analyses of newly analyzed (ie not already cached) items in storage.
+    /// 
+    /// Items which errored aren't cached, so they're retried next time. This is best-effort; failures to reach storage are logged.
+    async fn cache_analyses(
+        &mut self, 
+        criteria_hash: u64, 
+        analyzed_items: &HashMap<Marketplace, MarketplaceAnalyzedItems>,
+        cached_analyses: &HashMap<ItemId, CachedItemAnalysis>
+    ) {
+        if self.cache_ttl.is_zero() {
+            return;
+        }
+        let cached_at = UnixUtcDateTime::now();
+        let analyses: Vec<_> = analyzed_items
+            .values()
+            .flat_map(|items| items.relevant_items
+                .iter()
+                .map(|item| (item, true))
+                .chain(items.irrelevant_items.iter().map(|item| (item, false)))
+            )
+            .filter(|(item, _)| !cached_analyses.contains_key(&item.item.id))
+            .map(|(item, relevant)| CachedItemAnalysis {
+                item: item.clone(),
+                relevant,
+                cached_at: cached_at.clone()
+            })
+            .collect();
+        if analyses.is_empty() {
+            return;
+        }
+        if let Err(err) = self.storage_sender.send(StorageMessage::CacheAnalyses { criteria_hash, analyses }).await {
+            tracing::warn!("Failed to message storage to cache analyses: {err}");
+        }
+    }
+
+    /// Retries the analysis of each queued marketplace's items once, merging the outcome into their stored gallery.
+    /// 
+    /// Failures to reach storage are logged, and the affected marketplaces are left queued.
+    pub async fn process_analysis_retries(mut self) {
+        let (msg, receiver) = GetAnalysisRetriesMessage::new(());
+        if let Err(err) = self.storage_sender.send(StorageMessage::GetAnalysisRetries(msg)).await {
+            tracing::warn!("Failed to message storage for queued analysis retries: {err}");
+            return;
+        }
+        let entries = match receiver.await {
+            Ok(entries) => entries,
+            Err(err) => {
+                tracing::warn!("Failed to receive a response from storage for queued analysis retries: {err}");
+                return;
+            }
+        };
+        if !entries.is_empty() {
+            tracing::info!("Retrying the analysis of {} queued marketplaces", entries.len());
+        }
+        for entry in entries {
+            let AnalysisRetryEntry { gallery_id, marketplace, items, evaluation_criteria, attempts, .. } = entry;
+            tracing::debug!("Retrying analysis of {} {marketplace} items for gallery {gallery_id} (attempt {})", items.len(), attempts + 1);
+            let mut failed_marketplace_reasons = HashMap::new();
+            let mut token_usage = ModelTokenUsage::default();
+            let mut analyzed_items = self.analyzer
+                .analyze_gallery(
+                    HashMap::from([(marketplace.clone(), items.clone())]),
+                    &evaluation_criteria,
+                    None,
+                    &HashMap::new(),
+                    &mut failed_marketplace_reasons,
+                    &mut token_usage
+                )
+                .await;
+            let analyzed_items = analyzed_items
+                .remove(&marketplace)
+                .unwrap_or_else(|| {
+                    let error = failed_marketplace_reasons
+                        .remove(&marketplace)
+                        .unwrap_or_else(|| "Marketplace was missing from the analysis results".into());
+                    MarketplaceAnalyzedItems {
+                        relevant_items: vec![],
+                        irrelevant_items: vec![],
+                        error_items: items
+                            .into_iter()
+                            .map(|item| ErrorAnalyzedMarketplaceItem { item, error: error.clone() })
+                            .collect(),
+                        unsampled_items: vec![]
+                    }
+                });
+            let outcome = AnalysisRetryOutcome {
+                gallery_id: gallery_id.clone(),
+                marketplace,
+                analyzed_items,
+                token_usage,
+                max_attempts: self.retry_max_attempts
+            };
+            let (msg, receiver) = ResolveAnalysisRetryMessage::new(outcome);
+            if let Err(err) = self.storage_sender.send(StorageMessage::ResolveAnalysisRetry(msg)).await {
+                tracing::warn!("Failed to message storage to resolve an analysis retry for gallery {gallery_id}: {err}");
+                continue;
+            }
+            match receiver.await {
+                Ok(Ok(())) => (),
+                Ok(Err(err)) => tracing::warn!("Failed to resolve an analysis retry for gallery {gallery_id}: {err}"),
+                Err(err) => tracing::warn!("Failed to receive a response from storage resolving an analysis retry for gallery {gallery_id}: {err}")
+            }
+        }
+    }
+
+    /// Queues the items of each marketplace which failed analysis in storage, to be retried later.
+    /// 
+    /// This is best-effort; failures to reach storage are logged, and those items aren't retried.
+    async fn enqueue_analysis_retries(
+        &mut self,
+        gallery: &GalleryItemAnalysisState,
+        analyzed_items: &HashMap<Marketplace, MarketplaceAnalyzedItems>
+    ) {
+        if !self.retry_enabled {
+            return;
+        }
+        for (marketplace, items) in analyzed_items {
+            let Some(first_error) = items.error_items.first() else {
+                continue;
+            };
+            let entry = AnalysisRetryEntry {
+                gallery_id: gallery.gallery_id.clone(),
+                marketplace: marketplace.clone(),
+                items: items.error_items
+                    .iter()
+                    .map(|error_item| error_item.item.clone())
+                    .collect(),
+                evaluation_criteria: gallery.evaluation_criteria.clone(),
+                attempts: 0,
+                last_error: first_error.error.clone()
+            };
+            let (msg, receiver) = EnqueueAnalysisRetryMessage::new(entry);
+            if let Err(err) = self.storage_sender.send(StorageMessage::EnqueueAnalysisRetry(msg)).await {
+                tracing::warn!("Failed to message storage to queue {marketplace} items of gallery {} for retry: {err}", gallery.gallery_id);
+                continue;
+            }
+            match receiver.await {
+                Ok(Ok(())) => (),
+                Ok(Err(err)) => tracing::warn!("Failed to queue {marketplace} items of gallery {} for retry: {err}", gallery.gallery_id),
+                Err(err) => tracing::warn!("Failed to receive a response from storage queueing {marketplace} items of gallery {} for retry: {err}", gallery.gallery_id)
+            }
+        }
+    }
+
+    /// Add a new gallery to the state, returning the ID of its run.
     /// 
     /// Returns an `Err` if it already exists.
     async fn add_gallery_to_state(
         &mut self, 
         gallery_id: GalleryId, 
         gallery: GalleryItemAnalysisState
-    ) -> Result<(), ItemAnalysisError> {
+    ) -> Result<RunId, ItemAnalysisError> {
         self.state_tracker_sender
-            .add_gallery(gallery_id.clone(), GalleryPipelineStates::ItemAnalysis(gallery))
+            .add_gallery(GalleryPipelineStates::ItemAnalysis(gallery))
             .await
             .map_err(|err| ItemAnalysisError::Other { 
                 gallery_id: gallery_id.clone(), 
@@ -112,30 +408,33 @@ impl Handler {
         }
     }
 
-    /// Updates the state for a search-scraped gallery.
+    /// Updates the state for an analyzed gallery.
+    /// 
+    /// If the gallery skips embedding, it's moved straight to the final state (and its completion is emitted);
+    /// otherwise it's moved to the item embedding state.
     /// 
     /// Returns an `Err` if:
-    /// - all marketplaces failed to scrape (also removing the gallery from state),
     /// - the gallery is not in state/is in the wrong state/has already been taken,
     /// - the state tracker module couldn't be contacted.
     async fn update_gallery_state(
         &mut self, 
-        gallery_id: GalleryId,
-        analyzed_items: HashMap<Marketplace, MarketplaceAnalyzedItems>,
-        marketplace_updated_datetimes: HashMap<Marketplace, UnixUtcDateTime>,
-        failed_marketplace_reasons: HashMap<Marketplace, String>,
+        gallery: GalleryItemAnalysisState,
+        analyzed_items: HashMap<Marketplace, MarketplaceAnalyzedItems>
     ) -> Result<(), ItemAnalysisError> {
-        let new_state = self.process_to_next_state(
-            gallery_id.clone(), 
-            analyzed_items, 
-            marketplace_updated_datetimes, 
-            failed_marketplace_reasons
-        );
+        let gallery_id = gallery.gallery_id.clone();
+        let new_state = GalleryPipelineStates::ItemAnalysis(gallery)
+            .advance(StageAdvancePayload::ItemsAnalyzed(analyzed_items))
+            .map_err(|err| ItemAnalysisError::Other { 
+                gallery_id: gallery_id.clone(), 
+                message: format!("Could not advance gallery state: {err}")
+            })?;
+        let summary = match &new_state {
+            GalleryPipelineStates::Final(final_state) => Some(FinalStateSummary::new(final_state)),
+            _ => None
+        };
+        let new_stage = new_state.state_type();
         self.state_tracker_sender
-            .update_gallery_state(
-                gallery_id.clone(), 
-                GalleryPipelineStates::ItemEmbedding(new_state)
-            )
+            .update_gallery_state(new_state)
             .await
             .map_err(|err| ItemAnalysisError::Other { 
                 gallery_id: gallery_id.clone(), 
@@ -144,22 +443,11 @@ impl Handler {
             .map_err(|err| ItemAnalysisError::StateErr { 
                 gallery_id, 
                 err 
-            })
-    }
-
-    /// Process the gallery's state into the next state.
-    fn process_to_next_state(
-        &self,
-        gallery_id: GalleryId,
-        analyzed_items: HashMap<Marketplace, MarketplaceAnalyzedItems>,
-        marketplace_updated_datetimes: HashMap<Marketplace, UnixUtcDateTime>,
-        failed_marketplace_reasons: HashMap<Marketplace, String>,
-    ) -> GalleryItemEmbedderState {
-        GalleryItemEmbedderState {
-            gallery_id,
-            items: analyzed_items,
-            marketplace_updated_datetimes,
-            failed_marketplace_reasons,
+            })?;
+        self.pipeline_metrics.record_stage_entered(&new_stage);
+        if let Some(summary) = summary {
+            self.notifier.emit(PipelineEvent::GalleryCompleted(summary));
         }
+        Ok(())
     }
 }
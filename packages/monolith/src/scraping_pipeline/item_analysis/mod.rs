@@ -1,5 +1,8 @@
+use std::{sync::Arc, time::Duration};
+use tokio::{sync::Semaphore, task::JoinHandle};
+use tracing::Instrument;
 use handler::Handler;
-use crate::{config::ItemAnalysisConfig, messages::{message_types::item_analysis::ItemAnalysisMessage, ItemEmbedderSender, ItemAnalysisReceiver, StateTrackerSender}};
+use crate::{config::ItemAnalysisConfig, messages::{message_types::item_analysis::ItemAnalysisMessage, ItemEmbedderSender, ItemAnalysisReceiver, StateTrackerSender, StorageSender}, notifications::Notifier, scraping_pipeline::{module_health::{ModuleHealth, PipelineModule}, pipeline_metrics::PipelineMetrics}, utils::{http_client::HttpClientFactory, tracing_context::module_span}};
 
 mod handler;
 mod analyzer;
@@ -11,43 +14,88 @@ mod analyzer;
 pub struct ItemAnalysisModule {
     config: ItemAnalysisConfig,
     msg_receiver: ItemAnalysisReceiver,
-    handler: Handler
+    handler: Handler,
+    concurrency_limit: Arc<Semaphore>
 }
 
 impl ItemAnalysisModule {
     /// Initialize the module.
     pub fn init(
         config: ItemAnalysisConfig, 
+        http_clients: &HttpClientFactory,
         msg_receiver: ItemAnalysisReceiver,
         state_tracker_sender: StateTrackerSender,
-        image_classifier_sender: ItemEmbedderSender
+        image_classifier_sender: ItemEmbedderSender,
+        storage_sender: StorageSender,
+        notifier: Notifier,
+        pipeline_metrics: Arc<PipelineMetrics>
     ) -> Self {
         let handler = Handler::new(
             &config, 
+            http_clients,
             state_tracker_sender, 
-            image_classifier_sender
+            image_classifier_sender,
+            storage_sender,
+            notifier,
+            pipeline_metrics
         );
+        let concurrency_limit = Arc::new(Semaphore::new(config.max_concurrent_galleries.max(1)));
         Self { 
             config,
             msg_receiver,
-            handler
+            handler,
+            concurrency_limit
         }
     }
 
     /// Start accepting and handling messages.
-    pub async fn run(&mut self) {
+    /// 
+    /// Each message is processed in its own task, with at most `max_concurrent_galleries` tasks running at once.
+    /// 
+    /// If enabled, queued analysis retries are also processed every `retry_queue_interval_secs`,
+    /// skipping a tick if the previous retries are still running.
+    pub async fn run(&mut self, module_health: &ModuleHealth) {
         tracing::info!("ItemAnalysisModule is running...");
-        while let Some(msg) = self.msg_receiver.receive().await {
-            self.process_msg(msg).await;
+        let retry_enabled = self.config.retry_queue_interval_secs > 0;
+        let mut retry_interval = tokio::time::interval(Duration::from_secs(self.config.retry_queue_interval_secs.max(1)));
+        let mut retry_task: Option<JoinHandle<()>> = None;
+        loop {
+            tokio::select! {
+                received = module_health.receive_with_span(PipelineModule::ItemAnalysis, &mut self.msg_receiver) => {
+                    let Some((msg, span)) = received else {
+                        break;
+                    };
+                    let permit = self.concurrency_limit
+                        .clone()
+                        .acquire_owned()
+                        .await
+                        .expect("Semaphore should never be closed");
+                    let mut handler = self.handler.clone();
+                    tokio::spawn(
+                        async move {
+                            Self::process_msg(&mut handler, msg).await;
+                            drop(permit);
+                        }
+                            .instrument(module_span(&span, PipelineModule::ItemAnalysis))
+                    );
+                },
+                _ = retry_interval.tick(), if retry_enabled => {
+                    if retry_task.as_ref().is_some_and(|task| !task.is_finished()) {
+                        tracing::debug!("Previous analysis retries are still running; skipping this tick");
+                        continue;
+                    }
+                    retry_task = Some(tokio::spawn(self.handler.clone().process_analysis_retries()));
+                }
+            }
         }
     }
 
     /// Handle each message variant.
-    async fn process_msg(&mut self, msg: ItemAnalysisMessage) {
+    async fn process_msg(handler: &mut Handler, msg: ItemAnalysisMessage) {
         match msg {
             ItemAnalysisMessage::AnalyzeGallery { gallery_id } => {
                 tracing::trace!("Received message to start analyzing gallery {gallery_id} in state");
-                let schedule_result = self.handler
+                let schedule_result = handler
                     .analyze_gallery_in_state(gallery_id)
                     .await;
                 if let Err(err) = schedule_result {
@@ -56,12 +104,21 @@ impl ItemAnalysisModule {
             },
             ItemAnalysisMessage::AnalyzeGalleryNew { gallery } => {
                 tracing::trace!("Received message to start analyzing new gallery {}", gallery.gallery_id);
-                let schedule_result = self.handler
+                let schedule_result = handler
                     .analyze_new_gallery(gallery)
                     .await;
                 if let Err(err) = schedule_result {
                     tracing::error!("Error(s) performing analysis ({err:#?})");
                 };
+            },
+            ItemAnalysisMessage::PreviewCriteria(msg) => {
+                tracing::trace!("Received message to preview evaluation criteria");
+                let respond_result = msg
+                    .act_async(|request| handler.preview_criteria(request))
+                    .await;
+                if respond_result.is_err() {
+                    tracing::warn!("Failed to respond to a criteria preview message");
+                }
             }
         }
     }
@@ -1,9 +1,11 @@
-use std::{collections::HashMap, error::Error, io::Cursor};
-use image::DynamicImage;
+use std::{collections::HashMap, error::Error, sync::Arc};
+use futures::future::join_all;
+use tokio::sync::Semaphore;
 use reqwest::{multipart::{self, Part}, Client, RequestBuilder};
 use serde::{Deserialize, Serialize};
 
-use crate::{config::ItemEmbedderConfig, galleries::{domain_types::Marketplace, items::pipeline_items::{AnalyzedMarketplaceItem, EmbeddedMarketplaceItem, ErrorEmbeddedMarketplaceItem, MarketplaceAnalyzedItems, MarketplaceEmbeddedAndAnalyzedItems}}};
+use super::image_prep::prepare_image;
+use crate::{config::ItemEmbedderConfig, galleries::{domain_types::Marketplace, items::pipeline_items::{AnalyzedMarketplaceItem, EmbeddedMarketplaceItem, ErrorEmbeddedMarketplaceItem, MarketplaceAnalyzedItems, MarketplaceEmbeddedAndAnalyzedItems, SkippedEmbeddingMarketplaceItem}}};
 
 /// The response from the embedder.
 /// 
@@ -16,37 +18,58 @@ struct EmbedderResponse {
 }
 
 
+/// Why an item's image couldn't be used for embedding.
+enum ItemImageError {
+    /// The image couldn't be fetched; the item is recorded as an embedding error.
+    Fetch(String),
+    /// The image was fetched, but can't be decoded or transcoded; the item is skipped.
+    Unusable(String)
+}
+
 /// In charge of handling requests to the actual embedding service.
+/// 
+/// Clones share the same limit on concurrent image downloads.
+#[derive(Clone)]
 pub(super) struct Embedder {
     config: ItemEmbedderConfig,
-    request_client: Client
+    request_client: Client,
+    image_download_limit: Arc<Semaphore>
 }
 
 impl Embedder {
     /// Initialize the struct.
-    pub fn new(config: ItemEmbedderConfig) -> Self {
+    pub fn new(config: ItemEmbedderConfig, request_client: Client) -> Self {
+        let image_download_limit = Arc::new(Semaphore::new(config.max_concurrent_image_downloads.max(1)));
         Self {
             config,
-            request_client: Client::new()
+            request_client,
+            image_download_limit
         }
     }
 
     /// Embed a gallery's items' description and chosen images.
+    /// 
+    /// Relevant items with fewer than `min_images` images, or whose image can't be decoded or transcoded, are skipped rather than embedded.
     pub async fn embed_gallery(&mut self, items: HashMap<Marketplace, MarketplaceAnalyzedItems>) -> HashMap<Marketplace, MarketplaceEmbeddedAndAnalyzedItems> {
         let mut embedded_items = HashMap::new();
         for (marketplace, items) in items {
+            let (embeddable_items, mut skipped_items) = self.skip_items_with_few_images(items.relevant_items);
             let (
                 request,
                 valid_items,
-                failed_items
-            ) = self.build_marketplace_request(items.relevant_items).await;
+                failed_items,
+                mut unusable_image_items
+            ) = self.build_marketplace_request(embeddable_items).await;
+            skipped_items.append(&mut unusable_image_items);
             let marketplace_items = match self.execute_and_handle_request(request, valid_items).await {
                 Ok(marketplace_embedded_items) => {
                     MarketplaceEmbeddedAndAnalyzedItems {
                         embedded_items: marketplace_embedded_items,
                         irrelevant_analyzed_items: items.irrelevant_items,
                         error_analyzed_items: items.error_items,
-                        error_embedded_items: Vec::new()
+                        error_embedded_items: Vec::new(),
+                        skipped_embedding_items: skipped_items,
+                        unsampled_items: items.unsampled_items
                     }
                 },
                 Err((error_valid_items, err)) => {
@@ -58,7 +81,9 @@ impl Embedder {
                         embedded_items: Vec::new(),
                         irrelevant_analyzed_items: items.irrelevant_items,
                         error_analyzed_items: items.error_items,
-                        error_embedded_items: error_items
+                        error_embedded_items: error_items,
+                        skipped_embedding_items: skipped_items,
+                        unsampled_items: items.unsampled_items
                     }
                 }
             };
@@ -67,34 +92,60 @@ impl Embedder {
         embedded_items
     }
 
+    /// Splits items into those with at least `min_images` images, and those skipped for having fewer.
+    fn skip_items_with_few_images(&self, items: Vec<AnalyzedMarketplaceItem>) -> (Vec<AnalyzedMarketplaceItem>, Vec<SkippedEmbeddingMarketplaceItem>) {
+        let min_images = self.config.min_images;
+        let mut embeddable_items = Vec::new();
+        let mut skipped_items = Vec::new();
+        for item in items {
+            let num_images = item.item.thumbnails.len();
+            match num_images >= min_images {
+                true => embeddable_items.push(item),
+                false => {
+                    tracing::debug!("Skipping embedding of item {}: it has {num_images} images (min: {min_images})", item.item.id);
+                    let reason = format!("Item has {num_images} images, fewer than the min of {min_images}");
+                    skipped_items.push(SkippedEmbeddingMarketplaceItem { item, reason });
+                }
+            }
+        }
+        (embeddable_items, skipped_items)
+    }
+
     /// Builds the request for items under a marketplace.
     /// 
     /// Returns:
     /// - The request
     /// - The items for which the request was successfully built
     /// - Items which encountered errors during image fetching (as `ErrorEmbeddedMarketplaceItem`)
+    /// - Items whose image couldn't be decoded or transcoded (as `SkippedEmbeddingMarketplaceItem`)
     async fn build_marketplace_request(&mut self, items: Vec<AnalyzedMarketplaceItem>) 
-    -> (RequestBuilder, Vec<AnalyzedMarketplaceItem>, Vec<ErrorEmbeddedMarketplaceItem>) {
+    -> (RequestBuilder, Vec<AnalyzedMarketplaceItem>, Vec<ErrorEmbeddedMarketplaceItem>, Vec<SkippedEmbeddingMarketplaceItem>) {
         let mut form = multipart::Form::new();
         let mut failed_items = Vec::new();
+        let mut skipped_items = Vec::new();
         let mut valid_items_and_images = Vec::new();
-        for item in items {
-            match self
-                .get_item_image(&item.item.thumbnails, item.best_fit_image)
-                .await {
-                    Ok(item_image) => valid_items_and_images.push((item, item_image)),
-                    Err(error) => {
-                        let err_item = ErrorEmbeddedMarketplaceItem { item, error };
-                        failed_items.push(err_item);
-                    }
+        let image_futures = items
+            .iter()
+            .map(|item| self.get_item_image(&item.item.thumbnails, item.best_fit_image));
+        let images = join_all(image_futures).await;
+        for (item, image) in items.into_iter().zip(images) {
+            match image {
+                Ok(item_image) => valid_items_and_images.push((item, item_image)),
+                Err(ItemImageError::Unusable(reason)) => {
+                    tracing::debug!("Skipping embedding of item {}: {reason}", item.item.id);
+                    skipped_items.push(SkippedEmbeddingMarketplaceItem { item, reason });
+                },
+                Err(ItemImageError::Fetch(error)) => {
+                    tracing::debug!("Skipping embedding of item {}: {error}", item.item.id);
+                    let err_item = ErrorEmbeddedMarketplaceItem { item, error };
+                    failed_items.push(err_item);
                 }
+            }
         }
         // We add parts to the form in order of the valid items; the embedder will return embeddings in the same order
         let mut valid_items = Vec::new();
         for (index, (valid_item, item_image)) in valid_items_and_images.into_iter().enumerate() {
-            let mut image_bytes = Cursor::new(Vec::new());
-            item_image.write_to(&mut image_bytes, image::ImageFormat::Png);
-            let image_part = Part::bytes(image_bytes.into_inner())
+            let image_part = Part::bytes(item_image)
                 .file_name(format!("image{index}"));
             let text_part = Part::text(valid_item.item_description.clone());
             form = form
@@ -105,7 +156,7 @@ impl Embedder {
         let request = self.request_client
             .post(&self.config.embedder_endpoint)
             .multipart(form);
-        (request, valid_items, failed_items)
+        (request, valid_items, failed_items, skipped_items)
     }
 
     /// Executes and handles the request for a marketplace.
@@ -140,7 +191,9 @@ impl Embedder {
                                             evaluation_answers: item.evaluation_answers,
                                             item_description: item.item_description,
                                             description_embedding: text_embedding,
-                                            image_embedding
+                                            image_embedding,
+                                            confidence: item.confidence,
+                                            analysis_provider: item.analysis_provider
                                         }
                                     )
                                     .collect();
@@ -168,35 +221,60 @@ impl Embedder {
     /// Fetches the image pointed by the item's `best_fit_image`.
     /// If this number is invalid for some reason, fetches the first image.
     /// 
-    /// Returns an `Err` if the image couldn't be fetched, or some error occurred during its parsing.
+    /// At most `max_concurrent_image_downloads` images are downloaded at once.
+    /// 
+    /// The image is then prepared for the embedder (see `prepare_image`), so it's returned as encoded bytes.
+    /// 
+    /// Returns an `Err` if the image couldn't be fetched or was larger than `max_image_bytes`,
+    /// or an `Unusable` one if it couldn't be decoded or transcoded.
     async fn get_item_image(
-        &mut self, 
+        &self, 
         image_urls: &Vec<String>,
         best_fit_image: usize
-    ) -> Result<DynamicImage, String> {
+    ) -> Result<Vec<u8>, ItemImageError> {
         let chosen_image_url = match image_urls.get(best_fit_image) {
             Some(url) => url,
             None => match image_urls.get(0) {
                 Some(url) => url,
-                None => return Err("Item doesn't contain any image URLs".to_string())
+                None => return Err(ItemImageError::Fetch("Item doesn't contain any image URLs".to_string()))
             }
         };
-        match self.request_client
-            .get(chosen_image_url)
+        let bytes = {
+            let _permit = self.image_download_limit
+                .acquire()
+                .await
+                .expect("Semaphore should never be closed");
+            self.download_image(chosen_image_url)
+                .await
+                .map_err(ItemImageError::Fetch)?
+        };
+        prepare_image(bytes, &self.config).map_err(ItemImageError::Unusable)
+    }
+
+    /// Downloads an image, streaming its body so the download is aborted as soon as it exceeds `max_image_bytes`.
+    /// 
+    /// Returns an `Err` if the image couldn't be fetched, or was too large.
+    async fn download_image(&self, image_url: &str) -> Result<Vec<u8>, String> {
+        let max_bytes = self.config.max_image_bytes;
+        let mut res = self.request_client
+            .get(image_url)
             .send()
-            .await {
-                Ok(res) => {
-                    match res.bytes().await {
-                        Ok(bytes) => {
-                            match image::load_from_memory(&bytes) {
-                                Ok(image) => Ok(image),
-                                Err(err) => Err(format!("Failed to decode fetched image URL bytes into an image: {err}"))
-                            }
-                        },
-                        Err(err) => Err(format!("Failed to decode fetched image URL into bytes: {err}"))
-                    }
-                },
-                Err(err) => Err(format!("Failed to fetch an image URL: {err}"))
+            .await
+            .map_err(|err| format!("Failed to fetch an image URL: {err}"))?;
+        if res.content_length().is_some_and(|len| len as usize > max_bytes) {
+            return Err(format!("Image is larger than the max of {max_bytes} bytes (Content-Length: {:?})", res.content_length()));
+        }
+        let mut bytes = Vec::new();
+        while let Some(chunk) = res
+            .chunk()
+            .await
+            .map_err(|err| format!("Failed to decode fetched image URL into bytes: {err}"))? 
+        {
+            if bytes.len() + chunk.len() > max_bytes {
+                return Err(format!("Image is larger than the max of {max_bytes} bytes; aborted its download"));
             }
+            bytes.extend_from_slice(&chunk);
+        }
+        Ok(bytes)
     }
 }
\ No newline at end of file
@@ -1,10 +1,15 @@
-use std::collections::HashMap;
+use std::{collections::HashMap, sync::Arc};
+use tokio_util::sync::CancellationToken;
+use tracing::Instrument;
 use crate::{
     config::ItemEmbedderConfig, 
-    galleries::{domain_types::{GalleryId, Marketplace, UnixUtcDateTime}, items::pipeline_items::MarketplaceEmbeddedAndAnalyzedItems, pipeline_states::{GalleryFinalState, GalleryItemEmbedderState, GalleryPipelineStateTypes, GalleryPipelineStates}}, 
+    galleries::{domain_types::{GalleryId, RunId, Marketplace}, items::pipeline_items::MarketplaceEmbeddedAndAnalyzedItems, pipeline_states::{GalleryItemEmbedderState, GalleryPipelineStateTypes, GalleryPipelineStates, StageAdvancePayload}}, 
     messages::{
         message_types::{item_embedder::ItemEmbedderError, storage::StorageMessage}, StateTrackerSender, StorageSender
-    }
+    },
+    notifications::{FinalStateSummary, Notifier, PipelineEvent},
+    scraping_pipeline::pipeline_metrics::PipelineMetrics,
+    utils::{http_client::HttpClientFactory, tracing_context::gallery_run_span}
 };
 
 use super::embedder::Embedder;
@@ -28,52 +33,83 @@ to the gallery's past data.
 */
 
 /// Coordinates the internal workings of the module.
+/// 
+/// This is cloned for each gallery being processed, so its fields must be cheaply cloneable.
+#[derive(Clone)]
 pub(super) struct Handler {
     state_tracker_sender: StateTrackerSender,
     storage_sender: StorageSender,
-    embedder: Embedder
+    embedder: Embedder,
+    notifier: Notifier,
+    pipeline_metrics: Arc<PipelineMetrics>
 }
 
 impl Handler {
     /// Instantiate the state.
     pub fn new(
         config: &ItemEmbedderConfig,
+        http_clients: &HttpClientFactory,
         state_tracker_sender: StateTrackerSender,
-        storage_sender: StorageSender
+        storage_sender: StorageSender,
+        notifier: Notifier,
+        pipeline_metrics: Arc<PipelineMetrics>
     ) -> Self {
-        let embedder = Embedder::new(config.clone());
+        let embedder = Embedder::new(config.clone(), http_clients.client());
         Self {
             state_tracker_sender,
             storage_sender,
-            embedder
+            embedder,
+            notifier,
+            pipeline_metrics
         }
     }
     
     /// Embed a new gallery.
     pub async fn embed_new_gallery(&mut self, gallery: GalleryItemEmbedderState) -> Result<(), ItemEmbedderError> {
         let gallery_id = gallery.gallery_id.clone();
-        self.add_gallery_to_state(gallery_id.clone(), gallery).await?;
-        self.embed_gallery_in_state(gallery_id).await
+        let run_id = self.add_gallery_to_state(gallery_id.clone(), gallery).await?;
+        self.embed_gallery_in_state(gallery_id.clone())
+            .instrument(gallery_run_span(&gallery_id, &run_id))
+            .await
     }
 
     /// Embed items of a gallery in state.
+    /// 
+    /// Aborts if the gallery's run is cancelled (ie it's deleted) in the meantime.
     pub async fn embed_gallery_in_state(&mut self, gallery_id: GalleryId) -> Result<(), ItemEmbedderError> {
-        let gallery = self.fetch_gallery_state(gallery_id).await?;
-        self.embed_gallery(gallery).await
+        let cancellation = self.fetch_cancellation_token(gallery_id.clone()).await?;
+        let gallery = self.fetch_gallery_state(gallery_id.clone()).await?;
+        match cancellation.run_until_cancelled(self.embed_gallery(gallery)).await {
+            Some(result) => result,
+            None => {
+                tracing::info!("Gallery {gallery_id} was cancelled; aborted its embedding");
+                Ok(())
+            }
+        }
+    }
+
+    /// Fetch the cancellation token for the gallery's current run.
+    async fn fetch_cancellation_token(&mut self, gallery_id: GalleryId) -> Result<CancellationToken, ItemEmbedderError> {
+        self.state_tracker_sender
+            .get_cancellation_token(gallery_id.clone())
+            .await
+            .map_err(|err| ItemEmbedderError::Other { 
+                gallery_id: gallery_id.clone(), 
+                message: format!("Could not receive response from state tracker: {err}") 
+            })?
+            .map_err(|err| ItemEmbedderError::StateErr { 
+                gallery_id, 
+                err 
+            })
     }
 
     /// Embed a gallery's items' descriptions + images and send it to the next stage.
-    async fn embed_gallery(&mut self, gallery: GalleryItemEmbedderState) -> Result<(), ItemEmbedderError> {
+    async fn embed_gallery(&mut self, mut gallery: GalleryItemEmbedderState) -> Result<(), ItemEmbedderError> {
         let embedded_items = self.embedder
-            .embed_gallery(gallery.items)
+            .embed_gallery(std::mem::take(&mut gallery.items))
             .await;
         let gallery_id = gallery.gallery_id.clone();
-        self.update_gallery_state(
-            gallery.gallery_id,
-            embedded_items,
-            gallery.marketplace_updated_datetimes,
-            gallery.failed_marketplace_reasons,
-        ).await?;
+        self.update_gallery_state(gallery, embedded_items).await?;
         self.storage_sender
             .send(StorageMessage::StoreGallery { gallery_id: gallery_id.clone() })
             .await
@@ -81,16 +117,16 @@ impl Handler {
             Ok(())
     }
     
-    /// Add a new gallery to the state.
+    /// Add a new gallery to the state, returning the ID of its run.
     /// 
     /// Returns an `Err` if it already exists.
     async fn add_gallery_to_state(
         &mut self, 
         gallery_id: GalleryId, 
         gallery: GalleryItemEmbedderState
-    ) -> Result<(), ItemEmbedderError> {
+    ) -> Result<RunId, ItemEmbedderError> {
         self.state_tracker_sender
-            .add_gallery(gallery_id.clone(), GalleryPipelineStates::ItemEmbedding(gallery))
+            .add_gallery(GalleryPipelineStates::ItemEmbedding(gallery))
             .await
             .map_err(|err| ItemEmbedderError::Other { 
                 gallery_id: gallery_id.clone(), 
@@ -129,27 +165,32 @@ impl Handler {
         }
     }
 
-    /// Updates the state for a search-scraped gallery.
+    /// Updates the state for an embedded gallery.
     /// 
     /// Returns an `Err` if:
-    /// - all marketplaces failed to scrape (also removing the gallery from state),
     /// - the gallery is not in state/is in the wrong state/has already been taken,
     /// - the state tracker module couldn't be contacted.
     async fn update_gallery_state(
         &mut self, 
-        gallery_id: GalleryId,
-        embedded_items: HashMap<Marketplace, MarketplaceEmbeddedAndAnalyzedItems>,
-        marketplace_updated_datetimes: HashMap<Marketplace, UnixUtcDateTime>,
-        failed_marketplace_reasons: HashMap<Marketplace, String>,
+        gallery: GalleryItemEmbedderState,
+        embedded_items: HashMap<Marketplace, MarketplaceEmbeddedAndAnalyzedItems>
     ) -> Result<(), ItemEmbedderError> {
-        let new_state = self.process_to_next_state(
-            gallery_id.clone(), 
-            embedded_items, 
-            marketplace_updated_datetimes, 
-            failed_marketplace_reasons
-        );
+        let gallery_id = gallery.gallery_id.clone();
+        let new_state = GalleryPipelineStates::ItemEmbedding(gallery)
+            .advance(StageAdvancePayload::ItemsEmbedded(embedded_items))
+            .map_err(|err| ItemEmbedderError::Other { 
+                gallery_id: gallery_id.clone(), 
+                message: format!("Could not advance gallery state: {err}")
+            })?;
+        let GalleryPipelineStates::Final(final_state) = &new_state else {
+            return Err(ItemEmbedderError::Other { 
+                gallery_id, 
+                message: "Embedded gallery didn't advance to the final state".into()
+            });
+        };
+        let summary = FinalStateSummary::new(final_state);
         self.state_tracker_sender
-            .update_gallery_state(gallery_id.clone(), GalleryPipelineStates::Final(new_state))
+            .update_gallery_state(new_state)
             .await
             .map_err(|err| ItemEmbedderError::Other { 
                 gallery_id: gallery_id.clone(), 
@@ -158,22 +199,9 @@ impl Handler {
             .map_err(|err| ItemEmbedderError::StateErr { 
                 gallery_id, 
                 err 
-            })
-    }
-
-    /// Process the gallery's state into the next state.
-    fn process_to_next_state(
-        &self,
-        gallery_id: GalleryId,
-        embedded_items: HashMap<Marketplace, MarketplaceEmbeddedAndAnalyzedItems>,
-        marketplace_updated_datetimes: HashMap<Marketplace, UnixUtcDateTime>,
-        failed_marketplace_reasons: HashMap<Marketplace, String>,
-    ) -> GalleryFinalState {
-        GalleryFinalState {
-            gallery_id,
-            items: embedded_items,
-            marketplace_updated_datetimes,
-            failed_marketplace_reasons,
-        }
+            })?;
+        self.pipeline_metrics.record_stage_entered(&GalleryPipelineStateTypes::Final);
+        self.notifier.emit(PipelineEvent::GalleryCompleted(summary));
+        Ok(())
     }
 }
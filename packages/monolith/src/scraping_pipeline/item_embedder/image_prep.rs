@@ -0,0 +1,108 @@
+//! Contains the preparation of downloaded images into a form the embedder can ingest.
+use std::io::Cursor;
+use image::{imageops::FilterType, DynamicImage, ImageFormat};
+use crate::config::ItemEmbedderConfig;
+
+/// The formats the embedder can ingest as is.
+const SUPPORTED_FORMATS: [ImageFormat; 2] = [ImageFormat::Jpeg, ImageFormat::Png];
+
+/// Prepare a downloaded image for the embedder:
+/// - images in a supported format (JPEG or PNG) within `max_image_dimension` are passed through untouched
+/// - images in other formats (ie WebP or AVIF) are transcoded into `transcode_format`, if `transcode_images` is set
+/// - images larger than `max_image_dimension` are downscaled to fit (keeping their aspect ratio), and re-encoded into `transcode_format`
+/// 
+/// Returns an `Err` with the reason if the image can't be decoded, or is in an unsupported format and transcoding is disabled.
+pub(super) fn prepare_image(bytes: Vec<u8>, config: &ItemEmbedderConfig) -> Result<Vec<u8>, String> {
+    let format = image::guess_format(&bytes)
+        .map_err(|err| format!("Could not detect the image's format: {err}"))?;
+    let image = image::load_from_memory_with_format(&bytes, format)
+        .map_err(|err| format!("Could not decode the image ({format:?}): {err}"))?;
+    let is_supported = SUPPORTED_FORMATS.contains(&format);
+    if !is_supported && !config.transcode_images {
+        return Err(format!("Image is in an unsupported format ({format:?}), and transcoding is disabled"));
+    }
+    let max_dimension = config.max_image_dimension;
+    let is_oversized = max_dimension > 0 && (image.width() > max_dimension || image.height() > max_dimension);
+    if is_supported && !is_oversized {
+        return Ok(bytes);
+    }
+    let image = match is_oversized {
+        true => image.resize(max_dimension, max_dimension, FilterType::Triangle),
+        false => image
+    };
+    encode(image, config.transcode_format.image_format())
+}
+
+/// Encode an image into the format.
+/// 
+/// JPEG has no alpha channel, so images are flattened to RGB for it.
+fn encode(image: DynamicImage, format: ImageFormat) -> Result<Vec<u8>, String> {
+    let image = match format {
+        ImageFormat::Jpeg => DynamicImage::ImageRgb8(image.into_rgb8()),
+        _ => image
+    };
+    let mut encoded = Cursor::new(Vec::new());
+    image
+        .write_to(&mut encoded, format)
+        .map_err(|err| format!("Could not encode the image as {format:?}: {err}"))?;
+    Ok(encoded.into_inner())
+}
+
+#[cfg(test)]
+mod tests {
+    use image::RgbaImage;
+    use crate::config::image_classifier::TranscodeFormat;
+    use super::*;
+
+    fn config(transcode_images: bool, max_image_dimension: u32) -> ItemEmbedderConfig {
+        ItemEmbedderConfig {
+            embedder_endpoint: String::new(),
+            max_concurrent_galleries: 1,
+            max_concurrent_image_downloads: 1,
+            max_image_bytes: 0,
+            min_images: 1,
+            transcode_images,
+            transcode_format: TranscodeFormat::Png,
+            max_image_dimension
+        }
+    }
+
+    /// A `width` by `height` image, encoded in the format.
+    fn image_fixture(width: u32, height: u32, format: ImageFormat) -> Vec<u8> {
+        let image = DynamicImage::ImageRgba8(RgbaImage::from_pixel(width, height, [200, 100, 50, 255].into()));
+        let mut encoded = Cursor::new(Vec::new());
+        image.write_to(&mut encoded, format).unwrap();
+        encoded.into_inner()
+    }
+
+    #[test]
+    fn a_webp_image_is_transcoded() {
+        let prepared = prepare_image(image_fixture(8, 4, ImageFormat::WebP), &config(true, 0)).unwrap();
+        assert_eq!(image::guess_format(&prepared).unwrap(), ImageFormat::Png);
+        let image = image::load_from_memory(&prepared).unwrap();
+        assert_eq!((image.width(), image.height()), (8, 4));
+    }
+
+    #[test]
+    fn a_webp_image_is_rejected_if_transcoding_is_disabled() {
+        assert!(prepare_image(image_fixture(8, 4, ImageFormat::WebP), &config(false, 0)).is_err());
+    }
+
+    #[test]
+    fn a_supported_image_within_the_max_dimension_is_untouched() {
+        let bytes = image_fixture(8, 4, ImageFormat::Png);
+        assert_eq!(prepare_image(bytes.clone(), &config(true, 8)).unwrap(), bytes);
+    }
+
+    #[test]
+    fn an_oversized_image_is_downscaled_to_fit() {
+        let prepared = prepare_image(image_fixture(40, 20, ImageFormat::Png), &config(true, 10)).unwrap();
+        let image = image::load_from_memory(&prepared).unwrap();
+        assert_eq!((image.width(), image.height()), (10, 5));
+    }
+
+    #[test]
+    fn an_undecodable_image_is_rejected() {
+        assert!(prepare_image(b"not an image".to_vec(), &config(true, 0)).is_err());
+    }
+}
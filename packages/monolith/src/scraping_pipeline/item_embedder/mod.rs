@@ -1,54 +1,81 @@
 // TODO: https://towardsdatascience.com/building-an-image-similarity-search-engine-with-faiss-and-clip-2211126d08fa 
 // this sounds pretty solid
 
+use std::sync::Arc;
+use tokio::sync::Semaphore;
+use tracing::Instrument;
 use handler::Handler;
 
-use crate::{config::ItemEmbedderConfig, messages::{message_types::item_embedder::ItemEmbedderMessage, ItemEmbedderReceiver, StateTrackerSender, StorageSender}};
+use crate::{config::ItemEmbedderConfig, messages::{message_types::item_embedder::ItemEmbedderMessage, ItemEmbedderReceiver, StateTrackerSender, StorageSender}, notifications::Notifier, scraping_pipeline::{module_health::{ModuleHealth, PipelineModule}, pipeline_metrics::PipelineMetrics}, utils::{http_client::HttpClientFactory, tracing_context::module_span}};
 
 mod handler;
 mod embedder;
+mod image_prep;
 
 /// This module handles classification of scraped and analyzed items under a gallery.
 pub struct ItemEmbedderModule {
     config: ItemEmbedderConfig,
     msg_receiver: ItemEmbedderReceiver,
-    handler: Handler
+    handler: Handler,
+    concurrency_limit: Arc<Semaphore>
 }
 
 impl ItemEmbedderModule {
     /// Instantiate the module.
     pub fn init(
         config: ItemEmbedderConfig,
+        http_clients: &HttpClientFactory,
         msg_receiver: ItemEmbedderReceiver,
         state_tracker_sender: StateTrackerSender,
-        storage_sender: StorageSender
+        storage_sender: StorageSender,
+        notifier: Notifier,
+        pipeline_metrics: Arc<PipelineMetrics>
     ) -> Self {
         let handler = Handler::new(
             &config, 
+            http_clients,
             state_tracker_sender, 
-            storage_sender
+            storage_sender,
+            notifier,
+            pipeline_metrics
         );
+        let concurrency_limit = Arc::new(Semaphore::new(config.max_concurrent_galleries.max(1)));
         Self {
             config,
             msg_receiver,
-            handler
+            handler,
+            concurrency_limit
         }
     }
     
     /// Start accepting and handling messages.
-    pub async fn run(&mut self) {
+    /// 
+    /// Each message is processed in its own task, with at most `max_concurrent_galleries` tasks running at once.
+    pub async fn run(&mut self, module_health: &ModuleHealth) {
         tracing::info!("ItemEmbedderModule is running...");
-        while let Some(msg) = self.msg_receiver.receive().await {
-            self.process_msg(msg).await;
+        while let Some((msg, span)) = module_health.receive_with_span(PipelineModule::ItemEmbedder, &mut self.msg_receiver).await {
+            let permit = self.concurrency_limit
+                .clone()
+                .acquire_owned()
+                .await
+                .expect("Semaphore should never be closed");
+            let mut handler = self.handler.clone();
+            tokio::spawn(
+                async move {
+                    Self::process_msg(&mut handler, msg).await;
+                    drop(permit);
+                }
+                    .instrument(module_span(&span, PipelineModule::ItemEmbedder))
+            );
         }
     }
 
     /// Handle each message variant.
-    async fn process_msg(&mut self, msg: ItemEmbedderMessage) {
+    async fn process_msg(handler: &mut Handler, msg: ItemEmbedderMessage) {
         match msg {
             ItemEmbedderMessage::Classify { gallery_id } => {
                 tracing::trace!("Received message to start embedding gallery {} in state", gallery_id);
-                let schedule_result = self.handler
+                let schedule_result = handler
                     .embed_gallery_in_state(gallery_id)
                     .await;
                 if let Err(err) = schedule_result {
@@ -57,7 +84,7 @@ impl ItemEmbedderModule {
             },
             ItemEmbedderMessage::ClassifyNew { gallery } => {
                 tracing::trace!("Received message to start embedding new gallery {}", gallery.gallery_id);
-                let schedule_result = self.handler
+                let schedule_result = handler
                     .embed_new_gallery(gallery)
                     .await;
                 if let Err(err) = schedule_result {
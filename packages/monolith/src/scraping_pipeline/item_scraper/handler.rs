@@ -1,53 +1,155 @@
-use std::collections::HashMap;
+use std::{collections::HashMap, sync::Arc, time::Duration};
+use futures::{stream::FuturesUnordered, StreamExt};
+use tokio_util::sync::CancellationToken;
+use tracing::Instrument;
 use crate::{
     config::ItemScraperConfig, 
-    galleries::{domain_types::{GalleryId, Marketplace}, items::item_data::MarketplaceItemData, pipeline_states::{GalleryItemAnalysisState, GalleryItemScrapingState, GalleryPipelineStateTypes, GalleryPipelineStates}}, 
-    messages::{message_types::{item_analysis::ItemAnalysisMessage, item_scraper::ItemScraperError}, ItemAnalysisSender, StateTrackerSender}
+    galleries::{domain_types::{GalleryId, RunId, Marketplace, UnixUtcDateTime}, items::item_data::MarketplaceItemData, pipeline_states::{GalleryItemScrapingState, GalleryPipelineStateTypes, GalleryPipelineStates, StageAdvancePayload, StateTransitionError}}, 
+    messages::{message_types::{item_analysis::ItemAnalysisMessage, item_scraper::ItemScraperError, storage::{GetScrapedItemsMessage, StorageMessage, UpsertScrapedItemsMessage}}, ItemAnalysisSender, StateTrackerSender, StorageSender},
+    scraping_pipeline::pipeline_metrics::PipelineMetrics,
+    utils::{http_client::HttpClientFactory, tracing_context::gallery_run_span}
     };
 
 use super::scrapers::ItemScraper;
 
+/// The outcome of scraping a marketplace's items, including any retries.
+struct MarketplaceScrape {
+    marketplace: Marketplace,
+    results: Vec<Result<MarketplaceItemData, String>>,
+    /// The number of retries so far, including any from an interrupted run.
+    retry_attempts: u32,
+    /// Set if the marketplace still failed after the max retries, in which case it has no results.
+    failure_reason: Option<String>
+}
+
 /// Coordinates the internal workings of the module.
+/// 
+/// This is cloned for each gallery being processed, so its fields must be cheaply cloneable.
+#[derive(Clone)]
 pub(super) struct Handler {
     state_tracker_sender: StateTrackerSender,
     item_analysis_sender: ItemAnalysisSender,
-    item_scraper: ItemScraper
+    storage_sender: StorageSender,
+    item_scraper: ItemScraper,
+    max_retries: u32,
+    retry_base_delay: Duration,
+    item_refetch_ttl: Duration,
+    pipeline_metrics: Arc<PipelineMetrics>
 }
 
 impl Handler {
     /// Instantiate the state.
     pub fn new(
         config: &ItemScraperConfig,
+        http_clients: &HttpClientFactory,
         state_tracker_sender: StateTrackerSender,
-        item_analysis_sender: ItemAnalysisSender
+        item_analysis_sender: ItemAnalysisSender,
+        storage_sender: StorageSender,
+        pipeline_metrics: Arc<PipelineMetrics>
     ) -> Self {
-        let item_scraper = ItemScraper::new(config);
+        let item_scraper = ItemScraper::new(config, http_clients);
         Self {
             state_tracker_sender,
             item_analysis_sender,
-            item_scraper
+            storage_sender,
+            item_scraper,
+            max_retries: config.max_retries,
+            retry_base_delay: Duration::from_millis(config.retry_base_delay_ms),
+            item_refetch_ttl: Duration::from_secs(config.item_refetch_ttl_secs),
+            pipeline_metrics
         }
     }
     
     /// Perform the entire scraping of a new gallery.
     pub async fn scrape_new_gallery(&mut self, gallery: GalleryItemScrapingState) -> Result<(), ItemScraperError> {
         let gallery_id = gallery.gallery_id.clone();
-        self.add_gallery_to_state(gallery_id.clone(), gallery).await?;
-        self.scrape_gallery_in_state(gallery_id).await
+        let run_id = self.add_gallery_to_state(gallery_id.clone(), gallery).await?;
+        self.scrape_gallery_in_state(gallery_id.clone())
+            .instrument(gallery_run_span(&gallery_id, &run_id))
+            .await
     }
 
     /// Perform the scraping of a gallery in state.
+    /// 
+    /// Aborts if the gallery's run is cancelled (ie it's deleted) in the meantime.
     pub async fn scrape_gallery_in_state(&mut self, gallery_id: GalleryId) -> Result<(), ItemScraperError> {
-        let gallery = self.fetch_gallery_state(gallery_id).await?;
-        self.scrape_gallery(gallery).await
+        let cancellation = self.fetch_cancellation_token(gallery_id.clone()).await?;
+        let gallery = self.fetch_gallery_state(gallery_id.clone()).await?;
+        match cancellation.run_until_cancelled(self.scrape_gallery(gallery)).await {
+            Some(result) => result,
+            None => {
+                tracing::info!("Gallery {gallery_id} was cancelled; aborted its scraping");
+                Ok(())
+            }
+        }
     }
 
-    /// Scrapes the search for a gallery and sends it to the item scraper.
-    async fn scrape_gallery(&mut self, gallery: GalleryItemScrapingState) -> Result<(), ItemScraperError> {
-        let scraped_items = self.item_scraper
-            .scrape_items(&gallery)
-            .await;
+    /// Fetch the cancellation token for the gallery's current run.
+    async fn fetch_cancellation_token(&mut self, gallery_id: GalleryId) -> Result<CancellationToken, ItemScraperError> {
+        self.state_tracker_sender
+            .get_cancellation_token(gallery_id.clone())
+            .await
+            .map_err(|err| ItemScraperError::Other { 
+                gallery_id: gallery_id.clone(), 
+                message: format!("Could not receive response from state tracker: {err}") 
+            })?
+            .map_err(|err| ItemScraperError::StateErr { 
+                gallery_id, 
+                err 
+            })
+    }
+
+    /// Scrapes the gallery's items and sends it to item analysis.
+    /// 
+    /// Marketplaces are scraped concurrently, checkpointing the gallery's progress to the state tracker as each one finishes;
+    /// marketplaces which already finished in an interrupted run are skipped, so only the rest are scraped.
+    /// Within the rest, items which were already scraped and aren't stale are reused (see `fetch_fresh_scraped_items`).
+    async fn scrape_gallery(&mut self, mut gallery: GalleryItemScrapingState) -> Result<(), ItemScraperError> {
         let gallery_id = gallery.gallery_id.clone();
+        let remaining_marketplaces: Vec<_> = gallery.item_ids
+            .keys()
+            .filter(|marketplace| !gallery.completed_marketplaces.contains_key(marketplace))
+            .filter(|marketplace| !gallery.failed_marketplace_reasons.contains_key(marketplace))
+            .cloned()
+            .collect();
+        if !gallery.completed_marketplaces.is_empty() {
+            tracing::info!(
+                "Resuming item scrape for gallery {gallery_id}; skipping {} completed marketplaces, scraping {} remaining", 
+                gallery.completed_marketplaces.len(),
+                remaining_marketplaces.len()
+            );
+        }
+        let mut fresh_items = self.fetch_fresh_scraped_items(&gallery).await;
+        // The scrapes only read from a copy of the handler, so it can still be used to checkpoint each one as it finishes
+        let scraper = self.clone();
+        let initial_gallery = gallery.clone();
+        let mut marketplace_scrapes: FuturesUnordered<_> = remaining_marketplaces
+            .into_iter()
+            .map(|marketplace| {
+                let marketplace_fresh_items = fresh_items.remove(&marketplace).unwrap_or_default();
+                scraper.scrape_marketplace_with_retries(&initial_gallery, marketplace, marketplace_fresh_items)
+            })
+            .collect();
+        while let Some(marketplace_scrape) = marketplace_scrapes.next().await {
+            let marketplace = marketplace_scrape.marketplace;
+            match marketplace_scrape.failure_reason {
+                Some(reason) => {
+                    gallery.failed_marketplace_reasons.insert(marketplace.clone(), reason);
+                    gallery.marketplace_retry_attempts.remove(&marketplace);
+                },
+                None => {
+                    if marketplace_scrape.retry_attempts > 0 {
+                        gallery.marketplace_retry_attempts.insert(marketplace.clone(), marketplace_scrape.retry_attempts);
+                    }
+                    let scraped_items = HashMap::from([(marketplace, marketplace_scrape.results)]);
+                    self.persist_scraped_items(&gallery_id, &scraped_items).await;
+                    gallery.completed_marketplaces.extend(scraped_items);
+                }
+            }
+            self.checkpoint_gallery_state(&gallery).await?;
+        }
+        let scraped_items = std::mem::take(&mut gallery.completed_marketplaces);
+        self.record_scrape_diff(&gallery_id, &scraped_items).await;
         self.update_gallery_state(
             gallery,
             scraped_items.clone()
@@ -59,16 +161,196 @@ impl Handler {
             Ok(())
     }
     
-    /// Add a new gallery to the state.
+    /// Scrapes a marketplace's items, re-scraping them with exponential backoff while they all fail.
+    /// 
+    /// If the gallery has a `max_items_per_marketplace`, only that many items are scraped.
+    /// Items in `fresh_items` aren't scraped again, and are returned as is; a marketplace with any of these never fails.
+    /// 
+    /// Retries carry on from the marketplace's attempts in the gallery (ie from an interrupted run).
+    /// If it still fails after `max_retries` attempts, its failure reason is returned instead of its results.
+    async fn scrape_marketplace_with_retries(
+        &self, 
+        gallery: &GalleryItemScrapingState, 
+        marketplace: Marketplace, 
+        fresh_items: Vec<MarketplaceItemData>
+    ) -> MarketplaceScrape {
+        let mut item_ids = gallery.item_ids
+            .get(&marketplace)
+            .cloned()
+            .unwrap_or_default();
+        if let Some(max_items) = gallery.max_items_per_marketplace {
+            item_ids.truncate(max_items);
+        }
+        if !fresh_items.is_empty() {
+            tracing::debug!("Reusing {} already scraped {marketplace} items for gallery {}", fresh_items.len(), gallery.gallery_id);
+            item_ids.retain(|item_id| !fresh_items.iter().any(|item| item.id == *item_id));
+        }
+        let mut results = self.item_scraper
+            .scrape_marketplace_items(&marketplace, item_ids.clone())
+            .await;
+        tracing::trace!("Item scrape results for {marketplace}: {results:#?}");
+        results.extend(fresh_items.into_iter().map(Ok));
+        let mut attempts = gallery.marketplace_retry_attempts
+            .get(&marketplace)
+            .copied()
+            .unwrap_or(0);
+        while Self::marketplace_failed(&results) {
+            if attempts >= self.max_retries {
+                let reason = results
+                    .into_iter()
+                    .find_map(|res| res.err())
+                    .unwrap_or_else(|| "Unknown error".into());
+                tracing::warn!("Item scrape for {marketplace} in gallery {} failed after {attempts} retries: {reason}", gallery.gallery_id);
+                return MarketplaceScrape {
+                    marketplace,
+                    results: vec![],
+                    retry_attempts: attempts,
+                    failure_reason: Some(format!("Item scrape failed after {attempts} retries: {reason}"))
+                };
+            }
+            let delay = self.retry_base_delay.saturating_mul(2u32.saturating_pow(attempts));
+            tracing::debug!("Retrying item scrape for {marketplace} in gallery {} in {delay:?} (attempt {})", gallery.gallery_id, attempts + 1);
+            tokio::time::sleep(delay).await;
+            results = self.item_scraper
+                .scrape_marketplace_items(&marketplace, item_ids.clone())
+                .await;
+            attempts += 1;
+        }
+        MarketplaceScrape {
+            marketplace,
+            results,
+            retry_attempts: attempts,
+            failure_reason: None
+        }
+    }
+
+    /// Fetches the items already scraped for the gallery's run from storage (ie before a restart) which aren't stale yet,
+    /// ie were updated within `item_refetch_ttl`, so they can be reused rather than re-fetched.
+    /// 
+    /// Only items which are still among the gallery's item IDs are returned. If `item_refetch_ttl` is 0, nothing is fetched.
+    /// This is best-effort; failures to reach storage are logged, and every item is re-fetched.
+    async fn fetch_fresh_scraped_items(&mut self, gallery: &GalleryItemScrapingState) -> HashMap<Marketplace, Vec<MarketplaceItemData>> {
+        if self.item_refetch_ttl.is_zero() {
+            return HashMap::new();
+        }
+        let gallery_id = gallery.gallery_id.clone();
+        let (msg, receiver) = GetScrapedItemsMessage::new(gallery_id.clone());
+        if let Err(err) = self.storage_sender.send(StorageMessage::GetScrapedItems(msg)).await {
+            tracing::warn!("Failed to message storage for scraped items of gallery {gallery_id}: {err}");
+            return HashMap::new();
+        }
+        let stored_items = match receiver.await {
+            Ok(Ok(stored_items)) => stored_items,
+            Ok(Err(err)) => {
+                tracing::warn!("Failed to fetch scraped items of gallery {gallery_id} from storage: {err}");
+                return HashMap::new();
+            },
+            Err(err) => {
+                tracing::warn!("Failed to receive a response from storage for scraped items of gallery {gallery_id}: {err}");
+                return HashMap::new();
+            }
+        };
+        let now = UnixUtcDateTime::now();
+        stored_items
+            .into_iter()
+            .filter_map(|(marketplace, items)| {
+                let item_ids = gallery.item_ids.get(&marketplace)?;
+                let fresh_items: Vec<_> = items
+                    .into_iter()
+                    .filter(|item| item_ids.contains(&item.id) && !item.is_stale(now.clone(), self.item_refetch_ttl))
+                    .collect();
+                Some((marketplace, fresh_items))
+            })
+            .collect()
+    }
+
+    /// Upserts the successfully scraped items of each marketplace into storage, so they survive the in-memory state being lost.
+    /// 
+    /// This is best-effort; failures are logged, and don't stop the gallery from continuing through the pipeline.
+    async fn persist_scraped_items(
+        &mut self,
+        gallery_id: &GalleryId,
+        scraped_items: &HashMap<Marketplace, Vec<Result<MarketplaceItemData, String>>>
+    ) {
+        for (marketplace, results) in scraped_items {
+            let items: Vec<_> = results
+                .iter()
+                .filter_map(|res| res.as_ref().ok().cloned())
+                .collect();
+            if items.is_empty() {
+                continue;
+            }
+            let (msg, receiver) = UpsertScrapedItemsMessage::new((gallery_id.clone(), marketplace.clone(), items));
+            if let Err(err) = self.storage_sender.send(StorageMessage::UpsertScrapedItems(msg)).await {
+                tracing::warn!("Failed to message storage to persist scraped {marketplace} items for gallery {gallery_id}: {err}");
+                continue;
+            }
+            match receiver.await {
+                Ok(Ok(num_inserted)) => tracing::trace!("Persisted {num_inserted} new scraped {marketplace} items for gallery {gallery_id}"),
+                Ok(Err(err)) => tracing::warn!("Failed to persist scraped {marketplace} items for gallery {gallery_id}: {err}"),
+                Err(err) => tracing::warn!("Failed to receive a response from storage while persisting scraped {marketplace} items for gallery {gallery_id}: {err}")
+            }
+        }
+    }
+
+    /// Sends the successfully scraped items of each marketplace to storage, to be diffed against the gallery's previous scrape.
+    /// 
+    /// This is best-effort; failures are logged, and don't stop the gallery from continuing through the pipeline.
+    async fn record_scrape_diff(
+        &mut self,
+        gallery_id: &GalleryId,
+        scraped_items: &HashMap<Marketplace, Vec<Result<MarketplaceItemData, String>>>
+    ) {
+        let items = scraped_items
+            .iter()
+            .map(|(marketplace, results)| {
+                let items = results
+                    .iter()
+                    .filter_map(|res| res.as_ref().ok().cloned())
+                    .collect();
+                (marketplace.clone(), items)
+            })
+            .collect();
+        if let Err(err) = self.storage_sender.send(StorageMessage::RecordScrapeDiff { gallery_id: gallery_id.clone(), items }).await {
+            tracing::warn!("Failed to message storage to record the scrape diff for gallery {gallery_id}: {err}");
+        }
+    }
+
+    /// Checkpoints the gallery's scrape progress to the state tracker, then takes its state again to keep working on it.
+    /// 
+    /// Returns an `Err` if the state tracker couldn't be contacted, or the gallery was removed from state in the meantime.
+    async fn checkpoint_gallery_state(&mut self, gallery: &GalleryItemScrapingState) -> Result<(), ItemScraperError> {
+        let gallery_id = gallery.gallery_id.clone();
+        self.state_tracker_sender
+            .update_gallery_state(GalleryPipelineStates::ItemScraping(gallery.clone()))
+            .await
+            .map_err(|err| ItemScraperError::Other { 
+                gallery_id: gallery_id.clone(), 
+                message: format!("Could not receive response from state tracker: {err}") 
+            })?
+            .map_err(|err| ItemScraperError::StateErr { 
+                gallery_id: gallery_id.clone(), 
+                err 
+            })?;
+        self.fetch_gallery_state(gallery_id).await?;
+        Ok(())
+    }
+
+    /// Whether a marketplace's item scrape failed; ie, it has results and they're all errors.
+    fn marketplace_failed(results: &[Result<MarketplaceItemData, String>]) -> bool {
+        !results.is_empty() && results.iter().all(|res| res.is_err())
+    }
+
+    /// Add a new gallery to the state, returning the ID of its run.
     /// 
     /// Returns an `Err` if it already exists.
     async fn add_gallery_to_state(
         &mut self, 
         gallery_id: GalleryId, 
         gallery: GalleryItemScrapingState
-    ) -> Result<(), ItemScraperError> {
+    ) -> Result<RunId, ItemScraperError> {
         self.state_tracker_sender
-            .add_gallery(gallery_id.clone(), GalleryPipelineStates::ItemScraping(gallery))
+            .add_gallery(GalleryPipelineStates::ItemScraping(gallery))
             .await
             .map_err(|err| ItemScraperError::Other { 
                 gallery_id: gallery_id.clone(), 
@@ -120,12 +402,14 @@ impl Handler {
         scraped_items: HashMap<Marketplace, Vec<Result<MarketplaceItemData, String>>>
     ) -> Result<(), ItemScraperError> {
         let gallery_id = cur_state.gallery_id.clone();
+        let num_failed_marketplaces = scraped_items
+            .values()
+            .filter(|result| Self::marketplace_failed(result))
+            .count();
+        self.pipeline_metrics.record_failed_marketplaces(&GalleryPipelineStateTypes::ItemScraping, num_failed_marketplaces);
         match scraped_items
             .iter()
-            .all(|(_, result)| {
-                result.len() > 0 && // we allow empty results, as long as they aren't all errors
-                result.iter().all(|res| res.is_err())
-            })
+            .all(|(_, result)| Self::marketplace_failed(result)) // we allow empty results, as long as they aren't all errors
             {
                 true => { // if all items are errors, remove gallery from state and return an Err
                     self.state_tracker_sender
@@ -142,9 +426,13 @@ impl Handler {
                     Err(ItemScraperError::TotalScrapeFailure { gallery_id })
                 },
                 false => {
-                    let new_state = self.process_to_next_state(scraped_items, cur_state);
+                    let new_state = self
+                        .process_to_next_state(scraped_items, cur_state)
+                        .map_err(|err| 
+                            ItemScraperError::Other { gallery_id: gallery_id.clone(), message: format!("Could not advance gallery state: {err}") }
+                        )?;
                     self.state_tracker_sender
-                        .update_gallery_state(gallery_id.clone(), GalleryPipelineStates::ItemAnalysis(new_state))
+                        .update_gallery_state(new_state)
                         .await
                         .map_err(|err| 
                             ItemScraperError::Other { gallery_id: gallery_id.clone(), message: format!("Could not receive response from state tracker: {err}") }
@@ -152,33 +440,178 @@ impl Handler {
                         .map_err(|err| 
                             ItemScraperError::StateErr { gallery_id, err }
                         )?;
+                    self.pipeline_metrics.record_stage_entered(&GalleryPipelineStateTypes::ItemAnalysis);
                     Ok(())
                 }
             }
     }
 
     /// Process the gallery's state into the next state.
+    /// 
+    /// Items from sellers rated below the gallery's `min_seller_rating` are dropped here, 
+    /// as seller ratings are only known once an item is scraped.
     fn process_to_next_state(
         &self,
         scraped_items: HashMap<Marketplace, Vec<Result<MarketplaceItemData, String>>>,
         gallery_state: GalleryItemScrapingState,
-    ) -> GalleryItemAnalysisState {
+    ) -> Result<GalleryPipelineStates, StateTransitionError> {
+        let min_seller_rating = gallery_state.min_seller_rating;
         let valid_items = scraped_items
             .into_iter()
             .map(|(marketplace, results)| {
-                let valid_items = results
+                let num_items = results.len();
+                let valid_items: Vec<_> = results
                     .into_iter()
                     .filter_map(|res| res.ok())
+                    .filter(|item| item.meets_seller_rating(min_seller_rating))
                     .collect();
+                tracing::trace!("Kept {}/{num_items} scraped items for {marketplace} after filtering", valid_items.len());
                 (marketplace, valid_items)
             })
             .collect();
-        GalleryItemAnalysisState {
-            gallery_id: gallery_state.gallery_id,
-            items: valid_items,
-            marketplace_updated_datetimes: gallery_state.marketplace_updated_datetimes,
-            failed_marketplace_reasons: gallery_state.failed_marketplace_reasons,
-            evaluation_criteria: gallery_state.evaluation_criteria,
+        GalleryPipelineStates::ItemScraping(gallery_state).advance(StageAdvancePayload::ItemsScraped(valid_items))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use async_trait::async_trait;
+    use crate::{galleries::domain_types::ItemId, test_support::{http_clients, item_data, scheduler_state, TestHarness}};
+    use super::{super::scrapers::ItemScraperBackend, *};
+
+    /// Scrapes every item successfully, counting the scraped items.
+    struct CountingBackend {
+        scrapes: Arc<AtomicUsize>
+    }
+
+    #[async_trait]
+    impl ItemScraperBackend for CountingBackend {
+        async fn scrape(&self, item_ids: Vec<ItemId>) -> Vec<Result<MarketplaceItemData, String>> {
+            self.scrapes.fetch_add(item_ids.len(), Ordering::Relaxed);
+            item_ids
+                .iter()
+                .map(|item_id| Ok(item_data(item_id, 100.0, 0)))
+                .collect()
         }
     }
+
+    /// A handler wired to the harness's buses, whose scraped Mercari items are counted in `scrapes`.
+    fn handler(harness: &TestHarness, scrapes: Arc<AtomicUsize>) -> Handler {
+        let config = ItemScraperConfig {
+            max_retries: 0,
+            retry_base_delay_ms: 0,
+            max_concurrent_galleries: 1,
+            breaker_failure_threshold: 0,
+            breaker_cooldown_secs: 0,
+            user_agents: vec![],
+            proxy: None,
+            marketplace_proxies: HashMap::new(),
+            base_currency: "JPY".into(),
+            exchange_rate_api_endpoint: String::new(),
+            exchange_rate_cache_ttl_secs: 0,
+            item_refetch_ttl_secs: 0,
+            message_buffer: 1
+        };
+        let mut handler = Handler::new(
+            &config,
+            &http_clients(),
+            harness.state_tracker_sender(),
+            harness.item_analysis.sender(),
+            harness.storage.sender(),
+            Arc::new(PipelineMetrics::new())
+        );
+        handler.item_scraper.register_backend(&Marketplace::Mercari, Arc::new(CountingBackend { scrapes }));
+        handler
+    }
+
+    /// A gallery to be item scraped, with 2 Mercari item IDs.
+    fn item_scraping_state() -> GalleryItemScrapingState {
+        let item_ids = vec![ItemId::from("a".to_string()), ItemId::from("b".to_string())];
+        scheduler_state("gallery")
+            .to_next_stage()
+            .to_next_stage(HashMap::from([(Marketplace::Mercari, item_ids)]), HashMap::new(), HashMap::new())
+    }
+
+    /// Assert the gallery was sent to item analysis, with the Mercari items in state.
+    async fn assert_sent_to_analysis(harness: &mut TestHarness, num_items: usize) {
+        let gallery_id = GalleryId::from("gallery".to_string());
+        match harness.item_analysis.expect_message().await {
+            ItemAnalysisMessage::AnalyzeGallery { gallery_id: analyzed_id } => assert_eq!(analyzed_id, gallery_id),
+            other => panic!("Expected the gallery to be sent to item analysis, but got {other:?}")
+        }
+        let state = harness.state_tracker_sender()
+            .peek_gallery_state(gallery_id, GalleryPipelineStateTypes::ItemAnalysis)
+            .await
+            .unwrap()
+            .unwrap();
+        match state {
+            GalleryPipelineStates::ItemAnalysis(state) => assert_eq!(state.items[&Marketplace::Mercari].len(), num_items),
+            other => panic!("Expected the gallery to be in item analysis, but it's in {:?}", other.state_type())
+        }
+    }
+
+    #[tokio::test]
+    async fn scraped_marketplaces_are_persisted_and_checkpointed() {
+        let mut harness = TestHarness::new();
+        harness.spawn_state_tracker().await;
+        let scrapes = Arc::new(AtomicUsize::new(0));
+        let mut handler = handler(&harness, scrapes.clone());
+        let scrape = tokio::spawn(async move { handler.scrape_new_gallery(item_scraping_state()).await });
+
+        match harness.storage.expect_message().await {
+            StorageMessage::UpsertScrapedItems(msg) => msg.act(|(_, _, items)| Ok(items.len())).unwrap(),
+            other => panic!("Expected the scraped items to be persisted, but got {other:?}")
+        }
+        scrape.await.unwrap().unwrap();
+        assert_eq!(scrapes.load(Ordering::Relaxed), 2);
+        assert_sent_to_analysis(&mut harness, 2).await;
+    }
+
+    #[tokio::test]
+    async fn already_scraped_items_are_only_refetched_once_stale() {
+        let mut harness = TestHarness::new();
+        harness.spawn_state_tracker().await;
+        let scrapes = Arc::new(AtomicUsize::new(0));
+        let mut handler = handler(&harness, scrapes.clone());
+        handler.item_refetch_ttl = Duration::from_secs(3600);
+        let scrape = tokio::spawn(async move { handler.scrape_new_gallery(item_scraping_state()).await });
+
+        match harness.storage.expect_message().await {
+            StorageMessage::GetScrapedItems(msg) => msg.act(|_| {
+                let fresh_item = item_data("a", 100.0, UnixUtcDateTime::now().timestamp());
+                let stale_item = item_data("b", 100.0, 0);
+                Ok(HashMap::from([(Marketplace::Mercari, vec![fresh_item, stale_item])]))
+            }).unwrap(),
+            other => panic!("Expected the already scraped items to be fetched, but got {other:?}")
+        }
+        match harness.storage.expect_message().await {
+            StorageMessage::UpsertScrapedItems(msg) => msg.act(|(_, _, items)| Ok(items.len())).unwrap(),
+            other => panic!("Expected the scraped items to be persisted, but got {other:?}")
+        }
+        scrape.await.unwrap().unwrap();
+        assert_eq!(scrapes.load(Ordering::Relaxed), 1);
+        assert_sent_to_analysis(&mut harness, 2).await;
+    }
+
+    #[tokio::test]
+    async fn a_resumed_scrape_skips_the_marketplaces_completed_before_the_crash() {
+        let mut harness = TestHarness::new();
+        harness.spawn_state_tracker().await;
+        // Mercari's results were checkpointed before the crash, so it's already complete when the scrape resumes
+        let mut gallery = item_scraping_state();
+        gallery.completed_marketplaces.insert(Marketplace::Mercari, vec![Ok(item_data("a", 100.0, 0)), Ok(item_data("b", 100.0, 0))]);
+        harness.state_tracker_sender()
+            .add_gallery(GalleryPipelineStates::ItemScraping(gallery))
+            .await
+            .unwrap()
+            .unwrap();
+        let scrapes = Arc::new(AtomicUsize::new(0));
+        handler(&harness, scrapes.clone())
+            .scrape_gallery_in_state(GalleryId::from("gallery".to_string()))
+            .await
+            .unwrap();
+        assert_eq!(scrapes.load(Ordering::Relaxed), 0);
+        assert_sent_to_analysis(&mut harness, 2).await;
+    }
 }
@@ -1,47 +1,73 @@
+use std::sync::Arc;
+use tokio::sync::Semaphore;
+use tracing::Instrument;
 use handler::Handler;
-use crate::{config::ItemScraperConfig, messages::{message_types::item_scraper::ItemScraperMessage, ItemAnalysisSender, ItemScraperReceiver, StateTrackerSender}};
+use crate::{config::ItemScraperConfig, messages::{message_types::item_scraper::ItemScraperMessage, ItemAnalysisSender, ItemScraperReceiver, StateTrackerSender, StorageSender}, scraping_pipeline::{module_health::{ModuleHealth, PipelineModule}, pipeline_metrics::PipelineMetrics}, utils::{http_client::HttpClientFactory, tracing_context::module_span}};
 
 mod handler;
 mod scrapers;
 
 pub struct ItemScraperModule {
     handler: Handler,
-    msg_receiver: ItemScraperReceiver
+    msg_receiver: ItemScraperReceiver,
+    concurrency_limit: Arc<Semaphore>
 }
 
 impl ItemScraperModule {
     /// Initialize the module.
     pub fn init(
         config: ItemScraperConfig, 
+        http_clients: &HttpClientFactory,
         msg_receiver: ItemScraperReceiver,
         state_tracker_sender: StateTrackerSender,
-        item_analysis_sender: ItemAnalysisSender
+        item_analysis_sender: ItemAnalysisSender,
+        storage_sender: StorageSender,
+        pipeline_metrics: Arc<PipelineMetrics>
     ) -> Self {
         let handler = Handler::new(
             &config, 
+            http_clients,
             state_tracker_sender, 
-            item_analysis_sender
+            item_analysis_sender,
+            storage_sender,
+            pipeline_metrics
         );
+        let concurrency_limit = Arc::new(Semaphore::new(config.max_concurrent_galleries.max(1)));
         Self {
             handler,
-            msg_receiver
+            msg_receiver,
+            concurrency_limit
         }
     }
 
     /// Start accepting and acting on messages.
-    pub async fn run(&mut self) {
+    /// 
+    /// Each message is processed in its own task, with at most `max_concurrent_galleries` tasks running at once.
+    pub async fn run(&mut self, module_health: &ModuleHealth) {
         tracing::info!("ItemScraperModule is running...");
-        while let Some(msg) = self.msg_receiver.receive().await {
-            self.process_msg(msg).await;
+        while let Some((msg, span)) = module_health.receive_with_span(PipelineModule::ItemScraper, &mut self.msg_receiver).await {
+            let permit = self.concurrency_limit
+                .clone()
+                .acquire_owned()
+                .await
+                .expect("Semaphore should never be closed");
+            let mut handler = self.handler.clone();
+            tokio::spawn(
+                async move {
+                    Self::process_msg(&mut handler, msg).await;
+                    drop(permit);
+                }
+                    .instrument(module_span(&span, PipelineModule::ItemScraper))
+            );
         }
     }
 
     /// Handle each message variant.
-    async fn process_msg(&mut self, msg: ItemScraperMessage) {
+    async fn process_msg(handler: &mut Handler, msg: ItemScraperMessage) {
         match msg {
             ItemScraperMessage::ScrapeItems { gallery_id } => {
                 tracing::trace!("Received message to start item scraping gallery {} in state", gallery_id);
-                let schedule_result = self.handler
+                let schedule_result = handler
                     .scrape_gallery_in_state(gallery_id)
                     .await;
                 if let Err(err) = schedule_result {
@@ -50,7 +76,7 @@ impl ItemScraperModule {
             },
             ItemScraperMessage::ScrapeItemsNew { gallery } => {
                 tracing::trace!("Received message to start search-scraping new gallery {}", gallery.gallery_id);
-                let schedule_result = self.handler
+                let schedule_result = handler
                     .scrape_new_gallery(gallery)
                     .await;
                 if let Err(err) = schedule_result {
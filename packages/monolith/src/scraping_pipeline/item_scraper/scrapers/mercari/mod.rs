@@ -1,23 +1,42 @@
 use std::error::Error;
 
+use async_trait::async_trait;
 use futures::future::join_all;
-use reqwest::{Client, RequestBuilder};
+use reqwest::{header::USER_AGENT, Client, RequestBuilder};
 use types::{MercariItemData, MercariItemResponse};
-use crate::{galleries::{domain_types::ItemId, items::item_data::{MarketplaceItemData, MarketplaceSeller}}, utils::generate_dpop::generate_dpop};
+use crate::{galleries::{domain_types::ItemId, items::item_data::{MarketplaceItemData, MarketplaceSeller}}, utils::{generate_dpop::generate_dpop, user_agent_pool::UserAgentPool}};
 
 const REQ_URL: &str = "https://api.mercari.jp/items/get"; // TODO: move to config
 
+use super::ItemScraperBackend;
+
 mod types;
 
 /// This struct is in charge of scraping items from Mercari.
+#[derive(Clone)]
 pub(super) struct MercariItemScraper {
-    client: Client
+    client: Client,
+    /// Whether `client` routes requests through a proxy.
+    proxied: bool,
+    user_agents: UserAgentPool
+}
+
+#[async_trait]
+impl ItemScraperBackend for MercariItemScraper {
+    async fn scrape(&self, item_ids: Vec<ItemId>) -> Vec<Result<MarketplaceItemData, String>> {
+        self.request(item_ids).await
+    }
 }
 
 impl MercariItemScraper {
-    pub fn new() -> Self {
+    /// Instantiate the scraper.
+    /// 
+    /// `proxied` should be set if `client` routes requests through a proxy, so that connection errors can be attributed to it.
+    pub fn new(client: Client, proxied: bool, user_agents: UserAgentPool) -> Self {
         Self {
-            client: Client::new()
+            client,
+            proxied,
+            user_agents
         }
     }
 
@@ -44,13 +63,14 @@ impl MercariItemScraper {
         self.handle_responses(responses).await
     }
 
-    /// Create the request for an item ID.
+    /// Create the request for an item ID, with the next user agent from the pool.
     fn create_request(&self, dpop_key: &String, item_id: &ItemId) -> RequestBuilder {
         self.client
             .get(format!("{REQ_URL}?id={item_id}"))
             .header("dpop", dpop_key)
             .header("x-platform", "web") 
             .header("accept", "application/json")
+            .header(USER_AGENT, self.user_agents.next_user_agent())
     }    
 
     /// Handle the raw responses from the item scrape. 
@@ -72,6 +92,7 @@ impl MercariItemScraper {
                             Err(err) => Err(format!("Error code while requesting for item {id}: {err}")),
                         }
                     },
+                    Err(err) if err.is_connect() && self.proxied => Err(format!("Error connecting through the proxy to request item {id}: {err}")),
                     Err(err) => Err(format!("Error requesting for item {id}: {err}")),
                 }   
             });
@@ -80,6 +101,10 @@ impl MercariItemScraper {
 
     /// Map from Mercari's raw data to the internal type.
     fn map_to_marketplace_item(&self, data: MercariItemData) -> MarketplaceItemData {
+        let seller_rating = match data.seller.num_ratings {
+            0 => None,
+            _ => Some(data.seller.star_rating_score as f32)
+        };
         let seller = MarketplaceSeller {
             id: data.seller.id.to_string(),
             name: data.seller.name
@@ -88,10 +113,13 @@ impl MercariItemScraper {
             id: data.id.into(),
             name: data.name,
             price: data.price.into(),
+            currency: "JPY".into(),
+            normalized_price: None,
             description: data.description,
             status: data.status.into(),
             created: data.created.into(),
             seller,
+            seller_rating,
             category: data.item_category.name,
             thumbnails: data.thumbnails,
             item_condition: data.item_condition.name,
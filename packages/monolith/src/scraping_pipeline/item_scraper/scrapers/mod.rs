@@ -1,48 +1,105 @@
-use std::collections::HashMap;
+use std::{sync::Arc, time::Duration};
 
-use futures::future::join_all;
+use async_trait::async_trait;
 use mercari::MercariItemScraper;
-use crate::{config::ItemScraperConfig, galleries::{domain_types::Marketplace, items::item_data::MarketplaceItemData, pipeline_states::GalleryItemScrapingState}};
+use crate::{config::ItemScraperConfig, galleries::{domain_types::{ItemId, Marketplace}, items::item_data::MarketplaceItemData}, utils::{circuit_breaker::MarketplaceCircuitBreaker, exchange_rates::ExchangeRates, marketplace_registry::MarketplaceRegistry, http_client::HttpClientFactory, user_agent_pool::UserAgentPool}};
 
 mod mercari;
 
+/// The interface for a marketplace's item scraper.
+/// 
+/// Adding a new marketplace only requires implementing this, and registering it in `ItemScraper::new`.
+#[async_trait]
+pub(super) trait ItemScraperBackend {
+    /// Scrape the data of each item ID, returning (in order) the item's data, or an `Err` if the item's scrape wasn't successful.
+    async fn scrape(&self, item_ids: Vec<ItemId>) -> Vec<Result<MarketplaceItemData, String>>;
+}
+
 /// This scraper is in charge of scraping detailed data for each item ID.
+#[derive(Clone)]
 pub(super) struct ItemScraper { 
     config: ItemScraperConfig,
-    mercari_scraper: MercariItemScraper
+    circuit_breaker: MarketplaceCircuitBreaker,
+    exchange_rates: ExchangeRates,
+    backends: MarketplaceRegistry<dyn ItemScraperBackend + Send + Sync>
 }
 
 impl ItemScraper {
     /// Instantiate a `IndividualScraper`.
-    pub fn new(config: &ItemScraperConfig) -> Self {
+    /// 
+    /// Panics if a marketplace's proxy is invalid, rather than letting its requests go out directly.
+    pub fn new(config: &ItemScraperConfig, http_clients: &HttpClientFactory) -> Self {
+        let circuit_breaker = MarketplaceCircuitBreaker::new(
+            config.breaker_failure_threshold, 
+            Duration::from_secs(config.breaker_cooldown_secs)
+        );
+        let user_agents = UserAgentPool::new(config.user_agents.clone());
+        let mut backends: MarketplaceRegistry<dyn ItemScraperBackend + Send + Sync> = MarketplaceRegistry::new();
+        let mercari_proxy = config.proxy_for(&Marketplace::Mercari);
+        let mercari_client = http_clients.client_with_proxy(mercari_proxy)
+            .unwrap_or_else(|err| panic!("Could not build the Mercari item scraper's client: {err}"));
+        backends.register(
+            Marketplace::Mercari.to_string(), 
+            Arc::new(MercariItemScraper::new(mercari_client, mercari_proxy.is_some(), user_agents))
+        );
+        let exchange_rates = ExchangeRates::new(
+            http_clients.client(),
+            config.exchange_rate_api_endpoint.clone(),
+            config.base_currency.clone(),
+            Duration::from_secs(config.exchange_rate_cache_ttl_secs)
+        );
         Self {
             config: config.clone(),
-            mercari_scraper: MercariItemScraper::new()
+            circuit_breaker,
+            exchange_rates,
+            backends
         }
     }
 
-    /// Attempt to scrape a list of item IDs,
-    /// returning a list with (in order) the item's data, or an `Err` if the item's scrape wasn't successful.
+    /// Replace the backend registered for a marketplace, ie with a fake one in tests.
+    #[cfg(test)]
+    pub fn register_backend(&mut self, marketplace: &Marketplace, backend: Arc<dyn ItemScraperBackend + Send + Sync>) {
+        self.backends.register(marketplace.to_string(), backend);
+    }
+
+    /// Attempt to scrape a list of item IDs for a single marketplace.
+    /// 
+    /// Successfully scraped items have their price normalized into the base currency.
     /// 
-    /// Returns the list with a single `Err` if the *dpop* key generation was unsuccessful (should never happen).
-    pub async fn scrape_items(
-        &self, 
-        gallery: &GalleryItemScrapingState
-    ) -> HashMap<Marketplace, Vec<Result<MarketplaceItemData, String>>> {
-        let results = join_all(
-            gallery.item_ids
-                .clone()
-                .into_iter()
-                .map(|(marketplace, item_ids)| async {
-                    let item_results = match marketplace {
-                        Marketplace::Mercari => self.mercari_scraper.request(item_ids).await
-                    };
-                    (marketplace, item_results)
-                })
-            ).await;
-        tracing::trace!("Item scrape results: {results:#?}");
+    /// The scrape counts as failed for the circuit breaker if every item failed;
+    /// if the breaker is open, every item is returned as an `Err` without being scraped.
+    pub async fn scrape_marketplace_items(
+        &self,
+        marketplace: &Marketplace,
+        item_ids: Vec<ItemId>
+    ) -> Vec<Result<MarketplaceItemData, String>> {
+        if item_ids.is_empty() {
+            return vec![];
+        }
+        if let Err(remaining_cooldown) = self.circuit_breaker.check(marketplace).await {
+            let err = format!("Circuit breaker is open for {marketplace}; skipping item scrape (cooldown remaining: {remaining_cooldown:?})");
+            return item_ids
+                .iter()
+                .map(|_| Err(err.clone()))
+                .collect();
+        }
+        let mut results = match self.backends.get(marketplace) {
+            Some(backend) => backend.scrape(item_ids).await,
+            None => {
+                let err = format!("No item scraper is registered for {marketplace}");
+                return item_ids
+                    .iter()
+                    .map(|_| Err(err.clone()))
+                    .collect();
+            }
+        };
+        match results.iter().all(|result| result.is_err()) {
+            true => self.circuit_breaker.record_failure(marketplace).await,
+            false => self.circuit_breaker.record_success(marketplace).await
+        };
+        self.exchange_rates
+            .normalize(results.iter_mut().filter_map(|result| result.as_mut().ok()))
+            .await;
         results
-            .into_iter()
-            .collect()
     }
 }
\ No newline at end of file
@@ -1,13 +1,16 @@
+use std::{sync::Arc, time::{Duration, Instant}};
 use item_embedder::ItemEmbedderModule;
 use item_analysis::ItemAnalysisModule;
 use item_scraper::ItemScraperModule;
 use state_tracker::StateTrackerModule;
 use storage::StorageModule;
-use tokio::sync::mpsc;
 use search_scraper::SearchScraperModule;
 use scraper_scheduler::ScraperSchedulerModule;
+use module_health::ModuleHealth;
+use pipeline_metrics::PipelineMetrics;
+use final_state_compactor::FinalStateCompactor;
 use tokio::task::JoinHandle;
-use crate::{config::AppConfig, messages::{message_buses::MessageSender, ItemAnalysisReceiver, ItemAnalysisSender, ItemEmbedderReceiver, ItemEmbedderSender, ItemScraperReceiver, ItemScraperSender, ScraperSchedulerReceiver, ScraperSchedulerSender, SearchScraperReceiver, SearchScraperSender, StateTrackerReceiver, StateTrackerSender, StorageReceiver, StorageSender}};
+use crate::{config::{state_tracker::StateTrackerConfig, AppConfig, ItemScraperConfig}, notifications::Notifier, utils::http_client::HttpClientFactory, messages::{message_buses::{message_bus_with_dead_letter, BusMetrics}, ItemAnalysisReceiver, ItemAnalysisSender, ItemEmbedderReceiver, ItemEmbedderSender, ItemScraperReceiver, ItemScraperSender, ScraperSchedulerReceiver, ScraperSchedulerSender, SearchScraperReceiver, SearchScraperSender, StateTrackerReceiver, StateTrackerSender, StorageReceiver, StorageSender}};
 
 pub mod state_tracker;
 pub mod scraper_scheduler;
@@ -16,6 +19,9 @@ pub mod item_scraper;
 pub mod item_analysis;
 pub mod item_embedder;
 pub mod storage;
+pub mod module_health;
+pub mod pipeline_metrics;
+pub mod final_state_compactor;
 
 const MODULE_MESSAGE_BUFFER: usize = 1000;
 
@@ -27,50 +33,78 @@ pub struct AppModules {
     item_scraper_module: ItemScraperModule,
     analysis_module: ItemAnalysisModule,
     classifier_module: ItemEmbedderModule,
-    storage_module: StorageModule
+    storage_module: StorageModule,
+    final_state_compactor: FinalStateCompactor,
+    module_health: Arc<ModuleHealth>,
+    state_tracker_sender: StateTrackerSender
 }
 
 impl AppModules {
     /// Initialize the app's modules.
     pub async fn init(config: AppConfig, connections: AppModuleConnections) -> Self {
+        let http_clients = HttpClientFactory::new(&config.http_client_config);
+        let notifier = Notifier::new(&config.notification_config, &http_clients);
+        let final_state_compactor = FinalStateCompactor::new(
+            &config.state_tracker_config,
+            connections.state_tracker.0.clone(),
+            connections.storage.0.clone()
+        );
         let state_tracker_module = StateTrackerModule::init(
             config.state_tracker_config, 
-            connections.state_tracker.1
+            connections.state_tracker.1,
+            connections.storage.0.clone(),
+            notifier.clone()
         ).await;
         let scheduler_module = ScraperSchedulerModule::init(
             config.scraper_scheduler_config,
             connections.scraper_scheduler.1, 
             connections.search_scraper.0,
-            connections.state_tracker.0.clone()
+            connections.state_tracker.0.clone(),
+            connections.storage.0.clone(),
+            connections.pipeline_metrics.clone()
         );
         let search_scraper_module = SearchScraperModule::init(
             config.search_scraper_config, 
+            &http_clients,
             connections.search_scraper.1, 
             connections.state_tracker.0.clone(),
             connections.item_scraper.0,
+            notifier.clone(),
+            connections.pipeline_metrics.clone()
         );
         let item_scraper_module = ItemScraperModule::init(
             config.item_scraper_config,
+            &http_clients,
             connections.item_scraper.1,
             connections.state_tracker.0.clone(),
-            connections.item_analysis.0
+            connections.item_analysis.0,
+            connections.storage.0.clone(),
+            connections.pipeline_metrics.clone()
         );
         let analysis_module = ItemAnalysisModule::init(
             config.item_analysis_config.clone(),
+            &http_clients,
             connections.item_analysis.1,
             connections.state_tracker.0.clone(),
-            connections.image_classifier.0
+            connections.image_classifier.0,
+            connections.storage.0.clone(),
+            notifier.clone(),
+            connections.pipeline_metrics.clone()
         );
         let classifier_module = ItemEmbedderModule::init(
             config.img_classifier_config.clone(),
+            &http_clients,
             connections.image_classifier.1,
             connections.state_tracker.0.clone(),
-            connections.storage.0.clone()
+            connections.storage.0.clone(),
+            notifier,
+            connections.pipeline_metrics.clone()
         );
         let storage_module = StorageModule::init(
             config.storage_config,
             connections.storage.1,
-            connections.state_tracker.0.clone()
+            connections.state_tracker.0.clone(),
+            connections.scraper_scheduler.0.clone()
         );
         AppModules {
             state_tracker_module,
@@ -79,33 +113,65 @@ impl AppModules {
             item_scraper_module,
             analysis_module,
             classifier_module,
-            storage_module
+            storage_module,
+            final_state_compactor,
+            module_health: connections.module_health,
+            state_tracker_sender: connections.state_tracker.0
         }
     }
 
     /// Start running all of the app's modules.
+    /// 
+    /// Each module records heartbeats in the shared `ModuleHealth` from its receive loop.
     pub fn run(mut self) -> AppModulesRunningHandles {
-        let state_tracker_task = tokio::spawn(async move { self.state_tracker_module.run().await; });
-        let scheduler_task = tokio::spawn(async move { self.scheduler_module.run().await; });
-        let search_scraper_task = tokio::spawn(async move { self.search_scraper_module.run().await; });
-        let item_scraper_task = tokio::spawn(async move { self.item_scraper_module.run().await; });
-        let analysis_task = tokio::spawn(async move { self.analysis_module.run().await; });
-        let classifier_task = tokio::spawn(async move { self.classifier_module.run().await; });
-        let storage_task = tokio::spawn(async move { self.storage_module.run().await; });
+        let health = self.module_health.clone();
+        let state_tracker_task = tokio::spawn(async move { 
+            self.state_tracker_module.run(&health).await; 
+        });
+        let health = self.module_health.clone();
+        let scheduler_task = tokio::spawn(async move { 
+            self.scheduler_module.run(&health).await; 
+        });
+        let health = self.module_health.clone();
+        let search_scraper_task = tokio::spawn(async move { 
+            self.search_scraper_module.run(&health).await; 
+        });
+        let health = self.module_health.clone();
+        let item_scraper_task = tokio::spawn(async move { 
+            self.item_scraper_module.run(&health).await; 
+        });
+        let health = self.module_health.clone();
+        let analysis_task = tokio::spawn(async move { 
+            self.analysis_module.run(&health).await; 
+        });
+        let health = self.module_health.clone();
+        let classifier_task = tokio::spawn(async move { 
+            self.classifier_module.run(&health).await; 
+        });
+        let health = self.module_health.clone();
+        let storage_task = tokio::spawn(async move { 
+            self.storage_module.run(&health).await; 
+        });
+        let final_state_compactor_task = tokio::spawn(async move {
+            self.final_state_compactor.run().await;
+        });
         AppModulesRunningHandles {
+            state_tracker_sender: self.state_tracker_sender,
             state_tracker_task,
             scheduler_task,
             search_scraper_task,
             item_scraper_task,
             analysis_task,
             classifier_task,
-            storage_task
+            storage_task,
+            final_state_compactor_task
         }
     }
 }
 
 /// Holds task handles for each module's running tasks.
 pub struct AppModulesRunningHandles {
+    state_tracker_sender: StateTrackerSender,
     state_tracker_task: JoinHandle<()>,
     scheduler_task: JoinHandle<()>,
     search_scraper_task: JoinHandle<()>,
@@ -113,6 +179,56 @@ pub struct AppModulesRunningHandles {
     analysis_task: JoinHandle<()>,
     classifier_task: JoinHandle<()>,
     storage_task: JoinHandle<()>,
+    final_state_compactor_task: JoinHandle<()>,
+}
+
+impl AppModulesRunningHandles {
+    /// How often to check whether in-flight galleries have finished while shutting down.
+    const SHUTDOWN_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+    /// Gracefully shut down the app's modules.
+    /// 
+    /// This stops the scheduler (so no new galleries enter the pipeline), waits for in-flight galleries 
+    /// to reach the `Final` state or for `timeout` to pass, then persists all remaining states via the state tracker's store.
+    pub async fn shutdown(mut self, timeout: Duration) {
+        tracing::info!("Shutting down modules...");
+        self.scheduler_task.abort();
+        self.final_state_compactor_task.abort();
+        let start = Instant::now();
+        loop {
+            match self.state_tracker_sender.get_in_flight_galleries().await {
+                Ok(Ok(in_flight)) if in_flight.is_empty() => {
+                    tracing::info!("All in-flight galleries have finished");
+                    break;
+                },
+                Ok(Ok(in_flight)) if start.elapsed() >= timeout => {
+                    tracing::warn!("Timed out waiting for {} in-flight galleries to finish: {in_flight:?}", in_flight.len());
+                    break;
+                },
+                Ok(Ok(_)) => tokio::time::sleep(Self::SHUTDOWN_POLL_INTERVAL).await,
+                Ok(Err(err)) => {
+                    tracing::error!("Failed to get in-flight galleries from the state tracker: {err}");
+                    break;
+                },
+                Err(err) => {
+                    tracing::error!("Failed to message the state tracker: {err}");
+                    break;
+                }
+            }
+        }
+        match self.state_tracker_sender.persist_all().await {
+            Ok(Ok(num_galleries)) => tracing::info!("Persisted {num_galleries} gallery states"),
+            Ok(Err(err)) => tracing::error!("Failed to persist gallery states: {err}"),
+            Err(err) => tracing::error!("Failed to message the state tracker: {err}")
+        }
+        self.search_scraper_task.abort();
+        self.item_scraper_task.abort();
+        self.analysis_task.abort();
+        self.classifier_task.abort();
+        self.storage_task.abort();
+        self.state_tracker_task.abort();
+        tracing::info!("All modules shut down");
+    }
 }
 
 /// Struct for initializing inter-module connections.
@@ -123,70 +239,76 @@ pub struct AppModuleConnections {
     pub item_scraper: (ItemScraperSender, ItemScraperReceiver),
     pub item_analysis: (ItemAnalysisSender, ItemAnalysisReceiver),
     pub image_classifier: (ItemEmbedderSender, ItemEmbedderReceiver),
-    pub storage: (StorageSender, StorageReceiver)
+    pub storage: (StorageSender, StorageReceiver),
+    pub module_health: Arc<ModuleHealth>,
+    pub pipeline_metrics: Arc<PipelineMetrics>,
+    pub bus_metrics: Arc<Vec<Arc<BusMetrics>>>
 }
 
 impl AppModuleConnections {
     /// Initialize the app module connections.
-    pub fn new() -> Self {
+    pub fn new(config: &AppConfig) -> Self {
+        let mut bus_metrics = vec![];
         Self {
-            state_tracker: Self::init_state_tracker_conn(),
-            scraper_scheduler: Self::init_scheduler_conn(),
-            search_scraper: Self::init_search_scraper_conn(),
-            item_scraper: Self::init_item_scraper_conn(),
-            item_analysis: Self::init_item_analysis_conn(),
-            image_classifier: Self::init_image_classifier_conn(),
-            storage: Self::storage_conn()
+            state_tracker: Self::init_state_tracker_conn(&config.state_tracker_config, &mut bus_metrics),
+            scraper_scheduler: Self::init_scheduler_conn(&mut bus_metrics),
+            search_scraper: Self::init_search_scraper_conn(&mut bus_metrics),
+            item_scraper: Self::init_item_scraper_conn(&config.item_scraper_config, &mut bus_metrics),
+            item_analysis: Self::init_item_analysis_conn(&mut bus_metrics),
+            image_classifier: Self::init_image_classifier_conn(&mut bus_metrics),
+            storage: Self::storage_conn(&mut bus_metrics),
+            module_health: Arc::new(ModuleHealth::new()),
+            pipeline_metrics: Arc::new(PipelineMetrics::new()),
+            bus_metrics: Arc::new(bus_metrics)
         }
     }
 
-    fn init_state_tracker_conn() -> (StateTrackerSender, StateTrackerReceiver) {
-        let (sender, receiver) = mpsc::channel(MODULE_MESSAGE_BUFFER);
-        let raw_sender = MessageSender::new(sender);
-        let sender = StateTrackerSender::new(raw_sender);
-        let receiver = StateTrackerReceiver::new(receiver);
+    /// A `send_timeout_ms` of 0 disables the state tracker sender's send timeout.
+    fn init_state_tracker_conn(config: &StateTrackerConfig, bus_metrics: &mut Vec<Arc<BusMetrics>>) -> (StateTrackerSender, StateTrackerReceiver) {
+        let (raw_sender, receiver, metrics) = message_bus_with_dead_letter(MODULE_MESSAGE_BUFFER);
+        bus_metrics.push(metrics);
+        let sender = match config.send_timeout_ms {
+            0 => StateTrackerSender::new(raw_sender),
+            send_timeout_ms => StateTrackerSender::new(raw_sender).with_send_timeout(Duration::from_millis(send_timeout_ms))
+        };
         (sender, receiver)
     }
 
-    fn init_scheduler_conn() -> (ScraperSchedulerSender, ScraperSchedulerReceiver) {
-        let (sender, receiver) = mpsc::channel(MODULE_MESSAGE_BUFFER);
-        let sender = ScraperSchedulerSender::new(sender);
-        let receiver = ScraperSchedulerReceiver::new(receiver);
+    fn init_scheduler_conn(bus_metrics: &mut Vec<Arc<BusMetrics>>) -> (ScraperSchedulerSender, ScraperSchedulerReceiver) {
+        let (sender, receiver, metrics) = message_bus_with_dead_letter(MODULE_MESSAGE_BUFFER);
+        bus_metrics.push(metrics);
         (sender, receiver)
     }
 
-    fn init_search_scraper_conn() -> (SearchScraperSender, SearchScraperReceiver) {
-        let (sender, receiver) = mpsc::channel(MODULE_MESSAGE_BUFFER);
-        let sender = SearchScraperSender::new(sender);
-        let receiver = SearchScraperReceiver::new(receiver);
+    fn init_search_scraper_conn(bus_metrics: &mut Vec<Arc<BusMetrics>>) -> (SearchScraperSender, SearchScraperReceiver) {
+        let (sender, receiver, metrics) = message_bus_with_dead_letter(MODULE_MESSAGE_BUFFER);
+        bus_metrics.push(metrics);
         (sender, receiver)
     }
 
-    fn init_item_scraper_conn() -> (ItemScraperSender, ItemScraperReceiver) {
-        let (sender, receiver) = mpsc::channel(MODULE_MESSAGE_BUFFER);
-        let sender = ItemScraperSender::new(sender);
-        let receiver = ItemScraperReceiver::new(receiver);
+    /// The item scraper's buffer is configurable, as it bounds how far the search scraper can get ahead of it.
+    /// It's kept at least 1, as a bus can't be empty.
+    fn init_item_scraper_conn(config: &ItemScraperConfig, bus_metrics: &mut Vec<Arc<BusMetrics>>) -> (ItemScraperSender, ItemScraperReceiver) {
+        let (sender, receiver, metrics) = message_bus_with_dead_letter(config.message_buffer.max(1));
+        bus_metrics.push(metrics);
         (sender, receiver)
     }
 
-    fn init_item_analysis_conn() -> (ItemAnalysisSender, ItemAnalysisReceiver) {
-        let (sender, receiver) = mpsc::channel(MODULE_MESSAGE_BUFFER);
-        let sender = ItemAnalysisSender::new(sender);
-        let receiver = ItemAnalysisReceiver::new(receiver);
+    fn init_item_analysis_conn(bus_metrics: &mut Vec<Arc<BusMetrics>>) -> (ItemAnalysisSender, ItemAnalysisReceiver) {
+        let (sender, receiver, metrics) = message_bus_with_dead_letter(MODULE_MESSAGE_BUFFER);
+        bus_metrics.push(metrics);
         (sender, receiver)
     }
 
-    fn init_image_classifier_conn() -> (ItemEmbedderSender, ItemEmbedderReceiver) {
-        let (sender, receiver) = mpsc::channel(MODULE_MESSAGE_BUFFER);
-        let sender = ItemEmbedderSender::new(sender);
-        let receiver = ItemEmbedderReceiver::new(receiver);
+    fn init_image_classifier_conn(bus_metrics: &mut Vec<Arc<BusMetrics>>) -> (ItemEmbedderSender, ItemEmbedderReceiver) {
+        let (sender, receiver, metrics) = message_bus_with_dead_letter(MODULE_MESSAGE_BUFFER);
+        bus_metrics.push(metrics);
         (sender, receiver)
     }
 
-    fn storage_conn() -> (StorageSender, StorageReceiver) {
-        let (sender, receiver) = mpsc::channel(MODULE_MESSAGE_BUFFER);
-        let sender = StorageSender::new(sender);
-        let receiver = StorageReceiver::new(receiver);
+    fn storage_conn(bus_metrics: &mut Vec<Arc<BusMetrics>>) -> (StorageSender, StorageReceiver) {
+        let (sender, receiver, metrics) = message_bus_with_dead_letter(MODULE_MESSAGE_BUFFER);
+        bus_metrics.push(metrics);
         (sender, receiver)
     }
 }
\ No newline at end of file
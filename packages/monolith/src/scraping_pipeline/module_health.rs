@@ -0,0 +1,153 @@
+//! This module contains liveness tracking for the pipeline modules.
+use std::{fmt::Debug, sync::atomic::{AtomicI64, Ordering}, time::Duration};
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use tracing::Span;
+use crate::messages::message_buses::MessageReceiver;
+
+/// How often a running module records a heartbeat.
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(5);
+
+/// How old a module's last heartbeat can be before it's considered unhealthy.
+const HEARTBEAT_THRESHOLD: Duration = Duration::from_secs(15);
+
+/// The modules in the pipeline.
+#[derive(Clone, Copy, Debug)]
+pub enum PipelineModule {
+    Scheduler,
+    SearchScraper,
+    ItemScraper,
+    ItemAnalysis,
+    ItemEmbedder,
+    Storage,
+    StateTracker
+}
+
+/// Tracks the last heartbeat (as UNIX milliseconds) of each module's running task.
+/// 
+/// A heartbeat of 0 means the module has never run.
+#[derive(Debug, Default)]
+pub struct ModuleHealth {
+    scheduler: AtomicI64,
+    search_scraper: AtomicI64,
+    item_scraper: AtomicI64,
+    item_analysis: AtomicI64,
+    item_embedder: AtomicI64,
+    storage: AtomicI64,
+    state_tracker: AtomicI64
+}
+
+/// Whether each module is healthy, ie has recorded a heartbeat recently.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ModuleHealthReport {
+    pub scheduler: bool,
+    pub search_scraper: bool,
+    pub item_scraper: bool,
+    pub item_analysis: bool,
+    pub item_embedder: bool,
+    pub storage: bool,
+    pub state_tracker: bool
+}
+
+impl ModuleHealthReport {
+    /// Whether all modules are healthy.
+    pub fn all_healthy(&self) -> bool {
+        self.scheduler 
+            && self.search_scraper 
+            && self.item_scraper 
+            && self.item_analysis 
+            && self.item_embedder 
+            && self.storage 
+            && self.state_tracker
+    }
+}
+
+impl ModuleHealth {
+    /// Instantiate, with no heartbeats recorded.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Record a heartbeat for a module.
+    pub fn beat(&self, module: PipelineModule) {
+        self.heartbeat_of(module).store(Utc::now().timestamp_millis(), Ordering::Relaxed);
+    }
+
+    /// Receive a module's next message (along with its span), recording heartbeats for the module while waiting for it.
+    /// 
+    /// Modules call this from their receive loop, so heartbeats stop if the loop is stuck (eg waiting on a message
+    /// which is never handled), exits, or its task panics.
+    pub async fn receive_with_span<T: Debug>(&self, module: PipelineModule, receiver: &mut MessageReceiver<T>) -> Option<(T, Span)> {
+        self.beat(module);
+        let mut interval = tokio::time::interval_at(tokio::time::Instant::now() + HEARTBEAT_INTERVAL, HEARTBEAT_INTERVAL);
+        let received = receiver.receive_with_span();
+        tokio::pin!(received);
+        loop {
+            tokio::select! {
+                received = &mut received => return received,
+                _ = interval.tick() => self.beat(module)
+            }
+        }
+    }
+
+    /// Report the health of each module.
+    pub fn report(&self) -> ModuleHealthReport {
+        ModuleHealthReport {
+            scheduler: self.is_healthy(PipelineModule::Scheduler),
+            search_scraper: self.is_healthy(PipelineModule::SearchScraper),
+            item_scraper: self.is_healthy(PipelineModule::ItemScraper),
+            item_analysis: self.is_healthy(PipelineModule::ItemAnalysis),
+            item_embedder: self.is_healthy(PipelineModule::ItemEmbedder),
+            storage: self.is_healthy(PipelineModule::Storage),
+            state_tracker: self.is_healthy(PipelineModule::StateTracker)
+        }
+    }
+
+    /// Whether a module's last heartbeat is recent enough.
+    fn is_healthy(&self, module: PipelineModule) -> bool {
+        let last_heartbeat = self.heartbeat_of(module).load(Ordering::Relaxed);
+        let threshold_millis = HEARTBEAT_THRESHOLD.as_millis() as i64;
+        Utc::now().timestamp_millis() - last_heartbeat <= threshold_millis
+    }
+
+    /// Get the heartbeat of a module.
+    fn heartbeat_of(&self, module: PipelineModule) -> &AtomicI64 {
+        match module {
+            PipelineModule::Scheduler => &self.scheduler,
+            PipelineModule::SearchScraper => &self.search_scraper,
+            PipelineModule::ItemScraper => &self.item_scraper,
+            PipelineModule::ItemAnalysis => &self.item_analysis,
+            PipelineModule::ItemEmbedder => &self.item_embedder,
+            PipelineModule::Storage => &self.storage,
+            PipelineModule::StateTracker => &self.state_tracker
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::messages::message_buses::message_bus;
+    use super::*;
+
+    #[tokio::test]
+    async fn receiving_records_a_heartbeat_for_only_that_module() {
+        let health = ModuleHealth::new();
+        let (mut sender, mut receiver, _) = message_bus::<u32>(1);
+        assert!(!health.report().storage);
+        sender.send(1).await.unwrap();
+        let received = health.receive_with_span(PipelineModule::Storage, &mut receiver).await;
+        assert_eq!(received.map(|(message, _)| message), Some(1));
+        let report = health.report();
+        assert!(report.storage);
+        assert!(!report.scheduler);
+        assert!(!report.all_healthy());
+    }
+
+    #[tokio::test]
+    async fn receiving_ends_when_the_bus_closes() {
+        let health = ModuleHealth::new();
+        let (sender, mut receiver, _) = message_bus::<u32>(1);
+        drop(sender);
+        assert!(health.receive_with_span(PipelineModule::Storage, &mut receiver).await.is_none());
+    }
+}
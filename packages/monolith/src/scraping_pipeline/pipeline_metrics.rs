@@ -0,0 +1,93 @@
+//! This module contains throughput counters for the pipeline.
+use std::{fmt::Write, sync::atomic::{AtomicU64, Ordering}};
+use crate::galleries::pipeline_states::GalleryPipelineStateTypes;
+
+/// Counts how many galleries have entered each pipeline stage,
+/// and how many marketplaces have failed in each stage that can fail them.
+#[derive(Debug, Default)]
+pub struct PipelineMetrics {
+    entered_initialization: AtomicU64,
+    entered_search_scraping: AtomicU64,
+    entered_item_scraping: AtomicU64,
+    entered_item_analysis: AtomicU64,
+    entered_item_embedding: AtomicU64,
+    entered_final: AtomicU64,
+    failed_search_scraping_marketplaces: AtomicU64,
+    failed_item_scraping_marketplaces: AtomicU64,
+    failed_item_analysis_marketplaces: AtomicU64
+}
+
+impl PipelineMetrics {
+    /// Instantiate, with all counters at 0.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Record a gallery entering a stage.
+    pub fn record_stage_entered(&self, state_type: &GalleryPipelineStateTypes) {
+        self.stage_counter(state_type).fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Record marketplaces failing in a stage.
+    ///
+    /// Stages which can't fail marketplaces are ignored.
+    pub fn record_failed_marketplaces(&self, state_type: &GalleryPipelineStateTypes, num_failed: usize) {
+        if let Some(counter) = self.failed_marketplaces_counter(state_type) {
+            counter.fetch_add(num_failed as u64, Ordering::Relaxed);
+        }
+    }
+
+    /// Render all counters in the Prometheus text exposition format.
+    pub fn render_prometheus(&self) -> String {
+        let mut output = String::new();
+        let _ = writeln!(output, "# HELP itemtracker_stage_entered_total Number of galleries which entered a pipeline stage.");
+        let _ = writeln!(output, "# TYPE itemtracker_stage_entered_total counter");
+        for state_type in Self::all_stages() {
+            let count = self.stage_counter(&state_type).load(Ordering::Relaxed);
+            let _ = writeln!(output, "itemtracker_stage_entered_total{{stage=\"{state_type:?}\"}} {count}");
+        }
+        let _ = writeln!(output, "# HELP itemtracker_failed_marketplaces_total Number of marketplaces which failed in a pipeline stage.");
+        let _ = writeln!(output, "# TYPE itemtracker_failed_marketplaces_total counter");
+        for state_type in Self::all_stages() {
+            if let Some(counter) = self.failed_marketplaces_counter(&state_type) {
+                let count = counter.load(Ordering::Relaxed);
+                let _ = writeln!(output, "itemtracker_failed_marketplaces_total{{stage=\"{state_type:?}\"}} {count}");
+            }
+        }
+        output
+    }
+
+    /// All stages, in pipeline order.
+    fn all_stages() -> [GalleryPipelineStateTypes; 6] {
+        [
+            GalleryPipelineStateTypes::Initialization,
+            GalleryPipelineStateTypes::SearchScraping,
+            GalleryPipelineStateTypes::ItemScraping,
+            GalleryPipelineStateTypes::ItemAnalysis,
+            GalleryPipelineStateTypes::ItemEmbedding,
+            GalleryPipelineStateTypes::Final
+        ]
+    }
+
+    /// Get the counter for galleries entering a stage.
+    fn stage_counter(&self, state_type: &GalleryPipelineStateTypes) -> &AtomicU64 {
+        match state_type {
+            GalleryPipelineStateTypes::Initialization => &self.entered_initialization,
+            GalleryPipelineStateTypes::SearchScraping => &self.entered_search_scraping,
+            GalleryPipelineStateTypes::ItemScraping => &self.entered_item_scraping,
+            GalleryPipelineStateTypes::ItemAnalysis => &self.entered_item_analysis,
+            GalleryPipelineStateTypes::ItemEmbedding => &self.entered_item_embedding,
+            GalleryPipelineStateTypes::Final => &self.entered_final
+        }
+    }
+
+    /// Get the counter for failed marketplaces in a stage, if the stage can fail marketplaces.
+    fn failed_marketplaces_counter(&self, state_type: &GalleryPipelineStateTypes) -> Option<&AtomicU64> {
+        match state_type {
+            GalleryPipelineStateTypes::SearchScraping => Some(&self.failed_search_scraping_marketplaces),
+            GalleryPipelineStateTypes::ItemScraping => Some(&self.failed_item_scraping_marketplaces),
+            GalleryPipelineStateTypes::ItemAnalysis => Some(&self.failed_item_analysis_marketplaces),
+            _ => None
+        }
+    }
+}
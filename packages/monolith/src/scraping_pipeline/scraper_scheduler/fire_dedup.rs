@@ -0,0 +1,105 @@
+//! Contains the de-dup window for galleries' scheduled fires.
+use std::{collections::HashMap, path::PathBuf, sync::Arc, time::Duration};
+use tokio::sync::Mutex;
+use crate::{config::ScraperSchedulerConfig, galleries::domain_types::{GalleryId, UnixUtcDateTime}};
+
+/// Suppresses a gallery's scheduled fire if it last fired within the de-dup window,
+/// so galleries whose fire time just passed aren't scraped twice around a restart (as each gallery fires on being scheduled).
+/// 
+/// If it has a path, each gallery's last fired time is rewritten to it as JSON on every fire, and loaded from it on startup,
+/// so they survive restarts.
+#[derive(Clone)]
+pub(super) struct FireDedup {
+    window: Duration,
+    path: Option<PathBuf>,
+    last_fired: Arc<Mutex<HashMap<GalleryId, UnixUtcDateTime>>>
+}
+
+impl FireDedup {
+    /// Initialize the de-dup window, loading the last fired times from its file if it has one.
+    /// 
+    /// The window is capped to the minimum scrape interval, so it can't suppress a gallery's regular fires.
+    /// An unreadable file is logged and ignored, starting with no last fired times.
+    pub fn load(config: &ScraperSchedulerConfig) -> Self {
+        let path = match config.last_fired_path.trim() {
+            "" => None,
+            path => Some(PathBuf::from(path))
+        };
+        let last_fired = match &path {
+            Some(path) => match std::fs::read_to_string(path) {
+                Ok(last_fired_str) => serde_json::from_str::<HashMap<GalleryId, UnixUtcDateTime>>(&last_fired_str)
+                    .unwrap_or_else(|err| {
+                        tracing::error!("Failed to parse scheduler last fired file {path:?}; starting without last fired times: {err}");
+                        HashMap::new()
+                    }),
+                Err(err) if err.kind() == std::io::ErrorKind::NotFound => HashMap::new(),
+                Err(err) => {
+                    tracing::error!("Failed to read scheduler last fired file {path:?}; starting without last fired times: {err}");
+                    HashMap::new()
+                }
+            },
+            None => HashMap::new()
+        };
+        if !last_fired.is_empty() {
+            tracing::info!("Loaded last fired times for {} galleries", last_fired.len());
+        }
+        Self {
+            window: Duration::from_secs(config.dedup_window_secs.min(config.min_scrape_interval_secs)),
+            path,
+            last_fired: Arc::new(Mutex::new(last_fired))
+        }
+    }
+
+    /// Returns whether the gallery should fire now; if so, this is recorded as its last fired time.
+    /// 
+    /// Always returns `true` if the window is 0.
+    pub async fn try_fire(&self, gallery_id: &GalleryId) -> bool {
+        if self.window.is_zero() {
+            return true;
+        }
+        let mut last_fired = self.last_fired.lock().await;
+        let now = UnixUtcDateTime::now();
+        if let Some(last_fired_time) = last_fired.get(gallery_id) {
+            let since_last_fired = (*now - **last_fired_time)
+                .to_std()
+                .unwrap_or(Duration::ZERO);
+            if since_last_fired < self.window {
+                tracing::info!("Gallery {gallery_id} last fired {since_last_fired:?} ago, within the de-dup window; skipping this fire");
+                return false;
+            }
+        }
+        last_fired.insert(gallery_id.clone(), now);
+        self.persist(&last_fired).await;
+        true
+    }
+
+    /// Forget a gallery's last fired time (ie once it's deleted).
+    pub async fn forget(&self, gallery_id: &GalleryId) {
+        let mut last_fired = self.last_fired.lock().await;
+        if last_fired.remove(gallery_id).is_some() {
+            self.persist(&last_fired).await;
+        }
+    }
+
+    /// Write the last fired times to the file, if there is one; failures are only logged.
+    /// 
+    /// Writes to a temporary file first, then renames it over the actual file, so a crash mid-write doesn't corrupt it.
+    async fn persist(&self, last_fired: &HashMap<GalleryId, UnixUtcDateTime>) {
+        let Some(path) = &self.path else { return };
+        let last_fired_str = match serde_json::to_string(last_fired) {
+            Ok(last_fired_str) => last_fired_str,
+            Err(err) => {
+                tracing::error!("Failed to serialize scheduler last fired times: {err}");
+                return;
+            }
+        };
+        let temp_path = path.with_extension("tmp");
+        if let Err(err) = tokio::fs::write(&temp_path, last_fired_str).await {
+            tracing::error!("Failed to write scheduler last fired file {path:?}: {err}");
+            return;
+        }
+        if let Err(err) = tokio::fs::rename(&temp_path, path).await {
+            tracing::error!("Failed to replace scheduler last fired file {path:?}: {err}");
+        }
+    }
+}
@@ -0,0 +1,135 @@
+//! Contains the limit on how many galleries can be in the pipeline at once, from scheduled fires.
+use std::{sync::Arc, time::Duration};
+use tokio::sync::{Mutex, MutexGuard};
+use crate::{config::ScraperSchedulerConfig, galleries::domain_types::GalleryId, messages::StateTrackerSender};
+
+/// Defers galleries' scheduled fires while `max_in_flight` galleries are in the pipeline, to protect downstream capacity.
+/// 
+/// The number of galleries in flight is taken from the state tracker, so it includes galleries started by any trigger;
+/// only scheduled fires are deferred, though.
+#[derive(Clone)]
+pub(super) struct InFlightLimit {
+    max_in_flight: usize,
+    poll_interval: Duration,
+    /// Held by a deferred fire until it's started, so fires are let through one at a time, in the order they were deferred.
+    waiting: Arc<Mutex<()>>,
+    state_tracker_sender: StateTrackerSender
+}
+
+impl InFlightLimit {
+    /// Instantiate the limit; a `max_in_flight_galleries` of 0 means there is no limit.
+    pub fn new(config: &ScraperSchedulerConfig, state_tracker_sender: StateTrackerSender) -> Self {
+        Self {
+            max_in_flight: config.max_in_flight_galleries,
+            poll_interval: Duration::from_secs(config.scrape_queue_poll_interval_secs.max(1)),
+            waiting: Arc::new(Mutex::new(())),
+            state_tracker_sender
+        }
+    }
+
+    /// Waits until fewer than `max_in_flight` galleries are in the pipeline, checking every poll interval, and logging the deferral.
+    /// 
+    /// The returned guard should be held until the gallery's scrape is started, so another fire can't take the same capacity.
+    /// If the state tracker can't be reached, this is logged, and the fire isn't deferred.
+    pub async fn wait_for_capacity(&self, gallery_id: &GalleryId) -> Option<MutexGuard<'_, ()>> {
+        if self.max_in_flight == 0 {
+            return None;
+        }
+        let guard = self.waiting.lock().await;
+        let mut deferred = false;
+        loop {
+            let num_in_flight = match self.state_tracker_sender.clone().get_in_flight_galleries().await {
+                Ok(Ok(galleries)) => galleries.len(),
+                Ok(Err(err)) => {
+                    tracing::warn!("Failed to get in-flight galleries from the state tracker; not deferring gallery {gallery_id}: {err}");
+                    break;
+                },
+                Err(err) => {
+                    tracing::warn!("Failed to message the state tracker for in-flight galleries; not deferring gallery {gallery_id}: {err}");
+                    break;
+                }
+            };
+            if num_in_flight < self.max_in_flight {
+                break;
+            }
+            match deferred {
+                false => tracing::info!(
+                    "{num_in_flight} galleries are in flight (max: {}); deferring scheduled scrape of gallery {gallery_id}",
+                    self.max_in_flight
+                ),
+                true => tracing::debug!("Scheduled scrape of gallery {gallery_id} is still deferred ({num_in_flight} galleries in flight)")
+            }
+            deferred = true;
+            tokio::time::sleep(self.poll_interval).await;
+        }
+        if deferred {
+            tracing::info!("Starting deferred scheduled scrape of gallery {gallery_id}");
+        }
+        Some(guard)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{config::scraper_scheduler::ConcurrentScrapePolicy, messages::message_types::state_tracker::StateTrackerMessage, test_support::TestHarness};
+    use super::*;
+
+    fn limit(harness: &TestHarness, max_in_flight_galleries: usize) -> InFlightLimit {
+        let config = ScraperSchedulerConfig {
+            min_scrape_interval_secs: 0,
+            jitter_window_secs: 0,
+            rescrape_on_criteria_change: false,
+            concurrent_scrape_policy: ConcurrentScrapePolicy::Reject,
+            scrape_queue_poll_interval_secs: 1,
+            dedup_window_secs: 0,
+            last_fired_path: String::new(),
+            sync_interval_secs: 0,
+            max_in_flight_galleries
+        };
+        InFlightLimit::new(&config, harness.state_tracker_sender())
+    }
+
+    /// Answer the state tracker's next in-flight galleries request with `num_in_flight` galleries.
+    async fn answer_in_flight(harness: &mut TestHarness, num_in_flight: usize) {
+        match harness.state_tracker.expect_message_within(Duration::from_secs(2)).await {
+            StateTrackerMessage::GetInFlightGalleries(msg) => msg
+                .act(|_| Ok((0..num_in_flight).map(|i| GalleryId::from(i.to_string())).collect()))
+                .unwrap(),
+            other => panic!("Expected the in-flight galleries to be requested, but got {other:?}")
+        }
+    }
+
+    #[tokio::test]
+    async fn a_fire_past_the_limit_is_deferred_until_theres_room() {
+        let mut harness = TestHarness::new();
+        let limit = limit(&harness, 2);
+        let fire = tokio::spawn(async move { 
+            limit.wait_for_capacity(&GalleryId::from("third".to_string())).await.is_some() 
+        });
+        answer_in_flight(&mut harness, 2).await;
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(!fire.is_finished());
+
+        answer_in_flight(&mut harness, 1).await;
+        assert!(fire.await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn a_fire_within_the_limit_isnt_deferred() {
+        let mut harness = TestHarness::new();
+        let limit = limit(&harness, 2);
+        let fire = tokio::spawn(async move { 
+            limit.wait_for_capacity(&GalleryId::from("second".to_string())).await.is_some() 
+        });
+        answer_in_flight(&mut harness, 1).await;
+        assert!(fire.await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn without_a_limit_the_state_tracker_isnt_consulted() {
+        let mut harness = TestHarness::new();
+        let limit = limit(&harness, 0);
+        assert!(limit.wait_for_capacity(&GalleryId::from("gallery".to_string())).await.is_none());
+        harness.state_tracker.expect_no_message_within(Duration::from_millis(100)).await;
+    }
+}
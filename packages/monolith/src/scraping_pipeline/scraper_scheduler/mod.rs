@@ -1,19 +1,26 @@
+use std::{sync::Arc, time::Duration};
 use scheduler::SchedulerHandler;
-use tracing::info;
-use crate::{config::ScraperSchedulerConfig, messages::{message_types::scraper_scheduler::SchedulerMessage, ScraperSchedulerReceiver, SearchScraperSender, StateTrackerSender}};
+use tracing::{info, Instrument};
+use crate::{config::ScraperSchedulerConfig, messages::{message_types::scraper_scheduler::SchedulerMessage, ScraperSchedulerReceiver, SearchScraperSender, StateTrackerSender, StorageSender}, scraping_pipeline::{module_health::{ModuleHealth, PipelineModule}, pipeline_metrics::PipelineMetrics}, utils::tracing_context::module_span};
 
+mod fire_dedup;
+mod in_flight_limit;
 mod scheduled_task;
 mod scheduler;
+mod scrape_lock;
 
 /// Module in charge of scheduling scraping tasks.
 /// 
 /// This module is fairly straightforward. Gallery creation/update/deletion is received through `msg_receiver`.
 /// 
 /// Whenever a gallery is scheduled to be scraped, it is sent through the `search_scraper_sender`.
+/// 
+/// Galleries' scheduler states are kept in storage, which the scheduler reloads from on startup and periodically re-syncs with.
 pub struct ScraperSchedulerModule {
     scheduler: SchedulerHandler,
     msg_receiver: ScraperSchedulerReceiver,
-    search_scraper_sender: SearchScraperSender
+    search_scraper_sender: SearchScraperSender,
+    sync_interval_secs: u64
 }
 
 impl ScraperSchedulerModule {
@@ -22,22 +29,42 @@ impl ScraperSchedulerModule {
         config: ScraperSchedulerConfig,
         msg_receiver: ScraperSchedulerReceiver,
         search_scraper_sender: SearchScraperSender,
-        state_tracker_sender: StateTrackerSender
+        state_tracker_sender: StateTrackerSender,
+        storage_sender: StorageSender,
+        pipeline_metrics: Arc<PipelineMetrics>
     ) -> Self
     {
         ScraperSchedulerModule {
-            scheduler: SchedulerHandler::new(search_scraper_sender.clone(), state_tracker_sender),
+            scheduler: SchedulerHandler::new(&config, search_scraper_sender.clone(), state_tracker_sender, storage_sender, pipeline_metrics),
             msg_receiver,
-            search_scraper_sender
+            search_scraper_sender,
+            sync_interval_secs: config.sync_interval_secs
         }
         
     }
     
-    /// Start accepting and acting on messages.
-    pub async fn run(&mut self) {
+    /// Load the galleries from storage, then start accepting and acting on messages.
+    /// 
+    /// If enabled, the galleries are also re-synced with storage every `sync_interval_secs`, to catch updates made there.
+    pub async fn run(&mut self, module_health: &ModuleHealth) {
         info!("ScraperSchedulerModule is running...");
-        while let Some(msg) = self.msg_receiver.receive().await {
-            self.process_msg(msg).await;
+        self.scheduler.sync_from_storage().await;
+        let sync_enabled = self.sync_interval_secs > 0;
+        let sync_interval = Duration::from_secs(self.sync_interval_secs.max(1));
+        // The first tick is delayed, as the galleries were just loaded
+        let mut sync_interval = tokio::time::interval_at(tokio::time::Instant::now() + sync_interval, sync_interval);
+        loop {
+            tokio::select! {
+                received = module_health.receive_with_span(PipelineModule::Scheduler, &mut self.msg_receiver) => {
+                    let Some((msg, span)) = received else {
+                        break;
+                    };
+                    self.process_msg(msg)
+                        .instrument(module_span(&span, PipelineModule::Scheduler))
+                        .await;
+                },
+                _ = sync_interval.tick(), if sync_enabled => self.scheduler.sync_from_storage().await
+            }
         }
     }
 
@@ -66,7 +93,7 @@ impl ScraperSchedulerModule {
             },
             SchedulerMessage::UpdateGallery(msg) => {
                 let result = msg.act_async(|gallery| async {
-                    tracing::info!("Received message to delete gallery {} in scheduler", gallery.gallery_id);
+                    tracing::info!("Received message to update gallery {} in scheduler", gallery.gallery_id);
                     self.scheduler.update_gallery(gallery).await
                 })
                     .await;
@@ -74,6 +101,188 @@ impl ScraperSchedulerModule {
                     tracing::error!("Could not respond to message; response: {err:?}");
                 };
             },
+            SchedulerMessage::GetNextRun(msg) => {
+                let result = msg.act_async(|gallery_id| async {
+                    tracing::trace!("Received message to get next run of gallery {gallery_id}");
+                    self.scheduler.next_run(gallery_id).await
+                })
+                    .await;
+                if let Err(err) = result {
+                    tracing::error!("Could not respond to message; response: {err:?}");
+                };
+            },
+            SchedulerMessage::ForceScrape(msg) => {
+                let result = msg.act_async(|gallery_id| async {
+                    tracing::info!("Received message to force a scrape of gallery {gallery_id}");
+                    self.scheduler.force_scrape(gallery_id).await
+                })
+                    .await;
+                if let Err(err) = result {
+                    tracing::error!("Could not respond to message; response: {err:?}");
+                };
+            },
+            SchedulerMessage::SetEnabled(msg) => {
+                let result = msg.act_async(|(gallery_id, enabled)| async move {
+                    tracing::info!("Received message to set gallery {gallery_id} to enabled: {enabled}");
+                    self.scheduler.set_enabled(gallery_id, enabled).await
+                })
+                    .await;
+                if let Err(err) = result {
+                    tracing::error!("Could not respond to message; response: {err:?}");
+                };
+            },
+            SchedulerMessage::GetSchedule(msg) => {
+                let result = msg.act_async(|_| async {
+                    tracing::trace!("Received message to get the schedule");
+                    self.scheduler.snapshot().await
+                })
+                    .await;
+                if let Err(err) = result {
+                    tracing::error!("Could not respond to message; response: {err:?}");
+                };
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use crate::{config::scraper_scheduler::ConcurrentScrapePolicy, galleries::domain_types::{GalleryId, Marketplace, RunId, UnixUtcDateTime, ValidCronString}, messages::message_types::{scraper_scheduler::{GetScheduleMessage, NewGalleryMessage, SchedulerError, UpdateGalleryMessage}, search_scraper::SearchScraperMessage, state_tracker::StateTrackerMessage, storage::StorageMessage}, test_support::{scheduler_state, TestHarness}};
+    use super::*;
+
+    fn config() -> ScraperSchedulerConfig {
+        ScraperSchedulerConfig {
+            min_scrape_interval_secs: 0,
+            jitter_window_secs: 0,
+            rescrape_on_criteria_change: false,
+            concurrent_scrape_policy: ConcurrentScrapePolicy::Reject,
+            scrape_queue_poll_interval_secs: 1,
+            dedup_window_secs: 0,
+            last_fired_path: String::new(),
+            sync_interval_secs: 0,
+            max_in_flight_galleries: 0
+        }
+    }
+
+    /// Spawn a scheduler driven over the harness's buses, which answers storage's initial sync with no galleries.
+    async fn spawn_scheduler(harness: &mut TestHarness) {
+        spawn_scheduler_with(harness, config()).await;
+    }
+
+    /// Spawn a scheduler with the given config, which answers storage's initial sync with no galleries.
+    async fn spawn_scheduler_with(harness: &mut TestHarness, config: ScraperSchedulerConfig) {
+        let mut module = ScraperSchedulerModule::init(
+            config,
+            harness.scraper_scheduler.take_receiver(),
+            harness.search_scraper.sender(),
+            harness.state_tracker_sender(),
+            harness.storage.sender(),
+            Arc::new(PipelineMetrics::new())
+        );
+        harness.spawn(async move { module.run(&ModuleHealth::new()).await });
+        match harness.storage.expect_message().await {
+            StorageMessage::GetSchedulerStates(msg) => msg.act(|_| vec![]).unwrap(),
+            other => panic!("Expected the scheduler to sync with storage, but got {other:?}")
+        }
+    }
+
+    #[tokio::test]
+    async fn a_new_gallery_is_stored_and_sent_into_the_pipeline() {
+        let mut harness = TestHarness::new();
+        spawn_scheduler(&mut harness).await;
+        let gallery_id = GalleryId::from("gallery".to_string());
+        let (msg, receiver) = NewGalleryMessage::new(scheduler_state("gallery"));
+        harness.scraper_scheduler.inject(SchedulerMessage::NewGallery(msg)).await;
+
+        match harness.storage.expect_message().await {
+            StorageMessage::PutSchedulerState { gallery } => assert_eq!(gallery.gallery_id, gallery_id),
+            other => panic!("Expected the gallery's scheduler state to be stored, but got {other:?}")
+        }
+        match harness.state_tracker.expect_message().await {
+            StateTrackerMessage::AddGallery(msg) => msg.act(|_| Ok(RunId::new())).unwrap(),
+            other => panic!("Expected the gallery to be added to the state tracker, but got {other:?}")
         }
+        match harness.search_scraper.expect_message().await {
+            SearchScraperMessage::ScrapeSearch { gallery_id: scraped_id } => assert_eq!(scraped_id, gallery_id),
+            other => panic!("Expected the gallery to be search scraped, but got {other:?}")
+        }
+        assert!(receiver.await.unwrap().is_ok());
+        // It isn't scraped again until its next scheduled time
+        harness.search_scraper.expect_no_message_within(Duration::from_millis(100)).await;
+    }
+
+    #[tokio::test]
+    async fn an_update_with_an_invalid_schedule_keeps_the_previous_one() {
+        let mut harness = TestHarness::new();
+        spawn_scheduler_with(&mut harness, ScraperSchedulerConfig { min_scrape_interval_secs: 300, ..config() }).await;
+        let (msg, receiver) = NewGalleryMessage::new(scheduler_state("gallery"));
+        harness.scraper_scheduler.inject(SchedulerMessage::NewGallery(msg)).await;
+        harness.storage.expect_message().await;
+        match harness.state_tracker.expect_message().await {
+            StateTrackerMessage::AddGallery(msg) => msg.act(|_| Ok(RunId::new())).unwrap(),
+            other => panic!("Expected the gallery to be added to the state tracker, but got {other:?}")
+        }
+        harness.search_scraper.expect_message().await;
+        assert!(receiver.await.unwrap().is_ok());
+
+        // Fires every minute, which is more often than the minimum interval
+        let mut updated_gallery = scheduler_state("gallery");
+        updated_gallery.scraping_periodicity = ValidCronString::new("* * * * *".into()).unwrap();
+        let (msg, receiver) = UpdateGalleryMessage::new(updated_gallery);
+        harness.scraper_scheduler.inject(SchedulerMessage::UpdateGallery(msg)).await;
+        assert!(matches!(receiver.await.unwrap(), Err(SchedulerError::InvalidSchedule { .. })));
+        harness.storage.expect_no_message_within(Duration::from_millis(100)).await;
+
+        let (msg, receiver) = GetScheduleMessage::new(());
+        harness.scraper_scheduler.inject(SchedulerMessage::GetSchedule(msg)).await;
+        let schedule = receiver.await.unwrap();
+        assert_eq!(schedule.len(), 1);
+        assert_eq!(schedule[0].scraping_periodicity.get_str(), "0 * * * *");
+        assert!(schedule[0].enabled);
+        assert!(schedule[0].next_fire_time.is_some());
+    }
+
+    #[tokio::test]
+    async fn an_update_merges_the_previous_scraped_datetimes() {
+        let mut harness = TestHarness::new();
+        spawn_scheduler(&mut harness).await;
+        let mut gallery = scheduler_state("gallery");
+        gallery.marketplace_previous_scraped_datetimes = HashMap::from([(Marketplace::Mercari, UnixUtcDateTime::from(100))]);
+        let (msg, receiver) = NewGalleryMessage::new(gallery);
+        harness.scraper_scheduler.inject(SchedulerMessage::NewGallery(msg)).await;
+        harness.storage.expect_message().await;
+        match harness.state_tracker.expect_message().await {
+            StateTrackerMessage::AddGallery(msg) => msg.act(|_| Ok(RunId::new())).unwrap(),
+            other => panic!("Expected the gallery to be added to the state tracker, but got {other:?}")
+        }
+        harness.search_scraper.expect_message().await;
+        assert!(receiver.await.unwrap().is_ok());
+
+        // An update without any datetimes keeps the gallery's
+        let (msg, receiver) = UpdateGalleryMessage::new(scheduler_state("gallery"));
+        harness.scraper_scheduler.inject(SchedulerMessage::UpdateGallery(msg)).await;
+        match harness.storage.expect_message().await {
+            StorageMessage::PutSchedulerState { gallery } => assert_eq!(
+                gallery.marketplace_previous_scraped_datetimes, 
+                HashMap::from([(Marketplace::Mercari, UnixUtcDateTime::from(100))])
+            ),
+            other => panic!("Expected the gallery's scheduler state to be stored, but got {other:?}")
+        }
+        assert!(receiver.await.unwrap().is_ok());
+
+        // An update with datetimes moves the gallery's forward
+        let mut updated_gallery = scheduler_state("gallery");
+        updated_gallery.marketplace_previous_scraped_datetimes = HashMap::from([(Marketplace::Mercari, UnixUtcDateTime::from(200))]);
+        let (msg, receiver) = UpdateGalleryMessage::new(updated_gallery);
+        harness.scraper_scheduler.inject(SchedulerMessage::UpdateGallery(msg)).await;
+        match harness.storage.expect_message().await {
+            StorageMessage::PutSchedulerState { gallery } => assert_eq!(
+                gallery.marketplace_previous_scraped_datetimes, 
+                HashMap::from([(Marketplace::Mercari, UnixUtcDateTime::from(200))])
+            ),
+            other => panic!("Expected the gallery's scheduler state to be stored, but got {other:?}")
+        }
+        assert!(receiver.await.unwrap().is_ok());
     }
-}
\ No newline at end of file
+}
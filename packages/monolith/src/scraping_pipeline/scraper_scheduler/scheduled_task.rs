@@ -1,45 +1,113 @@
-use chrono::Utc;
-use crate::{galleries::pipeline_states::{GalleryPipelineStates, GallerySchedulerState, GallerySearchScrapingState}, messages::{message_types::{scraper_scheduler::SchedulerError, state_tracker::StateTrackerError}, SearchScraperSender, StateTrackerSender}};
+use std::{sync::{atomic::{AtomicBool, Ordering}, Arc, Mutex}, time::Duration};
+use crate::{galleries::{domain_types::{UnixUtcDateTime, ValidCronString}, pipeline_states::GallerySchedulerState}, messages::message_types::scraper_scheduler::{SchedulerError, ScrapeStart}};
+use super::{fire_dedup::FireDedup, in_flight_limit::InFlightLimit, scrape_lock::ScrapeLock};
+
+/// The jittered time a gallery's task is sleeping until.
+/// 
+/// This is shared with the scheduler, so it can report the gallery's next scrape without locking the running task.
+#[derive(Clone, Default)]
+pub struct NextFireTime(Arc<Mutex<Option<UnixUtcDateTime>>>);
+
+impl NextFireTime {
+    /// Set the time the task is sleeping until.
+    fn set(&self, fire_time: UnixUtcDateTime) {
+        *self.0.lock().expect("Next fire time lock should never be poisoned") = Some(fire_time);
+    }
+
+    /// Get the time the task is sleeping until, if it's still after `now`.
+    /// 
+    /// Returns `None` if the task hasn't slept yet, or the time has passed (ie the task is firing).
+    pub fn upcoming(&self, now: &UnixUtcDateTime) -> Option<UnixUtcDateTime> {
+        self.0
+            .lock()
+            .expect("Next fire time lock should never be poisoned")
+            .clone()
+            .filter(|fire_time| fire_time > now)
+    }
+}
+
+/// Returns the pattern's first occurrence after `now`, delayed by `jitter`.
+/// 
+/// Returns `None` if the pattern never fires again.
+pub fn jittered_fire_time(periodicity: &ValidCronString, now: &UnixUtcDateTime, jitter: Duration) -> Option<UnixUtcDateTime> {
+    let jitter = chrono::Duration::from_std(jitter).unwrap_or(chrono::Duration::zero());
+    periodicity
+        .next_fire_time(now)
+        .map(|next_time| UnixUtcDateTime::from(*next_time + jitter))
+}
 
 /// A wrapper representing the actual running scheduler task for a gallery, which starts on `run()`.
 pub struct ScheduledGalleryTask {
     gallery: GallerySchedulerState,
-    state_tracker_sender: StateTrackerSender,
-    search_scraper_sender: SearchScraperSender
+    jitter: Duration,
+    next_fire_time: NextFireTime,
+    enabled: Arc<AtomicBool>,
+    scrape_lock: ScrapeLock,
+    fire_dedup: FireDedup,
+    in_flight_limit: InFlightLimit
 }
 
 impl ScheduledGalleryTask {
     /// Initialize a `ScheduledGalleryTask`.
+    /// 
+    /// Each scheduled scrape is delayed by `jitter` past its Cron occurrence.
+    /// 
+    /// `next_fire_time` and `enabled` are shared with the scheduler, so the gallery's next scrape can be read 
+    /// and the gallery can be disabled without locking the running task.
     pub fn new(
         gallery: GallerySchedulerState,
-        state_tracker_sender: StateTrackerSender,
-        search_scraper_sender: SearchScraperSender
+        jitter: Duration,
+        next_fire_time: NextFireTime,
+        enabled: Arc<AtomicBool>,
+        scrape_lock: ScrapeLock,
+        fire_dedup: FireDedup,
+        in_flight_limit: InFlightLimit
     ) -> Self
     {
         Self { 
             gallery, 
-            state_tracker_sender,
-            search_scraper_sender
+            jitter,
+            next_fire_time,
+            enabled,
+            scrape_lock,
+            fire_dedup,
+            in_flight_limit
         }
     }
 
-    /// Schedules the gallery at the appointed periodicity, and registers the gallery in the state tracker.
+    /// Scrapes the gallery at the appointed periodicity, through the scrape lock.
     /// 
-    /// If the gallery is already registered with the state tracker, it won't be scheduled.
+    /// If the gallery is already being scraped, the scrape is queued or skipped, depending on the concurrent scrape policy.
+    /// If it's disabled, or it last fired within the de-dup window (ie just before a restart), it isn't scraped.
+    /// If the max number of galleries are in flight, the scrape is deferred until there's room, rather than dropped;
+    /// any fires missed while deferred are coalesced into it.
     /// 
     /// Returns with an `Err` if:
-    /// - we cannot send a message to or receive a response from the state tracker
+    /// - we cannot send a message to the state tracker or search scraper
     /// - the Cron schedule is unable to return the next occurrence
     pub async fn run(&mut self) -> Result<(), ()>  {   
         loop {
-            match self.add_gallery_to_state().await {
-                Ok(res) => {
-                    if let Err(err) = res {
-                        tracing::warn!("Could not add gallery {} to state; it already exists", self.gallery.gallery_id);
-                    }
+            if !self.enabled.load(Ordering::Relaxed) {
+                tracing::info!("Gallery {} is disabled; skipping its scheduled scrape", self.gallery.gallery_id);
+                self.sleep_to_next_time().await?;
+                continue;
+            }
+            if !self.fire_dedup.try_fire(&self.gallery.gallery_id).await {
+                self.sleep_to_next_time().await?;
+                continue;
+            }
+            let start_result = {
+                let _capacity = self.in_flight_limit.wait_for_capacity(&self.gallery.gallery_id).await;
+                self.scrape_lock.start_scrape(&self.gallery).await
+            };
+            match start_result {
+                Ok(ScrapeStart::Started) => tracing::info!("Started scheduled scrape of gallery {}", self.gallery.gallery_id),
+                Ok(ScrapeStart::Queued) => (),
+                Err(SchedulerError::ScrapeInProgress { .. }) => {
+                    tracing::warn!("Skipping scheduled scrape of gallery {}; a scrape is already in progress", self.gallery.gallery_id)
                 },
                 Err(err) => {
-                    tracing::error!("Error adding new gallery to state: {err}");
+                    tracing::error!("Error starting scheduled scrape: {err}");
                     return Err(());
                 } 
             }
@@ -47,64 +115,75 @@ impl ScheduledGalleryTask {
         }
     }
 
-    /// Update the gallery that will be sent to the scraper.
-    pub fn update_gallery(&mut self, updated_gallery: GallerySchedulerState) -> Result<(), SchedulerError> {
-        if updated_gallery.gallery_id != self.gallery.gallery_id {
-            return Err(SchedulerError::GalleryUpdateHasWrongId { gallery_id: self.gallery.gallery_id.clone() })
-        }
-        self.gallery = updated_gallery;
-        Ok(())
-    }
-
-    /// Adds a gallery to state.
+    /// Same as `run`, but waits for the next scheduled time before the first scrape.
     /// 
-    /// Returns an Err if unable to contact the state tracker.
-    /// Inside, returns an `Err` if the gallery already exists in state.
-    async fn add_gallery_to_state(&mut self) -> Result<Result<(), StateTrackerError>, SchedulerError> {
-        let gallery = self.gallery.clone();
-        let new_gallery_state = GallerySearchScrapingState {
-            gallery_id: gallery.gallery_id,
-            search_criteria: gallery.search_criteria,
-            marketplace_previous_scraped_datetimes: gallery.marketplace_previous_scraped_datetimes,
-            evaluation_criteria: gallery.evaluation_criteria
-        };
-        self.state_tracker_sender
-            .add_gallery(
-                new_gallery_state.gallery_id.clone(), 
-                GalleryPipelineStates::SearchScraping(new_gallery_state)
-            )
-            .await
-            .map_err(|err| SchedulerError::Other { 
-                gallery_id: self.gallery.gallery_id.clone(),
-                message: format!("Unable to send message to state tracker: {err}") 
-            })
+    /// Used for tasks replacing an updated gallery's task, so that updating a gallery doesn't scrape it.
+    pub async fn run_from_next_time(&mut self) -> Result<(), ()> {
+        self.sleep_to_next_time().await?;
+        self.run().await
     }
 
-    /// Sleeps till the next scheduled time.
+    /// Sleeps till the next scheduled time, plus the gallery's jitter, sharing that time with the scheduler.
     ///
-    /// Returns an `Err` if the Cron cannot get the next scheduled time (should never happen).
+    /// Returns an `Err` if the Cron never fires again.
     async fn sleep_to_next_time(&mut self) -> Result<(), ()> {
-        let cur_time = Utc::now();
-        let next_time = self.gallery.scraping_periodicity
-            .get_cron()
-            .find_next_occurrence(&cur_time, false);
+        let cur_time = UnixUtcDateTime::now();
+        let next_time = jittered_fire_time(&self.gallery.scraping_periodicity, &cur_time, self.jitter);
         match next_time {
-            Ok(next_time) => {
-                let time_to_next_schedule = (next_time - cur_time)
+            Some(next_time) => {
+                let time_to_next_schedule = (*next_time - *cur_time)
                     .to_std()
                     .expect("Should never fail, as this time should logically always be greater than 0");
+                self.next_fire_time.set(next_time);
                 tokio::time::sleep(time_to_next_schedule).await;
                 Ok(())
             },
-            Err(err) => {
+            None => {
                 // TODO: pretty critical error, should have some way to persist this info
                 tracing::error!(
-                    "Error trying to schedule the next scrape for gallery {}; this gallery will now stop: {}",
-                    &self.gallery.gallery_id,
-                    err
+                    "Gallery {}'s schedule never fires again; this gallery will now stop",
+                    &self.gallery.gallery_id
                 );
                 return Err(());
             },
         }
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Fires at the start of every hour.
+    fn hourly() -> ValidCronString {
+        ValidCronString::new("0 * * * *".into()).unwrap()
+    }
+
+    #[test]
+    fn the_fire_time_is_the_next_occurrence_plus_the_jitter() {
+        let just_before_the_hour = UnixUtcDateTime::from(3600 - 1);
+        let jitter = Duration::from_secs(59);
+        assert_eq!(jittered_fire_time(&hourly(), &just_before_the_hour, jitter), Some(UnixUtcDateTime::from(3600 + 59)));
+        assert_eq!(jittered_fire_time(&hourly(), &just_before_the_hour, Duration::ZERO), Some(UnixUtcDateTime::from(3600)));
+    }
+
+    #[test]
+    fn the_sleeping_fire_time_is_kept_within_the_jitter_window() {
+        let jitter = Duration::from_secs(60);
+        let next_fire_time = NextFireTime::default();
+        next_fire_time.set(jittered_fire_time(&hourly(), &UnixUtcDateTime::from(0), jitter).unwrap());
+        // Past the occurrence but within the jitter, recomputing would skip to the following occurrence
+        let within_jitter = UnixUtcDateTime::from(3600 + 30);
+        assert_eq!(jittered_fire_time(&hourly(), &within_jitter, jitter), Some(UnixUtcDateTime::from(7200 + 60)));
+        assert_eq!(next_fire_time.upcoming(&within_jitter), Some(UnixUtcDateTime::from(3600 + 60)));
+        assert_eq!(next_fire_time.upcoming(&UnixUtcDateTime::from(3600 + 59)), Some(UnixUtcDateTime::from(3600 + 60)));
+    }
+
+    #[test]
+    fn the_sleeping_fire_time_isnt_upcoming_once_reached() {
+        let next_fire_time = NextFireTime::default();
+        assert_eq!(next_fire_time.upcoming(&UnixUtcDateTime::from(0)), None);
+        next_fire_time.set(UnixUtcDateTime::from(3600 + 60));
+        assert_eq!(next_fire_time.upcoming(&UnixUtcDateTime::from(3600 + 60)), None);
+    }
+}
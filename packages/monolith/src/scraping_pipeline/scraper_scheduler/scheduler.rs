@@ -1,58 +1,92 @@
-use std::{collections::HashMap, sync::Arc};
-use tokio::sync::{Mutex, RwLock};
+use std::{collections::{HashMap, HashSet}, sync::{atomic::{AtomicBool, Ordering}, Arc}, time::Duration};
+use tokio::sync::RwLock;
 use tokio::task::JoinHandle;
-use crate::galleries::domain_types::GalleryId;
-use crate::messages::{SearchScraperSender, StateTrackerSender};
+use crate::config::ScraperSchedulerConfig;
+use crate::galleries::domain_types::{GalleryId, UnixUtcDateTime, ValidCronString};
+use crate::messages::{message_types::storage::{GetSchedulerStatesMessage, StorageMessage}, SearchScraperSender, StateTrackerSender, StorageSender};
+use crate::scraping_pipeline::pipeline_metrics::PipelineMetrics;
 use crate::{
     galleries::pipeline_states::GallerySchedulerState, 
-    messages::message_types::scraper_scheduler::SchedulerError
+    messages::message_types::scraper_scheduler::{ScheduledGallerySnapshot, SchedulerError, ScrapeStart}
 };
 
-use super::scheduled_task::ScheduledGalleryTask;
+use super::{fire_dedup::FireDedup, in_flight_limit::InFlightLimit, scheduled_task::{jittered_fire_time, NextFireTime, ScheduledGalleryTask}, scrape_lock::ScrapeLock};
 
-/// A map of gallery IDs to their scheduling task.
+/// A scheduled gallery's running task, along with a copy of its state, its enabled flag and its next fire time.
 /// 
-/// Aliased since the signature is pretty long.
-type GallerySchedulingHandles = Arc<RwLock<HashMap<GalleryId, (Arc<Mutex<ScheduledGalleryTask>>, JoinHandle<()>)>>>;
+/// The state, flag and fire time are kept outside the task, so they can be used without locking the (running) task.
+struct GallerySchedulingHandle {
+    handle: JoinHandle<()>,
+    gallery: GallerySchedulerState,
+    enabled: Arc<AtomicBool>,
+    next_fire_time: NextFireTime
+}
+
+/// A map of gallery IDs to their scheduling handle.
+type GallerySchedulingHandles = Arc<RwLock<HashMap<GalleryId, GallerySchedulingHandle>>>;
 
 /// The actual scheduler for the scraper.
 pub struct SchedulerHandler {
     galleries: GallerySchedulingHandles, 
-    scraper_msg_sender: SearchScraperSender,
-    state_tracker_sender: StateTrackerSender
+    scrape_lock: ScrapeLock,
+    fire_dedup: FireDedup,
+    in_flight_limit: InFlightLimit,
+    storage_sender: StorageSender,
+    min_scrape_interval: Duration,
+    jitter_window: Duration,
+    rescrape_on_criteria_change: bool
 }
 
 impl SchedulerHandler {
     /// Instantiate the scheduler.
     /// 
-    /// TODO: be able to instantiate from a Vec of galleries here
-    pub fn new(scraper_msg_sender: SearchScraperSender, state_tracker_sender: StateTrackerSender) -> Self {
+    /// The jitter window is capped to the minimum scrape interval, so a gallery's jitter can't skip over its next scrape.
+    /// 
+    /// This starts without any galleries; they're loaded from storage with `sync_from_storage`.
+    pub fn new(
+        config: &ScraperSchedulerConfig, 
+        scraper_msg_sender: SearchScraperSender, 
+        state_tracker_sender: StateTrackerSender,
+        storage_sender: StorageSender,
+        pipeline_metrics: Arc<PipelineMetrics>
+    ) -> Self {
         Self {
             galleries: Arc::new(RwLock::new(HashMap::new())),
-            scraper_msg_sender,
-            state_tracker_sender
+            in_flight_limit: InFlightLimit::new(config, state_tracker_sender.clone()),
+            scrape_lock: ScrapeLock::new(config, state_tracker_sender, scraper_msg_sender, pipeline_metrics),
+            fire_dedup: FireDedup::load(config),
+            storage_sender,
+            min_scrape_interval: Duration::from_secs(config.min_scrape_interval_secs),
+            jitter_window: Duration::from_secs(config.jitter_window_secs.min(config.min_scrape_interval_secs)),
+            rescrape_on_criteria_change: config.rescrape_on_criteria_change
         }
     }
 
-    /// Add a new gallery to the scheduler.
+    /// Add a new gallery to the scheduler, and store its state.
+    /// 
+    /// Returns an `Err` if it already exists, or it's scheduled more often than the minimum scrape interval.
     pub async fn add_gallery(&self, new_gallery: GallerySchedulerState) -> Result<(), SchedulerError>
     {
+        self.check_schedule(&new_gallery)?;
         let gallery_id = new_gallery.gallery_id.clone();
         let mut galleries = self.galleries.write().await;
         if galleries.contains_key(&gallery_id) {
             return Err(SchedulerError::GalleryAlreadyExists{ gallery_id });
         }
-        let handle = self.generate_gallery_task(new_gallery).await;
-        galleries.insert(gallery_id, handle);
+        let enabled = Arc::new(AtomicBool::new(new_gallery.enabled));
+        self.store_state(&new_gallery).await;
+        galleries.insert(gallery_id, self.generate_gallery_task(new_gallery, enabled, true));
         Ok(())
     }
 
-    /// Delete a gallery from the scheduler.
+    /// Delete a gallery from the scheduler, along with its last fired time and stored state.
     pub async fn delete_gallery(&self, gallery_id: GalleryId) -> Result<(), SchedulerError> 
     {
         let mut galleries = self.galleries.write().await;
-        if let Some(task) = galleries.remove(&gallery_id) {
-            task.1.abort();
+        if let Some(scheduled) = galleries.remove(&gallery_id) {
+            scheduled.handle.abort();
+            self.fire_dedup.forget(&gallery_id).await;
+            self.delete_stored_state(&gallery_id).await;
             Ok(())
         } 
         else {
@@ -60,41 +94,344 @@ impl SchedulerHandler {
         }
     }
 
-    /// Update a gallery in the scheduler.
-    pub async fn update_gallery(&self, updated_gallery: GallerySchedulerState) -> Result<(), SchedulerError>
+    /// Update a gallery in the scheduler, and store its new state.
+    /// 
+    /// The gallery's task is replaced, so it's rescheduled to its (possibly new) schedule without being scraped.
+    /// However, if its search criteria materially changed and `rescrape_on_criteria_change` is set,
+    /// it's also immediately re-scraped, since its previous results are now stale;
+    /// failing to do so is only logged, as the update itself has succeeded.
+    /// 
+    /// The gallery's enabled flag is kept as is; use `set_enabled` to change it.
+    /// Its previous scraped datetimes are merged with the update's, so an update without them (ie from the API)
+    /// doesn't make its next scrape start over, while one with them (ie from storage, after a run) moves them forward.
+    /// 
+    /// The new schedule is checked before anything is changed, so if it's invalid, an `InvalidSchedule` is returned
+    /// and the gallery keeps running on its previous schedule.
+    pub async fn update_gallery(&self, mut updated_gallery: GallerySchedulerState) -> Result<(), SchedulerError>
     {   
-        let mut galleries = self.galleries.write().await;
-        if let Some(task) = galleries.get_mut(&updated_gallery.gallery_id) {
-            let mut scheduled_gallery = task.0.lock().await;
-            scheduled_gallery.update_gallery(updated_gallery)?;
-            Ok(())
-        } 
+        if let Err(err) = self.check_schedule(&updated_gallery) {
+            tracing::warn!("Rejected update for gallery {}; keeping its previous schedule: {err}", updated_gallery.gallery_id);
+            return Err(err);
+        }
+        let gallery_id = updated_gallery.gallery_id.clone();
+        let changed_criteria = {
+            let mut galleries = self.galleries.write().await;
+            let old = galleries
+                .remove(&gallery_id)
+                .ok_or(SchedulerError::GalleryNotFound { gallery_id: gallery_id.clone() })?;
+            old.handle.abort();
+            updated_gallery.enabled = old.gallery.enabled;
+            let mut previous_scraped_datetimes = old.gallery.marketplace_previous_scraped_datetimes.clone();
+            previous_scraped_datetimes.extend(updated_gallery.marketplace_previous_scraped_datetimes);
+            updated_gallery.marketplace_previous_scraped_datetimes = previous_scraped_datetimes;
+            let changed_criteria = old.gallery.search_criteria.diff(&updated_gallery.search_criteria);
+            self.store_state(&updated_gallery).await;
+            galleries.insert(gallery_id.clone(), self.generate_gallery_task(updated_gallery, old.enabled, false));
+            changed_criteria
+        };
+        if changed_criteria.is_empty() {
+            tracing::info!("Rescheduled gallery {gallery_id}; its search criteria are unchanged");
+        }
+        else if self.rescrape_on_criteria_change {
+            tracing::info!("Gallery {gallery_id}'s search criteria changed ({}); re-scraping it", changed_criteria.join(", "));
+            if let Err(err) = self.force_scrape(gallery_id.clone()).await {
+                tracing::warn!("Failed to re-scrape gallery {gallery_id} after its search criteria changed: {err}");
+            }
+        }
         else {
-            Err(SchedulerError::GalleryNotFound{ gallery_id: updated_gallery.gallery_id})
+            tracing::info!("Gallery {gallery_id}'s search criteria changed ({}); it'll be scraped at its next scheduled time", changed_criteria.join(", "));
+        }
+        Ok(())
+    }
+
+    /// Enable or disable a gallery's scheduled scrapes, without removing it from the scheduler.
+    /// 
+    /// A disabled gallery skips firing until it's re-enabled, after which it's scraped at its next scheduled time.
+    /// Returns an `Err` if the gallery isn't scheduled.
+    pub async fn set_enabled(&self, gallery_id: GalleryId, enabled: bool) -> Result<(), SchedulerError> {
+        let mut galleries = self.galleries.write().await;
+        let scheduled = galleries
+            .get_mut(&gallery_id)
+            .ok_or(SchedulerError::GalleryNotFound { gallery_id: gallery_id.clone() })?;
+        scheduled.enabled.store(enabled, Ordering::Relaxed);
+        scheduled.gallery.enabled = enabled;
+        self.store_state(&scheduled.gallery).await;
+        tracing::info!("Set gallery {gallery_id} to enabled: {enabled}");
+        Ok(())
+    }
+
+    /// Get when a gallery will next be scraped, including its jitter.
+    /// 
+    /// Returns `None` if its schedule never fires again, or an `Err` if it isn't scheduled.
+    pub async fn next_run(&self, gallery_id: GalleryId) -> Result<Option<UnixUtcDateTime>, SchedulerError> {
+        let galleries = self.galleries.read().await;
+        let scheduled = galleries
+            .get(&gallery_id)
+            .ok_or(SchedulerError::GalleryNotFound { gallery_id: gallery_id.clone() })?;
+        Ok(self.next_fire_time(&scheduled.gallery, &scheduled.next_fire_time))
+    }
+
+    /// Get a snapshot of every scheduled gallery's schedule and next fire time, sorted by ID.
+    pub async fn snapshot(&self) -> Vec<ScheduledGallerySnapshot> {
+        let galleries = self.galleries.read().await;
+        let mut snapshots: Vec<_> = galleries
+            .values()
+            .map(|scheduled| ScheduledGallerySnapshot {
+                gallery_id: scheduled.gallery.gallery_id.clone(),
+                scraping_periodicity: scheduled.gallery.scraping_periodicity.clone(),
+                schedule_description: scheduled.gallery.scraping_periodicity.describe(),
+                enabled: scheduled.enabled.load(Ordering::Relaxed),
+                next_fire_time: self.next_fire_time(&scheduled.gallery, &scheduled.next_fire_time)
+            })
+            .collect();
+        snapshots.sort_by(|a, b| a.gallery_id.as_str().cmp(b.gallery_id.as_str()));
+        snapshots
+    }
+
+    /// Immediately sends a gallery to the search scraper through the scrape lock, regardless of (and without affecting) its schedule.
+    /// 
+    /// If the gallery is already being scraped, the scrape is queued or rejected, depending on the concurrent scrape policy.
+    /// 
+    /// Returns an `Err` if the gallery isn't scheduled, the scrape was rejected, 
+    /// or the state tracker/search scraper couldn't be messaged.
+    pub async fn force_scrape(&self, gallery_id: GalleryId) -> Result<ScrapeStart, SchedulerError> {
+        let gallery = self.galleries
+            .read()
+            .await
+            .get(&gallery_id)
+            .map(|scheduled| scheduled.gallery.clone())
+            .ok_or(SchedulerError::GalleryNotFound { gallery_id: gallery_id.clone() })?;
+        let scrape_start = self.scrape_lock.start_scrape(&gallery).await?;
+        if scrape_start == ScrapeStart::Started {
+            tracing::info!("Forced a scrape for gallery {gallery_id}");
         }
+        Ok(scrape_start)
+    }
+
+    /// Reconcile the scheduled galleries with the scheduler states in storage, which is treated as the source of truth:
+    /// - galleries which are only in storage are added, and scraped at their next scheduled time
+    /// - galleries whose stored schedule, criteria or enabled flag differ are rescheduled with their stored state
+    /// - galleries which are no longer in storage are removed
+    /// 
+    /// Unchanged galleries keep running undisturbed. Stored galleries with an invalid schedule are skipped (keeping their current task, if any).
+    /// 
+    /// Galleries are locked for the whole sync, so a gallery added while storage is being fetched can't be mistaken for a removed one.
+    /// If storage can't be reached, this is logged and nothing is changed.
+    pub async fn sync_from_storage(&self) {
+        let mut galleries = self.galleries.write().await;
+        let stored_galleries = match self.fetch_stored_states().await {
+            Ok(stored_galleries) => stored_galleries,
+            Err(err) => {
+                tracing::warn!("Failed to sync the scheduler with storage; keeping the current galleries: {err}");
+                return;
+            }
+        };
+        let stored_ids: HashSet<_> = stored_galleries
+            .iter()
+            .map(|gallery| gallery.gallery_id.clone())
+            .collect();
+        let removed_ids: Vec<_> = galleries
+            .keys()
+            .filter(|gallery_id| !stored_ids.contains(*gallery_id))
+            .cloned()
+            .collect();
+        for gallery_id in &removed_ids {
+            if let Some(scheduled) = galleries.remove(gallery_id) {
+                scheduled.handle.abort();
+                self.fire_dedup.forget(gallery_id).await;
+            }
+        }
+        let (mut num_added, mut num_updated) = (0, 0);
+        for stored_gallery in stored_galleries {
+            if let Err(err) = self.check_schedule(&stored_gallery) {
+                tracing::warn!("Skipping stored gallery while syncing the scheduler: {err}");
+                continue;
+            }
+            let gallery_id = stored_gallery.gallery_id.clone();
+            let enabled = match galleries.get(&gallery_id) {
+                Some(current) if !schedule_changed(&current.gallery, &stored_gallery) => continue,
+                Some(_) => {
+                    let old = galleries
+                        .remove(&gallery_id)
+                        .expect("Gallery should exist, as it was just found");
+                    old.handle.abort();
+                    old.enabled.store(stored_gallery.enabled, Ordering::Relaxed);
+                    num_updated += 1;
+                    old.enabled
+                },
+                None => {
+                    num_added += 1;
+                    Arc::new(AtomicBool::new(stored_gallery.enabled))
+                }
+            };
+            galleries.insert(gallery_id, self.generate_gallery_task(stored_gallery, enabled, false));
+        }
+        if num_added + num_updated + removed_ids.len() > 0 {
+            tracing::info!(
+                "Synced the scheduler with storage: {num_added} galleries added, {num_updated} updated, {} removed",
+                removed_ids.len()
+            );
+        }
+    }
+
+    /// Fetch every gallery's scheduler state from storage.
+    async fn fetch_stored_states(&self) -> Result<Vec<GallerySchedulerState>, String> {
+        let (msg, receiver) = GetSchedulerStatesMessage::new(());
+        self.storage_sender
+            .clone()
+            .send(StorageMessage::GetSchedulerStates(msg))
+            .await
+            .map_err(|err| format!("Failed to message storage: {err}"))?;
+        receiver
+            .await
+            .map_err(|err| format!("Failed to receive a response from storage: {err}"))
+    }
+
+    /// Store a gallery's scheduler state, so it's reloaded on restart.
+    /// 
+    /// Failing to do so is only logged, as the gallery is still scheduled.
+    async fn store_state(&self, gallery: &GallerySchedulerState) {
+        let msg = StorageMessage::PutSchedulerState { gallery: gallery.clone() };
+        if let Err(err) = self.storage_sender.clone().send(msg).await {
+            tracing::warn!("Failed to store scheduler state of gallery {}: {err}", gallery.gallery_id);
+        }
+    }
+
+    /// Delete a gallery's stored scheduler state.
+    /// 
+    /// Failing to do so is only logged; the gallery is then re-added on the next sync.
+    async fn delete_stored_state(&self, gallery_id: &GalleryId) {
+        let msg = StorageMessage::DeleteSchedulerState { gallery_id: gallery_id.clone() };
+        if let Err(err) = self.storage_sender.clone().send(msg).await {
+            tracing::warn!("Failed to delete scheduler state of gallery {gallery_id}: {err}");
+        }
+    }
+
+    /// Checks that the gallery isn't scheduled more often than the minimum scrape interval.
+    fn check_schedule(&self, gallery: &GallerySchedulerState) -> Result<(), SchedulerError> {
+        ValidCronString::new_with_min_interval(gallery.scraping_periodicity.get_str(), self.min_scrape_interval)
+            .map(|_| ())
+            .map_err(|err| SchedulerError::InvalidSchedule { 
+                gallery_id: gallery.gallery_id.clone(), 
+                message: err.to_string() 
+            })
+    }
+
+    /// Returns when the gallery will next be scraped, including its jitter, or `None` if its schedule never fires again.
+    /// 
+    /// This is the time its task is sleeping until; it's only computed from the schedule if the task isn't sleeping (ie it's firing),
+    /// as recomputing it within the jitter window after an occurrence would skip to the following occurrence.
+    fn next_fire_time(&self, gallery: &GallerySchedulerState, next_fire_time: &NextFireTime) -> Option<UnixUtcDateTime> {
+        let now = UnixUtcDateTime::now();
+        next_fire_time
+            .upcoming(&now)
+            .or_else(|| jittered_fire_time(
+                &gallery.scraping_periodicity, 
+                &now, 
+                gallery_jitter(&gallery.gallery_id, self.jitter_window)
+            ))
     }
 
     /// Spawns a task to periodically trigger scraper requests for the input gallery,
-    /// returning a handle to the task, and an Arc Mutex handle to the task struct.
-    async fn generate_gallery_task(&self, gallery: GallerySchedulerState) 
-    -> (Arc<Mutex<ScheduledGalleryTask>>, JoinHandle<()>) 
-    {
-        let task = ScheduledGalleryTask::new(
-            gallery, 
-            self.state_tracker_sender.clone(),
-            self.scraper_msg_sender.clone()
+    /// returning its scheduling handle.
+    /// 
+    /// If `scrape_on_start` is false, the task waits for the gallery's next scheduled time before its first scrape.
+    fn generate_gallery_task(&self, gallery: GallerySchedulerState, enabled: Arc<AtomicBool>, scrape_on_start: bool) -> GallerySchedulingHandle {
+        let jitter = gallery_jitter(&gallery.gallery_id, self.jitter_window);
+        let next_fire_time = NextFireTime::default();
+        let mut task = ScheduledGalleryTask::new(
+            gallery.clone(), 
+            jitter,
+            next_fire_time.clone(),
+            enabled.clone(),
+            self.scrape_lock.clone(),
+            self.fire_dedup.clone(),
+            self.in_flight_limit.clone()
         );
-        let task = Arc::new(Mutex::new(task));
-        let cloned_task = task.clone();
-        let task_handle = tokio::spawn(
+        let handle = tokio::spawn(
             async move {
-                cloned_task
-                    .lock()
-                    .await
-                    .run()
-                    .await;
+                let _ = match scrape_on_start {
+                    true => task.run().await,
+                    false => task.run_from_next_time().await
+                };
             }
         );
-        (task, task_handle)
+        GallerySchedulingHandle { handle, gallery, enabled, next_fire_time }
     }
-}
\ No newline at end of file
+}
+
+/// Returns how long to offset the gallery's scheduled scrapes by, within the jitter window (ie less than it).
+/// 
+/// This is derived from a hash of the gallery ID, so it's the same across restarts.
+fn gallery_jitter(gallery_id: &GalleryId, jitter_window: Duration) -> Duration {
+    let window_millis = jitter_window.as_millis() as u64;
+    if window_millis == 0 {
+        return Duration::ZERO;
+    }
+    // FNV-1a, as std's hashers aren't guaranteed to be stable across Rust versions
+    let hash = gallery_id
+        .as_bytes()
+        .iter()
+        .fold(0xcbf29ce484222325_u64, |hash, byte| (hash ^ *byte as u64).wrapping_mul(0x100000001b3));
+    Duration::from_millis(hash % window_millis)
+}
+
+/// Whether a gallery's schedule, search criteria, evaluation criteria or enabled flag differ between two of its states.
+/// 
+/// The criteria don't implement `PartialEq`, so they're compared by their JSON.
+fn schedule_changed(current: &GallerySchedulerState, stored: &GallerySchedulerState) -> bool {
+    current.scraping_periodicity.get_str() != stored.scraping_periodicity.get_str()
+        || current.enabled != stored.enabled
+        || serde_json::to_value(&current.search_criteria).ok() != serde_json::to_value(&stored.search_criteria).ok()
+        || serde_json::to_value(&current.evaluation_criteria).ok() != serde_json::to_value(&stored.evaluation_criteria).ok()
+}
+
+// Abort all scheduled gallery tasks once the scheduler goes away (ie on shutdown),
+// so that no new galleries are sent into the pipeline.
+impl Drop for SchedulerHandler {
+    fn drop(&mut self) {
+        if let Ok(galleries) = self.galleries.try_read() {
+            for scheduled in galleries.values() {
+                scheduled.handle.abort();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn gallery_ids() -> impl Iterator<Item = GalleryId> {
+        (0..1000).map(|i| GalleryId::from(format!("gallery-{i}")))
+    }
+
+    #[test]
+    fn jitter_is_always_within_the_window() {
+        let jitter_window = Duration::from_secs(60);
+        assert!(gallery_ids().all(|gallery_id| gallery_jitter(&gallery_id, jitter_window) < jitter_window));
+    }
+
+    #[test]
+    fn jitter_spreads_galleries_across_the_window() {
+        let jitter_window = Duration::from_secs(60);
+        let jitters: Vec<_> = gallery_ids()
+            .map(|gallery_id| gallery_jitter(&gallery_id, jitter_window))
+            .collect();
+        assert!(jitters.iter().any(|jitter| *jitter < Duration::from_secs(6)));
+        assert!(jitters.iter().any(|jitter| *jitter >= Duration::from_secs(54)));
+    }
+
+    #[test]
+    fn no_jitter_without_a_window() {
+        assert!(gallery_ids().all(|gallery_id| gallery_jitter(&gallery_id, Duration::ZERO).is_zero()));
+        assert!(gallery_ids().all(|gallery_id| gallery_jitter(&gallery_id, Duration::from_millis(1)).is_zero()));
+    }
+
+    #[test]
+    fn jitter_is_stable_for_a_gallery() {
+        let gallery_id = GalleryId::from("gallery".to_string());
+        let jitter_window = Duration::from_secs(60);
+        assert_eq!(gallery_jitter(&gallery_id, jitter_window), gallery_jitter(&gallery_id, jitter_window));
+    }
+}
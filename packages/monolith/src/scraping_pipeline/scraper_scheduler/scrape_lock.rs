@@ -0,0 +1,133 @@
+//! Contains the per-gallery lock which makes sure only one scrape of a gallery runs at a time.
+use std::{collections::HashSet, sync::{Arc, Mutex}, time::Duration};
+use tracing::Instrument;
+use crate::{
+    config::{scraper_scheduler::ConcurrentScrapePolicy, ScraperSchedulerConfig}, 
+    galleries::{domain_types::GalleryId, pipeline_states::{GalleryPipelineStateTypes, GalleryPipelineStates, GallerySchedulerState, StageAdvancePayload}}, 
+    messages::{message_types::{scraper_scheduler::{SchedulerError, ScrapeStart}, search_scraper::SearchScraperMessage, state_tracker::StateTrackerError}, SearchScraperSender, StateTrackerSender}, 
+    scraping_pipeline::pipeline_metrics::PipelineMetrics,
+    utils::tracing_context::gallery_run_span
+};
+
+/// Makes sure only one scrape of a gallery runs at a time, across its scheduled and manual triggers.
+/// 
+/// A gallery's presence in the state tracker acts as its lock; a scrape claims it by adding the gallery to the state tracker,
+/// which fails if it's already there (as the state tracker handles one message at a time), and it's released once the gallery leaves it (ie once stored).
+/// 
+/// Triggers for a gallery which is already being scraped are rejected or queued, depending on the policy;
+/// queued triggers for the same gallery are coalesced into one scrape.
+#[derive(Clone)]
+pub(super) struct ScrapeLock {
+    policy: ConcurrentScrapePolicy,
+    queue_poll_interval: Duration,
+    queued_galleries: Arc<Mutex<HashSet<GalleryId>>>,
+    state_tracker_sender: StateTrackerSender,
+    search_scraper_sender: SearchScraperSender,
+    pipeline_metrics: Arc<PipelineMetrics>
+}
+
+impl ScrapeLock {
+    /// Instantiate the lock.
+    pub fn new(
+        config: &ScraperSchedulerConfig,
+        state_tracker_sender: StateTrackerSender,
+        search_scraper_sender: SearchScraperSender,
+        pipeline_metrics: Arc<PipelineMetrics>
+    ) -> Self {
+        Self {
+            policy: config.concurrent_scrape_policy,
+            queue_poll_interval: Duration::from_secs(config.scrape_queue_poll_interval_secs.max(1)),
+            queued_galleries: Arc::new(Mutex::new(HashSet::new())),
+            state_tracker_sender,
+            search_scraper_sender,
+            pipeline_metrics
+        }
+    }
+
+    /// Claims the gallery and sends it to the search scraper.
+    /// 
+    /// If the gallery is already being scraped, this is queued or returns a `ScrapeInProgress` error, depending on the policy.
+    /// 
+    /// Also returns an `Err` if the state tracker or search scraper couldn't be messaged.
+    pub async fn start_scrape(&self, gallery: &GallerySchedulerState) -> Result<ScrapeStart, SchedulerError> {
+        let gallery_id = gallery.gallery_id.clone();
+        let search_scraping_state = GalleryPipelineStates::Initialization(gallery.clone())
+            .advance(StageAdvancePayload::Scheduled)
+            .map_err(|err| SchedulerError::Other { 
+                gallery_id: gallery_id.clone(), 
+                message: format!("Could not advance gallery state: {err}") 
+            })?;
+        let claim_result = self.state_tracker_sender
+            .clone()
+            .add_gallery(search_scraping_state)
+            .await
+            .map_err(|err| SchedulerError::MessageErr { gallery_id: gallery_id.clone(), err })?;
+        match claim_result {
+            Ok(run_id) => {
+                // The gallery passes straight through initialization, so it's only counted once its scrape is claimed
+                self.pipeline_metrics.record_stage_entered(&GalleryPipelineStateTypes::Initialization);
+                self.pipeline_metrics.record_stage_entered(&GalleryPipelineStateTypes::SearchScraping);
+                self.search_scraper_sender
+                    .clone()
+                    .send(SearchScraperMessage::ScrapeSearch { gallery_id: gallery_id.clone() })
+                    .instrument(gallery_run_span(&gallery_id, &run_id))
+                    .await
+                    .map_err(|err| SchedulerError::MessageErr { gallery_id, err })?;
+                Ok(ScrapeStart::Started)
+            },
+            Err(StateTrackerError::GalleryAlreadyExists) => match self.policy {
+                ConcurrentScrapePolicy::Reject => Err(SchedulerError::ScrapeInProgress { gallery_id }),
+                ConcurrentScrapePolicy::Queue => {
+                    self.queue_scrape(gallery.clone());
+                    Ok(ScrapeStart::Queued)
+                }
+            },
+            Err(err) => Err(SchedulerError::StateErr { gallery_id, err })
+        }
+    }
+
+    /// Spawns a task to scrape the gallery once its current scrape finishes, unless one is already queued.
+    fn queue_scrape(&self, gallery: GallerySchedulerState) {
+        let newly_queued = self.queued_galleries
+            .lock()
+            .expect("Queued galleries lock should never be poisoned")
+            .insert(gallery.gallery_id.clone());
+        if !newly_queued {
+            tracing::debug!("A scrape of gallery {} is already queued; coalescing this trigger into it", gallery.gallery_id);
+            return;
+        }
+        tracing::info!("Gallery {} is already being scraped; queued another scrape for when it finishes", gallery.gallery_id);
+        let lock = self.clone();
+        tokio::spawn(async move { lock.run_queued_scrape(gallery).await });
+    }
+
+    /// Waits for the gallery to leave the state tracker, then scrapes it.
+    async fn run_queued_scrape(self, gallery: GallerySchedulerState) {
+        let gallery_id = gallery.gallery_id.clone();
+        loop {
+            tokio::time::sleep(self.queue_poll_interval).await;
+            match self.state_tracker_sender.clone().check_gallery_doesnt_exist(gallery_id.clone()).await {
+                Ok(Ok(_)) => break,
+                Ok(Err(_)) => continue,
+                Err(err) => {
+                    tracing::error!("Failed to message the state tracker for queued scrape of gallery {gallery_id}; dropping it: {err}");
+                    self.dequeue(&gallery_id);
+                    return;
+                }
+            }
+        }
+        self.dequeue(&gallery_id);
+        match self.start_scrape(&gallery).await {
+            Ok(_) => tracing::info!("Started queued scrape of gallery {gallery_id}"),
+            Err(err) => tracing::warn!("Failed to start queued scrape of gallery {gallery_id}: {err}")
+        }
+    }
+
+    /// Removes the gallery from the queued galleries.
+    fn dequeue(&self, gallery_id: &GalleryId) {
+        self.queued_galleries
+            .lock()
+            .expect("Queued galleries lock should never be poisoned")
+            .remove(gallery_id);
+    }
+}
@@ -1,50 +1,89 @@
-use std::collections::HashMap;
+use std::{collections::HashMap, sync::Arc};
+use tokio_util::sync::CancellationToken;
+use tracing::Instrument;
 use crate::{
     config::SearchScraperConfig, 
-    galleries::{domain_types::{GalleryId, ItemId, Marketplace, UnixUtcDateTime}, 
-    pipeline_states::{GalleryItemScrapingState, GalleryPipelineStateTypes, GalleryPipelineStates, GallerySearchScrapingState}}, 
+    galleries::{domain_types::{GalleryId, RunId, ItemId, Marketplace, UnixUtcDateTime}, 
+    pipeline_states::{GalleryPipelineStateTypes, GalleryPipelineStates, GallerySearchScrapingState, StageAdvancePayload, StateTransitionError}}, 
     messages::{
         message_types::{item_scraper::ItemScraperMessage, search_scraper::SearchScraperError}, 
         ItemScraperSender, 
         StateTrackerSender
-    }
+    },
+    notifications::Notifier,
+    scraping_pipeline::pipeline_metrics::PipelineMetrics,
+    utils::{http_client::HttpClientFactory, tracing_context::gallery_run_span}
 };
 
 use super::scrapers::SearchScraper;
 
 /// Coordinates the internal workings of the module.
+/// 
+/// This is cloned for each gallery being processed, so its fields must be cheaply cloneable.
+#[derive(Clone)]
 pub(super) struct Handler {
     state_tracker_sender: StateTrackerSender,
     item_scraper_sender: ItemScraperSender,
-    search_scraper: SearchScraper
+    search_scraper: SearchScraper,
+    pipeline_metrics: Arc<PipelineMetrics>
 }
 
 impl Handler {
     /// Instantiate the state.
     pub fn new(
         config: &SearchScraperConfig,
+        http_clients: &HttpClientFactory,
         state_tracker_sender: StateTrackerSender,
-        item_scraper_sender: ItemScraperSender
+        item_scraper_sender: ItemScraperSender,
+        notifier: Notifier,
+        pipeline_metrics: Arc<PipelineMetrics>
     ) -> Self {
-        let search_scraper = SearchScraper::new(config);
+        let search_scraper = SearchScraper::new(config, http_clients, notifier);
         Self {
             state_tracker_sender,
             item_scraper_sender,
-            search_scraper
+            search_scraper,
+            pipeline_metrics
         }
     }
 
     /// Perform the entire scraping of a new gallery.
     pub async fn scrape_new_gallery(&mut self, gallery: GallerySearchScrapingState) -> Result<(), SearchScraperError> {
         let gallery_id = gallery.gallery_id.clone();
-        self.add_gallery_to_state(gallery_id.clone(), gallery).await?;
-        self.scrape_gallery_in_state(gallery_id).await
+        let run_id = self.add_gallery_to_state(gallery_id.clone(), gallery).await?;
+        self.scrape_gallery_in_state(gallery_id.clone())
+            .instrument(gallery_run_span(&gallery_id, &run_id))
+            .await
     }
 
     /// Perform the scraping of a gallery in state.
+    /// 
+    /// Aborts if the gallery's run is cancelled (ie it's deleted) in the meantime.
     pub async fn scrape_gallery_in_state(&mut self, gallery_id: GalleryId) -> Result<(), SearchScraperError> {
-        let gallery = self.fetch_gallery_state(gallery_id).await?;
-        self.scrape_gallery(gallery).await
+        let cancellation = self.fetch_cancellation_token(gallery_id.clone()).await?;
+        let gallery = self.fetch_gallery_state(gallery_id.clone()).await?;
+        match cancellation.run_until_cancelled(self.scrape_gallery(gallery)).await {
+            Some(result) => result,
+            None => {
+                tracing::info!("Gallery {gallery_id} was cancelled; aborted its scraping");
+                Ok(())
+            }
+        }
+    }
+
+    /// Fetch the cancellation token for the gallery's current run.
+    async fn fetch_cancellation_token(&mut self, gallery_id: GalleryId) -> Result<CancellationToken, SearchScraperError> {
+        self.state_tracker_sender
+            .get_cancellation_token(gallery_id.clone())
+            .await
+            .map_err(|err| SearchScraperError::Other { 
+                gallery_id: gallery_id.clone(), 
+                message: format!("Could not receive response from state tracker: {err}") 
+            })?
+            .map_err(|err| SearchScraperError::StateErr { 
+                gallery_id, 
+                err 
+            })
     }
 
     /// Scrapes the search for a gallery and sends it to the item scraper.
@@ -67,16 +106,16 @@ impl Handler {
         Ok(())
     }
 
-    /// Add a new gallery to the state.
+    /// Add a new gallery to the state, returning the ID of its run.
     /// 
     /// Returns an `Err` if it already exists.
     async fn add_gallery_to_state(
         &mut self, 
         gallery_id: GalleryId, 
         gallery: GallerySearchScrapingState
-    ) -> Result<(), SearchScraperError> {
+    ) -> Result<RunId, SearchScraperError> {
         self.state_tracker_sender
-            .add_gallery(gallery_id.clone(), GalleryPipelineStates::SearchScraping(gallery))
+            .add_gallery(GalleryPipelineStates::SearchScraping(gallery))
             .await
             .map_err(|err| SearchScraperError::Other { 
                 gallery_id: gallery_id.clone(), 
@@ -145,6 +184,11 @@ impl Handler {
         scraped_search_result: HashMap<Marketplace, Result<Vec<ItemId>, String>>
     ) -> Result<(), SearchScraperError> {
         let gallery_id = cur_state.gallery_id.clone();
+        let num_failed_marketplaces = scraped_search_result
+            .values()
+            .filter(|result| result.is_err())
+            .count();
+        self.pipeline_metrics.record_failed_marketplaces(&GalleryPipelineStateTypes::SearchScraping, num_failed_marketplaces);
         match scraped_search_result
             .iter()
             .all(|(_, result)| result.is_err())
@@ -168,13 +212,14 @@ impl Handler {
                     Err(SearchScraperError::TotalScrapeFailure { gallery_id })
                 },
                 false => {
-                    let new_state = self.process_to_next_state(
-                        &gallery_id, 
-                        scraped_search_result, 
-                        cur_state
-                    );
+                    let new_state = self
+                        .process_to_next_state(&gallery_id, scraped_search_result, cur_state)
+                        .map_err(|err| SearchScraperError::Other {
+                            gallery_id: gallery_id.clone(), 
+                            message: format!("Could not advance gallery state: {err}") 
+                        })?;
                     self.state_tracker_sender
-                        .update_gallery_state(gallery_id.clone(), GalleryPipelineStates::ItemScraping(new_state))
+                        .update_gallery_state(new_state)
                         .await
                         .map_err(|err| SearchScraperError::Other {
                             gallery_id: gallery_id.clone(), 
@@ -184,6 +229,7 @@ impl Handler {
                             gallery_id, 
                             err
                         })?;
+                    self.pipeline_metrics.record_stage_entered(&GalleryPipelineStateTypes::ItemScraping);
                     Ok(())
                 }
             }
@@ -195,7 +241,7 @@ impl Handler {
         gallery_id: &GalleryId,
         scraped_search_result: HashMap<Marketplace, Result<Vec<ItemId>, String>>,
         gallery_state: GallerySearchScrapingState,
-    ) -> GalleryItemScrapingState {
+    ) -> Result<GalleryPipelineStates, StateTransitionError> {
         let cur_datetime = UnixUtcDateTime::now();
         let marketplace_updated_datetimes = scraped_search_result
             .iter()
@@ -216,12 +262,10 @@ impl Handler {
             valid_scraped_search_ids,
             failed_marketplace_reasons
         );
-        GalleryItemScrapingState {
-            gallery_id: gallery_state.gallery_id,
-            item_ids: valid_scraped_search_ids,
-            failed_marketplace_reasons,
-            marketplace_updated_datetimes,
-            evaluation_criteria: gallery_state.evaluation_criteria
-        }
+        GalleryPipelineStates::SearchScraping(gallery_state).advance(StageAdvancePayload::SearchScraped {
+            item_ids: valid_scraped_search_ids, 
+            marketplace_updated_datetimes, 
+            failed_marketplace_reasons
+        })
     }
 }
@@ -1,7 +1,10 @@
+use std::sync::Arc;
+use tokio::sync::Semaphore;
+use tracing::Instrument;
 use handler::Handler;
 use crate::{config::SearchScraperConfig, messages::{
     message_types::search_scraper::SearchScraperMessage, ItemScraperSender, SearchScraperReceiver, StateTrackerSender
-}};
+}, notifications::Notifier, scraping_pipeline::{module_health::{ModuleHealth, PipelineModule}, pipeline_metrics::PipelineMetrics}, utils::{http_client::HttpClientFactory, tracing_context::module_span}};
 
 mod handler;
 mod scrapers;
@@ -10,42 +13,65 @@ mod scrapers;
 pub struct SearchScraperModule {
     msg_receiver: SearchScraperReceiver,
     handler: Handler,
+    concurrency_limit: Arc<Semaphore>
 }
 
 impl SearchScraperModule {
     /// Initialize the module.
     pub fn init(
         config: SearchScraperConfig,
+        http_clients: &HttpClientFactory,
         msg_receiver: SearchScraperReceiver,
         state_tracker_msg_sender: StateTrackerSender,
-        item_scraper_msg_sender: ItemScraperSender
+        item_scraper_msg_sender: ItemScraperSender,
+        notifier: Notifier,
+        pipeline_metrics: Arc<PipelineMetrics>
     ) -> Self
     {   
         let handler = Handler::new(
             &config, 
+            http_clients,
             state_tracker_msg_sender,
-            item_scraper_msg_sender
+            item_scraper_msg_sender,
+            notifier,
+            pipeline_metrics
         );
+        let concurrency_limit = Arc::new(Semaphore::new(config.max_concurrent_galleries.max(1)));
         Self { 
             msg_receiver, 
-            handler
+            handler,
+            concurrency_limit
         }
     }
     
     /// Start accepting and acting on messages.
-    pub async fn run(&mut self) {
+    /// 
+    /// Each message is processed in its own task, with at most `max_concurrent_galleries` tasks running at once.
+    pub async fn run(&mut self, module_health: &ModuleHealth) {
         tracing::info!("SearchScraperModule is running...");
-        while let Some(msg) = self.msg_receiver.receive().await {
-            self.process_msg(msg).await;
+        while let Some((msg, span)) = module_health.receive_with_span(PipelineModule::SearchScraper, &mut self.msg_receiver).await {
+            let permit = self.concurrency_limit
+                .clone()
+                .acquire_owned()
+                .await
+                .expect("Semaphore should never be closed");
+            let mut handler = self.handler.clone();
+            tokio::spawn(
+                async move {
+                    Self::process_msg(&mut handler, msg).await;
+                    drop(permit);
+                }
+                    .instrument(module_span(&span, PipelineModule::SearchScraper))
+            );
         }
     }
 
     /// Handle each message variant.
-    async fn process_msg(&mut self, msg: SearchScraperMessage) {
+    async fn process_msg(handler: &mut Handler, msg: SearchScraperMessage) {
         match msg {
             SearchScraperMessage::ScrapeSearchNew{ gallery } => {
                 tracing::info!("Received message to start search-scraping new gallery {}", gallery.gallery_id);
-                let schedule_result = self.handler
+                let schedule_result = handler
                     .scrape_new_gallery(gallery)
                     .await;
                 if let Err(err) = schedule_result {
@@ -54,7 +80,7 @@ impl SearchScraperModule {
             },
             SearchScraperMessage::ScrapeSearch{ gallery_id } => {
                 tracing::info!("Received message to start scraping gallery {}", gallery_id);
-                let schedule_result = self.handler
+                let schedule_result = handler
                     .scrape_gallery_in_state(gallery_id)
                     .await;
                 if let Err(err) = schedule_result {
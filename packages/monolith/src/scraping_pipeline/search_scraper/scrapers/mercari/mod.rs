@@ -1,33 +1,57 @@
 use std::error::Error;
 
-use reqwest::{Client, RequestBuilder};
+use async_trait::async_trait;
+use reqwest::{header::USER_AGENT, Client, RequestBuilder};
 use serde::{Deserialize, Serialize};
 use serde_json::{json, Value};
-use crate::galleries::domain_types::UnixUtcDateTime;
+use crate::galleries::domain_types::{Marketplace, UnixUtcDateTime};
 use crate::galleries::search_criteria::GallerySearchCriteria;
 use crate::galleries::domain_types::ItemId;
 use crate::utils::generate_dpop::generate_dpop;
+use crate::utils::rate_limiter::MarketplaceRateLimiter;
+use crate::utils::user_agent_pool::UserAgentPool;
+use super::SearchScraperBackend;
 
 const REQ_URL: &str = "https://api.mercari.jp/v2/entities:search";
 
+#[derive(Clone)]
 pub(super) struct MercariSearchScraper {
-    client: Client
+    client: Client,
+    /// Whether `client` routes requests through a proxy.
+    proxied: bool,
+    rate_limiter: MarketplaceRateLimiter,
+    user_agents: UserAgentPool
+}
+
+#[async_trait]
+impl SearchScraperBackend for MercariSearchScraper {
+    async fn search(&self, search_criteria: &GallerySearchCriteria) -> Result<Vec<(ItemId, UnixUtcDateTime)>, String> {
+        self.request(search_criteria).await
+    }
 }
 
 impl MercariSearchScraper {
     /// Instantiate the scraper.
-    pub(super) fn new() -> Self {
+    /// 
+    /// `proxied` should be set if `client` routes requests through a proxy, so that connection errors can be attributed to it.
+    pub(super) fn new(client: Client, proxied: bool, rate_limiter: MarketplaceRateLimiter, user_agents: UserAgentPool) -> Self {
         Self {
-            client: Client::new()
+            client,
+            proxied,
+            rate_limiter,
+            user_agents
         }
     }
 
     /// Performs the search scrape for Mercari.
+    /// 
+    /// Only items updated after the search criteria's `updated_after` are returned, if it's set.
+    /// 
+    /// Returns each item's ID along with when it was last updated.
     pub(super) async fn request(
         &self, 
-        search_criteria: &GallerySearchCriteria,
-        previous_scraped_item_datetime: UnixUtcDateTime
-    ) -> Result<Vec<ItemId>, String> {
+        search_criteria: &GallerySearchCriteria
+    ) -> Result<Vec<(ItemId, UnixUtcDateTime)>, String> {
         let dpop_key = match generate_dpop(&REQ_URL, "POST") {
             Ok(key) => {
                 tracing::trace!("Generated dpop key: {key}");
@@ -46,8 +70,9 @@ impl MercariSearchScraper {
                 search_criteria, 
                 &next_page_token
             );
+            self.rate_limiter.acquire(&Marketplace::Mercari).await;
             let response = request.send().await;
-            match self.handle_response(&previous_scraped_item_datetime, response).await {
+            match self.handle_response(search_criteria.updated_after.as_ref(), response).await {
                 Ok((scraped_item_ids, scraped_next_page_token)) => {
                     tracing::trace!("got following: {scraped_item_ids:?}, {scraped_next_page_token:?}");
                     item_ids.extend_from_slice(&scraped_item_ids);
@@ -64,57 +89,36 @@ impl MercariSearchScraper {
 
     /// Handle the raw response from the search scrape.
     /// 
-    /// Returns the item IDs in the response + an optional string containing the next page token;
+    /// Returns the item IDs (and updated datetimes) in the response + an optional string containing the next page token;
     /// if present, the next page should continue to be scraped as well.
     /// 
+    /// Items not updated after `previous_scraped_item_datetime` are filtered out;
+    /// if it's `None`, no items are filtered out.
+    /// 
     /// Returns an `Err` if the response had an error.
     async fn handle_response(
         &self, 
-        previous_scraped_item_datetime: &UnixUtcDateTime,
+        previous_scraped_item_datetime: Option<&UnixUtcDateTime>,
         response: Result<reqwest::Response, reqwest::Error>
-    ) -> Result<(Vec<ItemId>, Option<String>), String> {
+    ) -> Result<(Vec<(ItemId, UnixUtcDateTime)>, Option<String>), String> {
         match response {
             Ok(res) => {
                 match res.error_for_status() {
                     Ok(res) => {
                         match res.json::<MercariSearchData>().await {
-                            Ok(res) => {
-                                match res.items
-                                    .iter()
-                                    .all(|item| &item.updated > previous_scraped_item_datetime) 
-                                {
-                                    true => { // if all items are after our previous scraped datetime, go to the next page if possible
-                                        let item_ids = res.items
-                                            .into_iter()
-                                            .map(|item| item.id.into())
-                                            .collect();
-                                        let next_page_token = match res.meta.next_page_token.as_ref() {
-                                            "" => None,
-                                            _ => Some(res.meta.next_page_token)
-                                        };
-                                        return Ok((item_ids, next_page_token));
-                                    },
-                                    false => { // else, just return all items after this datetime
-                                        let item_ids = res.items
-                                            .into_iter()
-                                            .filter(|item| &item.updated > previous_scraped_item_datetime)
-                                            .map(|item| item.id.into())
-                                            .collect();
-                                        return Ok((item_ids, None));
-                                    }
-                                }
-                            },
+                            Ok(res) => Ok(res.into_new_items(previous_scraped_item_datetime)),
                             Err(err) => Err(format!("Error deserializing scraped search data:\n {err}\n (source: {:?})", err.source())),
                         }
                     },
                     Err(err) => Err(format!("Error code while scraping search:\n {err}"))
                 }
             },
+            Err(err) if err.is_connect() && self.proxied => Err(format!("Error connecting through the proxy to scrape search: {err}")),
             Err(err) => Err(format!("Error scraping search: {err}"))
         }
     }
 
-    /// Build the request for scraping the search.
+    /// Build the request for scraping the search, with the next user agent from the pool.
     fn build_request(
         &self, 
         dpop_key: &String,
@@ -126,6 +130,7 @@ impl MercariSearchScraper {
             .json(&self.build_payload(search_criteria, next_page_token))
             .header("dpop", dpop_key)
             .header("x-platform", "web") // TODO: is this necessary
+            .header(USER_AGENT, self.user_agents.next_user_agent())
     }
 
     /// Build the payload for scraping the search.
@@ -189,6 +194,42 @@ struct MercariSearchData {
     pub meta: MercariSearchMetadata
 }
 
+impl MercariSearchData {
+    /// Returns the IDs (and updated datetimes) of items updated after `previous_scraped_item_datetime` + the next page token, if the next page should be scraped.
+    /// 
+    /// If it's `None`, no items are filtered out.
+    fn into_new_items(self, previous_scraped_item_datetime: Option<&UnixUtcDateTime>) -> (Vec<(ItemId, UnixUtcDateTime)>, Option<String>) {
+        let is_new = |item: &MercariSearchItemData| match previous_scraped_item_datetime {
+            Some(datetime) => &item.updated > datetime,
+            None => true
+        };
+        match self.items
+            .iter()
+            .all(is_new) 
+        {
+            true => { // if all items are after our previous scraped datetime, go to the next page if possible
+                let item_ids = self.items
+                    .into_iter()
+                    .map(|item| (item.id.into(), item.updated))
+                    .collect();
+                let next_page_token = match self.meta.next_page_token.as_ref() {
+                    "" => None,
+                    _ => Some(self.meta.next_page_token)
+                };
+                (item_ids, next_page_token)
+            },
+            false => { // else, just return all items after this datetime
+                let item_ids = self.items
+                    .into_iter()
+                    .filter(|item| is_new(item))
+                    .map(|item| (item.id.into(), item.updated))
+                    .collect();
+                (item_ids, None)
+            }
+        }
+    }
+}
+
 /// Represents a single item's data from the search scrape.
 /// 
 /// Note: Other values are returned than what is here, but we only deserialize whatever we need.
@@ -205,4 +246,52 @@ struct MercariSearchItemData {
 struct MercariSearchMetadata {
     #[serde(rename(deserialize = "nextPageToken"))]
     pub next_page_token: String
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn search_data(items: &[(&str, i64)], next_page_token: &str) -> MercariSearchData {
+        MercariSearchData {
+            items: items
+                .iter()
+                .map(|(id, updated)| MercariSearchItemData { id: id.to_string(), updated: UnixUtcDateTime::from(*updated) })
+                .collect(),
+            meta: MercariSearchMetadata { next_page_token: next_page_token.into() }
+        }
+    }
+
+    fn ids(items: &[(ItemId, UnixUtcDateTime)]) -> Vec<String> {
+        items
+            .iter()
+            .map(|(id, _)| id.to_string())
+            .collect()
+    }
+
+    #[test]
+    fn items_not_updated_after_the_cutoff_are_excluded() {
+        let cutoff = UnixUtcDateTime::from(200);
+        let (items, next_page_token) = search_data(&[("new", 300), ("cutoff", 200), ("old", 100)], "next")
+            .into_new_items(Some(&cutoff));
+        assert_eq!(ids(&items), vec!["new"]);
+        assert_eq!(next_page_token, None);
+    }
+
+    #[test]
+    fn the_next_page_is_scraped_while_all_items_are_new() {
+        let cutoff = UnixUtcDateTime::from(100);
+        let (items, next_page_token) = search_data(&[("a", 300), ("b", 200)], "next")
+            .into_new_items(Some(&cutoff));
+        assert_eq!(ids(&items), vec!["a", "b"]);
+        assert_eq!(next_page_token.as_deref(), Some("next"));
+    }
+
+    #[test]
+    fn the_full_window_is_scraped_without_a_cutoff() {
+        let (items, next_page_token) = search_data(&[("a", 300), ("b", 100)], "")
+            .into_new_items(None);
+        assert_eq!(ids(&items), vec!["a", "b"]);
+        assert_eq!(next_page_token, None);
+    }
+}
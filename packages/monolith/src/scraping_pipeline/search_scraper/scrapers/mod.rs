@@ -1,50 +1,286 @@
-use std::collections::HashMap;
+use std::{collections::HashMap, sync::Arc, time::Duration};
 
-use futures::future::join_all;
+use async_trait::async_trait;
+use futures::{stream, StreamExt};
 use mercari::MercariSearchScraper;
-use crate::{config::SearchScraperConfig, galleries::{domain_types::{ItemId, Marketplace}, pipeline_states::GallerySearchScrapingState}};
+use crate::{config::SearchScraperConfig, notifications::{LayoutBreakSummary, Notifier, PipelineEvent}, galleries::{domain_types::{ItemId, Marketplace, UnixUtcDateTime}, pipeline_states::GallerySearchScrapingState, search_criteria::GallerySearchCriteria}, utils::{circuit_breaker::MarketplaceCircuitBreaker, layout_monitor::MarketplaceLayoutMonitor, marketplace_registry::MarketplaceRegistry, http_client::HttpClientFactory, rate_limiter::MarketplaceRateLimiter, user_agent_pool::UserAgentPool}};
 
 mod mercari;
 
+/// The interface for a marketplace's search scraper.
+/// 
+/// Adding a new marketplace only requires implementing this, and registering it in `SearchScraper::new`.
+#[async_trait]
+pub(super) trait SearchScraperBackend {
+    /// Scrape the marketplace's search for items matching the search criteria.
+    /// 
+    /// Returns each item's ID along with when it was last updated, or an `Err` if the search couldn't be scraped.
+    async fn search(&self, search_criteria: &GallerySearchCriteria) -> Result<Vec<(ItemId, UnixUtcDateTime)>, String>;
+}
+
 /// This scraper is in charge of using item IDs to scrape detailed data for each item.
+#[derive(Clone)]
 pub(super) struct SearchScraper {
     config: SearchScraperConfig,
-    mercari_scraper: MercariSearchScraper
+    circuit_breaker: MarketplaceCircuitBreaker,
+    layout_monitor: MarketplaceLayoutMonitor,
+    notifier: Notifier,
+    backends: MarketplaceRegistry<dyn SearchScraperBackend + Send + Sync>
 }
 
 impl SearchScraper {
     /// Instantiate a `SearchScraper`.
-    pub fn new(config: &SearchScraperConfig) -> Self {
+    /// 
+    /// Panics if a marketplace's proxy is invalid, rather than letting its requests go out directly.
+    pub fn new(config: &SearchScraperConfig, http_clients: &HttpClientFactory, notifier: Notifier) -> Self {
+        let rate_limiter = MarketplaceRateLimiter::new(&config.marketplace_rate_limits);
+        let circuit_breaker = MarketplaceCircuitBreaker::new(
+            config.breaker_failure_threshold, 
+            Duration::from_secs(config.breaker_cooldown_secs)
+        );
+        let layout_monitor = MarketplaceLayoutMonitor::new(config.layout_check_window, config.layout_check_min_average);
+        let user_agents = UserAgentPool::new(config.user_agents.clone());
+        let mut backends: MarketplaceRegistry<dyn SearchScraperBackend + Send + Sync> = MarketplaceRegistry::new();
+        let mercari_proxy = config.proxy_for(&Marketplace::Mercari);
+        let mercari_client = http_clients.client_with_proxy(mercari_proxy)
+            .unwrap_or_else(|err| panic!("Could not build the Mercari search scraper's client: {err}"));
+        backends.register(
+            Marketplace::Mercari.to_string(), 
+            Arc::new(MercariSearchScraper::new(mercari_client, mercari_proxy.is_some(), rate_limiter, user_agents))
+        );
         SearchScraper {
             config: config.clone(),
-            mercari_scraper: MercariSearchScraper::new()
+            circuit_breaker,
+            layout_monitor,
+            notifier,
+            backends
         }
     }
 
-    /// Attempt to scrape item IDs according to a search criteria.
+    /// Attempt to scrape item IDs according to a search criteria, for each of the gallery's marketplaces.
+    /// 
+    /// Each marketplace is searched with its effective criteria, ie with its override (if any) applied.
+    /// 
+    /// Only items updated after a marketplace's previous scraped datetime are scraped;
+    /// if it has none (ie this is its first scrape), the full search window is scraped.
+    /// 
+    /// If the search criteria has a `max_items_per_marketplace`, only that many of the most recently updated items are kept for each marketplace.
     /// 
-    /// Returns an `Err` for whichever marketplaces had errors while scraping.
+    /// Marketplaces are searched concurrently, with at most `max_concurrent_marketplaces` at once.
+    /// 
+    /// A search returning no items, when the marketplace's recent searches usually return plenty, is treated as a probable layout change:
+    /// it's failed with a distinct error, and a `LayoutBreakSuspected` event is emitted.
+    /// 
+    /// Returns an `Err` for whichever marketplaces had errors while scraping, are short-circuited by the circuit breaker,
+    /// or probably had a layout change; a marketplace failing doesn't affect the others.
     pub async fn scrape_search(&mut self, gallery: &GallerySearchScrapingState) -> HashMap<Marketplace, Result<Vec<ItemId>, String>> {
         tracing::debug!("Starting scrape search for gallery {}", gallery.gallery_id);
-        let results = join_all(
-            gallery.marketplace_previous_scraped_datetimes
-                .clone()
-                .into_iter()
-                .map(|(marketplace, previous_scraped_item_datetime)| async {
-                    let result = match marketplace {
-                        Marketplace::Mercari => self.mercari_scraper
-                            .request(&gallery.search_criteria, previous_scraped_item_datetime)
-                            .await
-                    };
-                    match &result {
-                        Ok(ids) => tracing::debug!("Gallery {}, marketplace {}: scraped {} item IDs", gallery.gallery_id, marketplace, ids.len()),
-                        Err(err) => tracing::debug!("Gallery {}, marketplace {} encountered error: {}", gallery.gallery_id, marketplace, err)
-                    };
-                    (marketplace, result)
-                })
-            ).await;
-        results
-            .into_iter()
+        stream::iter(gallery.search_criteria.marketplaces.clone())
+            .map(|marketplace| async {
+                let previous_scraped_item_datetime = gallery.marketplace_previous_scraped_datetimes
+                    .get(&marketplace)
+                    .cloned();
+                let search_criteria = gallery.search_criteria
+                    .for_marketplace(&marketplace)
+                    .with_updated_after(previous_scraped_item_datetime);
+                if let Err(remaining_cooldown) = self.circuit_breaker.check(&marketplace).await {
+                    let err = format!("Circuit breaker is open for {marketplace}; skipping search (cooldown remaining: {remaining_cooldown:?})");
+                    return (marketplace, Err(err));
+                }
+                let result = match self.backends.get(&marketplace) {
+                    Some(backend) => backend
+                        .search(&search_criteria)
+                        .await,
+                    None => Err(format!("No search scraper is registered for {marketplace}"))
+                };
+                let result = match result {
+                    Ok(items) => self.check_layout(gallery, &marketplace, items).await,
+                    Err(err) => Err(err)
+                };
+                let result = result.map(|items| keep_newest(items, search_criteria.max_items_per_marketplace));
+                match &result {
+                    Ok(_) => self.circuit_breaker.record_success(&marketplace).await,
+                    Err(_) => self.circuit_breaker.record_failure(&marketplace).await
+                };
+                match &result {
+                    Ok(ids) => tracing::debug!("Gallery {}, marketplace {}: scraped {} item IDs", gallery.gallery_id, marketplace, ids.len()),
+                    Err(err) => tracing::debug!("Gallery {}, marketplace {} encountered error: {}", gallery.gallery_id, marketplace, err)
+                };
+                (marketplace, result)
+            })
+            .buffer_unordered(self.config.max_concurrent_marketplaces.max(1))
+            .collect()
+            .await
+    }
+
+    /// Run the layout self-check on a marketplace's search results, returning them unchanged if they look normal.
+    /// 
+    /// Returns an `Err` (and emits a `LayoutBreakSuspected` event) if the marketplace probably had a layout change.
+    async fn check_layout(
+        &self, 
+        gallery: &GallerySearchScrapingState, 
+        marketplace: &Marketplace, 
+        items: Vec<(ItemId, UnixUtcDateTime)>
+    ) -> Result<Vec<(ItemId, UnixUtcDateTime)>, String> {
+        match self.layout_monitor.record(marketplace, items.len()).await {
+            Ok(()) => Ok(items),
+            Err(average_item_count) => {
+                tracing::warn!(
+                    "Search of {marketplace} for gallery {} returned no items, but recent searches averaged {average_item_count:.1}; its layout probably changed",
+                    gallery.gallery_id
+                );
+                self.notifier.emit(PipelineEvent::LayoutBreakSuspected(LayoutBreakSummary {
+                    gallery_id: gallery.gallery_id.clone(),
+                    marketplace: marketplace.clone(),
+                    average_item_count
+                }));
+                Err(format!("Probable layout change: search returned no items, but recent searches averaged {average_item_count:.1}"))
+            }
+        }
+    }
+}
+
+/// Returns the IDs of the `max_items` most recently updated items, newest first.
+/// 
+/// If `max_items` is `None`, all IDs are returned (still newest first).
+fn keep_newest(mut items: Vec<(ItemId, UnixUtcDateTime)>, max_items: Option<usize>) -> Vec<ItemId> {
+    items.sort_by(|(_, a), (_, b)| b.cmp(a));
+    if let Some(max_items) = max_items {
+        items.truncate(max_items);
+    }
+    items
+        .into_iter()
+        .map(|(item_id, _)| item_id)
+        .collect()
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use crate::{config::SearchScraperConfig, test_support::{notifier, scheduler_state}};
+    use super::*;
+
+    /// Returns the same items for every search, counting the searches.
+    struct CountingBackend {
+        searches: Arc<AtomicUsize>,
+        items: Vec<(ItemId, UnixUtcDateTime)>
+    }
+
+    #[async_trait]
+    impl SearchScraperBackend for CountingBackend {
+        async fn search(&self, _: &GallerySearchCriteria) -> Result<Vec<(ItemId, UnixUtcDateTime)>, String> {
+            self.searches.fetch_add(1, Ordering::Relaxed);
+            Ok(self.items.clone())
+        }
+    }
+
+    /// Fails every search with the same error.
+    struct FailingBackend;
+
+    #[async_trait]
+    impl SearchScraperBackend for FailingBackend {
+        async fn search(&self, _: &GallerySearchCriteria) -> Result<Vec<(ItemId, UnixUtcDateTime)>, String> {
+            Err("Blocked by the marketplace".into())
+        }
+    }
+
+    /// `num_items` items, with IDs 0 up to `num_items`, where higher IDs were updated later.
+    fn search_results(num_items: i64) -> Vec<(ItemId, UnixUtcDateTime)> {
+        (0..num_items)
+            .map(|i| (ItemId::from(i.to_string()), UnixUtcDateTime::from(i * 60)))
             .collect()
     }
-}
\ No newline at end of file
+
+    fn scraper(searches: Arc<AtomicUsize>) -> SearchScraper {
+        scraper_returning(searches, search_results(1))
+    }
+
+    fn scraper_returning(searches: Arc<AtomicUsize>, items: Vec<(ItemId, UnixUtcDateTime)>) -> SearchScraper {
+        let config = SearchScraperConfig {
+            marketplace_rate_limits: HashMap::new(),
+            max_concurrent_galleries: 1,
+            max_concurrent_marketplaces: 1,
+            breaker_failure_threshold: 0,
+            breaker_cooldown_secs: 0,
+            user_agents: vec![],
+            proxy: None,
+            marketplace_proxies: HashMap::new(),
+            layout_check_window: 0,
+            layout_check_min_average: 0.0
+        };
+        let mut backends: MarketplaceRegistry<dyn SearchScraperBackend + Send + Sync> = MarketplaceRegistry::new();
+        backends.register(Marketplace::Mercari.to_string(), Arc::new(CountingBackend { searches, items }));
+        SearchScraper {
+            circuit_breaker: MarketplaceCircuitBreaker::new(config.breaker_failure_threshold, Duration::ZERO),
+            layout_monitor: MarketplaceLayoutMonitor::new(config.layout_check_window, config.layout_check_min_average),
+            notifier: notifier(),
+            backends,
+            config
+        }
+    }
+
+    #[tokio::test]
+    async fn searches_the_gallerys_marketplaces() {
+        let searches = Arc::new(AtomicUsize::new(0));
+        let gallery = scheduler_state("gallery").to_next_stage();
+        let results = scraper(searches.clone()).scrape_search(&gallery).await;
+        assert_eq!(searches.load(Ordering::Relaxed), 1);
+        assert!(matches!(results.get(&Marketplace::Mercari), Some(Ok(ids)) if ids.len() == 1));
+    }
+
+    #[tokio::test]
+    async fn skips_marketplaces_the_gallery_isnt_on() {
+        let searches = Arc::new(AtomicUsize::new(0));
+        let mut gallery = scheduler_state("gallery").to_next_stage();
+        gallery.search_criteria.marketplaces = vec![];
+        let results = scraper(searches.clone()).scrape_search(&gallery).await;
+        assert_eq!(searches.load(Ordering::Relaxed), 0);
+        assert!(results.is_empty());
+    }
+
+    #[tokio::test]
+    async fn a_failing_marketplace_is_returned_as_its_reason() {
+        let mut scraper = scraper(Arc::new(AtomicUsize::new(0)));
+        scraper.config.max_concurrent_marketplaces = 4;
+        scraper.backends.register(Marketplace::Mercari.to_string(), Arc::new(FailingBackend));
+        let gallery = scheduler_state("gallery").to_next_stage();
+        let results = scraper.scrape_search(&gallery).await;
+        assert_eq!(
+            results.get(&Marketplace::Mercari), 
+            Some(&Err("Blocked by the marketplace".to_string()))
+        );
+        let failed_marketplace_reasons: HashMap<_, _> = results
+            .into_iter()
+            .filter_map(|(marketplace, result)| result.err().map(|err| (marketplace, err)))
+            .collect();
+        let item_scraping_state = gallery.to_next_stage(HashMap::new(), HashMap::new(), failed_marketplace_reasons);
+        assert!(item_scraping_state.failed_marketplace_reasons.contains_key(&Marketplace::Mercari));
+    }
+
+    #[test]
+    fn keeps_only_the_newest_items_up_to_the_cap() {
+        let kept = keep_newest(search_results(50), Some(10));
+        let expected: Vec<_> = (40..50)
+            .rev()
+            .map(|i| ItemId::from(i.to_string()))
+            .collect();
+        assert_eq!(kept, expected);
+    }
+
+    #[test]
+    fn keeps_every_item_without_a_cap() {
+        assert_eq!(keep_newest(search_results(50), None).len(), 50);
+    }
+
+    #[tokio::test]
+    async fn the_cap_is_applied_to_the_search_and_carried_into_item_scraping() {
+        let searches = Arc::new(AtomicUsize::new(0));
+        let mut gallery = scheduler_state("gallery").to_next_stage();
+        gallery.search_criteria.max_items_per_marketplace = Some(10);
+        let results = scraper_returning(searches, search_results(50)).scrape_search(&gallery).await;
+        assert!(matches!(results.get(&Marketplace::Mercari), Some(Ok(ids)) if ids.len() == 10));
+        let item_scraping_state = gallery.to_next_stage(HashMap::new(), HashMap::new(), HashMap::new());
+        assert_eq!(item_scraping_state.max_items_per_marketplace, Some(10));
+    }
+}
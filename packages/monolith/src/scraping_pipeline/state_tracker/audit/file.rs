@@ -0,0 +1,59 @@
+use std::path::PathBuf;
+use tokio::io::AsyncWriteExt;
+
+use crate::{config::state_tracker::StateTrackerConfig, galleries::domain_types::GalleryId, messages::message_types::state_tracker::{StateTrackerError, StateTransition}};
+use super::AuditLog;
+
+/// A file-backed audit log, with one JSON transition per line.
+/// 
+/// Transitions are only ever appended, so existing lines are never rewritten.
+pub struct FileAuditLog {
+    path: PathBuf
+}
+
+impl FileAuditLog {
+    /// Initialize the audit log.
+    pub fn init(config: &StateTrackerConfig) -> Self {
+        Self {
+            path: PathBuf::from(&config.audit_log_path)
+        }
+    }
+}
+
+impl AuditLog for FileAuditLog {
+    async fn record(&mut self, transition: StateTransition) -> Result<(), StateTrackerError> {
+        let mut line = serde_json::to_string(&transition)?;
+        line.push('\n');
+        let mut file = tokio::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .await
+            .map_err(|err| StateTrackerError::Other(format!("Failed to open audit log file {:?}: {err}", self.path)))?;
+        file.write_all(line.as_bytes())
+            .await
+            .map_err(|err| StateTrackerError::Other(format!("Failed to append to audit log file {:?}: {err}", self.path)))
+    }
+
+    /// Unparseable lines (ie one cut off by a crash mid-write) are logged and skipped.
+    async fn history(&mut self, gallery_id: &GalleryId) -> Result<Vec<StateTransition>, StateTrackerError> {
+        let contents = match tokio::fs::read_to_string(&self.path).await {
+            Ok(contents) => contents,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(vec![]),
+            Err(err) => return Err(StateTrackerError::Other(format!("Failed to read audit log file {:?}: {err}", self.path)))
+        };
+        let transitions = contents
+            .lines()
+            .filter(|line| !line.trim().is_empty())
+            .filter_map(|line| match serde_json::from_str::<StateTransition>(line) {
+                Ok(transition) => Some(transition),
+                Err(err) => {
+                    tracing::warn!("Skipping unparseable line in audit log file {:?}: {err}", self.path);
+                    None
+                }
+            })
+            .filter(|transition| &transition.gallery_id == gallery_id)
+            .collect();
+        Ok(transitions)
+    }
+}
@@ -0,0 +1,49 @@
+use file::FileAuditLog;
+use noop::NoopAuditLog;
+use crate::{config::state_tracker::{AuditLogKind, StateTrackerConfig}, galleries::domain_types::GalleryId, messages::message_types::state_tracker::{StateTrackerError, StateTransition}};
+
+mod file;
+mod noop;
+
+/// The interface for an append-only log of galleries' state transitions.
+/// 
+/// The state tracker records to this on every add, update and removal of a gallery.
+/// Transitions are never removed, so a gallery's history outlives its state.
+pub(super) trait AuditLog {
+    /// Append a transition to the log.
+    async fn record(&mut self, transition: StateTransition) -> Result<(), StateTrackerError>;
+
+    /// Get all of a gallery's transitions, oldest first.
+    async fn history(&mut self, gallery_id: &GalleryId) -> Result<Vec<StateTransition>, StateTrackerError>;
+}
+
+/// The audit log of the state tracker.
+pub(super) enum InnerAuditLog {
+    Noop(NoopAuditLog),
+    File(FileAuditLog)
+}
+
+impl InnerAuditLog {
+    pub(super) fn init(config: &StateTrackerConfig) -> Self {
+        match config.audit_log_kind {
+            AuditLogKind::None => Self::Noop(NoopAuditLog),
+            AuditLogKind::File => Self::File(FileAuditLog::init(config))
+        }
+    }
+}
+
+impl AuditLog for InnerAuditLog {
+    async fn record(&mut self, transition: StateTransition) -> Result<(), StateTrackerError> {
+        match self {
+            InnerAuditLog::Noop(audit_log) => audit_log.record(transition).await,
+            InnerAuditLog::File(audit_log) => audit_log.record(transition).await,
+        }
+    }
+
+    async fn history(&mut self, gallery_id: &GalleryId) -> Result<Vec<StateTransition>, StateTrackerError> {
+        match self {
+            InnerAuditLog::Noop(audit_log) => audit_log.history(gallery_id).await,
+            InnerAuditLog::File(audit_log) => audit_log.history(gallery_id).await,
+        }
+    }
+}
@@ -0,0 +1,15 @@
+use crate::{galleries::domain_types::GalleryId, messages::message_types::state_tracker::{StateTrackerError, StateTransition}};
+use super::AuditLog;
+
+/// An audit log which discards all transitions.
+pub struct NoopAuditLog;
+
+impl AuditLog for NoopAuditLog {
+    async fn record(&mut self, _transition: StateTransition) -> Result<(), StateTrackerError> {
+        Ok(())
+    }
+
+    async fn history(&mut self, _gallery_id: &GalleryId) -> Result<Vec<StateTransition>, StateTrackerError> {
+        Ok(vec![])
+    }
+}
@@ -0,0 +1,68 @@
+//! Contains the cancellation tokens for galleries' runs, which are cancelled when a gallery is deleted mid-run.
+use std::collections::{HashMap, HashSet};
+use tokio_util::sync::CancellationToken;
+use crate::{galleries::domain_types::GalleryId, messages::message_types::state_tracker::StateTrackerError};
+
+/// Tracks a cancellation token for each gallery's current run, and which galleries were cancelled.
+///
+/// Cancelled galleries are remembered, so late writes for them (ie from a module which was mid-run) can be rejected.
+pub(super) struct RunCancellations {
+    tokens: HashMap<GalleryId, CancellationToken>,
+    cancelled: HashSet<GalleryId>
+}
+
+impl RunCancellations {
+    /// Instantiate with no runs.
+    pub fn new() -> Self {
+        Self {
+            tokens: HashMap::new(),
+            cancelled: HashSet::new()
+        }
+    }
+
+    /// Start tracking a new run of the gallery, replacing any previous run's token.
+    pub fn start_run(&mut self, gallery_id: GalleryId) {
+        self.tokens.insert(gallery_id, CancellationToken::new());
+    }
+
+    /// Get the token for the gallery's current run, starting one if it has none (ie it was replayed from the store).
+    ///
+    /// If the gallery was cancelled, an already cancelled token is returned.
+    pub fn token(&mut self, gallery_id: GalleryId) -> CancellationToken {
+        if self.cancelled.contains(&gallery_id) {
+            let token = CancellationToken::new();
+            token.cancel();
+            return token;
+        }
+        self.tokens
+            .entry(gallery_id)
+            .or_default()
+            .clone()
+    }
+
+    /// Stop tracking the gallery's run without cancelling it, ie when it's removed after completing.
+    pub fn finish_run(&mut self, gallery_id: &GalleryId) {
+        self.tokens.remove(gallery_id);
+    }
+
+    /// Cancel the gallery's current run (if any), and reject any later writes for it.
+    pub fn cancel(&mut self, gallery_id: GalleryId) {
+        self.cancel_run(&gallery_id);
+        self.cancelled.insert(gallery_id);
+    }
+
+    /// Cancel the gallery's current run (if any), without rejecting later runs of it.
+    pub fn cancel_run(&mut self, gallery_id: &GalleryId) {
+        if let Some(token) = self.tokens.remove(gallery_id) {
+            token.cancel();
+        }
+    }
+
+    /// Returns an `Err` if the gallery was cancelled.
+    pub fn check_not_cancelled(&self, gallery_id: &GalleryId) -> Result<(), StateTrackerError> {
+        match self.cancelled.contains(gallery_id) {
+            true => Err(StateTrackerError::GalleryCancelled),
+            false => Ok(())
+        }
+    }
+}
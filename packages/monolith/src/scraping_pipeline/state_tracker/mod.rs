@@ -1,8 +1,18 @@
+use std::{collections::HashMap, time::Duration};
+use audit::{AuditLog, InnerAuditLog};
+use cancellation::RunCancellations;
 use state::{InnerState, State};
+use store::{InnerStore, StateTrackerStore};
+use watchdog::StallWatchdog;
+use tracing::Instrument;
 
-use crate::{config::state_tracker::StateTrackerConfig, messages::{message_types::state_tracker::StateTrackerMessage, StateTrackerReceiver}};
+use crate::{config::state_tracker::StateTrackerConfig, galleries::{domain_types::{GalleryId, RunId, UnixUtcDateTime}, pipeline_states::GalleryPipelineStateTypes}, messages::{message_types::{state_tracker::{StateTrackerError, StateTrackerMessage, StateTransition}, storage::StorageMessage}, StateTrackerReceiver, StorageSender}, notifications::{Notifier, PipelineEvent, StalledGallerySummary}, scraping_pipeline::module_health::{ModuleHealth, PipelineModule}, utils::tracing_context::module_span};
 
+mod audit;
+mod cancellation;
 mod state;
+mod store;
+mod watchdog;
 // mod inner_state;
 
 /// This module tracks and manages the state of galleries in the pipeline.
@@ -10,6 +20,18 @@ mod state;
 /// This is useful since we can persist the state to a temporary store like Redis,
 /// and continue from the previous state in case of application restarts.
 /// 
+/// All changes are also written through to a backing store, which is replayed into the state on startup.
+/// 
+/// A watchdog periodically checks for galleries which haven't advanced within their stage's configured timeout
+/// (ie if a downstream module never responded). Their runs are cancelled as below, and they're marked as stalled, 
+/// keeping their last state for inspection; unlike cancelled galleries, they can then be scraped again as usual.
+/// 
+/// If enabled, every add, update and removal of a gallery is also appended to an audit log (see `AuditLog`).
+/// 
+/// Each gallery's run has a cancellation token, which modules check while processing it. Cancelling a gallery
+/// (ie when it's deleted) triggers the token and removes the gallery; any later adds or updates for it are rejected.
+/// Its scraped items are also dropped from storage, as its run won't reach storage to drop them itself.
+/// 
 /// # API
 /// The module has the following API.
 /// 
@@ -21,6 +43,11 @@ mod state;
 /// ### Check
 /// Check if the gallery exists in state.
 /// 
+/// ### Check Exists
+/// Check that the gallery exists in state, returning its state type.
+/// 
+/// Returns an `Err` if it doesn't exist.
+/// 
 /// ### Check State
 /// Check the gallery's state type.
 /// 
@@ -29,6 +56,11 @@ mod state;
 /// ### Get
 /// Get a gallery's data, leaving it stored as `None`.
 /// 
+/// ### Peek
+/// Get a copy of a gallery's data, leaving it stored as is.
+/// 
+/// Returns an `Err` if its data is currently taken.
+/// 
 /// ### Update
 /// Update a gallery by setting a new state for it.
 /// 
@@ -36,63 +68,400 @@ mod state;
 /// Remove the gallery from the state. It can be removed while in any state.
 /// 
 /// Returns an `Err` if the gallery doesn't exist.
+/// 
+/// ### Remove If State
+/// Remove the gallery from the state, only if it's in the expected stage and its state isn't taken;
+/// this is safe to use for cleanup, as it can't clobber a gallery which a module is working on.
+/// 
+/// Returns an `Err` if the gallery doesn't exist, is in another stage, or its state is taken.
+/// 
+/// ### Cancel
+/// Cancel the gallery's current run and remove it from the state. Later adds or updates for it are rejected.
+/// 
+/// Returns an `Err` if the gallery doesn't exist, though it's still cancelled.
+/// 
+/// ### Get Cancellation Token
+/// Get the cancellation token for the gallery's current run; it's already cancelled if the gallery was cancelled.
+/// 
+/// Returns an `Err` if the gallery doesn't exist.
+/// 
+/// ### Get In-Flight
+/// Get the IDs of all galleries which haven't reached the `Final` state.
+/// 
+/// ### List
+/// Get the IDs and state types of all galleries, sorted by ID.
+/// 
+/// ### Persist All
+/// Write all gallery states through to the backing store; used on shutdown.
+/// 
+/// ### Get Snapshot
+/// Get the latest snapshot of a gallery's state in a stage, from the backing store; used for replaying a gallery from that stage.
+/// 
+/// Returns an `Err` if the gallery never reached the stage.
+/// 
+/// ### Get Stalled
+/// Get all galleries which were marked as stalled, sorted by ID.
+/// 
+/// ### Get Audit Log
+/// Get a gallery's recorded state transitions, oldest first.
 pub struct StateTrackerModule {
     config: StateTrackerConfig,
     state: InnerState,
+    store: InnerStore,
+    audit_log: InnerAuditLog,
+    watchdog: StallWatchdog,
+    cancellations: RunCancellations,
+    notifier: Notifier,
+    storage_sender: StorageSender,
     msg_receiver: StateTrackerReceiver
 }
 
 impl StateTrackerModule {
-    pub async fn init(config: StateTrackerConfig, msg_receiver: StateTrackerReceiver) -> Self {
-        let state = InnerState::init(&config).await;
+    pub async fn init(
+        config: StateTrackerConfig, 
+        msg_receiver: StateTrackerReceiver, 
+        storage_sender: StorageSender, 
+        notifier: Notifier
+    ) -> Self {
+        let mut state = InnerState::init(&config).await;
+        let mut store = InnerStore::init(&config).await;
+        let mut watchdog = StallWatchdog::new(&config.stage_timeouts_secs, config.max_stalled_galleries);
+        Self::replay_store(&mut state, &mut store, &mut watchdog).await;
+        let audit_log = InnerAuditLog::init(&config);
         Self {
             config,
             state,
+            store,
+            audit_log,
+            watchdog,
+            cancellations: RunCancellations::new(),
+            notifier,
+            storage_sender,
             msg_receiver
         }
     }
     
-    /// Start accepting and acting on messages.
-    pub async fn run(&mut self) {
+    /// Start accepting and acting on messages, checking for stalled galleries every `watchdog_interval_secs`.
+    pub async fn run(&mut self, module_health: &ModuleHealth) {
         tracing::info!("StateTrackerModule is running...");
-        while let Some(msg) = self.msg_receiver.receive().await {
-            self.process_msg(msg).await;
+        let mut watchdog_interval = tokio::time::interval(Duration::from_secs(self.config.watchdog_interval_secs.max(1)));
+        loop {
+            tokio::select! {
+                msg = module_health.receive_with_span(PipelineModule::StateTracker, &mut self.msg_receiver) => match msg {
+                    Some((msg, span)) => {
+                        self.process_msg(msg)
+                            .instrument(module_span(&span, PipelineModule::StateTracker))
+                            .await
+                    },
+                    None => break
+                },
+                _ = watchdog_interval.tick() => self.mark_stalled_galleries().await
+            }
+        }
+    }
+
+    /// Cancel the runs of galleries which haven't advanced within their stage's timeout, marking them as stalled
+    /// and emitting a `GalleryStalled` event for each.
+    async fn mark_stalled_galleries(&mut self) {
+        let galleries = match self.state.all_galleries().await {
+            Ok(galleries) => galleries,
+            Err(err) => {
+                tracing::error!("Watchdog failed to get galleries from state: {err}");
+                return;
+            }
+        };
+        let stalled_galleries = self.watchdog.find_stalled(&galleries);
+        let mut states: HashMap<_, _> = galleries.into_iter().collect();
+        for (gallery_id, stalled_for) in stalled_galleries {
+            let Some(last_state) = states.remove(&gallery_id) else { continue };
+            let failed_marketplaces: Vec<_> = last_state
+                .failed_marketplace_reasons()
+                .map(|reasons| reasons.keys().cloned().collect())
+                .unwrap_or_default();
+            tracing::warn!(
+                "Gallery {gallery_id} has been stalled in the {:?} stage for {stalled_for:?} ({} failed marketplaces); marking it as stalled",
+                last_state.state_type(),
+                failed_marketplaces.len()
+            );
+            if let Err(err) = self.cancel_gallery(gallery_id.clone(), false).await {
+                tracing::error!("Failed to cancel stalled gallery {gallery_id}: {err}");
+                continue;
+            }
+            self.notifier.emit(PipelineEvent::GalleryStalled(StalledGallerySummary {
+                gallery_id: gallery_id.clone(),
+                stage: last_state.state_type(),
+                stalled_for_secs: stalled_for.as_secs(),
+                failed_marketplaces
+            }));
+            self.watchdog.mark_stalled(last_state, stalled_for);
+        }
+    }
+
+    /// Cancel a gallery's current run and remove it from the state, dropping its scraped items.
+    /// 
+    /// If `reject_later_runs` is set (ie it was deleted), later adds or updates for it are rejected and its stage snapshots are dropped;
+    /// otherwise (ie it stalled), it can be scraped again.
+    /// 
+    /// Returns an `Err` if the gallery doesn't exist, though its run is still cancelled.
+    async fn cancel_gallery(&mut self, gallery_id: GalleryId, reject_later_runs: bool) -> Result<(), StateTrackerError> {
+        match reject_later_runs {
+            true => self.cancellations.cancel(gallery_id.clone()),
+            false => self.cancellations.cancel_run(&gallery_id)
+        }
+        self.clear_scraped_items(gallery_id.clone());
+        let from_stage = self.state.check_gallery_exists(gallery_id.clone()).await.ok();
+        self.state.remove_gallery(gallery_id.clone()).await?;
+        self.watchdog.forget(&gallery_id);
+        self.record_transition(gallery_id.clone(), from_stage, None).await;
+        tracing::debug!("Cancelled gallery {gallery_id}");
+        if reject_later_runs {
+            self.store.remove_snapshots(&gallery_id).await?;
+        }
+        self.store.remove(gallery_id).await
+    }
+
+    /// Tell storage to drop a gallery's scraped items.
+    /// 
+    /// This doesn't wait for room on the bus, as storage messages the state tracker and waiting could deadlock;
+    /// failing to send is only logged, as the items are otherwise dropped once the gallery is next stored.
+    fn clear_scraped_items(&self, gallery_id: GalleryId) {
+        if let Err(err) = self.storage_sender.try_send(StorageMessage::ClearScrapedItems { gallery_id: gallery_id.clone() }) {
+            tracing::warn!("Failed to tell storage to drop scraped items of gallery {gallery_id}: {err}");
+        }
+    }
+
+    /// Append a gallery's state transition to the audit log.
+    /// 
+    /// Failing to do so is only logged, as the transition itself has already happened.
+    async fn record_transition(
+        &mut self, 
+        gallery_id: GalleryId, 
+        from_stage: Option<GalleryPipelineStateTypes>, 
+        to_stage: Option<GalleryPipelineStateTypes>
+    ) {
+        let transition = StateTransition {
+            gallery_id: gallery_id.clone(),
+            from_stage,
+            to_stage,
+            timestamp: UnixUtcDateTime::now()
+        };
+        if let Err(err) = self.audit_log.record(transition).await {
+            tracing::error!("Failed to record state transition of gallery {gallery_id} in the audit log: {err}");
         }
     }
 
-    /// Handle each message variant.
+    /// Rebuild the state from all gallery states in the store.
+    /// 
+    /// Replayed galleries are tracked by the watchdog as if they just transitioned.
+    async fn replay_store(state: &mut InnerState, store: &mut InnerStore, watchdog: &mut StallWatchdog) {
+        let stored_states = match store.load_all().await {
+            Ok(stored_states) => stored_states,
+            Err(err) => {
+                tracing::error!("Failed to load gallery states from the state tracker store: {err}");
+                return;
+            }
+        };
+        let num_stored_states = stored_states.len();
+        for (gallery_id, gallery_state) in stored_states {
+            match state.add_gallery(gallery_id.clone(), gallery_state).await {
+                Ok(_) => watchdog.record_added(gallery_id),
+                Err(StateTrackerError::GalleryAlreadyExists) => tracing::debug!("Gallery {gallery_id} from store already exists in state; skipping"),
+                Err(err) => tracing::error!("Failed to replay gallery {gallery_id} from store into state: {err}")
+            }
+        }
+        tracing::info!("Replayed {num_stored_states} gallery states from the state tracker store");
+    }
+
+    /// Handle a message, dead-lettering it by its kind and gallery ID if its response couldn't be delivered.
     async fn process_msg(&mut self, msg: StateTrackerMessage) {
+        let kind = msg.kind();
+        let gallery_id = msg.gallery_id();
+        if !self.act_on_msg(msg).await {
+            let gallery = gallery_id.map(|gallery_id| format!(" for gallery {gallery_id}")).unwrap_or_default();
+            self.msg_receiver
+                .dead_letter_response(format!("Could not deliver the response to a {kind} message{gallery}, as its sender stopped waiting for it"))
+                .await;
+        }
+    }
+
+    /// Handle each message variant, returning whether its response was delivered.
+    async fn act_on_msg(&mut self, msg: StateTrackerMessage) -> bool {
         match msg {
             StateTrackerMessage::AddGallery(msg) => {
-                msg.act_async(|(gallery_id, gallery)| async {
+                msg.act_async(|(gallery_id, gallery)| async move {
                     tracing::trace!("Got message to add gallery {gallery_id} to state"); 
-                    self.state.add_gallery(gallery_id, gallery).await
-                }).await;
+                    let stage = gallery.state_type();
+                    self.cancellations.check_not_cancelled(&gallery_id)?;
+                    self.state.add_gallery(gallery_id.clone(), gallery.clone()).await?;
+                    self.cancellations.start_run(gallery_id.clone());
+                    self.watchdog.record_added(gallery_id.clone());
+                    self.record_transition(gallery_id.clone(), None, Some(stage)).await;
+                    self.store.upsert(gallery).await?;
+                    let run_id = RunId::new();
+                    tracing::debug!("Started run {run_id} of gallery {gallery_id}");
+                    Ok(run_id)
+                }).await.is_ok()
             },
             StateTrackerMessage::CheckGalleryDoesntExist(msg) => {
                 msg.act_async(|gallery_id| async {
                     tracing::trace!("Got message to check (non-)existence of gallery {gallery_id} state"); 
                     self.state.check_gallery_doesnt_exist(gallery_id).await
-                }).await;
+                }).await.is_ok()
+            },
+            StateTrackerMessage::CheckGalleryExists(msg) => {
+                msg.act_async(|gallery_id| async {
+                    tracing::trace!("Got message to check existence of gallery {gallery_id} state"); 
+                    self.state.check_gallery_exists(gallery_id).await
+                }).await.is_ok()
             },
             StateTrackerMessage::GetGalleryState(msg) => {
                 msg.act_async(|(gallery_id, requested_state_type)| async {
                     tracing::trace!("Got message to take gallery {gallery_id} state"); 
                     self.state.get_gallery_state(gallery_id, requested_state_type).await
-                }).await;
+                }).await.is_ok()
+            },
+            StateTrackerMessage::PeekGalleryState(msg) => {
+                msg.act_async(|(gallery_id, requested_state_type)| async {
+                    tracing::trace!("Got message to peek gallery {gallery_id} state"); 
+                    self.state.peek_gallery_state(gallery_id, requested_state_type).await
+                }).await.is_ok()
             },
             StateTrackerMessage::UpdateGalleryState(msg) => {
-                msg.act_async(|(gallery_id, updated_state)| async {
+                msg.act_async(|(gallery_id, updated_state)| async move {
                     tracing::trace!("Got message to update gallery {gallery_id} from state"); 
-                    self.state.update_gallery_state(gallery_id, updated_state).await
-                }).await;
+                    self.cancellations.check_not_cancelled(&gallery_id)?;
+                    let from_stage = self.state.check_gallery_exists(gallery_id.clone()).await.ok();
+                    self.state.update_gallery_state(gallery_id.clone(), updated_state.clone()).await?;
+                    self.watchdog.record_transition(gallery_id.clone());
+                    self.record_transition(gallery_id.clone(), from_stage, Some(updated_state.state_type())).await;
+                    self.store.upsert(updated_state).await
+                }).await.is_ok()
             },
             StateTrackerMessage::RemoveGallery(msg) => {
                 msg.act_async(|gallery_id| async {
                     tracing::trace!("Got message to remove gallery {gallery_id} from state"); 
-                    self.state.remove_gallery(gallery_id).await
-                }).await;
+                    let from_stage = self.state.check_gallery_exists(gallery_id.clone()).await.ok();
+                    self.state.remove_gallery(gallery_id.clone()).await?;
+                    self.watchdog.forget(&gallery_id);
+                    self.cancellations.finish_run(&gallery_id);
+                    self.record_transition(gallery_id.clone(), from_stage, None).await;
+                    self.store.remove(gallery_id).await
+                }).await.is_ok()
+            },
+            StateTrackerMessage::CancelGallery(msg) => {
+                msg.act_async(|gallery_id| async {
+                    tracing::trace!("Got message to cancel gallery {gallery_id}"); 
+                    self.cancel_gallery(gallery_id, true).await
+                }).await.is_ok()
             },
+            StateTrackerMessage::GetCancellationToken(msg) => {
+                msg.act_async(|gallery_id| async {
+                    tracing::trace!("Got message to get cancellation token of gallery {gallery_id}"); 
+                    if self.cancellations.check_not_cancelled(&gallery_id).is_ok() {
+                        self.state.check_gallery_exists(gallery_id.clone()).await?;
+                    }
+                    Ok(self.cancellations.token(gallery_id))
+                }).await.is_ok()
+            },
+            StateTrackerMessage::RemoveGalleryIfState(msg) => {
+                msg.act_async(|(gallery_id, expected)| async move {
+                    tracing::trace!("Got message to remove gallery {gallery_id} from state if it's in the {expected:?} stage"); 
+                    let actual = self.state.check_gallery_exists(gallery_id.clone()).await?;
+                    if actual != expected {
+                        return Err(StateTrackerError::UnexpectedStage { expected, actual });
+                    }
+                    // Fails if the state is taken, ie a module is processing it
+                    self.state.peek_gallery_state(gallery_id.clone(), expected).await?;
+                    self.state.remove_gallery(gallery_id.clone()).await?;
+                    self.watchdog.forget(&gallery_id);
+                    self.cancellations.finish_run(&gallery_id);
+                    self.record_transition(gallery_id.clone(), Some(actual), None).await;
+                    self.store.remove(gallery_id).await
+                }).await.is_ok()
+            },
+            StateTrackerMessage::GetInFlightGalleries(msg) => {
+                msg.act_async(|_| async {
+                    tracing::trace!("Got message to get in-flight galleries"); 
+                    let galleries = self.state.all_galleries().await?;
+                    Ok(
+                        galleries
+                            .into_iter()
+                            .filter(|(_, state)| !state.matches(&GalleryPipelineStateTypes::Final))
+                            .map(|(gallery_id, _)| gallery_id)
+                            .collect()
+                    )
+                }).await.is_ok()
+            },
+            StateTrackerMessage::ListGalleries(msg) => {
+                msg.act_async(|_| async {
+                    tracing::trace!("Got message to list galleries"); 
+                    let galleries = self.state.all_galleries().await?;
+                    let mut galleries: Vec<_> = galleries
+                        .into_iter()
+                        .map(|(gallery_id, state)| (gallery_id, state.state_type()))
+                        .collect();
+                    galleries.sort_by(|(a, _), (b, _)| a.as_str().cmp(b.as_str()));
+                    Ok(galleries)
+                }).await.is_ok()
+            },
+            StateTrackerMessage::PersistAll(msg) => {
+                msg.act_async(|_| async {
+                    tracing::trace!("Got message to persist all gallery states"); 
+                    let galleries = self.state.all_galleries().await?;
+                    let num_galleries = galleries.len();
+                    for (_, state) in galleries {
+                        self.store.upsert(state).await?;
+                    }
+                    Ok(num_galleries)
+                }).await.is_ok()
+            },
+            StateTrackerMessage::GetStageSnapshot(msg) => {
+                msg.act_async(|(gallery_id, stage)| async move {
+                    tracing::trace!("Got message to get gallery {gallery_id} snapshot for stage {stage:?}"); 
+                    self.store
+                        .get_snapshot(&gallery_id, &stage)
+                        .await?
+                        .ok_or(StateTrackerError::SnapshotNotFound)
+                }).await.is_ok()
+            },
+            StateTrackerMessage::GetStalledGalleries(msg) => {
+                msg.act(|_| {
+                    tracing::trace!("Got message to get stalled galleries"); 
+                    Ok(self.watchdog.stalled_galleries())
+                }).is_ok()
+            },
+            StateTrackerMessage::GetAuditLog(msg) => {
+                msg.act_async(|gallery_id| async move {
+                    tracing::trace!("Got message to get audit log of gallery {gallery_id}"); 
+                    self.audit_log.history(&gallery_id).await
+                }).await.is_ok()
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{galleries::pipeline_states::GalleryPipelineStates, test_support::{scheduler_state, TestHarness}};
+    use super::*;
+
+    #[tokio::test]
+    async fn cancelling_a_gallery_removes_it_and_clears_its_scraped_items() {
+        let mut harness = TestHarness::new();
+        harness.spawn_state_tracker().await;
+        let mut sender = harness.state_tracker_sender();
+        let gallery_id = GalleryId::from("gallery".to_string());
+        let gallery = GalleryPipelineStates::Initialization(scheduler_state("gallery"));
+        assert!(sender.add_gallery(gallery.clone()).await.unwrap().is_ok());
+        assert!(sender.check_gallery_exists(gallery_id.clone()).await.unwrap().is_ok());
+
+        assert!(sender.cancel_gallery(gallery_id.clone()).await.unwrap().is_ok());
+        match harness.storage.expect_message().await {
+            StorageMessage::ClearScrapedItems { gallery_id: cleared_id } => assert_eq!(cleared_id, gallery_id),
+            other => panic!("Expected the gallery's scraped items to be cleared, but got {other:?}")
         }
+        assert!(sender.check_gallery_exists(gallery_id).await.unwrap().is_err());
+        // A deleted gallery's later runs are rejected
+        assert!(sender.add_gallery(gallery).await.unwrap().is_err());
     }
-}
\ No newline at end of file
+}
@@ -1,4 +1,4 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 
 use crate::{galleries::{domain_types::GalleryId, pipeline_states::{GalleryPipelineStateTypes, GalleryPipelineStates}}, messages::message_types::state_tracker::StateTrackerError};
 use super::State;
@@ -7,14 +7,16 @@ use super::State;
 /// 
 /// Does not persist states anywhere.
 pub struct InternalState {
-    states: HashMap<GalleryId, GalleryPipelineStates>
+    states: HashMap<GalleryId, GalleryPipelineStates>,
+    taken_galleries: HashSet<GalleryId>
 }
 
 impl InternalState {
     /// Initialize the internal state.
     pub fn init() -> Self {
         Self {
-            states: HashMap::new()
+            states: HashMap::new(),
+            taken_galleries: HashSet::new()
         }
     }
 }
@@ -35,9 +37,32 @@ impl State for InternalState {
         Ok(())
     }
 
+    async fn check_gallery_exists(&mut self, gallery_id: GalleryId) -> Result<GalleryPipelineStateTypes, StateTrackerError> {
+        match self.states.get(&gallery_id) {
+            Some(state) => Ok(state.state_type()),
+            None => Err(StateTrackerError::GalleryDoesntExist)
+        }
+    }
+
     async fn get_gallery_state(&mut self, gallery_id: GalleryId, requested_state_type: GalleryPipelineStateTypes) -> Result<GalleryPipelineStates, StateTrackerError> {
         match self.states.get(&gallery_id) {
             Some(state) => {
+                if state.matches(&requested_state_type) {
+                    self.taken_galleries.insert(gallery_id);
+                    return Ok(state.clone());
+                }
+                Err(StateTrackerError::GalleryHasWrongState)
+            },
+            None => Err(StateTrackerError::GalleryDoesntExist)
+        }
+    }
+
+    async fn peek_gallery_state(&mut self, gallery_id: GalleryId, requested_state_type: GalleryPipelineStateTypes) -> Result<GalleryPipelineStates, StateTrackerError> {
+        match self.states.get(&gallery_id) {
+            Some(state) => {
+                if self.taken_galleries.contains(&gallery_id) {
+                    return Err(StateTrackerError::GalleryStateTaken);
+                }
                 if state.matches(&requested_state_type) {
                     return Ok(state.clone());
                 }
@@ -52,13 +77,24 @@ impl State for InternalState {
             Some(state) => *state = updated_state,
             None => return Err(StateTrackerError::GalleryDoesntExist)
         }
+        self.taken_galleries.remove(&gallery_id);
         Ok(())
     }
 
     async fn remove_gallery(&mut self, gallery_id: GalleryId) -> Result<(), StateTrackerError> {
+        self.taken_galleries.remove(&gallery_id);
         match self.states.remove(&gallery_id) {
             Some(_) => Ok(()),
             None => Err(StateTrackerError::GalleryDoesntExist)
         }
     }
+
+    async fn all_galleries(&mut self) -> Result<Vec<(GalleryId, GalleryPipelineStates)>, StateTrackerError> {
+        Ok(
+            self.states
+                .iter()
+                .map(|(gallery_id, state)| (gallery_id.clone(), state.clone()))
+                .collect()
+        )
+    }
 }
\ No newline at end of file
@@ -17,11 +17,21 @@ pub(super) trait State {
     /// Returns an `Err` if it exists.
     async fn check_gallery_doesnt_exist(&mut self, gallery_id: GalleryId) -> Result<(), StateTrackerError>;
 
+    /// Verify that a gallery exists, returning its current state type.
+    /// 
+    /// Returns an `Err` if it doesn't exist.
+    async fn check_gallery_exists(&mut self, gallery_id: GalleryId) -> Result<GalleryPipelineStateTypes, StateTrackerError>;
+
     /// Get the gallery's state.
     /// 
     /// Returns an `Err` if the gallery doesn't exist, or the state doesn't match the requested type.
     async fn get_gallery_state(&mut self, gallery_id: GalleryId, requested_state_type: GalleryPipelineStateTypes) -> Result<GalleryPipelineStates, StateTrackerError>;
 
+    /// Get a copy of the gallery's state, without marking it as taken.
+    /// 
+    /// Returns an `Err` if the gallery doesn't exist, its state is currently taken, or the state doesn't match the requested type.
+    async fn peek_gallery_state(&mut self, gallery_id: GalleryId, requested_state_type: GalleryPipelineStateTypes) -> Result<GalleryPipelineStates, StateTrackerError>;
+
     /// Update a gallery's state.
     /// 
     /// Returns an `Err` if the gallery doesn't exist.
@@ -31,6 +41,9 @@ pub(super) trait State {
     /// 
     /// Returns an `Err` if the gallery doesn't exist.
     async fn remove_gallery(&mut self, gallery_id: GalleryId) -> Result<(), StateTrackerError>;
+
+    /// Get a copy of all galleries in the state.
+    async fn all_galleries(&mut self) -> Result<Vec<(GalleryId, GalleryPipelineStates)>, StateTrackerError>;
 }
 
 /// The inner state of the state tracker.
@@ -67,6 +80,13 @@ impl State for InnerState {
         }
     }
 
+    async fn check_gallery_exists(&mut self, gallery_id: GalleryId) -> Result<GalleryPipelineStateTypes, StateTrackerError> {
+        match self {
+            InnerState::Internal(state) => state.check_gallery_exists(gallery_id).await,
+            InnerState::Redis(state) => state.check_gallery_exists(gallery_id).await,
+        }
+    }
+
     async fn get_gallery_state(&mut self, gallery_id: GalleryId, requested_state_type: GalleryPipelineStateTypes) -> Result<GalleryPipelineStates, StateTrackerError> {
         match self {
             InnerState::Internal(state) => state.get_gallery_state(gallery_id, requested_state_type).await,
@@ -74,6 +94,13 @@ impl State for InnerState {
         }
     }
 
+    async fn peek_gallery_state(&mut self, gallery_id: GalleryId, requested_state_type: GalleryPipelineStateTypes) -> Result<GalleryPipelineStates, StateTrackerError> {
+        match self {
+            InnerState::Internal(state) => state.peek_gallery_state(gallery_id, requested_state_type).await,
+            InnerState::Redis(state) => state.peek_gallery_state(gallery_id, requested_state_type).await,
+        }
+    }
+
     async fn update_gallery_state(&mut self, gallery_id: GalleryId, updated_state: GalleryPipelineStates) -> Result<(), StateTrackerError> {
         match self {
             InnerState::Internal(state) => state.update_gallery_state(gallery_id, updated_state).await,
@@ -87,4 +114,11 @@ impl State for InnerState {
             InnerState::Redis(state) => state.remove_gallery(gallery_id).await,
         }
     }
+
+    async fn all_galleries(&mut self) -> Result<Vec<(GalleryId, GalleryPipelineStates)>, StateTrackerError> {
+        match self {
+            InnerState::Internal(state) => state.all_galleries().await,
+            InnerState::Redis(state) => state.all_galleries().await,
+        }
+    }
 }
\ No newline at end of file
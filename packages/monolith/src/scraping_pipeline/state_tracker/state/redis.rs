@@ -1,8 +1,19 @@
 use std::error::Error;
-use redis::{aio::MultiplexedConnection, AsyncCommands, Client};
+use redis::{aio::MultiplexedConnection, AsyncCommands, AsyncIter, Client};
 use crate::{config::state_tracker::StateTrackerConfig, galleries::{domain_types::GalleryId, pipeline_states::{GalleryPipelineStateTypes, GalleryPipelineStates}}, messages::message_types::state_tracker::StateTrackerError};
 use super::State;
 
+/// The key of the Redis set holding IDs of galleries whose state is currently taken.
+const TAKEN_GALLERIES_KEY: &str = "state_tracker:taken_galleries";
+
+/// The prefix of the Redis keys holding each gallery's state, so they can be scanned for without touching other keys.
+const GALLERY_KEY_PREFIX: &str = "state_tracker:gallery:";
+
+/// The Redis key holding a gallery's state.
+fn gallery_key(gallery_id: &GalleryId) -> String {
+    format!("{GALLERY_KEY_PREFIX}{gallery_id}")
+}
+
 /// The Redis-backed inner state of the state tracker. 
 /// 
 /// Allows for persisting of pipeline states.
@@ -40,7 +51,7 @@ impl State for RedisState {
     async fn add_gallery(&mut self, gallery_id: GalleryId, gallery_state: GalleryPipelineStates) -> Result<(), StateTrackerError> {
         let gallery_str = serde_json::to_string(&gallery_state)?;
         let res: Option<()> = self.connection
-            .set_nx(gallery_id.as_str(), gallery_str)
+            .set_nx(gallery_key(&gallery_id), gallery_str)
             .await?;
         match res {
             Some(v) => return Ok(()),
@@ -50,7 +61,7 @@ impl State for RedisState {
 
     async fn check_gallery_doesnt_exist(&mut self, gallery_id: GalleryId) -> Result<(), StateTrackerError> {
         let res: bool = self.connection
-            .exists(gallery_id.as_str())
+            .exists(gallery_key(&gallery_id))
             .await?;
         match res {
             true => Err(StateTrackerError::GalleryAlreadyExists),
@@ -58,11 +69,43 @@ impl State for RedisState {
         }
     }
 
+    async fn check_gallery_exists(&mut self, gallery_id: GalleryId) -> Result<GalleryPipelineStateTypes, StateTrackerError> {
+        let gallery_str: Option<String> = self.connection
+            .get(gallery_key(&gallery_id))
+            .await?;
+        let gallery_str = gallery_str.ok_or(StateTrackerError::GalleryDoesntExist)?;
+        let gallery: GalleryPipelineStates = serde_json::from_str(&gallery_str)?;
+        Ok(gallery.state_type())
+    }
+
     async fn get_gallery_state(&mut self, gallery_id: GalleryId, requested_state_type: GalleryPipelineStateTypes) -> Result<GalleryPipelineStates, StateTrackerError> {
         let gallery_str: String = self.connection
-            .get(gallery_id.as_str())
+            .get(gallery_key(&gallery_id))
             .await?;
         let gallery: GalleryPipelineStates = serde_json::from_str(&gallery_str)?;
+        match gallery.matches(&requested_state_type) {
+            true => {
+                let _: () = self.connection
+                    .sadd(TAKEN_GALLERIES_KEY, gallery_id.as_str())
+                    .await?;
+                Ok(gallery)
+            },
+            false => Err(StateTrackerError::GalleryHasWrongState)
+        }
+    }
+
+    async fn peek_gallery_state(&mut self, gallery_id: GalleryId, requested_state_type: GalleryPipelineStateTypes) -> Result<GalleryPipelineStates, StateTrackerError> {
+        let gallery_str: Option<String> = self.connection
+            .get(gallery_key(&gallery_id))
+            .await?;
+        let gallery_str = gallery_str.ok_or(StateTrackerError::GalleryDoesntExist)?;
+        let is_taken: bool = self.connection
+            .sismember(TAKEN_GALLERIES_KEY, gallery_id.as_str())
+            .await?;
+        if is_taken {
+            return Err(StateTrackerError::GalleryStateTaken);
+        }
+        let gallery: GalleryPipelineStates = serde_json::from_str(&gallery_str)?;
         match gallery.matches(&requested_state_type) {
             true => Ok(gallery),
             false => Err(StateTrackerError::GalleryHasWrongState)
@@ -71,13 +114,16 @@ impl State for RedisState {
 
     async fn update_gallery_state(&mut self, gallery_id: GalleryId, updated_state: GalleryPipelineStates) -> Result<(), StateTrackerError> {
         match self.connection
-            .exists(gallery_id.as_str())
+            .exists(gallery_key(&gallery_id))
             .await?
         {
             true => {
                 let gallery_str = serde_json::to_string(&updated_state)?;
                 let _: () = self.connection
-                    .set(gallery_id.as_str(), gallery_str)
+                    .set(gallery_key(&gallery_id), gallery_str)
+                    .await?;
+                let _: () = self.connection
+                    .srem(TAKEN_GALLERIES_KEY, gallery_id.as_str())
                     .await?;
                 Ok(())
             },
@@ -87,16 +133,41 @@ impl State for RedisState {
 
     async fn remove_gallery(&mut self, gallery_id: GalleryId) -> Result<(), StateTrackerError> {
         match self.connection
-            .exists(gallery_id.as_str())
+            .exists(gallery_key(&gallery_id))
             .await?
         {
             true => {
                 let _: () = self.connection
-                    .del(gallery_id.as_str())
+                    .del(gallery_key(&gallery_id))
+                    .await?;
+                let _: () = self.connection
+                    .srem(TAKEN_GALLERIES_KEY, gallery_id.as_str())
                     .await?;
                 Ok(())
             },
             false => Err(StateTrackerError::GalleryDoesntExist)
         }
     }
+
+    async fn all_galleries(&mut self) -> Result<Vec<(GalleryId, GalleryPipelineStates)>, StateTrackerError> {
+        let mut keys = vec![];
+        let mut key_iter: AsyncIter<String> = self.connection
+            .scan_match(format!("{GALLERY_KEY_PREFIX}*"))
+            .await?;
+        while let Some(key) = key_iter.next_item().await {
+            keys.push(key);
+        }
+        drop(key_iter);
+        let mut galleries = vec![];
+        for key in keys {
+            let gallery_str: Option<String> = self.connection
+                .get(&key)
+                .await?;
+            if let (Some(gallery_str), Some(gallery_id)) = (gallery_str, key.strip_prefix(GALLERY_KEY_PREFIX)) {
+                let gallery: GalleryPipelineStates = serde_json::from_str(&gallery_str)?;
+                galleries.push((GalleryId::from(gallery_id.to_string()), gallery));
+            }
+        }
+        Ok(galleries)
+    }
 }
\ No newline at end of file
@@ -0,0 +1,212 @@
+use std::{collections::HashMap, path::{Path, PathBuf}};
+
+use crate::{config::{state_tracker::StateTrackerConfig, SerializationFormat}, galleries::{domain_types::GalleryId, pipeline_states::{GalleryPipelineStateTypes, GalleryPipelineStates}}, messages::message_types::state_tracker::StateTrackerError, utils::serialization};
+use super::{find_snapshot, record_snapshot, StageSnapshots, StateTrackerStore};
+
+/// A file-backed store for the state tracker, in the configured serialization format.
+/// 
+/// Keeps a copy of all states in memory, and rewrites the whole file on every change.
+/// Stage snapshots are kept in a separate file next to it, so the states file's format is unchanged;
+/// it's only rewritten when a snapshot actually changes.
+pub struct FileStore {
+    format: SerializationFormat,
+    path: PathBuf,
+    snapshots_path: PathBuf,
+    states: HashMap<GalleryId, GalleryPipelineStates>,
+    snapshots: StageSnapshots
+}
+
+impl FileStore {
+    /// Initialize the store.
+    pub fn init(config: &StateTrackerConfig) -> Self {
+        let path = PathBuf::from(&config.store_file_path);
+        let format = config.serialization_format;
+        Self {
+            snapshots_path: path.with_extension(format!("snapshots.{}", format.extension())),
+            format,
+            path,
+            states: HashMap::new(),
+            snapshots: HashMap::new()
+        }
+    }
+
+    /// Write all states to the file.
+    async fn persist(&self) -> Result<(), StateTrackerError> {
+        let states_bytes = serialization::serialize(&self.format, &self.states)
+            .map_err(StateTrackerError::Other)?;
+        Self::write_file(&self.path, states_bytes).await
+    }
+
+    /// Write all stage snapshots to their file.
+    async fn persist_snapshots(&self) -> Result<(), StateTrackerError> {
+        let snapshots_bytes = serialization::serialize(&self.format, &self.snapshots)
+            .map_err(StateTrackerError::Other)?;
+        Self::write_file(&self.snapshots_path, snapshots_bytes).await
+    }
+
+    /// Write the contents to a file.
+    /// 
+    /// Writes to a temporary file first, then renames it over the actual file, so a crash mid-write doesn't corrupt it.
+    async fn write_file(path: &Path, contents: Vec<u8>) -> Result<(), StateTrackerError> {
+        let temp_path = path.with_extension("tmp");
+        tokio::fs::write(&temp_path, contents).await
+            .map_err(|err| StateTrackerError::Other(format!("Failed to write state tracker store file {path:?}: {err}")))?;
+        tokio::fs::rename(&temp_path, path).await
+            .map_err(|err| StateTrackerError::Other(format!("Failed to replace state tracker store file {path:?}: {err}")))?;
+        Ok(())
+    }
+
+    /// Load the stage snapshots from their file, if it exists.
+    async fn load_snapshots(&mut self) -> Result<(), StateTrackerError> {
+        match tokio::fs::read(&self.snapshots_path).await {
+            Ok(snapshots_bytes) => {
+                self.snapshots = serialization::deserialize(&self.format, &snapshots_bytes)
+                    .map_err(StateTrackerError::Other)?;
+                Ok(())
+            },
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(()),
+            Err(err) => Err(StateTrackerError::Other(format!("Failed to read state tracker snapshots file: {err}")))
+        }
+    }
+}
+
+impl StateTrackerStore for FileStore {
+    async fn load_all(&mut self) -> Result<Vec<(GalleryId, GalleryPipelineStates)>, StateTrackerError> {
+        self.load_snapshots().await?;
+        let states_bytes = match tokio::fs::read(&self.path).await {
+            Ok(states_bytes) => states_bytes,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
+                tracing::info!("No state tracker store file found at {:?}; starting with an empty store", self.path);
+                return Ok(vec![]);
+            },
+            Err(err) => return Err(StateTrackerError::Other(format!("Failed to read state tracker store file: {err}")))
+        };
+        self.states = serialization::deserialize(&self.format, &states_bytes)
+            .map_err(StateTrackerError::Other)?;
+        Ok(
+            self.states
+                .iter()
+                .map(|(gallery_id, gallery_state)| (gallery_id.clone(), gallery_state.clone()))
+                .collect()
+        )
+    }
+
+    async fn upsert(&mut self, gallery_state: GalleryPipelineStates) -> Result<(), StateTrackerError> {
+        let snapshot_changed = record_snapshot(&mut self.snapshots, &gallery_state);
+        self.states.insert(gallery_state.gallery_id().clone(), gallery_state);
+        self.persist().await?;
+        if snapshot_changed {
+            self.persist_snapshots().await?;
+        }
+        Ok(())
+    }
+
+    async fn remove(&mut self, gallery_id: GalleryId) -> Result<(), StateTrackerError> {
+        if self.states.remove(&gallery_id).is_some() {
+            return self.persist().await;
+        }
+        Ok(())
+    }
+
+    async fn remove_snapshots(&mut self, gallery_id: &GalleryId) -> Result<(), StateTrackerError> {
+        if self.snapshots.remove(gallery_id).is_some() {
+            return self.persist_snapshots().await;
+        }
+        Ok(())
+    }
+
+    async fn get_snapshot(&mut self, gallery_id: &GalleryId, stage: &GalleryPipelineStateTypes) -> Result<Option<GalleryPipelineStates>, StateTrackerError> {
+        Ok(find_snapshot(&self.snapshots, gallery_id, stage))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{config::state_tracker::{AuditLogKind, StateTrackerStoreKind}, test_support::{final_state, scheduler_state}};
+    use super::*;
+
+    /// A JSON store in the temp dir, unique to the test.
+    fn store(name: &str) -> FileStore {
+        store_in_format(name, SerializationFormat::Json)
+    }
+
+    /// A store in the given format in the temp dir, unique to the test.
+    fn store_in_format(name: &str, format: SerializationFormat) -> FileStore {
+        let path = std::env::temp_dir().join(format!("state_tracker_{name}_{}.{}", std::process::id(), format.extension()));
+        FileStore::init(&StateTrackerConfig {
+            use_redis: false,
+            redis_uri: String::new(),
+            store_kind: StateTrackerStoreKind::File,
+            store_file_path: path.to_string_lossy().into_owned(),
+            stage_timeouts_secs: HashMap::new(),
+            watchdog_interval_secs: 0,
+            max_stalled_galleries: 0,
+            final_retention_secs: 0,
+            compaction_interval_secs: 0,
+            send_timeout_ms: 0,
+            serialization_format: format,
+            audit_log_kind: AuditLogKind::None,
+            audit_log_path: String::new()
+        })
+    }
+
+    fn cleanup(store: &FileStore) {
+        let _ = std::fs::remove_file(&store.path);
+        let _ = std::fs::remove_file(&store.snapshots_path);
+    }
+
+    #[tokio::test]
+    async fn states_round_trip_through_the_file_in_each_format() {
+        for format in [SerializationFormat::Json, SerializationFormat::MessagePack] {
+            let mut store = store_in_format("round_trip", format);
+            let states = [
+                GalleryPipelineStates::Initialization(scheduler_state("scheduled")),
+                GalleryPipelineStates::Final(final_state("final", 1000, &["1", "2"]))
+            ];
+            for state in &states {
+                store.upsert(state.clone()).await.unwrap();
+            }
+            let mut reloaded_store = store_in_format("round_trip", format);
+            let reloaded: HashMap<_, _> = reloaded_store.load_all().await.unwrap().into_iter().collect();
+            cleanup(&store);
+            assert_eq!(reloaded.len(), states.len(), "{format:?}");
+            for state in &states {
+                assert_eq!(
+                    serde_json::to_value(&reloaded[state.gallery_id()]).unwrap(),
+                    serde_json::to_value(state).unwrap(),
+                    "{format:?}"
+                );
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn snapshots_are_only_rewritten_when_they_change() {
+        let mut store = store("unchanged");
+        let state = GalleryPipelineStates::Initialization(scheduler_state("gallery"));
+        store.upsert(state.clone()).await.unwrap();
+        assert!(store.snapshots_path.exists());
+        std::fs::remove_file(&store.snapshots_path).unwrap();
+        store.upsert(state).await.unwrap();
+        let rewritten = store.snapshots_path.exists();
+        store.upsert(GalleryPipelineStates::SearchScraping(scheduler_state("gallery").to_next_stage())).await.unwrap();
+        let written_on_change = store.snapshots_path.exists();
+        cleanup(&store);
+        assert!(!rewritten);
+        assert!(written_on_change);
+    }
+
+    #[tokio::test]
+    async fn removing_a_gallerys_snapshots_drops_them_from_the_file() {
+        let mut store = store("removed");
+        let gallery_id = GalleryId::from("gallery".to_string());
+        store.upsert(GalleryPipelineStates::Initialization(scheduler_state("gallery"))).await.unwrap();
+        store.remove(gallery_id.clone()).await.unwrap();
+        let kept_after_removal = store.get_snapshot(&gallery_id, &GalleryPipelineStateTypes::Initialization).await.unwrap().is_some();
+        store.remove_snapshots(&gallery_id).await.unwrap();
+        store.load_snapshots().await.unwrap();
+        cleanup(&store);
+        assert!(kept_after_removal);
+        assert!(store.snapshots.is_empty());
+    }
+}
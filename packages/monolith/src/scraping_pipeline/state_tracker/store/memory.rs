@@ -0,0 +1,53 @@
+use std::collections::HashMap;
+
+use crate::{galleries::{domain_types::GalleryId, pipeline_states::{GalleryPipelineStateTypes, GalleryPipelineStates}}, messages::message_types::state_tracker::StateTrackerError};
+use super::{find_snapshot, record_snapshot, StageSnapshots, StateTrackerStore};
+
+/// A hashmap-backed store for the state tracker.
+/// 
+/// Does not persist states across restarts.
+pub struct MemoryStore {
+    states: HashMap<GalleryId, GalleryPipelineStates>,
+    snapshots: StageSnapshots
+}
+
+impl MemoryStore {
+    /// Initialize the store.
+    pub fn init() -> Self {
+        Self {
+            states: HashMap::new(),
+            snapshots: HashMap::new()
+        }
+    }
+}
+
+impl StateTrackerStore for MemoryStore {
+    async fn load_all(&mut self) -> Result<Vec<(GalleryId, GalleryPipelineStates)>, StateTrackerError> {
+        Ok(
+            self.states
+                .iter()
+                .map(|(gallery_id, gallery_state)| (gallery_id.clone(), gallery_state.clone()))
+                .collect()
+        )
+    }
+
+    async fn upsert(&mut self, gallery_state: GalleryPipelineStates) -> Result<(), StateTrackerError> {
+        record_snapshot(&mut self.snapshots, &gallery_state);
+        self.states.insert(gallery_state.gallery_id().clone(), gallery_state);
+        Ok(())
+    }
+
+    async fn remove(&mut self, gallery_id: GalleryId) -> Result<(), StateTrackerError> {
+        self.states.remove(&gallery_id);
+        Ok(())
+    }
+
+    async fn remove_snapshots(&mut self, gallery_id: &GalleryId) -> Result<(), StateTrackerError> {
+        self.snapshots.remove(gallery_id);
+        Ok(())
+    }
+
+    async fn get_snapshot(&mut self, gallery_id: &GalleryId, stage: &GalleryPipelineStateTypes) -> Result<Option<GalleryPipelineStates>, StateTrackerError> {
+        Ok(find_snapshot(&self.snapshots, gallery_id, stage))
+    }
+}
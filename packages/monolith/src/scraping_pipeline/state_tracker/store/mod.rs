@@ -0,0 +1,107 @@
+use std::collections::HashMap;
+use file::FileStore;
+use memory::MemoryStore;
+use crate::{config::state_tracker::{StateTrackerConfig, StateTrackerStoreKind}, galleries::{domain_types::GalleryId, pipeline_states::{GalleryPipelineStateTypes, GalleryPipelineStates}}, messages::message_types::state_tracker::StateTrackerError};
+
+mod file;
+mod memory;
+
+/// The interface for a backing store of the state tracker.
+/// 
+/// The state tracker writes through to this on every change, and replays it on startup.
+/// 
+/// The latest state a gallery had in each stage is also kept as a snapshot, even after the gallery is removed,
+/// so a gallery can be replayed from any stage it previously reached; these are only dropped once the gallery is deleted.
+pub(super) trait StateTrackerStore {
+    /// Load all gallery states from the store.
+    async fn load_all(&mut self) -> Result<Vec<(GalleryId, GalleryPipelineStates)>, StateTrackerError>;
+
+    /// Insert or overwrite a gallery's state in the store, also overwriting its snapshot for the state's stage.
+    async fn upsert(&mut self, gallery_state: GalleryPipelineStates) -> Result<(), StateTrackerError>;
+
+    /// Remove a gallery's state from the store, if it exists. Its snapshots are kept.
+    async fn remove(&mut self, gallery_id: GalleryId) -> Result<(), StateTrackerError>;
+
+    /// Remove all of a gallery's snapshots, ie once it's deleted.
+    async fn remove_snapshots(&mut self, gallery_id: &GalleryId) -> Result<(), StateTrackerError>;
+
+    /// Get the latest snapshot of a gallery's state in a stage, if it ever reached it.
+    async fn get_snapshot(&mut self, gallery_id: &GalleryId, stage: &GalleryPipelineStateTypes) -> Result<Option<GalleryPipelineStates>, StateTrackerError>;
+}
+
+/// The latest snapshot of each gallery's state in each stage it reached.
+pub(super) type StageSnapshots = HashMap<GalleryId, HashMap<GalleryPipelineStateTypes, GalleryPipelineStates>>;
+
+/// Overwrite a gallery's snapshot for its state's stage.
+/// 
+/// Returns whether the snapshot changed, ie whether the snapshots need to be persisted again.
+fn record_snapshot(snapshots: &mut StageSnapshots, gallery_state: &GalleryPipelineStates) -> bool {
+    let previous = snapshots
+        .entry(gallery_state.gallery_id().clone())
+        .or_default()
+        .insert(gallery_state.state_type(), gallery_state.clone());
+    match previous {
+        Some(previous) => serde_json::to_value(&previous).ok() != serde_json::to_value(gallery_state).ok(),
+        None => true
+    }
+}
+
+/// Get a copy of a gallery's snapshot for a stage.
+fn find_snapshot(snapshots: &StageSnapshots, gallery_id: &GalleryId, stage: &GalleryPipelineStateTypes) -> Option<GalleryPipelineStates> {
+    snapshots
+        .get(gallery_id)
+        .and_then(|gallery_snapshots| gallery_snapshots.get(stage))
+        .cloned()
+}
+
+/// The backing store of the state tracker.
+pub(super) enum InnerStore {
+    Memory(MemoryStore),
+    File(FileStore)
+}
+
+impl InnerStore {
+    pub(super) async fn init(config: &StateTrackerConfig) -> Self {
+        match config.store_kind {
+            StateTrackerStoreKind::Memory => Self::Memory(MemoryStore::init()),
+            StateTrackerStoreKind::File => Self::File(FileStore::init(config))
+        }
+    }
+}
+
+impl StateTrackerStore for InnerStore {
+    async fn load_all(&mut self) -> Result<Vec<(GalleryId, GalleryPipelineStates)>, StateTrackerError> {
+        match self {
+            InnerStore::Memory(store) => store.load_all().await,
+            InnerStore::File(store) => store.load_all().await,
+        }
+    }
+
+    async fn upsert(&mut self, gallery_state: GalleryPipelineStates) -> Result<(), StateTrackerError> {
+        match self {
+            InnerStore::Memory(store) => store.upsert(gallery_state).await,
+            InnerStore::File(store) => store.upsert(gallery_state).await,
+        }
+    }
+
+    async fn remove(&mut self, gallery_id: GalleryId) -> Result<(), StateTrackerError> {
+        match self {
+            InnerStore::Memory(store) => store.remove(gallery_id).await,
+            InnerStore::File(store) => store.remove(gallery_id).await,
+        }
+    }
+
+    async fn remove_snapshots(&mut self, gallery_id: &GalleryId) -> Result<(), StateTrackerError> {
+        match self {
+            InnerStore::Memory(store) => store.remove_snapshots(gallery_id).await,
+            InnerStore::File(store) => store.remove_snapshots(gallery_id).await,
+        }
+    }
+
+    async fn get_snapshot(&mut self, gallery_id: &GalleryId, stage: &GalleryPipelineStateTypes) -> Result<Option<GalleryPipelineStates>, StateTrackerError> {
+        match self {
+            InnerStore::Memory(store) => store.get_snapshot(gallery_id, stage).await,
+            InnerStore::File(store) => store.get_snapshot(gallery_id, stage).await,
+        }
+    }
+}
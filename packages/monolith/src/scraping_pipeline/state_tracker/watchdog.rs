@@ -0,0 +1,152 @@
+//! Contains the watchdog for galleries which stall mid-pipeline.
+use std::{collections::{HashMap, VecDeque}, time::{Duration, Instant}};
+use chrono::Utc;
+use crate::{galleries::{domain_types::GalleryId, pipeline_states::{GalleryPipelineStateTypes, GalleryPipelineStates}}, messages::message_types::state_tracker::StalledGallery};
+
+/// Tracks when each gallery last transitioned, to find those which haven't advanced within their stage's timeout.
+///
+/// Stalled galleries are kept after being marked, until they're added to the state again;
+/// at most `max_stalled_galleries` are kept, dropping the earliest marked past that.
+pub(super) struct StallWatchdog {
+    stage_timeouts: HashMap<GalleryPipelineStateTypes, Duration>,
+    transition_times: HashMap<GalleryId, Instant>,
+    stalled_galleries: HashMap<GalleryId, StalledGallery>,
+    /// The stalled galleries' IDs, earliest marked first.
+    stalled_order: VecDeque<GalleryId>,
+    max_stalled_galleries: usize
+}
+
+impl StallWatchdog {
+    /// Instantiate the watchdog, with the timeout (in seconds) of each stage and the max number of stalled galleries kept.
+    pub fn new(stage_timeouts_secs: &HashMap<GalleryPipelineStateTypes, u64>, max_stalled_galleries: usize) -> Self {
+        let stage_timeouts = stage_timeouts_secs
+            .iter()
+            .map(|(stage, secs)| (stage.clone(), Duration::from_secs(*secs)))
+            .collect();
+        Self {
+            stage_timeouts,
+            transition_times: HashMap::new(),
+            stalled_galleries: HashMap::new(),
+            stalled_order: VecDeque::new(),
+            max_stalled_galleries
+        }
+    }
+
+    /// Record that a gallery was added to the state.
+    ///
+    /// This clears any previous stall marked for the gallery.
+    pub fn record_added(&mut self, gallery_id: GalleryId) {
+        self.unmark_stalled(&gallery_id);
+        self.transition_times.insert(gallery_id, Instant::now());
+    }
+
+    /// Record that a gallery transitioned to a new state.
+    pub fn record_transition(&mut self, gallery_id: GalleryId) {
+        self.transition_times.insert(gallery_id, Instant::now());
+    }
+
+    /// Stop tracking a gallery which was removed from the state.
+    pub fn forget(&mut self, gallery_id: &GalleryId) {
+        self.transition_times.remove(gallery_id);
+    }
+
+    /// Returns the galleries which have been in their current stage for longer than its timeout,
+    /// along with how long they've been in it.
+    pub fn find_stalled(&self, galleries: &[(GalleryId, GalleryPipelineStates)]) -> Vec<(GalleryId, Duration)> {
+        galleries
+            .iter()
+            .filter_map(|(gallery_id, state)| {
+                let timeout = self.stage_timeouts.get(&state.state_type())?;
+                let elapsed = self.transition_times.get(gallery_id)?.elapsed();
+                (elapsed > *timeout).then(|| (gallery_id.clone(), elapsed))
+            })
+            .collect()
+    }
+
+    /// Mark a gallery as stalled, keeping its last state for inspection.
+    /// 
+    /// If this is over the max stalled galleries, the earliest marked are dropped.
+    pub fn mark_stalled(&mut self, last_state: GalleryPipelineStates, stalled_for: Duration) {
+        let gallery_id = last_state.gallery_id().clone();
+        self.transition_times.remove(&gallery_id);
+        self.unmark_stalled(&gallery_id);
+        let stalled_gallery = StalledGallery {
+            gallery_id: gallery_id.clone(),
+            stage: last_state.state_type(),
+            stalled_for_secs: stalled_for.as_secs(),
+            marked_at: Utc::now().into(),
+            last_state
+        };
+        self.stalled_galleries.insert(gallery_id.clone(), stalled_gallery);
+        self.stalled_order.push_back(gallery_id);
+        while self.stalled_order.len() > self.max_stalled_galleries {
+            if let Some(dropped_id) = self.stalled_order.pop_front() {
+                self.stalled_galleries.remove(&dropped_id);
+                tracing::debug!("Dropped stalled gallery {dropped_id}, as over {} stalled galleries are marked", self.max_stalled_galleries);
+            }
+        }
+    }
+
+    /// Stop keeping a gallery as stalled, if it's marked.
+    fn unmark_stalled(&mut self, gallery_id: &GalleryId) {
+        if self.stalled_galleries.remove(gallery_id).is_some() {
+            self.stalled_order.retain(|stalled_id| stalled_id != gallery_id);
+        }
+    }
+
+    /// Get all stalled galleries, sorted by ID.
+    pub fn stalled_galleries(&self) -> Vec<StalledGallery> {
+        let mut stalled_galleries: Vec<_> = self.stalled_galleries
+            .values()
+            .cloned()
+            .collect();
+        stalled_galleries.sort_by(|a, b| a.gallery_id.as_str().cmp(b.gallery_id.as_str()));
+        stalled_galleries
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::test_support::scheduler_state;
+    use super::*;
+
+    fn stalled_state(gallery_id: &str) -> GalleryPipelineStates {
+        GalleryPipelineStates::Initialization(scheduler_state(gallery_id))
+    }
+
+    fn stalled_ids(watchdog: &StallWatchdog) -> Vec<String> {
+        watchdog
+            .stalled_galleries()
+            .into_iter()
+            .map(|stalled_gallery| stalled_gallery.gallery_id.as_str().to_string())
+            .collect()
+    }
+
+    #[test]
+    fn drops_the_earliest_marked_past_the_max() {
+        let mut watchdog = StallWatchdog::new(&HashMap::new(), 2);
+        for gallery_id in ["c", "a", "b"] {
+            watchdog.mark_stalled(stalled_state(gallery_id), Duration::from_secs(60));
+        }
+        assert_eq!(stalled_ids(&watchdog), vec!["a", "b"]);
+    }
+
+    #[test]
+    fn remarking_a_gallery_makes_it_the_latest() {
+        let mut watchdog = StallWatchdog::new(&HashMap::new(), 2);
+        for gallery_id in ["a", "b", "a", "c"] {
+            watchdog.mark_stalled(stalled_state(gallery_id), Duration::from_secs(60));
+        }
+        assert_eq!(stalled_ids(&watchdog), vec!["a", "c"]);
+    }
+
+    #[test]
+    fn re_adding_a_gallery_unmarks_it() {
+        let mut watchdog = StallWatchdog::new(&HashMap::new(), 2);
+        watchdog.mark_stalled(stalled_state("a"), Duration::from_secs(60));
+        watchdog.mark_stalled(stalled_state("b"), Duration::from_secs(60));
+        watchdog.record_added(GalleryId::from("a".to_string()));
+        watchdog.mark_stalled(stalled_state("c"), Duration::from_secs(60));
+        assert_eq!(stalled_ids(&watchdog), vec!["b", "c"]);
+    }
+}
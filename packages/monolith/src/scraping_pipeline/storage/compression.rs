@@ -0,0 +1,130 @@
+//! Contains the (optional) serialization and compression of records held by the storage module.
+use std::{borrow::Cow, io::{Read, Write}};
+use flate2::{read::GzDecoder, write::GzEncoder, Compression};
+use serde::{de::DeserializeOwned, Serialize};
+use crate::{config::{storage::{StorageCompression, StorageConfig}, SerializationFormat}, utils::serialization};
+
+/// A stored record; kept as is if compression is disabled and the format is JSON, otherwise serialized in the format and compressed.
+/// 
+/// Encoded records keep the format and algorithm they were encoded with, so they can always be decoded.
+#[derive(Debug)]
+pub(super) enum StoredRecord<T> {
+    Plain(T),
+    Encoded { format: SerializationFormat, compression: StorageCompression, bytes: Vec<u8> }
+}
+
+impl<T: Serialize + DeserializeOwned + Clone> StoredRecord<T> {
+    /// Get the record, decompressing it if needed.
+    /// 
+    /// Returns an `Err` if it couldn't be decompressed or deserialized.
+    pub fn decode(&self) -> Result<Cow<'_, T>, String> {
+        match self {
+            StoredRecord::Plain(record) => Ok(Cow::Borrowed(record)),
+            StoredRecord::Encoded { format, compression, bytes } => {
+                let serialized = decompress(compression, bytes)?;
+                serialization::deserialize(format, &serialized).map(Cow::Owned)
+            }
+        }
+    }
+}
+
+/// Serializes and compresses records with the configured format, algorithm and level.
+#[derive(Clone, Debug)]
+pub(super) struct RecordCodec {
+    format: SerializationFormat,
+    compression: StorageCompression,
+    level: i32
+}
+
+impl RecordCodec {
+    /// Instantiate the codec.
+    pub fn new(config: &StorageConfig) -> Self {
+        Self {
+            format: config.serialization_format,
+            compression: config.compression.clone(),
+            level: config.compression_level
+        }
+    }
+
+    /// Store a record, serializing and compressing it unless compression is disabled and the format is JSON.
+    /// 
+    /// Returns an `Err` if it couldn't be serialized or compressed.
+    pub fn encode<T: Serialize>(&self, record: T) -> Result<StoredRecord<T>, String> {
+        if let (StorageCompression::None, SerializationFormat::Json) = (&self.compression, self.format) {
+            return Ok(StoredRecord::Plain(record));
+        }
+        let serialized = serialization::serialize(&self.format, &record)?;
+        let bytes = compress(&self.compression, self.level, &serialized)?;
+        Ok(StoredRecord::Encoded { 
+            format: self.format,
+            compression: self.compression.clone(), 
+            bytes 
+        })
+    }
+}
+
+/// Compress bytes with the algorithm at the given level.
+fn compress(compression: &StorageCompression, level: i32, bytes: &[u8]) -> Result<Vec<u8>, String> {
+    match compression {
+        StorageCompression::None => Ok(bytes.to_vec()),
+        StorageCompression::Gzip => {
+            let mut encoder = GzEncoder::new(Vec::new(), Compression::new(level.clamp(0, 9) as u32));
+            encoder
+                .write_all(bytes)
+                .map_err(|err| format!("Failed to gzip record: {err}"))?;
+            encoder
+                .finish()
+                .map_err(|err| format!("Failed to gzip record: {err}"))
+        },
+        StorageCompression::Zstd => zstd::encode_all(bytes, level)
+            .map_err(|err| format!("Failed to zstd-compress record: {err}"))
+    }
+}
+
+/// Decompress bytes which were compressed with the algorithm.
+fn decompress(compression: &StorageCompression, bytes: &[u8]) -> Result<Vec<u8>, String> {
+    match compression {
+        StorageCompression::None => Ok(bytes.to_vec()),
+        StorageCompression::Gzip => {
+            let mut decompressed = Vec::new();
+            GzDecoder::new(bytes)
+                .read_to_end(&mut decompressed)
+                .map_err(|err| format!("Failed to gunzip record: {err}"))?;
+            Ok(decompressed)
+        },
+        StorageCompression::Zstd => zstd::decode_all(bytes)
+            .map_err(|err| format!("Failed to zstd-decompress record: {err}"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{galleries::items::item_data::MarketplaceItemData, test_support::item_data};
+    use super::*;
+
+    fn codec(compression: StorageCompression, format: SerializationFormat) -> RecordCodec {
+        RecordCodec { format, compression, level: 3 }
+    }
+
+    #[test]
+    fn a_stored_item_is_retrieved_byte_for_byte() {
+        let item = item_data("item", 1234.5, 1000);
+        let item_bytes = serde_json::to_vec(&item).unwrap();
+        for compression in [StorageCompression::None, StorageCompression::Gzip, StorageCompression::Zstd] {
+            for format in [SerializationFormat::Json, SerializationFormat::MessagePack] {
+                let record = codec(compression.clone(), format).encode(item.clone()).unwrap();
+                let decoded: Cow<'_, MarketplaceItemData> = record.decode().unwrap();
+                assert_eq!(serde_json::to_vec(decoded.as_ref()).unwrap(), item_bytes, "{compression:?}, {format:?}");
+            }
+        }
+    }
+
+    #[test]
+    fn records_are_only_encoded_when_compressed_or_not_json() {
+        let item = item_data("item", 1234.5, 1000);
+        let plain = codec(StorageCompression::None, SerializationFormat::Json).encode(item.clone()).unwrap();
+        assert!(matches!(plain, StoredRecord::Plain(_)));
+        let compressed = codec(StorageCompression::Zstd, SerializationFormat::Json).encode(item).unwrap();
+        assert!(matches!(compressed, StoredRecord::Encoded { .. }));
+    }
+}
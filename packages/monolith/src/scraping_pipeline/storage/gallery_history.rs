@@ -0,0 +1,86 @@
+//! Contains the history of galleries' runs, for aggregating stats across their recent scrapes.
+use std::collections::{HashMap, VecDeque};
+use crate::{galleries::{domain_types::{GalleryId, Marketplace, UnixUtcDateTime}, pipeline_states::GalleryFinalState}, messages::message_types::storage::{GalleryStats, MarketplacePriceStats}};
+
+/// A snapshot of a gallery's run: the comparable (ie normalized, if available) prices of its relevant items under each marketplace.
+struct RunSnapshot {
+    stored_at: UnixUtcDateTime,
+    prices: HashMap<Marketplace, Vec<f32>>
+}
+
+/// Keeps a snapshot of each gallery's latest runs, up to `max_runs` per gallery; older runs are dropped.
+pub(super) struct GalleryHistory {
+    runs: HashMap<GalleryId, VecDeque<RunSnapshot>>,
+    max_runs: usize
+}
+
+impl GalleryHistory {
+    /// Instantiate with no history.
+    pub fn new(max_runs: usize) -> Self {
+        Self {
+            runs: HashMap::new(),
+            max_runs
+        }
+    }
+
+    /// Record a snapshot of a gallery's run.
+    /// 
+    /// Only relevant items are recorded (ie embedded items, and relevant items which failed or skipped embedding),
+    /// as irrelevant items would skew the gallery's prices.
+    pub fn record(&mut self, gallery: &GalleryFinalState) {
+        if self.max_runs == 0 {
+            return;
+        }
+        let prices = gallery.items
+            .iter()
+            .map(|(marketplace, items)| {
+                let prices = items.embedded_items
+                    .iter()
+                    .map(|item| &item.item)
+                    .chain(items.error_embedded_items.iter().map(|item| &item.item.item))
+                    .chain(items.skipped_embedding_items.iter().map(|item| &item.item.item))
+                    .map(|item| item.comparable_price())
+                    .collect();
+                (marketplace.clone(), prices)
+            })
+            .collect();
+        let runs = self.runs
+            .entry(gallery.gallery_id.clone())
+            .or_default();
+        runs.push_back(RunSnapshot { stored_at: UnixUtcDateTime::now(), prices });
+        while runs.len() > self.max_runs {
+            runs.pop_front();
+        }
+    }
+
+    /// Aggregate the gallery's prices under each marketplace over its latest `window` runs.
+    /// 
+    /// Marketplaces without any items in the window are left out.
+    /// Returns `None` if the gallery has no recorded runs.
+    pub fn stats(&self, gallery_id: &GalleryId, window: usize) -> Option<GalleryStats> {
+        let runs = self.runs.get(gallery_id).filter(|runs| !runs.is_empty())?;
+        let window_runs: Vec<_> = runs
+            .iter()
+            .rev()
+            .take(window)
+            .collect();
+        let mut marketplaces: HashMap<Marketplace, MarketplacePriceStats> = HashMap::new();
+        for run in &window_runs {
+            for (marketplace, prices) in &run.prices {
+                for &price in prices {
+                    marketplaces
+                        .entry(marketplace.clone())
+                        .and_modify(|stats| stats.add(price))
+                        .or_insert_with(|| MarketplacePriceStats::new(price));
+                }
+            }
+        }
+        Some(GalleryStats {
+            gallery_id: gallery_id.clone(),
+            num_runs: window_runs.len(),
+            earliest_run_at: window_runs.last().map(|run| run.stored_at.clone())?,
+            latest_run_at: window_runs.first().map(|run| run.stored_at.clone())?,
+            marketplaces
+        })
+    }
+}
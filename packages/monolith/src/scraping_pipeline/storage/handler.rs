@@ -1,24 +1,697 @@
-use crate::{galleries::{domain_types::GalleryId, pipeline_states::GalleryFinalState}, messages::{message_types::storage::StorageError, StateTrackerSender}};
+use std::{borrow::Cow, collections::{HashMap, HashSet}};
+
+use crate::{config::storage::StorageConfig, galleries::{domain_types::{GalleryId, ItemId, Marketplace, ModelTokenUsage, UnixUtcDateTime}, items::{item_data::MarketplaceItemData, pipeline_items::{MarketplaceEmbeddedAndAnalyzedItems, SkippedEmbeddingMarketplaceItem}}, pipeline_states::{GalleryFinalState, GalleryItemAnalysisState, GalleryPipelineStateTypes, GalleryPipelineStates, GallerySchedulerState}}, messages::{message_types::{scraper_scheduler::{SchedulerMessage, UpdateGalleryMessage}, storage::{AnalysisRetryEntry, AnalysisRetryOutcome, CachedItemAnalysis, GalleryMergeSummary, GalleryStats, GalleryStatsRequest, ItemsPage, ItemsPageRequest, MergeGalleriesRequest, ScrapeDiff, SimilarItem, SimilarItemsRequest, StorageError}}, ScraperSchedulerSender, StateTrackerSender}};
+use super::{compression::{RecordCodec, StoredRecord}, gallery_history::GalleryHistory, retry::StorageRetry, retry_queue::AnalysisRetryQueue, scheduler_states::SchedulerStateStore, scraped_items::ScrapedItemsStore, scrape_diff::{diff_scraped_items, ScrapedItemsSnapshot}};
 
 pub(super) struct Handler {
-    state_tracker_sender: StateTrackerSender
+    state_tracker_sender: StateTrackerSender,
+    scheduler_sender: ScraperSchedulerSender,
+    /// Stored galleries, compressed if configured.
+    galleries: HashMap<GalleryId, StoredRecord<GalleryFinalState>>,
+    codec: RecordCodec,
+    /// Items scraped for galleries still in the pipeline; a gallery's are dropped once it's stored or cancelled.
+    scraped_items: ScrapedItemsStore,
+    /// Items' cached analyses, keyed by their ID and the hash of the evaluation criteria they were analyzed under.
+    analysis_cache: HashMap<(ItemId, u64), CachedItemAnalysis>,
+    analysis_retry_queue: AnalysisRetryQueue,
+    /// Each gallery's items from its latest scrape, to diff its next scrape against.
+    scraped_items_snapshots: HashMap<GalleryId, ScrapedItemsSnapshot>,
+    /// Each gallery's diff of its latest scrape against its previous one.
+    scrape_diffs: HashMap<GalleryId, ScrapeDiff>,
+    diff_price_change_threshold: f32,
+    scheduler_states: SchedulerStateStore,
+    /// Snapshots of each gallery's latest runs, for aggregating its stats.
+    history: GalleryHistory,
+    retry: StorageRetry
 }
 
 impl Handler {
     /// Initialize the handler.
-    pub fn new(state_tracker_sender: StateTrackerSender) -> Self {
+    pub fn new(config: &StorageConfig, state_tracker_sender: StateTrackerSender, scheduler_sender: ScraperSchedulerSender) -> Self {
         Self {
-            state_tracker_sender
+            state_tracker_sender,
+            scheduler_sender,
+            galleries: HashMap::new(),
+            codec: RecordCodec::new(config),
+            scraped_items: ScrapedItemsStore::load(&config.scraped_items_path),
+            analysis_cache: HashMap::new(),
+            analysis_retry_queue: AnalysisRetryQueue::load(&config.analysis_retry_queue_path),
+            scraped_items_snapshots: HashMap::new(),
+            scrape_diffs: HashMap::new(),
+            diff_price_change_threshold: config.diff_price_change_threshold,
+            scheduler_states: SchedulerStateStore::load(&config.scheduler_states_path),
+            history: GalleryHistory::new(config.history_max_runs),
+            retry: StorageRetry::new(config)
         }
     }
 
     /// Store a gallery in state.
+    /// 
+    /// The run is recorded in the gallery's history either way, as it completed even if the gallery is already stored.
+    /// Once it's stored, its marketplaces' updated datetimes are recorded in the gallery's scheduler state (see `record_scraped_datetimes`).
     pub async fn store_gallery_in_state(&mut self, gallery_id: GalleryId) -> Result<(), StorageError> {
-        todo!()
+        let gallery = self.fetch_gallery_state(gallery_id.clone()).await?;
+        self.history.record(&gallery);
+        let marketplace_updated_datetimes = gallery.marketplace_updated_datetimes.clone();
+        self.store_gallery(gallery).await?;
+        self.record_scraped_datetimes(&gallery_id, marketplace_updated_datetimes).await;
+        self.state_tracker_sender
+            .remove_gallery(gallery_id.clone())
+            .await
+            .map_err(|err| StorageError::Other {
+                gallery_id: gallery_id.clone(),
+                message: format!("Could not receive response from state tracker: {err}")
+            })?
+            .map_err(|err| StorageError::StateErr {
+                gallery_id,
+                err
+            })
     }
 
-    /// Store a new gallery.
+    /// Store a gallery's run, replacing its previously stored run (if any).
+    /// 
+    /// Returns an `Err` if a run which completed later is already stored, so a stale run can't overwrite it.
     pub async fn store_gallery(&mut self, gallery: GalleryFinalState) -> Result<(), StorageError> {
-        todo!()
+        if self.stored_completed_at(&gallery.gallery_id)?.is_some_and(|completed_at| completed_at > gallery.completed_at) {
+            return Err(StorageError::GalleryAlreadyExists { gallery_id: gallery.gallery_id });
+        }
+        let gallery_id = gallery.gallery_id.clone();
+        self.put_gallery(gallery)?;
+        self.clear_scraped_items(gallery_id).await
+    }
+
+    /// Store a gallery's run, unless it or a run which completed after it is already stored.
+    /// 
+    /// Only the stored run's completion time is compared, so an older stored run is replaced rather than mistaken for this one.
+    pub async fn ensure_gallery_stored(&mut self, gallery: GalleryFinalState) -> Result<(), StorageError> {
+        if self.stored_completed_at(&gallery.gallery_id)?.is_some_and(|completed_at| completed_at >= gallery.completed_at) {
+            return Ok(());
+        }
+        self.store_gallery(gallery).await
+    }
+
+    /// Get a page of a stored gallery's embedded items under a marketplace.
+    ///
+    /// Returns an `Err` if the gallery isn't stored. A marketplace without items returns an empty page.
+    pub fn get_items_paginated(&self, request: ItemsPageRequest) -> Result<ItemsPage, StorageError> {
+        let gallery = self.get_gallery(&request.gallery_id)?;
+        Ok(ItemsPage::paginate(gallery.items.get(&request.marketplace), &request))
     }
-}
\ No newline at end of file
+
+    /// Find a stored gallery's `top_k` embedded items most similar to the query embedding, most similar first.
+    /// 
+    /// Each item's similarity is the higher cosine similarity of its description and image embeddings to the query;
+    /// embeddings with different dimensions to the query are ignored, and items with neither are skipped.
+    /// 
+    /// This is a brute-force scan over all of the gallery's items; an ANN index could replace it if galleries get large.
+    /// 
+    /// Returns an `Err` if the gallery isn't stored, skips embedding (so has nothing to compare against), or the query embedding is empty.
+    pub fn find_similar_items(&self, request: SimilarItemsRequest) -> Result<Vec<SimilarItem>, StorageError> {
+        let gallery = self.get_gallery(&request.gallery_id)?;
+        if gallery.evaluation_criteria.skip_embedding() {
+            return Err(StorageError::Other { 
+                gallery_id: request.gallery_id, 
+                message: "Gallery skips embedding, so its items can't be searched by similarity".into() 
+            });
+        }
+        if request.query_embedding.is_empty() {
+            return Err(StorageError::Other { 
+                gallery_id: request.gallery_id, 
+                message: "Query embedding is empty".into() 
+            });
+        }
+        let mut similar_items: Vec<SimilarItem> = gallery.items
+            .iter()
+            .flat_map(|(marketplace, items)| items.embedded_items
+                .iter()
+                .map(move |item| (marketplace, item))
+            )
+            .filter_map(|(marketplace, item)| {
+                let similarity = [&item.description_embedding, &item.image_embedding]
+                    .into_iter()
+                    .filter_map(|embedding| cosine_similarity(&request.query_embedding, embedding))
+                    .reduce(f32::max)?;
+                Some(SimilarItem {
+                    marketplace: marketplace.clone(),
+                    item: item.clone(),
+                    similarity
+                })
+            })
+            .collect();
+        similar_items.sort_by(|a, b| b.similarity.total_cmp(&a.similarity));
+        similar_items.truncate(request.top_k);
+        Ok(similar_items)
+    }
+
+    /// Get a stored gallery in the item analysis state, with only its marketplaces which failed analysis.
+    /// 
+    /// The gallery stays stored, so nothing is lost if its retry never starts; the retried run replaces it once it's stored.
+    /// 
+    /// Returns an `Err` if the gallery isn't stored, or no marketplaces failed analysis.
+    pub fn get_gallery_for_analysis_retry(&self, gallery_id: GalleryId) -> Result<GalleryItemAnalysisState, StorageError> {
+        let gallery = self.get_gallery(&gallery_id)?.into_owned();
+        gallery
+            .into_analysis_retry()
+            .ok_or(StorageError::NoFailedAnalysis { gallery_id })
+    }
+
+    /// Upsert a gallery's scraped items under a marketplace, replacing any existing item with the same ID.
+    /// 
+    /// Returns the number of newly inserted items.
+    pub async fn upsert_scraped_items(
+        &mut self, 
+        gallery_id: GalleryId, 
+        marketplace: Marketplace, 
+        items: Vec<MarketplaceItemData>
+    ) -> Result<usize, StorageError> {
+        let num_inserted = self.scraped_items.upsert(gallery_id.clone(), marketplace, items);
+        self.persist_scraped_items(&gallery_id).await?;
+        Ok(num_inserted)
+    }
+
+    /// Get all scraped items stored for a gallery, by marketplace.
+    pub fn get_scraped_items(&self, gallery_id: &GalleryId) -> Result<HashMap<Marketplace, Vec<MarketplaceItemData>>, StorageError> {
+        Ok(self.scraped_items.get(gallery_id))
+    }
+
+    /// Drop a gallery's scraped items (if any are stored), and persist the items.
+    pub async fn clear_scraped_items(&mut self, gallery_id: GalleryId) -> Result<(), StorageError> {
+        if !self.scraped_items.remove(&gallery_id) {
+            return Ok(());
+        }
+        self.persist_scraped_items(&gallery_id).await
+    }
+
+    /// Diff a gallery's freshly scraped items against its previous scrape, keeping the diff and replacing the previous items.
+    pub fn record_scrape_diff(&mut self, gallery_id: GalleryId, items: HashMap<Marketplace, Vec<MarketplaceItemData>>) {
+        let snapshot = self.scraped_items_snapshots
+            .entry(gallery_id.clone())
+            .or_default();
+        let diff = diff_scraped_items(snapshot, items, self.diff_price_change_threshold);
+        tracing::debug!(
+            "Gallery {gallery_id}'s scrape had {} added, {} price changed and {} removed items", 
+            diff.added.len(), 
+            diff.price_changed.len(), 
+            diff.removed.len()
+        );
+        self.scrape_diffs.insert(gallery_id, diff);
+    }
+
+    /// Get the diff of a gallery's latest scrape against its previous one.
+    /// 
+    /// Returns an `Err` if the gallery hasn't been scraped yet.
+    pub fn get_scrape_diff(&self, gallery_id: &GalleryId) -> Result<ScrapeDiff, StorageError> {
+        self.scrape_diffs
+            .get(gallery_id)
+            .cloned()
+            .ok_or(StorageError::GalleryNotFound { gallery_id: gallery_id.clone() })
+    }
+
+    /// Store a gallery's scheduler state, replacing its existing state (if any), and persist the states.
+    pub async fn put_scheduler_state(&mut self, gallery: GallerySchedulerState) -> Result<(), StorageError> {
+        let gallery_id = gallery.gallery_id.clone();
+        self.scheduler_states.insert(gallery);
+        self.persist_scheduler_states(&gallery_id).await
+    }
+
+    /// Merge a run's marketplace updated datetimes into its gallery's scheduler state and persist the states,
+    /// then send the updated state to the scheduler, so the gallery's next scrape stops at the items this run already saw.
+    /// 
+    /// The scheduler is updated from a separate task, as it may itself be waiting on storage.
+    /// A gallery without a scheduler state (ie it was deleted mid-run) is skipped, and any failure is only logged, as the run itself is stored.
+    async fn record_scraped_datetimes(&mut self, gallery_id: &GalleryId, marketplace_updated_datetimes: HashMap<Marketplace, UnixUtcDateTime>) {
+        if marketplace_updated_datetimes.is_empty() {
+            return;
+        }
+        let Some(state) = self.scheduler_states.get_mut(gallery_id) else {
+            tracing::debug!("Gallery {gallery_id} has no scheduler state; not recording its scraped datetimes");
+            return;
+        };
+        state.marketplace_previous_scraped_datetimes.extend(marketplace_updated_datetimes);
+        let state = state.clone();
+        if let Err(err) = self.persist_scheduler_states(gallery_id).await {
+            tracing::error!("Failed to persist scheduler states after recording gallery {gallery_id}'s scraped datetimes: {err}");
+        }
+        let mut scheduler_sender = self.scheduler_sender.clone();
+        let gallery_id = gallery_id.clone();
+        tokio::spawn(async move {
+            let (msg, receiver) = UpdateGalleryMessage::new(state);
+            if let Err(err) = scheduler_sender.send(SchedulerMessage::UpdateGallery(msg)).await {
+                tracing::error!("Failed to send gallery {gallery_id}'s scraped datetimes to the scheduler: {err}");
+                return;
+            }
+            match receiver.await {
+                Ok(Ok(())) => tracing::trace!("Recorded gallery {gallery_id}'s scraped datetimes in the scheduler"),
+                Ok(Err(err)) => tracing::warn!("Scheduler rejected gallery {gallery_id}'s scraped datetimes: {err}"),
+                Err(err) => tracing::error!("Could not receive response from scheduler: {err}")
+            }
+        });
+    }
+
+    /// Remove a gallery's scheduler state (if it's stored), and persist the states.
+    pub async fn delete_scheduler_state(&mut self, gallery_id: GalleryId) -> Result<(), StorageError> {
+        if !self.scheduler_states.remove(&gallery_id) {
+            return Ok(());
+        }
+        self.persist_scheduler_states(&gallery_id).await
+    }
+
+    /// Get every stored gallery scheduler state.
+    pub fn get_scheduler_states(&self) -> Vec<GallerySchedulerState> {
+        self.scheduler_states
+            .states()
+            .cloned()
+            .collect()
+    }
+
+    /// Get the cached analyses of items under an evaluation criteria's hash, leaving out items without one.
+    pub fn get_cached_analyses(&self, criteria_hash: u64, item_ids: Vec<ItemId>) -> HashMap<ItemId, CachedItemAnalysis> {
+        item_ids
+            .into_iter()
+            .filter_map(|item_id| {
+                let cached_analysis = self.analysis_cache.get(&(item_id.clone(), criteria_hash))?;
+                Some((item_id, cached_analysis.clone()))
+            })
+            .collect()
+    }
+
+    /// Cache items' analyses under an evaluation criteria's hash.
+    /// 
+    /// Any analyses of the items under other criteria are dropped, as the criteria they were analyzed under has changed.
+    pub fn cache_analyses(&mut self, criteria_hash: u64, analyses: Vec<CachedItemAnalysis>) {
+        let item_ids: HashSet<_> = analyses
+            .iter()
+            .map(|analysis| analysis.item.item.id.clone())
+            .collect();
+        self.analysis_cache.retain(|(item_id, _), _| !item_ids.contains(item_id));
+        for analysis in analyses {
+            self.analysis_cache.insert((analysis.item.item.id.clone(), criteria_hash), analysis);
+        }
+    }
+
+    /// Queue a gallery's items which failed analysis to be retried, persisting the queue.
+    pub async fn enqueue_analysis_retry(&mut self, entry: AnalysisRetryEntry) -> Result<(), StorageError> {
+        let gallery_id = entry.gallery_id.clone();
+        self.analysis_retry_queue.enqueue(entry);
+        self.persist_analysis_retry_queue(&gallery_id).await
+    }
+
+    /// Get the queued analysis retries of stored galleries.
+    pub fn get_analysis_retries(&self) -> Vec<AnalysisRetryEntry> {
+        self.analysis_retry_queue
+            .entries()
+            .filter(|entry| self.galleries.contains_key(&entry.gallery_id))
+            .cloned()
+            .collect()
+    }
+
+    /// Merge the outcome of retrying a queued marketplace's analysis into its stored gallery, persisting the queue.
+    /// 
+    /// Items which were analyzed are removed from the marketplace's error items; irrelevant ones are added to its irrelevant items,
+    /// and relevant ones to its skipped embedding items, as the gallery's embedding has already finished.
+    /// 
+    /// Items which failed again are re-queued with another attempt, unless they've reached `max_attempts`,
+    /// in which case the marketplace is recorded in the gallery's `failed_marketplace_reasons`.
+    /// 
+    /// Returns an `Err` if the marketplace isn't queued, or the gallery isn't stored.
+    pub async fn resolve_analysis_retry(&mut self, outcome: AnalysisRetryOutcome) -> Result<(), StorageError> {
+        let AnalysisRetryOutcome { gallery_id, marketplace, analyzed_items, token_usage, max_attempts } = outcome;
+        let mut gallery = self.get_gallery(&gallery_id)?.into_owned();
+        let mut entry = self.analysis_retry_queue
+            .remove(&gallery_id, &marketplace)
+            .ok_or(StorageError::Other { 
+                gallery_id: gallery_id.clone(), 
+                message: format!("No analysis retry is queued for {marketplace}") 
+            })?;
+        let marketplace_items = gallery.items
+            .entry(marketplace.clone())
+            .or_insert_with(|| MarketplaceEmbeddedAndAnalyzedItems {
+                embedded_items: vec![],
+                irrelevant_analyzed_items: vec![],
+                error_analyzed_items: vec![],
+                error_embedded_items: vec![],
+                skipped_embedding_items: vec![],
+                unsampled_items: vec![]
+            });
+        let analyzed_ids: HashSet<_> = analyzed_items.relevant_items
+            .iter()
+            .chain(&analyzed_items.irrelevant_items)
+            .map(|item| item.item.id.clone())
+            .collect();
+        marketplace_items.error_analyzed_items.retain(|item| !analyzed_ids.contains(&item.item.id));
+        marketplace_items.irrelevant_analyzed_items.extend(analyzed_items.irrelevant_items);
+        marketplace_items.skipped_embedding_items.extend(
+            analyzed_items.relevant_items
+                .into_iter()
+                .map(|item| SkippedEmbeddingMarketplaceItem { 
+                    item, 
+                    reason: "Analyzed by a retry after the gallery was embedded".into() 
+                })
+        );
+        gallery.token_usage.merge(&token_usage);
+        if let Some(first_error) = analyzed_items.error_items.first() {
+            entry.attempts += 1;
+            entry.last_error = first_error.error.clone();
+            if entry.attempts >= max_attempts {
+                tracing::warn!(
+                    "{} {marketplace} items of gallery {gallery_id} still failed analysis after {} retries; giving up on them", 
+                    analyzed_items.error_items.len(), 
+                    entry.attempts
+                );
+                gallery.failed_marketplace_reasons.insert(
+                    marketplace,
+                    format!("{} items failed analysis after {} retries: {}", analyzed_items.error_items.len(), entry.attempts, entry.last_error)
+                );
+            }
+            else {
+                entry.items = analyzed_items.error_items
+                    .into_iter()
+                    .map(|error_item| error_item.item)
+                    .collect();
+                self.analysis_retry_queue.insert(entry);
+            }
+        }
+        self.put_gallery(gallery)?;
+        self.persist_analysis_retry_queue(&gallery_id).await
+    }
+
+    /// Aggregate a gallery's item prices under each marketplace over its latest `window` runs.
+    /// 
+    /// Returns an `Err` if the gallery has no recorded runs.
+    pub fn get_gallery_stats(&self, request: GalleryStatsRequest) -> Result<GalleryStats, StorageError> {
+        self.history
+            .stats(&request.gallery_id, request.window)
+            .ok_or(StorageError::GalleryNotFound { gallery_id: request.gallery_id })
+    }
+
+    /// Merge a stored gallery's items into another stored gallery, skipping items the target already has (by ID), then remove the source from storage.
+    /// 
+    /// Marketplaces the target doesn't have are taken from the source as-is. The source's queued analysis retries are moved to the target,
+    /// so their items are merged into it once resolved.
+    /// 
+    /// Returns an `Err` if either gallery isn't stored, or they're the same gallery; nothing is changed then.
+    pub async fn merge_galleries(&mut self, request: MergeGalleriesRequest) -> Result<GalleryMergeSummary, StorageError> {
+        let MergeGalleriesRequest { source_gallery_id, target_gallery_id } = request;
+        if source_gallery_id == target_gallery_id {
+            return Err(StorageError::Other { 
+                gallery_id: source_gallery_id, 
+                message: "Can't merge a gallery into itself".into() 
+            });
+        }
+        let source = self.get_gallery(&source_gallery_id)?.into_owned();
+        let mut target = self.get_gallery(&target_gallery_id)?.into_owned();
+
+        let mut merged_items = HashMap::new();
+        let mut duplicate_items = HashMap::new();
+        for (marketplace, items) in source.items {
+            let num_items = items.num_items();
+            let num_merged = match target.items.get_mut(&marketplace) {
+                Some(target_items) => target_items.merge(items),
+                None => {
+                    if let Some(updated_datetime) = source.marketplace_updated_datetimes.get(&marketplace) {
+                        target.marketplace_updated_datetimes.insert(marketplace.clone(), updated_datetime.clone());
+                    }
+                    target.failed_marketplace_reasons.remove(&marketplace);
+                    target.items.insert(marketplace.clone(), items);
+                    num_items
+                }
+            };
+            merged_items.insert(marketplace.clone(), num_merged);
+            duplicate_items.insert(marketplace, num_items - num_merged);
+        }
+        for (marketplace, items) in source.unanalyzed_items {
+            let target_items = target.unanalyzed_items
+                .entry(marketplace.clone())
+                .or_default();
+            let num_items = items.len();
+            let mut num_merged = 0;
+            for item in items {
+                if !target_items.iter().any(|target_item| target_item.id == item.id) {
+                    target_items.push(item);
+                    num_merged += 1;
+                }
+            }
+            *merged_items.entry(marketplace.clone()).or_default() += num_merged;
+            *duplicate_items.entry(marketplace).or_default() += num_items - num_merged;
+        }
+
+        self.put_gallery(target)?;
+        self.galleries.remove(&source_gallery_id);
+        self.clear_scraped_items(source_gallery_id.clone()).await?;
+        self.scraped_items_snapshots.remove(&source_gallery_id);
+        self.scrape_diffs.remove(&source_gallery_id);
+        let moved_analysis_retries = self.analysis_retry_queue.reassign(&source_gallery_id, &target_gallery_id);
+        if moved_analysis_retries > 0 {
+            self.persist_analysis_retry_queue(&target_gallery_id).await?;
+        }
+        Ok(GalleryMergeSummary {
+            source_gallery_id,
+            target_gallery_id,
+            merged_items,
+            duplicate_items,
+            moved_analysis_retries
+        })
+    }
+
+    /// Get the tokens used analyzing a stored gallery.
+    /// 
+    /// Returns an `Err` if the gallery isn't stored.
+    pub fn get_token_usage(&self, gallery_id: &GalleryId) -> Result<ModelTokenUsage, StorageError> {
+        self.get_gallery(gallery_id)
+            .map(|gallery| gallery.token_usage.clone())
+    }
+
+    /// Store a gallery, compressing it if configured; overwrites it if it's already stored.
+    /// 
+    /// Returns an `Err` if it couldn't be compressed.
+    fn put_gallery(&mut self, gallery: GalleryFinalState) -> Result<(), StorageError> {
+        let gallery_id = gallery.gallery_id.clone();
+        let record = self.codec
+            .encode(gallery)
+            .map_err(|message| StorageError::Serialization { gallery_id: gallery_id.clone(), message })?;
+        self.galleries.insert(gallery_id, record);
+        Ok(())
+    }
+
+    /// Get when a gallery's stored run completed, or `None` if it isn't stored.
+    fn stored_completed_at(&self, gallery_id: &GalleryId) -> Result<Option<UnixUtcDateTime>, StorageError> {
+        match self.get_gallery(gallery_id) {
+            Ok(gallery) => Ok(Some(gallery.completed_at.clone())),
+            Err(StorageError::GalleryNotFound { .. }) => Ok(None),
+            Err(err) => Err(err)
+        }
+    }
+
+    /// Get a stored gallery, decompressing it if needed.
+    /// 
+    /// Returns an `Err` if the gallery isn't stored, or couldn't be decompressed.
+    fn get_gallery(&self, gallery_id: &GalleryId) -> Result<Cow<'_, GalleryFinalState>, StorageError> {
+        self.galleries
+            .get(gallery_id)
+            .ok_or(StorageError::GalleryNotFound { gallery_id: gallery_id.clone() })?
+            .decode()
+            .map_err(|message| StorageError::Serialization { gallery_id: gallery_id.clone(), message })
+    }
+
+    /// Persist the analysis retry queue, retrying if it fails transiently.
+    /// 
+    /// `gallery_id` is the gallery whose change is being persisted, for errors.
+    async fn persist_analysis_retry_queue(&self, gallery_id: &GalleryId) -> Result<(), StorageError> {
+        let queue = &self.analysis_retry_queue;
+        self.retry
+            .run("persist analysis retry queue", || async move {
+                queue.persist()
+                    .await
+                    .map_err(|err| err.into_storage_error(gallery_id))
+            })
+            .await
+    }
+
+    /// Persist the scraped items, retrying if it fails transiently.
+    /// 
+    /// `gallery_id` is the gallery whose change is being persisted, for errors.
+    async fn persist_scraped_items(&self, gallery_id: &GalleryId) -> Result<(), StorageError> {
+        let scraped_items = &self.scraped_items;
+        self.retry
+            .run("persist scraped items", || async move {
+                scraped_items.persist()
+                    .await
+                    .map_err(|err| err.into_storage_error(gallery_id))
+            })
+            .await
+    }
+
+    /// Persist the scheduler states, retrying if it fails transiently.
+    /// 
+    /// `gallery_id` is the gallery whose change is being persisted, for errors.
+    async fn persist_scheduler_states(&self, gallery_id: &GalleryId) -> Result<(), StorageError> {
+        let states = &self.scheduler_states;
+        self.retry
+            .run("persist scheduler states", || async move {
+                states.persist()
+                    .await
+                    .map_err(|err| err.into_storage_error(gallery_id))
+            })
+            .await
+    }
+
+    /// Fetches a gallery from state.
+    ///
+    /// Returns an `Err` if:
+    /// - the gallery is not in state/is in the wrong state/has already been taken
+    /// - the state tracker is not contactable
+    async fn fetch_gallery_state(&mut self, gallery_id: GalleryId) -> Result<GalleryFinalState, StorageError> {
+        let state = self.state_tracker_sender
+            .get_gallery_state(gallery_id.clone(), GalleryPipelineStateTypes::Final)
+            .await
+            .map_err(|err| StorageError::Other {
+                gallery_id: gallery_id.clone(),
+                message: format!("Could not receive response from state tracker: {err}")
+            })?
+            .map_err(|err| StorageError::StateErr {
+                gallery_id: gallery_id.clone(),
+                err
+            })?;
+        match state {
+            GalleryPipelineStates::Final(gallery_state) => Ok(gallery_state),
+            _ => Err(
+                    StorageError::Other {
+                        gallery_id: gallery_id.clone(),
+                        message: "Gallery is not in expected state".into()
+                    }
+                )
+        }
+    }
+}
+
+/// Returns the cosine similarity of two embeddings, or `None` if their dimensions differ or either has no magnitude.
+fn cosine_similarity(a: &[f32], b: &[f32]) -> Option<f32> {
+    if a.len() != b.len() {
+        return None;
+    }
+    let (dot, norm_a, norm_b) = a
+        .iter()
+        .zip(b)
+        .fold((0.0, 0.0, 0.0), |(dot, norm_a, norm_b), (x, y)| (dot + x * y, norm_a + x * x, norm_b + y * y));
+    if norm_a == 0.0 || norm_b == 0.0 {
+        return None;
+    }
+    Some(dot / (norm_a.sqrt() * norm_b.sqrt()))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::test_support::{final_state, item_data, scheduler_state, storage_config, TestHarness};
+    use std::time::Duration;
+    use super::*;
+
+    /// A handler which only keeps everything in memory.
+    fn handler(harness: &TestHarness) -> Handler {
+        Handler::new(&storage_config(), harness.state_tracker_sender(), harness.scraper_scheduler.sender())
+    }
+
+    fn page_request(gallery_id: &str, offset: usize) -> ItemsPageRequest {
+        ItemsPageRequest {
+            gallery_id: GalleryId::from(gallery_id.to_string()),
+            marketplace: Marketplace::Mercari,
+            offset,
+            limit: 10,
+            sort_by_confidence: false,
+            min_confidence: None
+        }
+    }
+
+    #[tokio::test]
+    async fn storing_a_later_run_replaces_the_stored_one() {
+        let harness = TestHarness::new();
+        let mut handler = handler(&harness);
+        handler.store_gallery(final_state("gallery", 100, &["a"])).await.unwrap();
+        handler.store_gallery(final_state("gallery", 200, &["b", "c"])).await.unwrap();
+        let page = handler.get_items_paginated(page_request("gallery", 0)).unwrap();
+        assert_eq!(page.total, 2);
+        assert_eq!(page.items[0].item.id, ItemId::from("b".to_string()));
+    }
+
+    #[tokio::test]
+    async fn storing_an_earlier_run_keeps_the_stored_one() {
+        let harness = TestHarness::new();
+        let mut handler = handler(&harness);
+        handler.store_gallery(final_state("gallery", 200, &["b", "c"])).await.unwrap();
+        let result = handler.store_gallery(final_state("gallery", 100, &["a"])).await;
+        assert!(matches!(result, Err(StorageError::GalleryAlreadyExists { .. })));
+        assert_eq!(handler.get_items_paginated(page_request("gallery", 0)).unwrap().total, 2);
+    }
+
+    #[tokio::test]
+    async fn ensuring_a_newer_run_is_stored_replaces_the_older_one() {
+        let harness = TestHarness::new();
+        let mut handler = handler(&harness);
+        handler.store_gallery(final_state("gallery", 100, &["a"])).await.unwrap();
+        handler.ensure_gallery_stored(final_state("gallery", 200, &["b", "c"])).await.unwrap();
+        assert_eq!(handler.get_items_paginated(page_request("gallery", 0)).unwrap().total, 2);
+        handler.ensure_gallery_stored(final_state("gallery", 100, &["a"])).await.unwrap();
+        assert_eq!(handler.get_items_paginated(page_request("gallery", 0)).unwrap().total, 2);
+    }
+
+    #[tokio::test]
+    async fn getting_a_gallery_for_analysis_retry_keeps_it_stored() {
+        let harness = TestHarness::new();
+        let mut handler = handler(&harness);
+        let mut gallery = final_state("gallery", 100, &["a"]);
+        gallery.unanalyzed_items.insert(Marketplace::Mercari, vec![item_data("b", 1000.0, 100)]);
+        handler.store_gallery(gallery).await.unwrap();
+        let retry_gallery = handler.get_gallery_for_analysis_retry(GalleryId::from("gallery".to_string())).unwrap();
+        assert!(retry_gallery.items.contains_key(&Marketplace::Mercari));
+        assert_eq!(handler.get_items_paginated(page_request("gallery", 0)).unwrap().total, 1);
+    }
+
+    #[tokio::test]
+    async fn storing_a_gallery_drops_its_scraped_items() {
+        let harness = TestHarness::new();
+        let mut handler = handler(&harness);
+        let gallery_id = GalleryId::from("gallery".to_string());
+        handler.upsert_scraped_items(gallery_id.clone(), Marketplace::Mercari, vec![item_data("a", 1000.0, 100)]).await.unwrap();
+        assert!(!handler.get_scraped_items(&gallery_id).unwrap().is_empty());
+        handler.store_gallery(final_state("gallery", 100, &["a"])).await.unwrap();
+        assert!(handler.get_scraped_items(&gallery_id).unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn a_runs_scraped_datetimes_are_recorded_in_its_scheduler_state() {
+        let mut harness = TestHarness::new();
+        let mut handler = handler(&harness);
+        let gallery_id = GalleryId::from("gallery".to_string());
+        let datetimes = HashMap::from([(Marketplace::Mercari, UnixUtcDateTime::from(100))]);
+        handler.put_scheduler_state(scheduler_state("gallery")).await.unwrap();
+        handler.record_scraped_datetimes(&gallery_id, datetimes.clone()).await;
+
+        assert_eq!(handler.get_scheduler_states()[0].marketplace_previous_scraped_datetimes, datetimes);
+        match harness.scraper_scheduler.expect_message().await {
+            SchedulerMessage::UpdateGallery(msg) => msg.act(|gallery| {
+                assert_eq!(gallery.marketplace_previous_scraped_datetimes, datetimes);
+                Ok(())
+            }).unwrap(),
+            other => panic!("Expected the gallery's scheduler state to be updated, but got {other:?}")
+        }
+    }
+
+    #[tokio::test]
+    async fn a_run_without_a_scheduler_state_records_no_scraped_datetimes() {
+        let mut harness = TestHarness::new();
+        let mut handler = handler(&harness);
+        let datetimes = HashMap::from([(Marketplace::Mercari, UnixUtcDateTime::from(100))]);
+        handler.record_scraped_datetimes(&GalleryId::from("gallery".to_string()), datetimes).await;
+        assert!(handler.get_scheduler_states().is_empty());
+        harness.scraper_scheduler.expect_no_message_within(Duration::from_millis(100)).await;
+    }
+
+    #[tokio::test]
+    async fn a_page_past_the_end_is_empty_with_the_total() {
+        let harness = TestHarness::new();
+        let mut handler = handler(&harness);
+        handler.store_gallery(final_state("gallery", 100, &["a", "b", "c"])).await.unwrap();
+        let page = handler.get_items_paginated(page_request("gallery", 5)).unwrap();
+        assert!(page.items.is_empty());
+        assert_eq!(page.total, 3);
+    }
+}
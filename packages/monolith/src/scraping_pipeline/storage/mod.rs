@@ -1,9 +1,17 @@
+use tracing::Instrument;
 use crate::{config::StorageConfig, messages::{
-    message_types::storage::StorageMessage, StateTrackerSender, StorageReceiver
-}};
+    message_types::storage::StorageMessage, ScraperSchedulerSender, StateTrackerSender, StorageReceiver
+}, scraping_pipeline::module_health::{ModuleHealth, PipelineModule}, utils::tracing_context::module_span};
 use handler::Handler;
 
 mod handler;
+mod compression;
+mod gallery_history;
+mod retry;
+mod retry_queue;
+mod scheduler_states;
+mod scraped_items;
+mod scrape_diff;
 
 /// In charge of scraping the search of marketplaces under a gallery, for item IDs.
 pub struct StorageModule {
@@ -16,11 +24,14 @@ impl StorageModule {
     pub fn init(
         config: StorageConfig,
         msg_receiver: StorageReceiver,
-        state_tracker_sender: StateTrackerSender
+        state_tracker_sender: StateTrackerSender,
+        scheduler_sender: ScraperSchedulerSender
     ) -> Self
     {   
         let handler = Handler::new(
-            state_tracker_sender
+            &config,
+            state_tracker_sender,
+            scheduler_sender
         );
         Self { 
             msg_receiver, 
@@ -29,15 +40,29 @@ impl StorageModule {
     }
     
     /// Start accepting and acting on messages.
-    pub async fn run(&mut self) {
+    pub async fn run(&mut self, module_health: &ModuleHealth) {
         tracing::info!("StorageModule is running...");
-        while let Some(msg) = self.msg_receiver.receive().await {
-            self.process_msg(msg).await;
+        while let Some((msg, span)) = module_health.receive_with_span(PipelineModule::Storage, &mut self.msg_receiver).await {
+            self.process_msg(msg)
+                .instrument(module_span(&span, PipelineModule::Storage))
+                .await;
         }
     }
 
-    /// Handle each message variant.
+    /// Handle a message, dead-lettering it by its kind and gallery ID if its response couldn't be delivered.
     async fn process_msg(&mut self, msg: StorageMessage) {
+        let kind = msg.kind();
+        let gallery_id = msg.gallery_id();
+        if !self.act_on_msg(msg).await {
+            let gallery = gallery_id.map(|gallery_id| format!(" for gallery {gallery_id}")).unwrap_or_default();
+            self.msg_receiver
+                .dead_letter_response(format!("Could not deliver the response to a {kind} message{gallery}, as its sender stopped waiting for it"))
+                .await;
+        }
+    }
+
+    /// Handle each message variant, returning whether its response (if it has one) was delivered.
+    async fn act_on_msg(&mut self, msg: StorageMessage) -> bool {
         match msg {
             StorageMessage::StoreGalleryNew{ gallery } => {
                 tracing::info!("Received message to store new gallery {}", gallery.gallery_id);
@@ -47,6 +72,7 @@ impl StorageModule {
                 if let Err(err) = schedule_result {
                     tracing::error!("Error while search scraping: {err}");
                 };
+                true
             },
             StorageMessage::StoreGallery{ gallery_id } => {
                 tracing::info!("Received message to store gallery {}", gallery_id);
@@ -56,11 +82,225 @@ impl StorageModule {
                 if let Err(err) = schedule_result {
                     tracing::error!("Error while search scraping: {err}");
                 };
+                true
             }
-            StorageMessage::StoreGalleryError { gallery_id, error } => {
+            StorageMessage::StoreGalleryError { ref gallery_id, ref error } => {
                 tracing::info!("Received message to store error for gallery {gallery_id} (error: {error})");
-                todo!()
+                self.msg_receiver
+                    .dead_letter(msg, "Storing gallery errors is not supported yet")
+                    .await;
+                true
+            }
+            StorageMessage::GetItemsPaginated(msg) => {
+                msg.act(|request| {
+                    tracing::trace!("Got message to fetch items page for gallery {}", request.gallery_id);
+                    self.handler.get_items_paginated(request)
+                })
+                    .is_ok()
+            }
+            StorageMessage::GetGalleryForAnalysisRetry(msg) => {
+                msg.act(|gallery_id| {
+                    tracing::info!("Got message to get gallery {gallery_id} for retrying analysis");
+                    self.handler.get_gallery_for_analysis_retry(gallery_id)
+                })
+                    .is_ok()
+            }
+            StorageMessage::UpsertScrapedItems(msg) => {
+                msg.act_async(|(gallery_id, marketplace, items)| async {
+                    tracing::trace!("Got message to upsert {} scraped {marketplace} items for gallery {gallery_id}", items.len());
+                    self.handler.upsert_scraped_items(gallery_id, marketplace, items).await
+                })
+                    .await
+                    .is_ok()
+            }
+            StorageMessage::GetScrapedItems(msg) => {
+                msg.act(|gallery_id| {
+                    tracing::trace!("Got message to fetch scraped items for gallery {gallery_id}");
+                    self.handler.get_scraped_items(&gallery_id)
+                })
+                    .is_ok()
+            }
+            StorageMessage::ClearScrapedItems { gallery_id } => {
+                tracing::trace!("Got message to drop scraped items of gallery {gallery_id}");
+                if let Err(err) = self.handler.clear_scraped_items(gallery_id).await {
+                    tracing::error!("Failed to drop scraped items: {err}");
+                }
+                true
+            }
+            StorageMessage::GetTokenUsage(msg) => {
+                msg.act(|gallery_id| {
+                    tracing::trace!("Got message to fetch token usage for gallery {gallery_id}");
+                    self.handler.get_token_usage(&gallery_id)
+                })
+                    .is_ok()
+            }
+            StorageMessage::EnsureGalleryStored(msg) => {
+                msg.act_async(|gallery| async {
+                    tracing::trace!("Got message to ensure gallery {} is stored", gallery.gallery_id);
+                    self.handler.ensure_gallery_stored(gallery).await
+                })
+                    .await
+                    .is_ok()
+            }
+            StorageMessage::FindSimilarItems(msg) => {
+                msg.act(|request| {
+                    tracing::trace!("Got message to find the {} most similar items for gallery {}", request.top_k, request.gallery_id);
+                    self.handler.find_similar_items(request)
+                })
+                    .is_ok()
+            }
+            StorageMessage::GetCachedAnalyses(msg) => {
+                msg.act(|(criteria_hash, item_ids)| {
+                    tracing::trace!("Got message to fetch cached analyses of {} items", item_ids.len());
+                    self.handler.get_cached_analyses(criteria_hash, item_ids)
+                })
+                    .is_ok()
+            }
+            StorageMessage::CacheAnalyses { criteria_hash, analyses } => {
+                tracing::trace!("Got message to cache analyses of {} items", analyses.len());
+                self.handler.cache_analyses(criteria_hash, analyses);
+                true
+            }
+            StorageMessage::RecordScrapeDiff { gallery_id, items } => {
+                tracing::trace!("Got message to record scrape diff of gallery {gallery_id}");
+                self.handler.record_scrape_diff(gallery_id, items);
+                true
+            }
+            StorageMessage::GetScrapeDiff(msg) => {
+                msg.act(|gallery_id| {
+                    tracing::trace!("Got message to fetch scrape diff of gallery {gallery_id}");
+                    self.handler.get_scrape_diff(&gallery_id)
+                })
+                    .is_ok()
+            }
+            StorageMessage::EnqueueAnalysisRetry(msg) => {
+                msg.act_async(|entry| async {
+                    tracing::info!("Got message to queue {} {} items of gallery {} for retrying analysis", entry.items.len(), entry.marketplace, entry.gallery_id);
+                    self.handler.enqueue_analysis_retry(entry).await
+                })
+                    .await
+                    .is_ok()
+            }
+            StorageMessage::GetAnalysisRetries(msg) => {
+                msg.act(|_| {
+                    tracing::trace!("Got message to fetch queued analysis retries");
+                    self.handler.get_analysis_retries()
+                })
+                    .is_ok()
+            }
+            StorageMessage::ResolveAnalysisRetry(msg) => {
+                msg.act_async(|outcome| async {
+                    tracing::info!("Got message to resolve analysis retry of {} items for gallery {}", outcome.marketplace, outcome.gallery_id);
+                    self.handler.resolve_analysis_retry(outcome).await
+                })
+                    .await
+                    .is_ok()
+            }
+            StorageMessage::PutSchedulerState { gallery } => {
+                tracing::trace!("Got message to store scheduler state of gallery {}", gallery.gallery_id);
+                if let Err(err) = self.handler.put_scheduler_state(gallery).await {
+                    tracing::error!("Failed to store scheduler state: {err}");
+                }
+                true
+            }
+            StorageMessage::DeleteSchedulerState { gallery_id } => {
+                tracing::trace!("Got message to delete scheduler state of gallery {gallery_id}");
+                if let Err(err) = self.handler.delete_scheduler_state(gallery_id).await {
+                    tracing::error!("Failed to delete scheduler state: {err}");
+                }
+                true
+            }
+            StorageMessage::GetSchedulerStates(msg) => {
+                msg.act(|_| {
+                    tracing::trace!("Got message to fetch scheduler states");
+                    self.handler.get_scheduler_states()
+                })
+                    .is_ok()
+            }
+            StorageMessage::GetGalleryStats(msg) => {
+                msg.act(|request| {
+                    tracing::trace!("Got message to fetch stats of gallery {} over its latest {} runs", request.gallery_id, request.window);
+                    self.handler.get_gallery_stats(request)
+                })
+                    .is_ok()
+            }
+            StorageMessage::MergeGalleries(msg) => {
+                msg.act_async(|request| async {
+                    tracing::info!("Got message to merge gallery {} into gallery {}", request.source_gallery_id, request.target_gallery_id);
+                    self.handler.merge_galleries(request).await
+                })
+                    .await
+                    .is_ok()
             }
         }
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{galleries::domain_types::{GalleryId, ItemId, Marketplace}, messages::message_types::storage::{SimilarItemsRequest, StorageError}, test_support::{final_state, storage_config, TestHarness}};
+    use super::*;
+
+    /// Spawn a storage module driven over the harness's buses, which only keeps everything in memory.
+    fn spawn_storage(harness: &mut TestHarness) {
+        let mut module = StorageModule::init(
+            storage_config(),
+            harness.storage.take_receiver(),
+            harness.state_tracker_sender(),
+            harness.scraper_scheduler.sender()
+        );
+        harness.spawn(async move { module.run(&ModuleHealth::new()).await });
+    }
+
+    #[tokio::test]
+    async fn similar_items_are_found_most_similar_first_up_to_top_k() {
+        let mut harness = TestHarness::new();
+        spawn_storage(&mut harness);
+        let mut gallery = final_state("gallery", 100, &["orthogonal", "identical", "diagonal", "mismatched"]);
+        let items = &mut gallery.items.get_mut(&Marketplace::Mercari).unwrap().embedded_items;
+        items[0].description_embedding = vec![0.0, 1.0];
+        items[1].description_embedding = vec![2.0, 0.0];
+        // Only its image embedding has the query's dimensions
+        items[2].description_embedding = vec![1.0, 0.0, 0.0];
+        items[2].image_embedding = vec![1.0, 1.0];
+        // Neither embedding has the query's dimensions, so it's skipped
+        items[3].description_embedding = vec![1.0, 0.0, 0.0];
+        harness.storage.inject(StorageMessage::StoreGalleryNew { gallery }).await;
+
+        let request = SimilarItemsRequest {
+            gallery_id: GalleryId::from("gallery".to_string()),
+            query_embedding: vec![1.0, 0.0],
+            top_k: 2
+        };
+        let similar_items = harness.storage
+            .sender()
+            .find_similar_items(request)
+            .await
+            .unwrap()
+            .unwrap();
+        let item_ids: Vec<_> = similar_items
+            .iter()
+            .map(|similar| similar.item.item.id.clone())
+            .collect();
+        assert_eq!(item_ids, vec![ItemId::from("identical".to_string()), ItemId::from("diagonal".to_string())]);
+        assert!((similar_items[0].similarity - 1.0).abs() < 1e-6);
+        assert!((similar_items[1].similarity - std::f32::consts::FRAC_1_SQRT_2).abs() < 1e-6);
+    }
+
+    #[tokio::test]
+    async fn finding_similar_items_of_an_unstored_gallery_fails() {
+        let mut harness = TestHarness::new();
+        spawn_storage(&mut harness);
+        let request = SimilarItemsRequest {
+            gallery_id: GalleryId::from("gallery".to_string()),
+            query_embedding: vec![1.0, 0.0],
+            top_k: 2
+        };
+        let result = harness.storage
+            .sender()
+            .find_similar_items(request)
+            .await
+            .unwrap();
+        assert!(matches!(result, Err(StorageError::GalleryNotFound { .. })));
+    }
+}
@@ -0,0 +1,78 @@
+//! Contains the retrying of storage operations which fail transiently.
+use std::{future::Future, time::Duration};
+use crate::{config::storage::{StorageConfig, StorageErrorKind}, galleries::domain_types::GalleryId, messages::message_types::storage::StorageError};
+
+/// An error from persisting a store to its file.
+#[derive(Debug)]
+pub(super) enum PersistError {
+    /// The store couldn't be serialized, so retrying won't help.
+    Serialization(String),
+    /// The file couldn't be written, which may be transient.
+    Io(String)
+}
+
+impl PersistError {
+    /// Convert into a `StorageError` for the gallery the store was persisted for.
+    pub fn into_storage_error(self, gallery_id: &GalleryId) -> StorageError {
+        match self {
+            PersistError::Serialization(message) => StorageError::Serialization { gallery_id: gallery_id.clone(), message },
+            PersistError::Io(message) => StorageError::Io { gallery_id: gallery_id.clone(), message }
+        }
+    }
+}
+
+/// Retries storage operations with exponential backoff plus jitter, if their error is of a retryable kind;
+/// other errors are returned immediately.
+/// 
+/// Only operations which are safe to repeat (ie writing a store to its file) should be retried.
+pub(super) struct StorageRetry {
+    max_attempts: u32,
+    base_delay: Duration,
+    max_delay: Duration,
+    retryable_errors: Vec<StorageErrorKind>
+}
+
+impl StorageRetry {
+    /// Initialize from the config.
+    pub fn new(config: &StorageConfig) -> Self {
+        Self {
+            max_attempts: config.retry_max_attempts.max(1),
+            base_delay: Duration::from_millis(config.retry_base_delay_ms),
+            max_delay: Duration::from_millis(config.retry_max_delay_ms),
+            retryable_errors: config.retryable_errors.clone()
+        }
+    }
+
+    /// Run the operation, retrying it on a retryable error until it's been attempted `max_attempts` times.
+    /// 
+    /// `operation` describes it for logging.
+    pub async fn run<T, F, Fut>(&self, operation: &str, mut op: F) -> Result<T, StorageError>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<T, StorageError>>
+    {
+        let mut attempt = 0;
+        loop {
+            match op().await {
+                Ok(result) => return Ok(result),
+                Err(err) if attempt + 1 < self.max_attempts && self.retryable_errors.contains(&err.kind()) => {
+                    let delay = self.delay(attempt);
+                    tracing::warn!("Failed to {operation} (attempt {}); retrying in {delay:?}: {err}", attempt + 1);
+                    tokio::time::sleep(delay).await;
+                    attempt += 1;
+                },
+                Err(err) => return Err(err)
+            }
+        }
+    }
+
+    /// The delay before the retry following `attempt`: the base delay doubled for each previous retry (capped to the max delay),
+    /// plus a random jitter of up to half of that, so concurrent retries don't line up.
+    fn delay(&self, attempt: u32) -> Duration {
+        let backoff = self.base_delay
+            .saturating_mul(2u32.saturating_pow(attempt))
+            .min(self.max_delay);
+        let jitter_millis = rand::random_range(0..=backoff.as_millis() as u64 / 2);
+        backoff + Duration::from_millis(jitter_millis)
+    }
+}
@@ -0,0 +1,116 @@
+//! Contains the queue of items to retry analysis for.
+use std::{collections::HashMap, path::PathBuf};
+use crate::{galleries::domain_types::{GalleryId, Marketplace}, messages::message_types::storage::AnalysisRetryEntry};
+use super::retry::PersistError;
+
+/// The queue of gallery items which failed analysis, keyed by their gallery and marketplace.
+/// 
+/// If it has a path, the whole queue is rewritten to it as JSON on every change, and loaded from it on startup,
+/// so queued items survive restarts.
+pub(super) struct AnalysisRetryQueue {
+    path: Option<PathBuf>,
+    entries: HashMap<(GalleryId, Marketplace), AnalysisRetryEntry>
+}
+
+impl AnalysisRetryQueue {
+    /// Initialize the queue, loading it from its file if it has one.
+    /// 
+    /// An unreadable file is logged and ignored, starting with an empty queue.
+    pub fn load(path: &str) -> Self {
+        let path = match path.trim() {
+            "" => None,
+            path => Some(PathBuf::from(path))
+        };
+        let entries = match &path {
+            Some(path) => match std::fs::read_to_string(path) {
+                Ok(entries_str) => serde_json::from_str::<Vec<AnalysisRetryEntry>>(&entries_str)
+                    .unwrap_or_else(|err| {
+                        tracing::error!("Failed to parse analysis retry queue file {path:?}; starting with an empty queue: {err}");
+                        vec![]
+                    }),
+                Err(err) if err.kind() == std::io::ErrorKind::NotFound => vec![],
+                Err(err) => {
+                    tracing::error!("Failed to read analysis retry queue file {path:?}; starting with an empty queue: {err}");
+                    vec![]
+                }
+            },
+            None => vec![]
+        };
+        if !entries.is_empty() {
+            tracing::info!("Loaded {} queued analysis retries", entries.len());
+        }
+        Self {
+            path,
+            entries: entries
+                .into_iter()
+                .map(|entry| ((entry.gallery_id.clone(), entry.marketplace.clone()), entry))
+                .collect()
+        }
+    }
+
+    /// Queue an entry, adding its items to the existing entry for its gallery and marketplace (if any).
+    pub fn enqueue(&mut self, entry: AnalysisRetryEntry) {
+        let key = (entry.gallery_id.clone(), entry.marketplace.clone());
+        match self.entries.get_mut(&key) {
+            Some(existing_entry) => {
+                for item in entry.items {
+                    if !existing_entry.items.iter().any(|existing_item| existing_item.id == item.id) {
+                        existing_entry.items.push(item);
+                    }
+                }
+                existing_entry.last_error = entry.last_error;
+            },
+            None => {
+                self.entries.insert(key, entry);
+            }
+        }
+    }
+
+    /// Overwrite the entry for its gallery and marketplace.
+    pub fn insert(&mut self, entry: AnalysisRetryEntry) {
+        self.entries.insert((entry.gallery_id.clone(), entry.marketplace.clone()), entry);
+    }
+
+    /// Remove and return the entry for a gallery and marketplace, if it's queued.
+    pub fn remove(&mut self, gallery_id: &GalleryId, marketplace: &Marketplace) -> Option<AnalysisRetryEntry> {
+        self.entries.remove(&(gallery_id.clone(), marketplace.clone()))
+    }
+
+    /// Move a gallery's queued entries to another gallery, adding their items to any entries already queued for it.
+    /// 
+    /// Returns the number of entries moved.
+    pub fn reassign(&mut self, from_gallery_id: &GalleryId, to_gallery_id: &GalleryId) -> usize {
+        let keys: Vec<_> = self.entries
+            .keys()
+            .filter(|(gallery_id, _)| gallery_id == from_gallery_id)
+            .cloned()
+            .collect();
+        for key in &keys {
+            if let Some(mut entry) = self.entries.remove(key) {
+                entry.gallery_id = to_gallery_id.clone();
+                self.enqueue(entry);
+            }
+        }
+        keys.len()
+    }
+
+    /// Iterate over all queued entries.
+    pub fn entries(&self) -> impl Iterator<Item = &AnalysisRetryEntry> {
+        self.entries.values()
+    }
+
+    /// Write the whole queue to its file, if it has one.
+    /// 
+    /// Writes to a temporary file first, then renames it over the actual file, so a crash mid-write doesn't corrupt it.
+    pub async fn persist(&self) -> Result<(), PersistError> {
+        let Some(path) = &self.path else { return Ok(()) };
+        let entries: Vec<_> = self.entries.values().collect();
+        let entries_str = serde_json::to_string(&entries)
+            .map_err(|err| PersistError::Serialization(format!("Failed to serialize analysis retry queue: {err}")))?;
+        let temp_path = path.with_extension("tmp");
+        tokio::fs::write(&temp_path, entries_str).await
+            .map_err(|err| PersistError::Io(format!("Failed to write analysis retry queue file {path:?}: {err}")))?;
+        tokio::fs::rename(&temp_path, path).await
+            .map_err(|err| PersistError::Io(format!("Failed to replace analysis retry queue file {path:?}: {err}")))
+    }
+}
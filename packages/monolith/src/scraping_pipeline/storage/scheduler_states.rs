@@ -0,0 +1,85 @@
+//! Contains the galleries' scheduler states.
+use std::{collections::HashMap, path::PathBuf};
+use crate::galleries::{domain_types::GalleryId, pipeline_states::GallerySchedulerState};
+use super::retry::PersistError;
+
+/// The scheduler state of each gallery, which the scheduler reconciles its galleries with.
+/// 
+/// If it has a path, all states are rewritten to it as JSON on every change, and loaded from it on startup,
+/// so scheduled galleries survive restarts.
+pub(super) struct SchedulerStateStore {
+    path: Option<PathBuf>,
+    states: HashMap<GalleryId, GallerySchedulerState>
+}
+
+impl SchedulerStateStore {
+    /// Initialize the store, loading it from its file if it has one.
+    /// 
+    /// An unreadable file is logged and ignored, starting with no states.
+    pub fn load(path: &str) -> Self {
+        let path = match path.trim() {
+            "" => None,
+            path => Some(PathBuf::from(path))
+        };
+        let states = match &path {
+            Some(path) => match std::fs::read_to_string(path) {
+                Ok(states_str) => serde_json::from_str::<Vec<GallerySchedulerState>>(&states_str)
+                    .unwrap_or_else(|err| {
+                        tracing::error!("Failed to parse scheduler states file {path:?}; starting without scheduler states: {err}");
+                        vec![]
+                    }),
+                Err(err) if err.kind() == std::io::ErrorKind::NotFound => vec![],
+                Err(err) => {
+                    tracing::error!("Failed to read scheduler states file {path:?}; starting without scheduler states: {err}");
+                    vec![]
+                }
+            },
+            None => vec![]
+        };
+        if !states.is_empty() {
+            tracing::info!("Loaded scheduler states for {} galleries", states.len());
+        }
+        Self {
+            path,
+            states: states
+                .into_iter()
+                .map(|state| (state.gallery_id.clone(), state))
+                .collect()
+        }
+    }
+
+    /// Insert a gallery's state, overwriting its existing state (if any).
+    pub fn insert(&mut self, state: GallerySchedulerState) {
+        self.states.insert(state.gallery_id.clone(), state);
+    }
+
+    /// Get a mutable reference to a gallery's state, if it's stored.
+    pub fn get_mut(&mut self, gallery_id: &GalleryId) -> Option<&mut GallerySchedulerState> {
+        self.states.get_mut(gallery_id)
+    }
+
+    /// Remove a gallery's state, returning whether it was stored.
+    pub fn remove(&mut self, gallery_id: &GalleryId) -> bool {
+        self.states.remove(gallery_id).is_some()
+    }
+
+    /// Iterate over all stored states.
+    pub fn states(&self) -> impl Iterator<Item = &GallerySchedulerState> {
+        self.states.values()
+    }
+
+    /// Write all states to its file, if it has one.
+    /// 
+    /// Writes to a temporary file first, then renames it over the actual file, so a crash mid-write doesn't corrupt it.
+    pub async fn persist(&self) -> Result<(), PersistError> {
+        let Some(path) = &self.path else { return Ok(()) };
+        let states: Vec<_> = self.states.values().collect();
+        let states_str = serde_json::to_string(&states)
+            .map_err(|err| PersistError::Serialization(format!("Failed to serialize scheduler states: {err}")))?;
+        let temp_path = path.with_extension("tmp");
+        tokio::fs::write(&temp_path, states_str).await
+            .map_err(|err| PersistError::Io(format!("Failed to write scheduler states file {path:?}: {err}")))?;
+        tokio::fs::rename(&temp_path, path).await
+            .map_err(|err| PersistError::Io(format!("Failed to replace scheduler states file {path:?}: {err}")))
+    }
+}
@@ -0,0 +1,75 @@
+//! Contains the diffing of a gallery's scraped items between scrapes.
+use std::collections::HashMap;
+use crate::{galleries::{domain_types::{ItemId, Marketplace, UnixUtcDateTime}, items::item_data::MarketplaceItemData}, messages::message_types::storage::{PriceChangedItem, ScrapeDiff, ScrapeDiffItem}};
+
+/// The items of each marketplace from a gallery's latest scrape, as (name, comparable price) by item ID.
+pub(super) type ScrapedItemsSnapshot = HashMap<Marketplace, HashMap<ItemId, (String, f32)>>;
+
+/// Diffs the freshly scraped items against the previous scrape's snapshot, updating the snapshot with them.
+/// 
+/// Only the scraped marketplaces are diffed and updated. A price only counts as changed 
+/// if it changed by more than `price_change_threshold` relative to the old price.
+pub(super) fn diff_scraped_items(
+    snapshot: &mut ScrapedItemsSnapshot,
+    items: HashMap<Marketplace, Vec<MarketplaceItemData>>,
+    price_change_threshold: f32
+) -> ScrapeDiff {
+    let mut diff = ScrapeDiff {
+        scraped_at: UnixUtcDateTime::now(),
+        added: vec![],
+        price_changed: vec![],
+        removed: vec![]
+    };
+    for (marketplace, items) in items {
+        let mut previous_items = snapshot
+            .remove(&marketplace)
+            .unwrap_or_default();
+        let current_items: HashMap<_, _> = items
+            .into_iter()
+            .map(|item| {
+                let price = item.comparable_price();
+                (item.id, (item.name, price))
+            })
+            .collect();
+        for (item_id, (name, price)) in &current_items {
+            match previous_items.remove(item_id) {
+                Some((_, old_price)) if price_changed(old_price, *price, price_change_threshold) => {
+                    diff.price_changed.push(PriceChangedItem {
+                        marketplace: marketplace.clone(),
+                        item_id: item_id.clone(),
+                        name: name.clone(),
+                        old_price,
+                        new_price: *price
+                    });
+                },
+                Some(_) => (),
+                None => diff.added.push(ScrapeDiffItem {
+                    marketplace: marketplace.clone(),
+                    item_id: item_id.clone(),
+                    name: name.clone(),
+                    price: *price
+                })
+            }
+        }
+        diff.removed.extend(
+            previous_items
+                .into_iter()
+                .map(|(item_id, (name, price))| ScrapeDiffItem { 
+                    marketplace: marketplace.clone(), 
+                    item_id, 
+                    name, 
+                    price 
+                })
+        );
+        snapshot.insert(marketplace, current_items);
+    }
+    diff
+}
+
+/// Returns whether the price changed by more than the threshold, relative to the old price.
+fn price_changed(old_price: f32, new_price: f32, threshold: f32) -> bool {
+    if old_price == 0.0 {
+        return new_price != 0.0;
+    }
+    ((new_price - old_price) / old_price).abs() > threshold
+}
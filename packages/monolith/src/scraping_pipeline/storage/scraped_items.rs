@@ -0,0 +1,163 @@
+//! Contains the items scraped for galleries still in the pipeline.
+use std::{collections::HashMap, path::PathBuf};
+use serde::{Deserialize, Serialize};
+use crate::galleries::{domain_types::{GalleryId, ItemId, Marketplace}, items::item_data::MarketplaceItemData};
+use super::retry::PersistError;
+
+/// A gallery's scraped items under a marketplace, as they're persisted.
+#[derive(Serialize, Deserialize)]
+struct ScrapedItemsEntry {
+    gallery_id: GalleryId,
+    marketplace: Marketplace,
+    items: Vec<MarketplaceItemData>
+}
+
+/// The items scraped for each gallery still in the pipeline, by marketplace and item ID.
+///
+/// If it has a path, all items are rewritten to it as JSON on every change, and loaded from it on startup,
+/// so later stages can recover a gallery's items after a restart.
+pub(super) struct ScrapedItemsStore {
+    path: Option<PathBuf>,
+    items: HashMap<GalleryId, HashMap<Marketplace, HashMap<ItemId, MarketplaceItemData>>>
+}
+
+impl ScrapedItemsStore {
+    /// Initialize the store, loading it from its file if it has one.
+    ///
+    /// An unreadable file is logged and ignored, starting with no items.
+    pub fn load(path: &str) -> Self {
+        let path = match path.trim() {
+            "" => None,
+            path => Some(PathBuf::from(path))
+        };
+        let entries = match &path {
+            Some(path) => match std::fs::read_to_string(path) {
+                Ok(entries_str) => serde_json::from_str::<Vec<ScrapedItemsEntry>>(&entries_str)
+                    .unwrap_or_else(|err| {
+                        tracing::error!("Failed to parse scraped items file {path:?}; starting without scraped items: {err}");
+                        vec![]
+                    }),
+                Err(err) if err.kind() == std::io::ErrorKind::NotFound => vec![],
+                Err(err) => {
+                    tracing::error!("Failed to read scraped items file {path:?}; starting without scraped items: {err}");
+                    vec![]
+                }
+            },
+            None => vec![]
+        };
+        let mut store = Self { path, items: HashMap::new() };
+        for entry in entries {
+            store.upsert(entry.gallery_id, entry.marketplace, entry.items);
+        }
+        if !store.items.is_empty() {
+            tracing::info!("Loaded scraped items for {} galleries", store.items.len());
+        }
+        store
+    }
+
+    /// Upsert a gallery's items under a marketplace, replacing any existing item with the same ID.
+    ///
+    /// Returns the number of newly inserted items.
+    pub fn upsert(&mut self, gallery_id: GalleryId, marketplace: Marketplace, items: Vec<MarketplaceItemData>) -> usize {
+        let stored_items = self.items
+            .entry(gallery_id)
+            .or_default()
+            .entry(marketplace)
+            .or_default();
+        let mut num_inserted = 0;
+        for item in items {
+            if stored_items.insert(item.id.clone(), item).is_none() {
+                num_inserted += 1;
+            }
+        }
+        num_inserted
+    }
+
+    /// Get a gallery's items, by marketplace.
+    ///
+    /// Returns an empty map if none are stored.
+    pub fn get(&self, gallery_id: &GalleryId) -> HashMap<Marketplace, Vec<MarketplaceItemData>> {
+        self.items
+            .get(gallery_id)
+            .map(|marketplace_items| marketplace_items
+                .iter()
+                .map(|(marketplace, items)| (marketplace.clone(), items.values().cloned().collect()))
+                .collect()
+            )
+            .unwrap_or_default()
+    }
+
+    /// Remove a gallery's items, returning whether any were stored.
+    pub fn remove(&mut self, gallery_id: &GalleryId) -> bool {
+        self.items.remove(gallery_id).is_some()
+    }
+
+    /// Write all items to its file, if it has one.
+    ///
+    /// Writes to a temporary file first, then renames it over the actual file, so a crash mid-write doesn't corrupt it.
+    pub async fn persist(&self) -> Result<(), PersistError> {
+        let Some(path) = &self.path else { return Ok(()) };
+        let entries: Vec<_> = self.items
+            .iter()
+            .flat_map(|(gallery_id, marketplace_items)| marketplace_items
+                .iter()
+                .map(|(marketplace, items)| ScrapedItemsEntry {
+                    gallery_id: gallery_id.clone(),
+                    marketplace: marketplace.clone(),
+                    items: items.values().cloned().collect()
+                })
+            )
+            .collect();
+        let entries_str = serde_json::to_string(&entries)
+            .map_err(|err| PersistError::Serialization(format!("Failed to serialize scraped items: {err}")))?;
+        let temp_path = path.with_extension("tmp");
+        tokio::fs::write(&temp_path, entries_str).await
+            .map_err(|err| PersistError::Io(format!("Failed to write scraped items file {path:?}: {err}")))?;
+        tokio::fs::rename(&temp_path, path).await
+            .map_err(|err| PersistError::Io(format!("Failed to replace scraped items file {path:?}: {err}")))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::test_support::item_data;
+    use super::*;
+
+    fn gallery_id() -> GalleryId {
+        GalleryId::from("gallery".to_string())
+    }
+
+    #[test]
+    fn upserting_replaces_items_with_the_same_id() {
+        let mut store = ScrapedItemsStore::load("");
+        let inserted = store.upsert(gallery_id(), Marketplace::Mercari, vec![item_data("a", 100.0, 0), item_data("b", 100.0, 0)]);
+        assert_eq!(inserted, 2);
+        let inserted = store.upsert(gallery_id(), Marketplace::Mercari, vec![item_data("b", 200.0, 0), item_data("c", 100.0, 0)]);
+        assert_eq!(inserted, 1);
+        let items = &store.get(&gallery_id())[&Marketplace::Mercari];
+        assert_eq!(items.len(), 3);
+        let item_b = items.iter().find(|item| item.id == ItemId::from("b".to_string())).unwrap();
+        assert_eq!(item_b.price, 200.0);
+    }
+
+    #[test]
+    fn removing_drops_the_gallerys_items() {
+        let mut store = ScrapedItemsStore::load("");
+        store.upsert(gallery_id(), Marketplace::Mercari, vec![item_data("a", 100.0, 0)]);
+        assert!(store.remove(&gallery_id()));
+        assert!(store.get(&gallery_id()).is_empty());
+        assert!(!store.remove(&gallery_id()));
+    }
+
+    #[tokio::test]
+    async fn persisted_items_are_loaded_back() {
+        let path = std::env::temp_dir().join(format!("scraped_items_{}.json", std::process::id()));
+        let path_str = path.to_str().unwrap();
+        let mut store = ScrapedItemsStore::load(path_str);
+        store.upsert(gallery_id(), Marketplace::Mercari, vec![item_data("a", 100.0, 0), item_data("b", 100.0, 0)]);
+        store.persist().await.unwrap();
+        let loaded = ScrapedItemsStore::load(path_str);
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(loaded.get(&gallery_id())[&Marketplace::Mercari].len(), 2);
+    }
+}
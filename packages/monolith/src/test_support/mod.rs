@@ -0,0 +1,306 @@
+//! Contains a harness for driving a single module over in-memory message buses, without running the rest of the app.
+//! 
+//! This is only compiled for tests, or with the `test-support` feature.
+use std::{collections::HashMap, fmt::Debug, future::Future, time::Duration};
+use tokio::task::JoinHandle;
+use crate::{
+    config::{http_client::HttpClientConfig, notification::NotificationConfig, state_tracker::{AuditLogKind, StateTrackerConfig, StateTrackerStoreKind}, storage::{StorageCompression, StorageConfig}, SerializationFormat},
+    galleries::{domain_types::{GalleryId, ItemId, Marketplace, ModelTokenUsage, UnixUtcDateTime, ValidCronString}, eval_criteria::EvaluationCriteria, items::{item_data::{MarketplaceItemData, MarketplaceSeller}, pipeline_items::{EmbeddedMarketplaceItem, MarketplaceEmbeddedAndAnalyzedItems}}, pipeline_states::{GalleryFinalState, GallerySchedulerState}, search_criteria::GallerySearchCriteria}, 
+    messages::{
+        message_buses::{message_bus, MessageReceiver, MessageSender}, 
+        message_types::{item_analysis::ItemAnalysisMessage, item_embedder::ItemEmbedderMessage, item_scraper::ItemScraperMessage, scraper_scheduler::SchedulerMessage, search_scraper::SearchScraperMessage, state_tracker::StateTrackerMessage, storage::StorageMessage}, 
+        StateTrackerSender
+    },
+    notifications::Notifier,
+    scraping_pipeline::{module_health::ModuleHealth, state_tracker::StateTrackerModule},
+    utils::http_client::HttpClientFactory
+};
+
+/// The buffer size of each of the harness's buses.
+const HARNESS_MESSAGE_BUFFER: usize = 100;
+
+/// How long `expect_message` waits by default.
+pub const DEFAULT_EXPECT_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// An in-memory message bus, from which the module under test can take its end.
+/// 
+/// Before its receiver is taken, the bus acts as a probe: messages sent to it by the module can be asserted on.
+/// After it's taken, the sender can be used to inject messages into the module.
+pub struct Bus<T: Debug> {
+    sender: MessageSender<T>,
+    receiver: Option<MessageReceiver<T>>
+}
+
+impl<T: Debug> Bus<T> {
+    /// Create an empty bus.
+    fn new() -> Self {
+        let (sender, receiver, _) = message_bus(HARNESS_MESSAGE_BUFFER);
+        Self {
+            sender,
+            receiver: Some(receiver)
+        }
+    }
+
+    /// Get a sender for the bus.
+    pub fn sender(&self) -> MessageSender<T> {
+        self.sender.clone()
+    }
+
+    /// Take the bus's receiver, to hand to the module under test.
+    /// 
+    /// Panics if it was already taken.
+    pub fn take_receiver(&mut self) -> MessageReceiver<T> {
+        self.receiver
+            .take()
+            .expect("The bus's receiver was already taken")
+    }
+
+    /// Inject a message into the bus, as if another module had sent it.
+    pub async fn inject(&mut self, message: T) {
+        self.sender
+            .send(message)
+            .await
+            .expect("The bus's receiver should not be dropped");
+    }
+
+    /// Wait up to `timeout` for the next message sent to the bus.
+    /// 
+    /// Panics if the receiver was taken, or if no message arrives in time.
+    pub async fn expect_message_within(&mut self, timeout: Duration) -> T {
+        let receiver = self.receiver
+            .as_mut()
+            .expect("Can't expect messages on a bus whose receiver was taken");
+        match tokio::time::timeout(timeout, receiver.receive()).await {
+            Ok(Some(message)) => message,
+            Ok(None) => panic!("The bus was closed while expecting a message"),
+            Err(_) => panic!("Timed out after {timeout:?} expecting a message")
+        }
+    }
+
+    /// Wait up to `DEFAULT_EXPECT_TIMEOUT` for the next message sent to the bus.
+    pub async fn expect_message(&mut self) -> T {
+        self.expect_message_within(DEFAULT_EXPECT_TIMEOUT).await
+    }
+
+    /// Assert that no message is sent to the bus within `timeout`.
+    /// 
+    /// Panics if the receiver was taken, or if a message arrives.
+    pub async fn expect_no_message_within(&mut self, timeout: Duration) {
+        let receiver = self.receiver
+            .as_mut()
+            .expect("Can't expect messages on a bus whose receiver was taken");
+        if let Ok(Some(message)) = tokio::time::timeout(timeout, receiver.receive()).await {
+            panic!("Expected no message, but received {message:?}");
+        }
+    }
+}
+
+/// Wires a single module to in-memory buses for every other module.
+/// 
+/// To test a module, take its receiver from its bus and pass it (along with senders for the other buses) into its `init`,
+/// then `spawn` its `run`. Messages can then be injected through its bus, and those it emits are asserted on through the other buses.
+/// 
+/// Since the module's real `run` is used, this exercises its real message processing.
+/// 
+/// Any spawned tasks are aborted when the harness is dropped.
+pub struct TestHarness {
+    pub state_tracker: Bus<StateTrackerMessage>,
+    pub scraper_scheduler: Bus<SchedulerMessage>,
+    pub search_scraper: Bus<SearchScraperMessage>,
+    /// No test asserts on this yet; it's only held to keep the item scraper's channel open.
+    pub _item_scraper: Bus<ItemScraperMessage>,
+    pub item_analysis: Bus<ItemAnalysisMessage>,
+    /// No test asserts on this yet; it's only held to keep the item embedder's channel open.
+    pub _item_embedder: Bus<ItemEmbedderMessage>,
+    pub storage: Bus<StorageMessage>,
+    tasks: Vec<JoinHandle<()>>
+}
+
+impl TestHarness {
+    /// Create a harness, with an empty bus for each module.
+    pub fn new() -> Self {
+        Self {
+            state_tracker: Bus::new(),
+            scraper_scheduler: Bus::new(),
+            search_scraper: Bus::new(),
+            _item_scraper: Bus::new(),
+            item_analysis: Bus::new(),
+            _item_embedder: Bus::new(),
+            storage: Bus::new(),
+            tasks: vec![]
+        }
+    }
+
+    /// Get a `StateTrackerSender` for the state tracker bus, without a send timeout.
+    pub fn state_tracker_sender(&self) -> StateTrackerSender {
+        StateTrackerSender::new(self.state_tracker.sender())
+    }
+
+    /// Spawn a task (ie a module's `run`), which is aborted when the harness is dropped.
+    pub fn spawn(&mut self, task: impl Future<Output = ()> + Send + 'static) {
+        self.tasks.push(tokio::spawn(task));
+    }
+
+    /// Spawn a real state tracker over the state tracker bus, which only keeps states in memory.
+    /// 
+    /// This is for testing modules which work on galleries in state, rather than answering each state tracker message by hand.
+    pub async fn spawn_state_tracker(&mut self) {
+        let config = StateTrackerConfig {
+            use_redis: false,
+            redis_uri: String::new(),
+            store_kind: StateTrackerStoreKind::Memory,
+            store_file_path: String::new(),
+            stage_timeouts_secs: HashMap::new(),
+            watchdog_interval_secs: 3600,
+            max_stalled_galleries: 1,
+            final_retention_secs: 0,
+            compaction_interval_secs: 0,
+            send_timeout_ms: 0,
+            serialization_format: SerializationFormat::Json,
+            audit_log_kind: AuditLogKind::None,
+            audit_log_path: String::new()
+        };
+        let mut module = StateTrackerModule::init(
+            config, 
+            self.state_tracker.take_receiver(), 
+            self.storage.sender(), 
+            notifier()
+        ).await;
+        self.spawn(async move { module.run(&ModuleHealth::new()).await });
+    }
+}
+
+impl Default for TestHarness {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Drop for TestHarness {
+    fn drop(&mut self) {
+        for task in &self.tasks {
+            task.abort();
+        }
+    }
+}
+
+/// Build a notifier without any sinks, so events are dropped.
+pub fn notifier() -> Notifier {
+    let config = NotificationConfig {
+        sinks: vec![],
+        webhook_url: None,
+        webhook_secret: None,
+        webhook_max_retries: 0
+    };
+    Notifier::new(&config, &http_clients())
+}
+
+/// Build an HTTP client factory with short timeouts.
+pub fn http_clients() -> HttpClientFactory {
+    HttpClientFactory::new(&HttpClientConfig {
+        connect_timeout_secs: 1,
+        request_timeout_secs: 1,
+        pool_idle_timeout_secs: 1,
+        pool_max_idle_per_host: 1
+    })
+}
+
+/// Build a storage config which only keeps everything in memory, without compressing, retrying or keeping history.
+pub fn storage_config() -> StorageConfig {
+    StorageConfig {
+        compression: StorageCompression::None,
+        compression_level: 0,
+        serialization_format: SerializationFormat::Json,
+        analysis_retry_queue_path: String::new(),
+        diff_price_change_threshold: 0.01,
+        scheduler_states_path: String::new(),
+        scraped_items_path: String::new(),
+        history_max_runs: 0,
+        retry_max_attempts: 1,
+        retry_base_delay_ms: 0,
+        retry_max_delay_ms: 0,
+        retryable_errors: vec![]
+    }
+}
+
+/// Build a minimal valid scheduler state for a gallery, which is scraped hourly and has no criteria.
+pub fn scheduler_state(gallery_id: impl Into<String>) -> GallerySchedulerState {
+    GallerySchedulerState {
+        gallery_id: GalleryId::from(gallery_id.into()),
+        scraping_periodicity: ValidCronString::new("0 * * * *".into())
+            .expect("The test cron string should be valid"),
+        search_criteria: GallerySearchCriteria {
+            keyword: "test".into(),
+            exclude_keyword: String::new(),
+            min_price: None,
+            max_price: None,
+            max_items_per_marketplace: None,
+            min_seller_rating: None,
+            updated_after: None,
+            marketplaces: Marketplace::all(),
+            per_marketplace: HashMap::new()
+        },
+        marketplace_previous_scraped_datetimes: HashMap::new(),
+        evaluation_criteria: EvaluationCriteria::default(),
+        enabled: true
+    }
+}
+
+/// Build a minimal item, named after its ID and listed in JPY, which was created and last updated at `updated` (a UNIX timestamp).
+pub fn item_data(id: &str, price: f32, updated: i64) -> MarketplaceItemData {
+    MarketplaceItemData {
+        id: ItemId::from(id.to_string()),
+        name: format!("Item {id}"),
+        price,
+        currency: "JPY".into(),
+        normalized_price: None,
+        description: String::new(),
+        status: "on_sale".into(),
+        seller: MarketplaceSeller {
+            id: "seller".into(),
+            name: "Seller".into()
+        },
+        seller_rating: None,
+        category: String::new(),
+        thumbnails: vec![],
+        item_condition: String::new(),
+        created: UnixUtcDateTime::from(updated),
+        updated: UnixUtcDateTime::from(updated)
+    }
+}
+
+/// Build a final state for a gallery which completed at `completed_at` (a UNIX timestamp), with an embedded Mercari item for each ID.
+pub fn final_state(gallery_id: impl Into<String>, completed_at: i64, item_ids: &[&str]) -> GalleryFinalState {
+    let embedded_items = item_ids
+        .iter()
+        .map(|id| EmbeddedMarketplaceItem {
+            item: item_data(id, 1000.0, completed_at),
+            evaluation_answers: vec![],
+            item_description: String::new(),
+            description_embedding: vec![],
+            image_embedding: vec![],
+            confidence: None,
+            analysis_provider: None
+        })
+        .collect();
+    GalleryFinalState {
+        gallery_id: GalleryId::from(gallery_id.into()),
+        items: HashMap::from([(
+            Marketplace::Mercari,
+            MarketplaceEmbeddedAndAnalyzedItems {
+                embedded_items,
+                irrelevant_analyzed_items: vec![],
+                error_analyzed_items: vec![],
+                error_embedded_items: vec![],
+                skipped_embedding_items: vec![],
+                unsampled_items: vec![]
+            }
+        )]),
+        marketplace_updated_datetimes: HashMap::new(),
+        failed_marketplace_reasons: HashMap::new(),
+        unanalyzed_items: HashMap::new(),
+        evaluation_criteria: EvaluationCriteria::default(),
+        token_usage: ModelTokenUsage::default(),
+        completed_at: UnixUtcDateTime::from(completed_at)
+    }
+}
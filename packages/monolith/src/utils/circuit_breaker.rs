@@ -0,0 +1,108 @@
+//! Contains a circuit breaker for requests to marketplaces, keyed by marketplace.
+use std::{collections::HashMap, sync::Arc, time::{Duration, Instant}};
+use tokio::sync::Mutex;
+use crate::galleries::domain_types::Marketplace;
+
+/// The state of a single marketplace's circuit breaker.
+#[derive(Debug, Clone)]
+pub enum BreakerState {
+    /// Requests are allowed; tracks the number of consecutive failures so far.
+    Closed { consecutive_failures: u32 },
+    /// Requests are short-circuited until the cooldown since `opened_at` passes.
+    Open { opened_at: Instant },
+    /// A single trial request is allowed through to test if the marketplace has recovered.
+    HalfOpen
+}
+
+/// A circuit breaker per marketplace.
+///
+/// Opens after `failure_threshold` consecutive failures, short-circuiting requests for `cooldown`,
+/// then half-opens to let a single trial request through; its success closes the breaker, and its failure re-opens it.
+///
+/// Clones share the same breaker states.
+#[derive(Debug, Clone)]
+pub struct MarketplaceCircuitBreaker {
+    states: Arc<Mutex<HashMap<Marketplace, BreakerState>>>,
+    failure_threshold: u32,
+    cooldown: Duration
+}
+
+impl MarketplaceCircuitBreaker {
+    /// Instantiate the circuit breaker, with all marketplaces closed.
+    ///
+    /// A `failure_threshold` of 0 disables the breaker.
+    pub fn new(failure_threshold: u32, cooldown: Duration) -> Self {
+        Self {
+            states: Arc::new(Mutex::new(HashMap::new())),
+            failure_threshold,
+            cooldown
+        }
+    }
+
+    /// Check whether a request to the marketplace should be made.
+    ///
+    /// Returns an `Err` with the remaining cooldown if the breaker is open, or a trial request is already in flight.
+    pub async fn check(&self, marketplace: &Marketplace) -> Result<(), Duration> {
+        if self.failure_threshold == 0 {
+            return Ok(());
+        }
+        let mut states = self.states.lock().await;
+        let state = states
+            .entry(marketplace.clone())
+            .or_insert(BreakerState::Closed { consecutive_failures: 0 });
+        match state {
+            BreakerState::Closed { .. } => Ok(()),
+            BreakerState::Open { opened_at } => {
+                let elapsed = opened_at.elapsed();
+                if elapsed < self.cooldown {
+                    return Err(self.cooldown - elapsed);
+                }
+                tracing::info!("Circuit breaker for {marketplace} is now half-open; allowing a trial request");
+                *state = BreakerState::HalfOpen;
+                Ok(())
+            },
+            BreakerState::HalfOpen => Err(Duration::ZERO)
+        }
+    }
+
+    /// Record a successful request to the marketplace, closing its breaker.
+    pub async fn record_success(&self, marketplace: &Marketplace) {
+        if self.failure_threshold == 0 {
+            return;
+        }
+        let mut states = self.states.lock().await;
+        let previous_state = states.insert(marketplace.clone(), BreakerState::Closed { consecutive_failures: 0 });
+        if matches!(previous_state, Some(BreakerState::HalfOpen | BreakerState::Open { .. })) {
+            tracing::info!("Circuit breaker for {marketplace} is now closed");
+        }
+    }
+
+    /// Record a failed request to the marketplace, opening its breaker if the failure threshold is reached
+    /// (or if it was half-open).
+    pub async fn record_failure(&self, marketplace: &Marketplace) {
+        if self.failure_threshold == 0 {
+            return;
+        }
+        let mut states = self.states.lock().await;
+        let state = states
+            .entry(marketplace.clone())
+            .or_insert(BreakerState::Closed { consecutive_failures: 0 });
+        match state {
+            BreakerState::Closed { consecutive_failures } => {
+                *consecutive_failures += 1;
+                if *consecutive_failures >= self.failure_threshold {
+                    tracing::warn!(
+                        "Circuit breaker for {marketplace} is now open after {consecutive_failures} consecutive failures; short-circuiting requests for {:?}",
+                        self.cooldown
+                    );
+                    *state = BreakerState::Open { opened_at: Instant::now() };
+                }
+            },
+            BreakerState::HalfOpen => {
+                tracing::warn!("Trial request to {marketplace} failed; circuit breaker is open again for {:?}", self.cooldown);
+                *state = BreakerState::Open { opened_at: Instant::now() };
+            },
+            BreakerState::Open { .. } => ()
+        }
+    }
+}
@@ -0,0 +1,115 @@
+//! Contains an exchange-rate provider, for normalizing item prices into a base currency.
+use std::{collections::HashMap, sync::Arc, time::{Duration, Instant}};
+use reqwest::Client;
+use serde::Deserialize;
+use tokio::sync::Mutex;
+use crate::galleries::items::item_data::MarketplaceItemData;
+
+/// The response from the exchange-rate API.
+///
+/// Rates are the amount of each currency equal to 1 unit of the base currency.
+#[derive(Deserialize, Debug)]
+struct ExchangeRateResponse {
+    rates: HashMap<String, f64>
+}
+
+/// Fetched rates, along with when they were fetched.
+#[derive(Debug)]
+struct CachedRates {
+    rates: HashMap<String, f64>,
+    fetched_at: Instant
+}
+
+/// Converts prices into a base currency, using rates fetched from an exchange-rate API.
+///
+/// Rates are cached for `cache_ttl`; if they can't be refreshed, the stale rates are used until they can.
+///
+/// Clones share the same cache.
+#[derive(Debug, Clone)]
+pub struct ExchangeRates {
+    client: Client,
+    api_endpoint: String,
+    base_currency: String,
+    cache_ttl: Duration,
+    cache: Arc<Mutex<Option<CachedRates>>>
+}
+
+impl ExchangeRates {
+    /// Instantiate the provider.
+    ///
+    /// `api_endpoint` must return a JSON object with a `rates` map of currency codes to their rate against `base_currency`.
+    /// If it's empty, only prices already in the base currency are normalized.
+    pub fn new(client: Client, api_endpoint: String, base_currency: String, cache_ttl: Duration) -> Self {
+        Self {
+            client,
+            api_endpoint,
+            base_currency: base_currency.to_uppercase(),
+            cache_ttl,
+            cache: Arc::new(Mutex::new(None))
+        }
+    }
+
+    /// Set each item's normalized price, converting its price from its currency into the base currency.
+    ///
+    /// Items whose currency has no known rate are left without a normalized price.
+    pub async fn normalize<'a>(&self, items: impl IntoIterator<Item = &'a mut MarketplaceItemData>) {
+        let items: Vec<_> = items.into_iter().collect();
+        if items.is_empty() {
+            return;
+        }
+        let rates = self.get_rates().await;
+        for item in items {
+            let currency = item.currency.to_uppercase();
+            item.normalized_price = match currency == self.base_currency {
+                true => Some(item.price),
+                false => match rates.get(&currency) {
+                    Some(rate) if *rate > 0.0 => Some((item.price as f64 / rate) as f32),
+                    _ => {
+                        tracing::warn!("No exchange rate from {currency} to {}; leaving item {} unnormalized", self.base_currency, item.id);
+                        None
+                    }
+                }
+            };
+        }
+    }
+
+    /// Get the cached rates, refreshing them first if they're missing or older than the cache TTL.
+    ///
+    /// Returns an empty map if there are no rates and they couldn't be fetched.
+    async fn get_rates(&self) -> HashMap<String, f64> {
+        let mut cache = self.cache.lock().await;
+        let is_fresh = cache
+            .as_ref()
+            .is_some_and(|cached| cached.fetched_at.elapsed() < self.cache_ttl);
+        if !is_fresh && !self.api_endpoint.is_empty() {
+            match self.fetch_rates().await {
+                Ok(rates) => *cache = Some(CachedRates { rates, fetched_at: Instant::now() }),
+                Err(err) => tracing::warn!("Failed to refresh exchange rates; using cached rates (if any): {err}")
+            }
+        }
+        cache
+            .as_ref()
+            .map(|cached| cached.rates.clone())
+            .unwrap_or_default()
+    }
+
+    /// Fetch the latest rates from the API, with currency codes uppercased.
+    async fn fetch_rates(&self) -> Result<HashMap<String, f64>, String> {
+        let response = self.client
+            .get(&self.api_endpoint)
+            .send()
+            .await
+            .map_err(|err| format!("Error requesting exchange rates: {err}"))?
+            .error_for_status()
+            .map_err(|err| format!("Error code while requesting exchange rates: {err}"))?
+            .json::<ExchangeRateResponse>()
+            .await
+            .map_err(|err| format!("Error deserializing exchange rates: {err}"))?;
+        Ok(
+            response.rates
+                .into_iter()
+                .map(|(currency, rate)| (currency.to_uppercase(), rate))
+                .collect()
+        )
+    }
+}
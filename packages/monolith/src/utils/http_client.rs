@@ -0,0 +1,73 @@
+//! Contains the factory for the HTTP clients used for all outbound requests.
+use std::time::Duration;
+use reqwest::{Client, ClientBuilder, Proxy};
+use crate::config::HttpClientConfig;
+use super::proxy::ProxyConfig;
+
+/// Builds HTTP clients with the configured timeouts and connection pooling, so they're tuned in one place.
+/// 
+/// Direct (unproxied) clients are all clones of one shared client, so they share its connection pool;
+/// proxied clients get their own pool, as their connections go through the proxy.
+/// 
+/// This is cheap to clone, and clones share the same pool.
+#[derive(Debug, Clone)]
+pub struct HttpClientFactory {
+    config: HttpClientConfig,
+    shared_client: Client
+}
+
+impl HttpClientFactory {
+    /// Instantiate the factory.
+    /// 
+    /// Panics if the shared client can't be built (ie if TLS can't be initialized), as no outbound requests could be made.
+    pub fn new(config: &HttpClientConfig) -> Self {
+        let shared_client = Self::builder(config)
+            .build()
+            .unwrap_or_else(|err| panic!("Could not build the shared HTTP client: {err}"));
+        Self {
+            config: config.clone(),
+            shared_client
+        }
+    }
+
+    /// Get a direct client, which shares the factory's connection pool.
+    pub fn client(&self) -> Client {
+        self.shared_client.clone()
+    }
+
+    /// Get a client which routes all requests through the proxy, or a direct client if there is none.
+    ///
+    /// Requests made through a proxied client fail if the proxy is unreachable; they never fall back to a direct connection.
+    ///
+    /// Returns an `Err` if the proxy's URL is invalid.
+    pub fn client_with_proxy(&self, proxy: Option<&ProxyConfig>) -> Result<Client, String> {
+        let Some(proxy) = proxy else {
+            return Ok(self.client());
+        };
+        let mut client_proxy = Proxy::all(&proxy.url)
+            .map_err(|err| format!("Invalid proxy URL {}: {err}", proxy.url))?;
+        if let Some(username) = &proxy.username {
+            client_proxy = client_proxy.basic_auth(username, proxy.password.as_deref().unwrap_or_default());
+        }
+        Self::builder(&self.config)
+            .proxy(client_proxy)
+            .build()
+            .map_err(|err| format!("Failed to build client with proxy {}: {err}", proxy.url))
+    }
+
+    /// Get a client builder with the configured timeouts and pooling.
+    fn builder(config: &HttpClientConfig) -> ClientBuilder {
+        let pool_idle_timeout = match config.pool_idle_timeout_secs {
+            0 => None,
+            secs => Some(Duration::from_secs(secs))
+        };
+        let builder = Client::builder()
+            .connect_timeout(Duration::from_secs(config.connect_timeout_secs))
+            .pool_idle_timeout(pool_idle_timeout)
+            .pool_max_idle_per_host(config.pool_max_idle_per_host);
+        match config.request_timeout_secs {
+            0 => builder,
+            secs => builder.timeout(Duration::from_secs(secs))
+        }
+    }
+}
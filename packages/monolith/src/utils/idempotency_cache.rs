@@ -0,0 +1,75 @@
+//! Contains a bounded, expiring cache of idempotency keys to the galleries they created.
+use std::{collections::HashMap, time::{Duration, Instant}};
+use crate::galleries::domain_types::GalleryId;
+
+/// A cached idempotency key.
+#[derive(Debug)]
+struct CacheEntry {
+    gallery_id: GalleryId,
+    inserted_at: Instant,
+    last_used: u64
+}
+
+/// An LRU cache of recently seen idempotency keys, and the gallery ID created for each.
+///
+/// Keys expire after `ttl`. Once `capacity` is reached, the least recently used key is evicted.
+#[derive(Debug)]
+pub struct IdempotencyCache {
+    entries: HashMap<String, CacheEntry>,
+    capacity: usize,
+    ttl: Duration,
+    use_counter: u64
+}
+
+impl IdempotencyCache {
+    /// Instantiate an empty cache.
+    pub fn new(capacity: usize, ttl: Duration) -> Self {
+        Self {
+            entries: HashMap::new(),
+            capacity,
+            ttl,
+            use_counter: 0
+        }
+    }
+
+    /// Get the gallery ID created for a key, if the key was seen and hasn't expired.
+    pub fn get(&mut self, key: &str) -> Option<GalleryId> {
+        let expired = self.entries
+            .get(key)
+            .map(|entry| entry.inserted_at.elapsed() > self.ttl)?;
+        if expired {
+            self.entries.remove(key);
+            return None;
+        }
+        self.use_counter += 1;
+        let entry = self.entries.get_mut(key)?;
+        entry.last_used = self.use_counter;
+        Some(entry.gallery_id.clone())
+    }
+
+    /// Record the gallery ID created for a key, evicting expired keys and then the least recently used key if full.
+    pub fn insert(&mut self, key: String, gallery_id: GalleryId) {
+        if self.capacity == 0 {
+            return;
+        }
+        if !self.entries.contains_key(&key) && self.entries.len() >= self.capacity {
+            let ttl = self.ttl;
+            self.entries.retain(|_, entry| entry.inserted_at.elapsed() <= ttl);
+            if self.entries.len() >= self.capacity {
+                let lru_key = self.entries
+                    .iter()
+                    .min_by_key(|(_, entry)| entry.last_used)
+                    .map(|(key, _)| key.clone());
+                if let Some(lru_key) = lru_key {
+                    self.entries.remove(&lru_key);
+                }
+            }
+        }
+        self.use_counter += 1;
+        self.entries.insert(key, CacheEntry {
+            gallery_id,
+            inserted_at: Instant::now(),
+            last_used: self.use_counter
+        });
+    }
+}
@@ -0,0 +1,56 @@
+//! Contains a self-check for marketplace scrapers, which flags searches that probably broke due to a marketplace layout change.
+use std::{collections::{HashMap, VecDeque}, sync::Arc};
+use tokio::sync::Mutex;
+use crate::galleries::domain_types::Marketplace;
+
+/// Tracks a rolling window of how many items each marketplace's searches return.
+///
+/// When a marketplace's HTML changes, its scraper tends to silently return no items rather than erroring;
+/// so a search returning nothing, when that marketplace's recent searches averaged plenty, is flagged as a probable layout break.
+///
+/// Clones share the same histories.
+#[derive(Debug, Clone)]
+pub struct MarketplaceLayoutMonitor {
+    histories: Arc<Mutex<HashMap<Marketplace, VecDeque<usize>>>>,
+    window: usize,
+    min_average: f64
+}
+
+impl MarketplaceLayoutMonitor {
+    /// Instantiate the monitor, with no history for any marketplace.
+    ///
+    /// A `window` or `min_average` of 0 disables the check.
+    pub fn new(window: usize, min_average: f64) -> Self {
+        Self {
+            histories: Arc::new(Mutex::new(HashMap::new())),
+            window,
+            min_average
+        }
+    }
+
+    /// Record the number of items a search of the marketplace returned.
+    ///
+    /// Returns an `Err` with the rolling average if the search returned no items, but the marketplace's last `window` searches
+    /// averaged at least `min_average` items. Flagged searches aren't recorded, so a persistent break keeps being flagged
+    /// rather than becoming the new normal.
+    pub async fn record(&self, marketplace: &Marketplace, item_count: usize) -> Result<(), f64> {
+        if self.window == 0 || self.min_average <= 0.0 {
+            return Ok(());
+        }
+        let mut histories = self.histories.lock().await;
+        let history = histories
+            .entry(marketplace.clone())
+            .or_default();
+        if item_count == 0 && history.len() >= self.window {
+            let average = history.iter().sum::<usize>() as f64 / history.len() as f64;
+            if average >= self.min_average {
+                return Err(average);
+            }
+        }
+        history.push_back(item_count);
+        while history.len() > self.window {
+            history.pop_front();
+        }
+        Ok(())
+    }
+}
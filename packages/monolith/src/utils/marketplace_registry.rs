@@ -0,0 +1,49 @@
+//! Contains a registry of per-marketplace backends, keyed by marketplace identifier.
+use std::{collections::HashMap, sync::Arc};
+use crate::galleries::domain_types::Marketplace;
+
+/// Maps marketplace identifiers to their backend (ie a scraper) as a trait object.
+/// 
+/// A marketplace's identifier is its `Display` string (ie `Mercari`).
+/// This lets modules dispatch to a marketplace's backend without matching on `Marketplace` everywhere;
+/// supporting a new marketplace only requires registering its backend at startup.
+/// 
+/// Clones share the same backends.
+pub struct MarketplaceRegistry<B: ?Sized> {
+    backends: HashMap<String, Arc<B>>
+}
+
+impl<B: ?Sized> MarketplaceRegistry<B> {
+    /// Instantiate an empty registry.
+    pub fn new() -> Self {
+        Self {
+            backends: HashMap::new()
+        }
+    }
+
+    /// Register a backend under a marketplace identifier, replacing (and returning) any previously registered backend.
+    pub fn register(&mut self, marketplace_id: impl Into<String>, backend: Arc<B>) -> Option<Arc<B>> {
+        let marketplace_id = marketplace_id.into();
+        tracing::debug!("Registering backend for marketplace {marketplace_id}");
+        self.backends.insert(marketplace_id, backend)
+    }
+
+    /// Get the backend registered for a marketplace, if there is one.
+    pub fn get(&self, marketplace: &Marketplace) -> Option<&Arc<B>> {
+        self.backends.get(&marketplace.to_string())
+    }
+}
+
+impl<B: ?Sized> Clone for MarketplaceRegistry<B> {
+    fn clone(&self) -> Self {
+        Self {
+            backends: self.backends.clone()
+        }
+    }
+}
+
+impl<B: ?Sized> Default for MarketplaceRegistry<B> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -1,2 +1,13 @@
 pub mod generate_dpop;
-pub mod serialize_to_string;
\ No newline at end of file
+pub mod serialize_to_string;
+pub mod rate_limiter;
+pub mod idempotency_cache;
+pub mod circuit_breaker;
+pub mod layout_monitor;
+pub mod marketplace_registry;
+pub mod user_agent_pool;
+pub mod proxy;
+pub mod http_client;
+pub mod exchange_rates;
+pub mod tracing_context;
+pub mod serialization;
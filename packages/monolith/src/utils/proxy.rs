@@ -0,0 +1,15 @@
+//! Contains proxy configuration for outbound marketplace requests.
+//! 
+//! Proxied clients are built by `HttpClientFactory::client_with_proxy`.
+use serde::{Deserialize, Serialize};
+
+/// A proxy to route requests through:
+/// - `url`: The proxy's URL; `http://`, `https://` and `socks5://` schemes are supported
+/// - `username`: The username for the proxy, if it requires authentication
+/// - `password`: The password for the proxy, if it requires authentication
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ProxyConfig {
+    pub url: String,
+    pub username: Option<String>,
+    pub password: Option<String>
+}
@@ -0,0 +1,96 @@
+//! Contains a token-bucket rate limiter, keyed by marketplace.
+use std::{collections::HashMap, sync::Arc, time::{Duration, Instant}};
+use serde::{Deserialize, Serialize};
+use tokio::sync::Mutex;
+use crate::galleries::domain_types::Marketplace;
+
+/// The rate limit for requests to a marketplace:
+/// - `requests_per_sec`: The rate at which tokens are refilled
+/// - `burst`: The max number of tokens that can be held at once
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RateLimit {
+    pub requests_per_sec: f64,
+    pub burst: u32
+}
+
+/// A token bucket for a single marketplace.
+#[derive(Debug)]
+struct TokenBucket {
+    rate_limit: RateLimit,
+    tokens: f64,
+    last_refill: Instant
+}
+
+impl TokenBucket {
+    /// Instantiate the bucket, starting full.
+    fn new(rate_limit: RateLimit) -> Self {
+        Self {
+            tokens: rate_limit.burst as f64,
+            rate_limit,
+            last_refill: Instant::now()
+        }
+    }
+
+    /// Try to take a token.
+    /// 
+    /// Returns an `Err` with how long to wait until a token is available if the bucket is empty.
+    fn try_take(&mut self) -> Result<(), Duration> {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last_refill).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate_limit.requests_per_sec).min(self.rate_limit.burst as f64);
+        self.last_refill = now;
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            return Ok(());
+        }
+        let wait_secs = (1.0 - self.tokens) / self.rate_limit.requests_per_sec;
+        Err(Duration::from_secs_f64(wait_secs))
+    }
+}
+
+/// A token-bucket rate limiter, with one bucket per marketplace.
+/// 
+/// Marketplaces without a configured rate limit aren't limited.
+/// 
+/// Clones share the same buckets.
+#[derive(Debug, Clone)]
+pub struct MarketplaceRateLimiter {
+    buckets: Arc<HashMap<Marketplace, Mutex<TokenBucket>>>
+}
+
+impl MarketplaceRateLimiter {
+    /// Instantiate the rate limiter.
+    /// 
+    /// Rate limits with a non-positive rate or no burst are ignored.
+    pub fn new(rate_limits: &HashMap<Marketplace, RateLimit>) -> Self {
+        let buckets = rate_limits
+            .iter()
+            .filter(|(marketplace, rate_limit)| {
+                let valid = rate_limit.requests_per_sec > 0.0 && rate_limit.burst > 0;
+                if !valid {
+                    tracing::warn!("Ignoring invalid rate limit for {marketplace}: {rate_limit:?}");
+                }
+                valid
+            })
+            .map(|(marketplace, rate_limit)| (marketplace.clone(), Mutex::new(TokenBucket::new(rate_limit.clone()))))
+            .collect();
+        Self {
+            buckets: Arc::new(buckets)
+        }
+    }
+
+    /// Acquire a token for a request to the marketplace, waiting until one is available if needed.
+    pub async fn acquire(&self, marketplace: &Marketplace) {
+        let Some(bucket) = self.buckets.get(marketplace) else {
+            return;
+        };
+        loop {
+            let wait = match bucket.lock().await.try_take() {
+                Ok(_) => return,
+                Err(wait) => wait
+            };
+            tracing::trace!("Rate limited for {marketplace}; waiting {wait:?}");
+            tokio::time::sleep(wait).await;
+        }
+    }
+}
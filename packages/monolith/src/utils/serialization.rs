@@ -0,0 +1,25 @@
+//! Contains the (de)serialization of states and items persisted by the state tracker store and storage.
+use serde::{de::DeserializeOwned, Serialize};
+use crate::config::SerializationFormat;
+
+/// Serialize a value in the given format.
+/// 
+/// MessagePack values keep their field names, so `#[serde(default)]` fields still deserialize as in JSON.
+pub fn serialize<T: Serialize + ?Sized>(format: &SerializationFormat, value: &T) -> Result<Vec<u8>, String> {
+    match format {
+        SerializationFormat::Json => serde_json::to_vec(value)
+            .map_err(|err| format!("Failed to serialize as JSON: {err}")),
+        SerializationFormat::MessagePack => rmp_serde::to_vec_named(value)
+            .map_err(|err| format!("Failed to serialize as MessagePack: {err}"))
+    }
+}
+
+/// Deserialize a value which was serialized in the given format.
+pub fn deserialize<T: DeserializeOwned>(format: &SerializationFormat, bytes: &[u8]) -> Result<T, String> {
+    match format {
+        SerializationFormat::Json => serde_json::from_slice(bytes)
+            .map_err(|err| format!("Failed to deserialize JSON: {err}")),
+        SerializationFormat::MessagePack => rmp_serde::from_slice(bytes)
+            .map_err(|err| format!("Failed to deserialize MessagePack: {err}"))
+    }
+}
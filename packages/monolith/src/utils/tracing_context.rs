@@ -0,0 +1,19 @@
+//! Contains the tracing spans used to correlate a gallery's logs across modules.
+//! 
+//! Each scrape of a gallery runs in a `gallery_run` span, keyed on its gallery ID and run ID.
+//! Messages carry the span they were sent in, and each module handles them in a child span of it,
+//! so all logs for one scrape share the `gallery_id` and `run_id` fields, whichever module they're from.
+use tracing::Span;
+use crate::{galleries::domain_types::{GalleryId, RunId}, scraping_pipeline::module_health::PipelineModule};
+
+/// Open the span for a single run of a gallery through the pipeline.
+pub fn gallery_run_span(gallery_id: &GalleryId, run_id: &RunId) -> Span {
+    tracing::info_span!("gallery_run", gallery_id = %gallery_id, run_id = %run_id)
+}
+
+/// Open the span for a module handling a message, as a child of the span the message was sent in.
+/// 
+/// If the message wasn't sent in a span, this is a root span.
+pub fn module_span(parent: &Span, module: PipelineModule) -> Span {
+    tracing::info_span!(parent: parent, "module", module = ?module)
+}
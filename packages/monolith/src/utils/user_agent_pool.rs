@@ -0,0 +1,38 @@
+//! Contains a pool of user agents, rotated through for outbound scraper requests.
+use std::sync::{atomic::{AtomicUsize, Ordering}, Arc};
+
+/// The user agent used if the pool is empty.
+const DEFAULT_USER_AGENT: &str = "Mozilla/5.0 (Windows NT 10.0; Win64; x64) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/124.0.0.0 Safari/537.36";
+
+/// A pool of user agents, handed out round-robin so that requests don't all share one fingerprint.
+///
+/// Clones share the same pool and position.
+#[derive(Debug, Clone)]
+pub struct UserAgentPool {
+    user_agents: Arc<Vec<String>>,
+    next: Arc<AtomicUsize>
+}
+
+impl UserAgentPool {
+    /// Instantiate the pool.
+    ///
+    /// If `user_agents` is empty, a default user agent is always used instead.
+    pub fn new(user_agents: Vec<String>) -> Self {
+        if user_agents.is_empty() {
+            tracing::warn!("No user agents configured; falling back to the default user agent");
+        }
+        Self {
+            user_agents: Arc::new(user_agents),
+            next: Arc::new(AtomicUsize::new(0))
+        }
+    }
+
+    /// Get the user agent to use for the next request.
+    pub fn next_user_agent(&self) -> &str {
+        if self.user_agents.is_empty() {
+            return DEFAULT_USER_AGENT;
+        }
+        let index = self.next.fetch_add(1, Ordering::Relaxed) % self.user_agents.len();
+        &self.user_agents[index]
+    }
+}